@@ -0,0 +1,368 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use rand::distr::{Alphanumeric, SampleString};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Default location of the invite list, relative to the working directory.
+pub const DEFAULT_INVITES_FILE: &str = "invites.json";
+
+const TOKEN_LENGTH: usize = 16;
+
+/// Who may connect at all, checked in the handshake before a client gets a
+/// nickname or joins a room.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AccessConfig {
+    /// Shared chatroom password. Invite tokens are accepted in its place.
+    pub password: Option<String>,
+    /// Turn away clients without a valid invite token or the password,
+    /// even when no password is set.
+    pub invite_only: bool,
+    pub invites_file: PathBuf,
+}
+
+impl Default for AccessConfig {
+    fn default() -> Self {
+        AccessConfig {
+            password: None,
+            invite_only: false,
+            invites_file: PathBuf::from(DEFAULT_INVITES_FILE),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Invite {
+    pub token: String,
+    /// `None` means the invite never expires.
+    pub until: Option<DateTime<Utc>>,
+    /// How many different redeemers may use it. A use covers an identity
+    /// key, or an IP address for clients without one, not a person: a
+    /// single-use invite lets in everyone behind the same NAT, while each
+    /// new identity key takes another use.
+    pub max_uses: u32,
+    /// `key:<public key>` or `ip:<address>` of each redeemer. They can keep
+    /// reconnecting with the same token until it expires.
+    pub redeemed_by: Vec<String>,
+    pub created: DateTime<Utc>,
+}
+
+impl Invite {
+    fn is_active(&self, now: DateTime<Utc>) -> bool {
+        self.until.is_none_or(|until| now < until)
+    }
+
+    fn uses_left(&self) -> u32 {
+        self.max_uses.saturating_sub(self.redeemed_by.len() as u32)
+    }
+
+    /// Human readable description used in the host console.
+    pub fn describe(&self) -> String {
+        let expiry = match self.until {
+            Some(until) => format!("until {}", until.format("%Y-%m-%d %H:%M:%S UTC")),
+            None => "with no expiry".to_string(),
+        };
+        format!(
+            "{}: {} of {} use(s) left, valid {}",
+            self.token,
+            self.uses_left(),
+            self.max_uses,
+            expiry
+        )
+    }
+}
+
+/// Invite tokens minted by the host, stored as a JSON array so redeemed
+/// uses survive a restart.
+pub struct InviteList {
+    path: PathBuf,
+    invites: Vec<Invite>,
+}
+
+impl InviteList {
+    /// Loads the list from `path`, starting empty if the file doesn't exist yet.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let invites = if path.exists() {
+            let data = fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read invite list {}", path.display()))?;
+            serde_json::from_str(&data)
+                .with_context(|| format!("Invite list {} is corrupt", path.display()))?
+        } else {
+            Vec::new()
+        };
+        let mut list = InviteList { path, invites };
+        list.prune();
+        Ok(list)
+    }
+
+    /// An empty list that is still saved to `path` once invites are created.
+    pub fn empty(path: impl AsRef<Path>) -> Self {
+        InviteList {
+            path: path.as_ref().to_path_buf(),
+            invites: Vec::new(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn save(&self) -> Result<()> {
+        let data = serde_json::to_string_pretty(&self.invites)?;
+        fs::write(&self.path, data)
+            .with_context(|| format!("Failed to write invite list {}", self.path.display()))
+    }
+
+    fn prune(&mut self) {
+        let now = Utc::now();
+        self.invites.retain(|invite| invite.is_active(now));
+    }
+
+    /// Mints a random token good for `max_uses` clients, expiring after
+    /// `duration` if given, and writes the list to disk.
+    pub fn create(&mut self, duration: Option<Duration>, max_uses: u32) -> Result<Invite> {
+        let now = Utc::now();
+        let until = duration
+            .map(|d| {
+                chrono::Duration::from_std(d)
+                    .ok()
+                    .and_then(|d| now.checked_add_signed(d))
+                    .context("Invite duration is too long")
+            })
+            .transpose()?;
+        self.prune();
+        let invite = Invite {
+            token: Alphanumeric.sample_string(&mut rand::rng(), TOKEN_LENGTH),
+            until,
+            max_uses: max_uses.max(1),
+            redeemed_by: Vec::new(),
+            created: now,
+        };
+        self.invites.push(invite.clone());
+        self.save()?;
+        Ok(invite)
+    }
+
+    /// Withdraws an invite, also from those who already redeemed it.
+    /// Returns false if there was no such token.
+    pub fn revoke(&mut self, token: &str) -> Result<bool> {
+        self.prune();
+        let before = self.invites.len();
+        self.invites.retain(|invite| invite.token != token);
+        let removed = self.invites.len() != before;
+        if removed {
+            self.save()?;
+        }
+        Ok(removed)
+    }
+
+    pub fn active(&self) -> Vec<&Invite> {
+        let now = Utc::now();
+        self.invites
+            .iter()
+            .filter(|invite| invite.is_active(now))
+            .collect()
+    }
+
+    /// Whether `token` lets `redeemer` in: `Some(true)` if that takes one
+    /// of its uses, `Some(false)` if they redeemed it before.
+    fn admits(&self, token: &str, redeemer: &str) -> Option<bool> {
+        let invite = self
+            .active()
+            .into_iter()
+            .find(|invite| same_secret(&invite.token, token))?;
+        if invite.redeemed_by.iter().any(|r| r == redeemer) {
+            Some(false)
+        } else {
+            (invite.uses_left() > 0).then_some(true)
+        }
+    }
+
+    /// Records that `redeemer` used `token` and writes the list to disk.
+    /// Returns false, recording nothing, if the invite no longer lets them
+    /// in, e.g. because another client took its last use in the meantime.
+    /// The use counts for this run even if writing fails.
+    pub fn redeem(&mut self, token: &str, redeemer: &str) -> Result<bool> {
+        match self.admits(token, redeemer) {
+            None => return Ok(false),
+            Some(false) => return Ok(true),
+            Some(true) => {}
+        }
+        if let Some(invite) = self.invites.iter_mut().find(|i| i.token == token) {
+            invite.redeemed_by.push(redeemer.to_string());
+        }
+        self.save()?;
+        Ok(true)
+    }
+}
+
+/// How a client got past `Access::check`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Admission {
+    /// The chatroom is open to anyone.
+    Open,
+    Password,
+    /// `first_use` is set when the client took one of the invite's uses.
+    Invite {
+        token: String,
+        first_use: bool,
+    },
+}
+
+/// The chatroom password and invites, see `AccessConfig`.
+pub struct Access {
+    password: Option<String>,
+    invite_only: bool,
+    pub invites: InviteList,
+}
+
+impl Access {
+    pub fn new(password: Option<String>, invite_only: bool, invites: InviteList) -> Self {
+        Access {
+            password: password.filter(|p| !p.is_empty()),
+            invite_only,
+            invites,
+        }
+    }
+
+    /// Whether clients need the password or an invite token to connect.
+    pub fn is_restricted(&self) -> bool {
+        self.password.is_some() || self.invite_only
+    }
+
+    /// Checks `secret`, the password or an invite token the client sent.
+    /// `redeemer` identifies the client across reconnects, so coming back
+    /// with an invite it already redeemed doesn't take another use; record
+    /// a first use with `InviteList::redeem`. See `Invite::max_uses` for
+    /// what counts as one client.
+    pub fn check(&self, secret: Option<&str>, redeemer: &str) -> Result<Admission, String> {
+        if !self.is_restricted() {
+            return Ok(Admission::Open);
+        }
+        let Some(secret) = secret.filter(|s| !s.is_empty()) else {
+            return Err(match (&self.password, self.invite_only) {
+                (Some(_), false) => "This chatroom requires a password",
+                (Some(_), true) => "This chatroom requires a password or an invite token",
+                (None, _) => "This chatroom requires an invite token",
+            }
+            .to_string());
+        };
+        if self
+            .password
+            .as_deref()
+            .is_some_and(|password| same_secret(password, secret))
+        {
+            return Ok(Admission::Password);
+        }
+        match self.invites.admits(secret, redeemer) {
+            Some(first_use) => Ok(Admission::Invite {
+                token: secret.to_string(),
+                first_use,
+            }),
+            None if self.password.is_some() => Err("Wrong password or invite token".to_string()),
+            None => Err("Invite token is invalid, expired or used up".to_string()),
+        }
+    }
+}
+
+/// Compares secrets without leaking through timing how much of a guess
+/// was right.
+fn same_secret(a: &str, b: &str) -> bool {
+    let (a, b) = (Sha256::digest(a), Sha256::digest(b));
+    a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("hotline-{}-{}.json", name, rand::random::<u64>()))
+    }
+
+    fn invite_only(path: &Path) -> Access {
+        Access::new(None, true, InviteList::empty(path))
+    }
+
+    #[test]
+    fn checking_an_invite_takes_no_use_until_it_is_redeemed() {
+        let path = temp_path("invites");
+        let mut access = invite_only(&path);
+        let token = access.invites.create(None, 1).unwrap().token;
+        let first_use = Admission::Invite {
+            token: token.clone(),
+            first_use: true,
+        };
+        // A handshake that fails after the check mustn't burn the invite
+        assert_eq!(
+            access.check(Some(&token), "ip:10.0.0.1"),
+            Ok(first_use.clone())
+        );
+        assert_eq!(access.check(Some(&token), "ip:10.0.0.2"), Ok(first_use));
+
+        assert!(access.invites.redeem(&token, "ip:10.0.0.1").unwrap());
+        assert_eq!(
+            access.check(Some(&token), "ip:10.0.0.1"),
+            Ok(Admission::Invite {
+                token: token.clone(),
+                first_use: false,
+            })
+        );
+        assert!(access.check(Some(&token), "ip:10.0.0.2").is_err());
+        assert!(!access.invites.redeem(&token, "ip:10.0.0.2").unwrap());
+
+        let reloaded = InviteList::load(&path).unwrap();
+        assert_eq!(reloaded.active()[0].redeemed_by, vec!["ip:10.0.0.1"]);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn each_use_covers_one_key_or_address() {
+        let path = temp_path("invites");
+        let mut access = invite_only(&path);
+        let token = access.invites.create(None, 2).unwrap().token;
+        // Everyone behind the same address counts as the one redeemer
+        assert!(access.invites.redeem(&token, "ip:10.0.0.1").unwrap());
+        assert!(access.invites.redeem(&token, "ip:10.0.0.1").unwrap());
+        assert!(access.invites.active()[0].describe().contains("1 of 2"));
+
+        // Whereas a new key takes a use even from that same address
+        assert!(access.invites.redeem(&token, "key:AAAA").unwrap());
+        assert!(!access.invites.redeem(&token, "key:BBBB").unwrap());
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn expired_and_unknown_invites_are_refused() {
+        let path = temp_path("invites");
+        let mut access = invite_only(&path);
+        let token = access
+            .invites
+            .create(Some(Duration::ZERO), 5)
+            .unwrap()
+            .token;
+        assert!(access.check(Some(&token), "ip:10.0.0.1").is_err());
+        assert!(access.check(Some("nonsense"), "ip:10.0.0.1").is_err());
+        assert!(access.check(None, "ip:10.0.0.1").is_err());
+        assert!(access.invites.create(Some(Duration::MAX), 1).is_err());
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn password_or_invite_lets_clients_in() {
+        let path = temp_path("invites");
+        let access = Access::new(Some("hunter2".to_string()), false, InviteList::empty(&path));
+        assert_eq!(
+            access.check(Some("hunter2"), "ip:10.0.0.1"),
+            Ok(Admission::Password)
+        );
+        assert!(access.check(Some("hunter3"), "ip:10.0.0.1").is_err());
+        let open = Access::new(Some(String::new()), false, InviteList::empty(&path));
+        assert_eq!(open.check(None, "ip:10.0.0.1"), Ok(Admission::Open));
+    }
+}
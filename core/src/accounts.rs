@@ -0,0 +1,190 @@
+use anyhow::{Context, Result, anyhow, bail};
+use argon2::Argon2;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::roles::Role;
+use crate::tls::write_atomic;
+
+/// Default location of the account store, relative to the working directory.
+pub const DEFAULT_ACCOUNTS_FILE: &str = "accounts.json";
+
+/// Shortest password `/register` accepts.
+pub const MIN_PASSWORD_CHARS: usize = 8;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Account {
+    /// As registered; lookups ignore case.
+    pub username: String,
+    /// Argon2id hash in PHC string format, salt included.
+    pub password_hash: String,
+    /// Accounts stored before roles existed are members.
+    #[serde(default)]
+    pub role: Role,
+    pub created: DateTime<Utc>,
+}
+
+/// Hashes `password` with Argon2id and a random salt. Slow and memory-hard
+/// on purpose, so call it off the async runtime.
+pub fn hash_password(password: &str) -> Result<String> {
+    let salt = SaltString::encode_b64(&rand::random::<[u8; 16]>())
+        .map_err(|e| anyhow!("Failed to generate a salt: {}", e))?;
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| anyhow!("Failed to hash the password: {}", e))
+}
+
+/// Checks `password` against a hash from `hash_password`. As slow as hashing.
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+}
+
+/// Registered accounts, stored as a JSON array so the names stay owned
+/// across restarts. Only password hashes are kept.
+pub struct AccountStore {
+    path: PathBuf,
+    accounts: BTreeMap<String, Account>,
+}
+
+impl AccountStore {
+    /// Loads the accounts from `path`, starting empty if the file doesn't exist yet.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let accounts: Vec<Account> = if path.exists() {
+            let data = fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read accounts {}", path.display()))?;
+            serde_json::from_str(&data)
+                .with_context(|| format!("Accounts file {} is corrupt", path.display()))?
+        } else {
+            Vec::new()
+        };
+        Ok(AccountStore {
+            path,
+            accounts: accounts
+                .into_iter()
+                .map(|a| (a.username.to_lowercase(), a))
+                .collect(),
+        })
+    }
+
+    /// No accounts yet, still saved to `path` once someone registers.
+    pub fn empty(path: impl AsRef<Path>) -> Self {
+        AccountStore {
+            path: path.as_ref().to_path_buf(),
+            accounts: BTreeMap::new(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn len(&self) -> usize {
+        self.accounts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.accounts.is_empty()
+    }
+
+    fn save(&self) -> Result<()> {
+        let accounts: Vec<&Account> = self.accounts.values().collect();
+        let data = serde_json::to_string_pretty(&accounts)?;
+        // Password hashes are nobody else's business
+        write_atomic(&self.path, &data, true)
+            .with_context(|| format!("Failed to write accounts {}", self.path.display()))
+    }
+
+    pub fn get(&self, username: &str) -> Option<&Account> {
+        self.accounts.get(&username.to_lowercase())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Account> {
+        self.accounts.values()
+    }
+
+    /// Adds an account with a hash from `hash_password` and writes the store
+    /// to disk. Fails if the name is taken.
+    pub fn register(&mut self, username: &str, password_hash: String) -> Result<()> {
+        let key = username.to_lowercase();
+        if self.accounts.contains_key(&key) {
+            bail!("'{}' is already registered", username);
+        }
+        self.accounts.insert(
+            key,
+            Account {
+                username: username.to_string(),
+                password_hash,
+                role: Role::default(),
+                created: Utc::now(),
+            },
+        );
+        self.save()
+    }
+
+    /// Changes the role of an account and writes the store to disk. Returns
+    /// false if there is no such account.
+    pub fn set_role(&mut self, username: &str, role: Role) -> Result<bool> {
+        let Some(account) = self.accounts.get_mut(&username.to_lowercase()) else {
+            return Ok(false);
+        };
+        account.role = role;
+        self.save()?;
+        Ok(true)
+    }
+
+    /// Deletes an account, freeing its name. Returns false if there was none.
+    pub fn remove(&mut self, username: &str) -> Result<bool> {
+        if self.accounts.remove(&username.to_lowercase()).is_none() {
+            return Ok(false);
+        }
+        self.save()?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passwords_verify_only_against_their_own_hash() {
+        let hash = hash_password("correct horse").unwrap();
+        assert!(hash.starts_with("$argon2id$"));
+        assert!(verify_password("correct horse", &hash));
+        assert!(!verify_password("correct horsE", &hash));
+        assert!(!verify_password("correct horse", "not a hash"));
+        // Salted, so the same password never hashes the same twice
+        assert_ne!(hash, hash_password("correct horse").unwrap());
+    }
+
+    #[test]
+    fn accounts_are_unique_case_insensitively_and_persist() {
+        let path =
+            std::env::temp_dir().join(format!("hotline-accounts-{}.json", rand::random::<u64>()));
+        let mut accounts = AccountStore::empty(&path);
+        accounts.register("Alice", "hash".to_string()).unwrap();
+        assert!(accounts.register("alice", "other".to_string()).is_err());
+        assert!(accounts.set_role("ALICE", Role::Moderator).unwrap());
+        assert!(!accounts.set_role("bob", Role::Admin).unwrap());
+
+        let mut accounts = AccountStore::load(&path).unwrap();
+        let alice = accounts.get("alice").unwrap();
+        assert_eq!(
+            (alice.username.as_str(), alice.role),
+            ("Alice", Role::Moderator)
+        );
+        assert!(accounts.remove("alice").unwrap());
+        assert!(accounts.is_empty());
+        let _ = fs::remove_file(&path);
+    }
+}
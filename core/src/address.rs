@@ -0,0 +1,130 @@
+use std::net::{IpAddr, Ipv6Addr};
+
+/// Splits a user supplied address into host and port. Accepts `host`,
+/// `host:port`, bare IPv6 literals such as `::1`, and the bracketed form
+/// `[::1]:9000`; `default_port` is used when no port is given.
+pub fn parse_host_port(input: &str, default_port: u16) -> Result<(String, u16), String> {
+    let input = input.trim();
+    if input.is_empty() {
+        return Err("Address cannot be empty".to_string());
+    }
+
+    let (host, port) = if let Some(rest) = input.strip_prefix('[') {
+        let (host, after) = rest
+            .split_once(']')
+            .ok_or_else(|| format!("Missing ']' in '{}'", input))?;
+        if host.parse::<Ipv6Addr>().is_err() {
+            return Err(format!("'{}' is not an IPv6 address", host));
+        }
+        let port = match after {
+            "" => None,
+            _ => Some(
+                after
+                    .strip_prefix(':')
+                    .ok_or_else(|| format!("Unexpected '{}' after ']'", after))?,
+            ),
+        };
+        (host, port)
+    } else if input.matches(':').count() > 1 {
+        // More than one colon only makes sense for an unbracketed IPv6 literal
+        if input.parse::<Ipv6Addr>().is_err() {
+            return Err(format!(
+                "'{}' is not a valid address; write IPv6 with a port as [addr]:port",
+                input
+            ));
+        }
+        (input, None)
+    } else {
+        match input.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (input, None),
+        }
+    };
+
+    if host.is_empty() || host.chars().any(char::is_whitespace) {
+        return Err(format!("'{}' is not a valid host", host));
+    }
+    let port = match port {
+        Some(port) => port
+            .parse::<u16>()
+            .map_err(|_| format!("'{}' is not a valid port", port))?,
+        None => default_port,
+    };
+    Ok((host.to_string(), port))
+}
+
+/// Joins host and port for display, bracketing IPv6 literals.
+pub fn format_host_port(host: &str, port: u16) -> String {
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+/// Parses a bind address such as `0.0.0.0`, `::` or `[::]`.
+pub fn parse_bind_address(input: &str) -> Result<IpAddr, String> {
+    let trimmed = input.trim();
+    let unbracketed = trimmed
+        .strip_prefix('[')
+        .and_then(|s| s.strip_suffix(']'))
+        .unwrap_or(trimmed);
+    unbracketed
+        .parse::<IpAddr>()
+        .map_err(|_| format!("'{}' is not an IP address to bind to", input))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(input: &str) -> Result<(String, u16), String> {
+        parse_host_port(input, 8080)
+    }
+
+    #[test]
+    fn parses_hosts_with_and_without_ports() {
+        assert_eq!(parsed("example.com"), Ok(("example.com".to_string(), 8080)));
+        assert_eq!(
+            parsed(" example.com:9000 "),
+            Ok(("example.com".to_string(), 9000))
+        );
+        assert_eq!(parsed("10.0.0.1:1"), Ok(("10.0.0.1".to_string(), 1)));
+        assert_eq!(parsed("::1"), Ok(("::1".to_string(), 8080)));
+        assert_eq!(parsed("[::1]"), Ok(("::1".to_string(), 8080)));
+        assert_eq!(parsed("[fe80::1]:9000"), Ok(("fe80::1".to_string(), 9000)));
+    }
+
+    #[test]
+    fn rejects_malformed_addresses() {
+        for input in [
+            "",
+            ":9000",
+            "example.com:",
+            "example.com:65536",
+            "example.com:port",
+            "exa mple.com",
+            "[::1",
+            "[::1]9000",
+            "[example.com]:9000",
+            "fe80::1:9000:x",
+        ] {
+            assert!(parsed(input).is_err(), "'{}' was accepted", input);
+        }
+    }
+
+    #[test]
+    fn formats_and_binds_ipv6_in_brackets() {
+        assert_eq!(format_host_port("::1", 9000), "[::1]:9000");
+        assert_eq!(format_host_port("example.com", 9000), "example.com:9000");
+        let (host, port) = parsed(&format_host_port("fe80::1", 9000)).unwrap();
+        assert_eq!((host.as_str(), port), ("fe80::1", 9000));
+
+        assert_eq!(parse_bind_address("[::]"), Ok("::".parse().unwrap()));
+        assert_eq!(
+            parse_bind_address(" 0.0.0.0 "),
+            Ok("0.0.0.0".parse().unwrap())
+        );
+        assert!(parse_bind_address("localhost").is_err());
+    }
+}
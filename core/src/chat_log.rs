@@ -0,0 +1,371 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, NaiveDate, Utc};
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+
+use crate::protocol::{Frame, ServerFrame};
+use crate::serializable_colours::{GREEN_COLOR, RED_COLOR};
+use crate::types::{OutputEvent, TextLine};
+
+/// How long to wait before trying to reopen a log that failed, e.g. because
+/// the disk filled up.
+const REOPEN_RETRY: Duration = Duration::from_secs(30);
+
+/// What happens to transcripts older than `LogConfig::keep_days`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Retention {
+    Delete,
+    /// Compress to `.jsonl.gz`; compressed files are kept.
+    Gzip,
+}
+
+/// Where transcripts go and when they are rotated and cleaned up.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LogConfig {
+    /// Created if missing.
+    pub directory: PathBuf,
+    /// Start a new file when the UTC date changes.
+    pub rotate_daily: bool,
+    /// Start a new file once the current one reaches this size; 0 disables.
+    pub max_file_bytes: u64,
+    pub keep_days: u32,
+    pub retention: Retention,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            directory: PathBuf::from("logs"),
+            rotate_daily: true,
+            max_file_bytes: 10 * 1024 * 1024,
+            keep_days: 30,
+            retention: Retention::Gzip,
+        }
+    }
+}
+
+/// Handle for sending events to the logging task. Cloning is cheap and every
+/// connection task can hold one.
+#[derive(Clone)]
+pub struct ChatLogger {
+    tx: mpsc::Sender<ServerFrame>,
+}
+
+impl ChatLogger {
+    /// Starts a task that appends every logged event as a JSON line to a
+    /// transcript for `chatroom`, see `transcript` for reading it back.
+    /// Problems are reported on `output_tx`; events that arrive while the log
+    /// can't be written are dropped until it can be reopened.
+    pub fn spawn(chatroom: &str, config: LogConfig, output_tx: mpsc::Sender<OutputEvent>) -> Self {
+        let (tx, mut rx) = mpsc::channel::<ServerFrame>(256);
+        let mut writer = LogWriter {
+            chatroom: chatroom.to_string(),
+            config,
+            output_tx,
+            file: None,
+            opened_on: None,
+            bytes: 0,
+            last_failure: None,
+            dropped: 0,
+        };
+
+        tokio::spawn(async move {
+            writer.open().await;
+            while let Some(frame) = rx.recv().await {
+                writer.write(&frame).await;
+            }
+        });
+
+        ChatLogger { tx }
+    }
+
+    /// Queues an event for the log.
+    pub async fn log(&self, frame: &ServerFrame) {
+        let _ = self.tx.send(frame.clone()).await;
+    }
+}
+
+struct LogWriter {
+    chatroom: String,
+    config: LogConfig,
+    output_tx: mpsc::Sender<OutputEvent>,
+    file: Option<(tokio::fs::File, PathBuf)>,
+    opened_on: Option<NaiveDate>,
+    bytes: u64,
+    last_failure: Option<Instant>,
+    /// Events lost since the last failure.
+    dropped: u64,
+}
+
+impl LogWriter {
+    async fn write(&mut self, frame: &ServerFrame) {
+        let Ok(line) = frame.to_line() else {
+            return;
+        };
+
+        if self.file.is_some() && self.needs_rotation(Utc::now()) {
+            self.file = None;
+            self.open().await;
+        }
+        if self.file.is_none() {
+            let retry_due = self
+                .last_failure
+                .is_none_or(|failed| failed.elapsed() >= REOPEN_RETRY);
+            if retry_due {
+                self.open().await;
+            }
+        }
+
+        let Some((file, path)) = &mut self.file else {
+            self.dropped += 1;
+            return;
+        };
+        let written = async {
+            file.write_all(line.as_bytes()).await?;
+            file.flush().await
+        }
+        .await;
+
+        match written {
+            Ok(()) => self.bytes += line.len() as u64,
+            Err(e) => {
+                let text = format!(
+                    "Failed to write chat log {}: {}; retrying in {}s",
+                    path.display(),
+                    e,
+                    REOPEN_RETRY.as_secs()
+                );
+                self.file = None;
+                self.last_failure = Some(Instant::now());
+                self.dropped += 1;
+                self.report(text, false).await;
+            }
+        }
+    }
+
+    fn needs_rotation(&self, now: DateTime<Utc>) -> bool {
+        let new_day = self.config.rotate_daily && self.opened_on != Some(now.date_naive());
+        let too_big = self.config.max_file_bytes > 0 && self.bytes >= self.config.max_file_bytes;
+        new_day || too_big
+    }
+
+    /// Opens a fresh transcript and applies the retention policy to old ones.
+    async fn open(&mut self) {
+        let now = Utc::now();
+        let result = create_log_file(&self.config.directory, &self.chatroom, now);
+
+        match result {
+            Ok((file, path)) => {
+                let text = if self.dropped > 0 {
+                    format!(
+                        "Logging resumed in {} ({} events were not logged)",
+                        path.display(),
+                        self.dropped
+                    )
+                } else {
+                    format!("Logging to {}", path.display())
+                };
+                self.report(text, true).await;
+
+                self.file = Some((tokio::fs::File::from_std(file), path.clone()));
+                self.opened_on = Some(now.date_naive());
+                self.bytes = 0;
+                self.last_failure = None;
+                self.dropped = 0;
+
+                let directory = self.config.directory.clone();
+                let chatroom = self.chatroom.clone();
+                let config = self.config.clone();
+                let swept = tokio::task::spawn_blocking(move || {
+                    apply_retention(&directory, &chatroom, &config, &path)
+                })
+                .await;
+                if let Ok(Err(e)) = swept {
+                    self.report(format!("Log retention failed: {:#}", e), false)
+                        .await;
+                }
+            }
+            Err(e) => {
+                self.last_failure = Some(Instant::now());
+                self.report(
+                    format!(
+                        "{:#}; chat is not being logged, retrying in {}s",
+                        e,
+                        REOPEN_RETRY.as_secs()
+                    ),
+                    false,
+                )
+                .await;
+            }
+        }
+    }
+
+    async fn report(&self, text: String, ok: bool) {
+        let color = if ok { &GREEN_COLOR } else { &RED_COLOR };
+        let _ = self
+            .output_tx
+            .send(OutputEvent::TextLine(TextLine {
+                text,
+                color: Some(color.clone()),
+            }))
+            .await;
+    }
+}
+
+fn log_prefix(chatroom: &str) -> String {
+    format!("chatroom_{}_", chatroom)
+}
+
+/// Whether `name` is one of `chatroom`'s transcripts, as named by
+/// `create_log_file` and maybe gzipped. Transcripts of a room whose name
+/// merely starts with this one's, such as `lobby_old` for `lobby`, aren't.
+fn is_log_of(name: &str, chatroom: &str) -> bool {
+    let Some(rest) = name.strip_prefix(&log_prefix(chatroom)) else {
+        return false;
+    };
+    let rest = rest.strip_suffix(".gz").unwrap_or(rest);
+    let Some(stamp) = rest.strip_suffix(".jsonl") else {
+        return false;
+    };
+    let digits = |part: Option<&str>, len: Option<usize>| {
+        part.is_some_and(|part| {
+            !part.is_empty()
+                && part.bytes().all(|b| b.is_ascii_digit())
+                && len.is_none_or(|len| part.len() == len)
+        })
+    };
+    // <YYYYMMDD>_<HHMMSS>, then an optional counter
+    let mut parts = stamp.split('_');
+    digits(parts.next(), Some(8))
+        && digits(parts.next(), Some(6))
+        && match parts.next() {
+            None => true,
+            counter => digits(counter, None) && parts.next().is_none(),
+        }
+}
+
+/// Creates `<dir>/chatroom_<name>_<timestamp>.jsonl`, adding a counter if a
+/// file rotated within the same second already has that name.
+fn create_log_file(
+    directory: &Path,
+    chatroom: &str,
+    now: DateTime<Utc>,
+) -> Result<(File, PathBuf)> {
+    fs::create_dir_all(directory)
+        .with_context(|| format!("Failed to create log directory {}", directory.display()))?;
+
+    let stem = format!("{}{}", log_prefix(chatroom), now.format("%Y%m%d_%H%M%S"));
+    let mut path = directory.join(format!("{}.jsonl", stem));
+    let mut counter = 1;
+    loop {
+        match File::create_new(&path) {
+            Ok(file) => return Ok((file, path)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                path = directory.join(format!("{}_{}.jsonl", stem, counter));
+                counter += 1;
+            }
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to create log file {}", path.display()));
+            }
+        }
+    }
+}
+
+/// Deletes or compresses this chatroom's transcripts older than `keep_days`,
+/// never touching `current`.
+fn apply_retention(
+    directory: &Path,
+    chatroom: &str,
+    config: &LogConfig,
+    current: &Path,
+) -> Result<()> {
+    let max_age = Duration::from_secs(u64::from(config.keep_days) * 24 * 60 * 60);
+
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+            continue;
+        };
+        let compressed = name.ends_with(".jsonl.gz");
+        if path == current || !is_log_of(name, chatroom) {
+            continue;
+        }
+
+        let age = fs::metadata(&path)?
+            .modified()?
+            .elapsed()
+            .unwrap_or_default();
+        if age < max_age {
+            continue;
+        }
+
+        match config.retention {
+            Retention::Delete => fs::remove_file(&path)
+                .with_context(|| format!("Failed to delete {}", path.display()))?,
+            Retention::Gzip if !compressed => gzip(&path)?,
+            Retention::Gzip => {}
+        }
+    }
+    Ok(())
+}
+
+/// Replaces `path` with `path.gz`, keeping the original modification time so
+/// the file's age is still known.
+fn gzip(path: &Path) -> Result<()> {
+    let target = PathBuf::from(format!("{}.gz", path.display()));
+    let modified = fs::metadata(path)?.modified().unwrap_or(SystemTime::now());
+
+    let compress = || -> io::Result<()> {
+        let mut input = File::open(path)?;
+        let mut encoder = GzEncoder::new(File::create(&target)?, Compression::default());
+        io::copy(&mut input, &mut encoder)?;
+        encoder.finish()?.set_modified(modified)
+    };
+    if let Err(e) = compress() {
+        // Don't leave a half written archive next to the original
+        let _ = fs::remove_file(&target);
+        return Err(e).with_context(|| format!("Failed to compress {}", path.display()));
+    }
+    fs::remove_file(path).with_context(|| format!("Failed to remove {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_only_this_rooms_transcripts() {
+        assert!(is_log_of("chatroom_lobby_20260101_120000.jsonl", "lobby"));
+        assert!(is_log_of("chatroom_lobby_20260101_120000_2.jsonl", "lobby"));
+        assert!(is_log_of(
+            "chatroom_lobby_20260101_120000.jsonl.gz",
+            "lobby"
+        ));
+
+        assert!(!is_log_of(
+            "chatroom_lobby_old_20260101_120000.jsonl",
+            "lobby"
+        ));
+        assert!(!is_log_of(
+            "chatroom_lobby_20260101_20260101_120000.jsonl",
+            "lobby"
+        ));
+        assert!(!is_log_of(
+            "chatroom_lobby_20260101_120000_1_2.jsonl",
+            "lobby"
+        ));
+        assert!(!is_log_of("chatroom_lobby_20260101_120000.txt", "lobby"));
+        assert!(!is_log_of("chatroom_lobby2_20260101_120000.jsonl", "lobby"));
+    }
+}
@@ -0,0 +1,1215 @@
+use anyhow::{Context, Result, anyhow, bail};
+use chrono::{DateTime, Utc};
+use futures_core::Stream;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{self, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+use tokio_stream::StreamExt;
+
+use crate::commands::ConnectRequest;
+use crate::e2e::{self, RoomKey};
+use crate::heartbeat::{Heartbeat, HeartbeatConfig};
+use crate::identity::Identity;
+use crate::protocol::{
+    Capability, ChatPayload, ClientFrame, DEFAULT_CLIENT_MAX_FRAME_BYTES, Frame, FrameReader,
+    Incoming, PROTOCOL_VERSION, Resume, RoomInfo, ServerFrame, frame_reader, negotiate_version,
+    supported_capabilities, write_frame,
+};
+use crate::roles::Role;
+use crate::tls::{self, KnownServers, TlsInfo};
+use crate::transport::{self, ReadHalf, WriteHalf};
+
+/// Events buffered before the reader waits for the application to catch up.
+const EVENT_BUFFER: usize = 100;
+
+/// How long the server has for each handshake step by default. Longer than
+/// its own limit, as it may be checking an account password before it answers.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The reader task answers pings, so it writes too.
+type SharedWriter = Arc<tokio::sync::Mutex<BufWriter<WriteHalf>>>;
+
+/// What the server told us when it accepted the connection.
+#[derive(Debug, Clone)]
+pub struct SessionInfo {
+    pub room: String,
+    pub version: u32,
+    pub capabilities: Vec<Capability>,
+    /// The name the server accepted, `None` when connected anonymously.
+    pub username: Option<String>,
+    pub local_addr: SocketAddr,
+    /// Longest frame the server accepts, if it said.
+    pub max_frame_bytes: Option<usize>,
+    /// Set when the connection is encrypted.
+    pub tls: Option<TlsInfo>,
+    /// Fingerprint of the identity key the server had us prove.
+    pub identity: Option<String>,
+}
+
+/// Tuning for `HotlineClient::connect_with`.
+#[derive(Debug, Clone)]
+pub struct ClientOptions {
+    /// When the server goes quiet for longer than the timeout, the client
+    /// gives up on the connection and reports `Disconnected`.
+    pub heartbeat: HeartbeatConfig,
+    /// Longest frame accepted from the server; longer ones are skipped with
+    /// an `Error` event.
+    pub max_frame_bytes: usize,
+    /// Where server certificates are pinned for TLS connections.
+    pub known_servers: PathBuf,
+    /// Proves who we are, so servers can keep others from using our nickname.
+    pub identity: Option<Identity>,
+    /// How long the TLS handshake and the server's welcome may each take.
+    pub handshake_timeout: Duration,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        ClientOptions {
+            heartbeat: HeartbeatConfig::default(),
+            max_frame_bytes: DEFAULT_CLIENT_MAX_FRAME_BYTES,
+            known_servers: KnownServers::default_path(),
+            identity: None,
+            handshake_timeout: HANDSHAKE_TIMEOUT,
+        }
+    }
+}
+
+/// Everything the server can tell a connected client.
+#[derive(Debug, Clone)]
+pub enum ClientEvent {
+    /// A chat message; `own` is set for the server's echo of our own messages.
+    Message {
+        message: ChatPayload,
+        own: bool,
+    },
+    UserJoined {
+        username: String,
+        room: String,
+        timestamp: DateTime<Utc>,
+    },
+    UserLeft {
+        username: String,
+        room: String,
+        timestamp: DateTime<Utc>,
+    },
+    UserRenamed {
+        old: String,
+        new: String,
+        room: String,
+        timestamp: DateTime<Utc>,
+    },
+    /// We moved into `room`. Messages in an `encrypted` room can only be
+    /// read and sent after `HotlineClient::encrypt_room`.
+    RoomJoined {
+        room: String,
+        members: Vec<String>,
+        encrypted: bool,
+        topic: Option<String>,
+    },
+    /// `by` set the topic of `room`, or cleared it when `topic` is `None`.
+    TopicChanged {
+        room: String,
+        topic: Option<String>,
+        by: String,
+        timestamp: DateTime<Utc>,
+    },
+    /// `by` switched `room` to end-to-end encryption.
+    RoomEncrypted {
+        room: String,
+        by: String,
+    },
+    Rooms(Vec<RoomInfo>),
+    /// Reply to `HotlineClient::whois`; `fingerprint` is `None` when the
+    /// name isn't bound to an identity key, and `account` is set when it
+    /// belongs to a registered account, whose `role` comes along.
+    /// Otherwise anyone may use it.
+    Whois {
+        username: String,
+        online: bool,
+        fingerprint: Option<String>,
+        account: bool,
+        role: Option<Role>,
+    },
+    /// We registered or logged in to the account `username`.
+    LoggedIn {
+        username: String,
+        registered: bool,
+    },
+    /// Earlier messages, oldest first. `resumed` marks the messages missed
+    /// while disconnected, sent after `HotlineClient::reconnect`.
+    History {
+        room: String,
+        messages: Vec<ChatPayload>,
+        has_more: bool,
+        resumed: bool,
+    },
+    Info(String),
+    /// The server refused a request, e.g. a taken nickname.
+    Error(String),
+    RateLimited {
+        seconds: f32,
+        reason: String,
+    },
+    /// The server dropped `missed` frames because we fell behind reading.
+    Lagged {
+        missed: usize,
+    },
+    /// Reply to `HotlineClient::ping`.
+    Pong {
+        rtt: Duration,
+    },
+    /// The server disconnected us on purpose; no more events follow.
+    Kicked {
+        reason: String,
+    },
+    /// The connection ended; `error` is set when it did not close cleanly.
+    /// No more events follow.
+    Disconnected {
+        error: Option<String>,
+    },
+}
+
+impl ClientEvent {
+    /// The line to show for join, leave, rename and topic events.
+    pub fn notice_message(&self) -> Option<ChatPayload> {
+        let frame = match self.clone() {
+            ClientEvent::UserJoined {
+                username,
+                room,
+                timestamp,
+            } => ServerFrame::Join {
+                username,
+                room,
+                timestamp,
+            },
+            ClientEvent::UserLeft {
+                username,
+                room,
+                timestamp,
+            } => ServerFrame::Leave {
+                username,
+                room,
+                timestamp,
+            },
+            ClientEvent::UserRenamed {
+                old,
+                new,
+                room,
+                timestamp,
+            } => ServerFrame::Renamed {
+                old,
+                new,
+                room,
+                timestamp,
+            },
+            ClientEvent::TopicChanged {
+                room,
+                topic,
+                by,
+                timestamp,
+            } => ServerFrame::Topic {
+                room,
+                topic,
+                by,
+                timestamp,
+            },
+            _ => return None,
+        };
+        frame.notice_message()
+    }
+}
+
+/// The server refused the connection, e.g. because of a ban or a taken
+/// nickname. Retrying won't help; look for it with `Error::downcast_ref`.
+#[derive(Debug)]
+pub struct Rejected(pub String);
+
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Server rejected connection: {}", self.0)
+    }
+}
+
+impl std::error::Error for Rejected {}
+
+/// The chatroom needs a password or invite token and ours was missing or
+/// wrong. Retrying with the same one won't help.
+#[derive(Debug)]
+pub struct AccessDenied(pub String);
+
+impl fmt::Display for AccessDenied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Access denied: {}", self.0)
+    }
+}
+
+impl std::error::Error for AccessDenied {}
+
+/// Exponentially growing delays between reconnect attempts, with some
+/// jitter so clients dropped together don't all return at once.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    next: Duration,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Backoff {
+            initial,
+            max,
+            next: initial,
+        }
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(self.max);
+        delay.mul_f64(rand::random_range(0.8..1.2))
+    }
+
+    /// Starts over after a successful connection.
+    pub fn reset(&mut self) {
+        self.next = self.initial;
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff::new(Duration::from_secs(1), Duration::from_secs(30))
+    }
+}
+
+/// What the reader has seen so far, kept so a reconnect can resume.
+#[derive(Debug)]
+struct Progress {
+    /// How the server names us in chat frames; anonymous clients go by address.
+    name: String,
+    username: Option<String>,
+    room: String,
+    last_id: Option<u64>,
+    /// When each unanswered `ping` was sent, by nonce.
+    pings: HashMap<u64, Instant>,
+    /// Keys of end-to-end encrypted rooms, kept across reconnects.
+    keys: HashMap<String, RoomKey>,
+    /// Rooms the server told us are end-to-end encrypted.
+    encrypted: HashSet<String>,
+    /// Account we are logged in to and its password, so reconnecting under
+    /// its name logs in again.
+    account: Option<(String, String)>,
+    /// Sent with `register` or `login`, until the server confirms it.
+    pending_login: Option<(String, String)>,
+}
+
+impl Progress {
+    fn saw(&mut self, message: &ChatPayload) {
+        self.last_id = self.last_id.max(message.id);
+    }
+
+    /// Replaces the placeholder content of an encrypted message with its
+    /// text, or with a note on why it can't be read.
+    fn open(&self, message: &mut ChatPayload) {
+        let Some(sealed) = &mut message.sealed else {
+            return;
+        };
+        let room = message.room.as_deref().unwrap_or_default();
+        message.content = match self.keys.get(room) {
+            None => e2e::PLACEHOLDER.to_string(),
+            Some(key) if key.id() != sealed.key_id => {
+                "[encrypted with a different passphrase]".to_string()
+            }
+            Some(key) => match key.open(room, sealed) {
+                Ok(text) => {
+                    sealed.opened = true;
+                    text
+                }
+                Err(_) => "[encrypted message that failed to decrypt]".to_string(),
+            },
+        };
+    }
+}
+
+/// A connection to a hotline server for bots and other frontends.
+///
+/// Requests are sent with the async methods; everything the server sends
+/// arrives as `ClientEvent`s through `next_event` or the `Stream` impl.
+/// After a `Disconnected` event, `reconnect` picks the session up again.
+pub struct HotlineClient {
+    request: ConnectRequest,
+    options: ClientOptions,
+    writer: SharedWriter,
+    events: mpsc::Receiver<ClientEvent>,
+    reader: JoinHandle<()>,
+    session: SessionInfo,
+    progress: Arc<Mutex<Progress>>,
+    resume_token: Option<String>,
+}
+
+impl HotlineClient {
+    /// Connects and completes the handshake. Fails if the server can't be
+    /// reached, rejects us (see `Rejected` and `AccessDenied`) or speaks an
+    /// incompatible protocol.
+    pub async fn connect(request: &ConnectRequest) -> Result<Self> {
+        Self::connect_with(request, ClientOptions::default()).await
+    }
+
+    /// Like `connect`, with custom heartbeat timing and frame limit.
+    pub async fn connect_with(request: &ConnectRequest, options: ClientOptions) -> Result<Self> {
+        Self::establish(request.clone(), options, None, HashMap::new()).await
+    }
+
+    /// Connects again after the connection dropped, under the current
+    /// nickname. The server puts us back in the same room and replays what
+    /// we missed as a `History` event with `resumed` set.
+    pub async fn reconnect(&mut self) -> Result<()> {
+        let (request, resume, keys) = {
+            let progress = lock(&self.progress);
+            let account_password = progress
+                .account
+                .as_ref()
+                .filter(|(name, _)| {
+                    progress
+                        .username
+                        .as_ref()
+                        .is_some_and(|u| u.eq_ignore_ascii_case(name))
+                })
+                .map(|(_, password)| password.clone());
+            let request = ConnectRequest {
+                username: progress.username.clone(),
+                account_password,
+                ..self.request.clone()
+            };
+            let resume = Resume {
+                token: self.resume_token.clone(),
+                room: progress.room.clone(),
+                last_id: progress.last_id,
+            };
+            (request, resume, progress.keys.clone())
+        };
+        let fresh = Self::establish(request, self.options.clone(), Some(resume), keys).await?;
+        // Dropping the old client aborts its reader task
+        let _ = std::mem::replace(self, fresh);
+        Ok(())
+    }
+
+    async fn establish(
+        request: ConnectRequest,
+        options: ClientOptions,
+        resume: Option<Resume>,
+        keys: HashMap<String, RoomKey>,
+    ) -> Result<Self> {
+        // A (host, port) pair resolves names and IPv6 literals alike
+        let stream = TcpStream::connect((request.host.as_str(), request.port))
+            .await
+            .with_context(|| format!("Failed to connect to {}", request.address()))?;
+        let local_addr = stream.local_addr()?;
+
+        let (tls, (reader, writer)) = if request.tls {
+            let (stream, info) = timeout(
+                options.handshake_timeout,
+                tls::connect(stream, &request, &options.known_servers),
+            )
+            .await
+            .map_err(|_| handshake_timed_out(&request, options.handshake_timeout))??;
+            (Some(info), transport::split(stream))
+        } else {
+            (None, transport::split(stream))
+        };
+        let mut frames = frame_reader(reader, options.max_frame_bytes);
+        let mut writer = BufWriter::new(writer);
+
+        let last_id = resume.as_ref().and_then(|r| r.last_id);
+        // The server expects a username field even when it is empty
+        write_frame(
+            &mut writer,
+            &ClientFrame::Hello {
+                version: PROTOCOL_VERSION,
+                capabilities: supported_capabilities(),
+                username: request.username.clone().unwrap_or_default(),
+                resume,
+                identity: options.identity.as_ref().map(Identity::public_key),
+                password: request.password.clone(),
+                account_password: request.account_password.clone(),
+            },
+        )
+        .await?;
+
+        let (session, resume_token) = timeout(
+            options.handshake_timeout,
+            read_welcome(
+                &mut frames,
+                &mut writer,
+                options.identity.as_ref(),
+                local_addr,
+                tls,
+            ),
+        )
+        .await
+        .map_err(|_| handshake_timed_out(&request, options.handshake_timeout))??;
+
+        let progress = Arc::new(Mutex::new(Progress {
+            name: session
+                .username
+                .clone()
+                .unwrap_or_else(|| local_addr.to_string()),
+            username: session.username.clone(),
+            room: session.room.clone(),
+            last_id,
+            pings: HashMap::new(),
+            keys,
+            encrypted: HashSet::new(),
+            // The welcome means the server accepted the password
+            account: session
+                .username
+                .clone()
+                .zip(request.account_password.clone()),
+            pending_login: None,
+        }));
+        // Older servers neither ping nor answer, so they would look dead
+        let liveness = if session.capabilities.contains(&Capability::Heartbeat) {
+            options.heartbeat.clone()
+        } else {
+            HeartbeatConfig::disabled()
+        };
+        let writer = Arc::new(tokio::sync::Mutex::new(writer));
+        let (events_tx, events) = mpsc::channel(EVENT_BUFFER);
+        let reader = tokio::spawn(read_events(
+            frames,
+            Arc::clone(&writer),
+            events_tx,
+            Arc::clone(&progress),
+            liveness,
+        ));
+
+        Ok(HotlineClient {
+            request,
+            options,
+            writer,
+            events,
+            reader,
+            session,
+            progress,
+            resume_token,
+        })
+    }
+
+    pub fn session(&self) -> &SessionInfo {
+        &self.session
+    }
+
+    /// Posts a chat message to the current room, encrypted if we hold the
+    /// room's key. Fails without sending if the message is over the server's
+    /// size limit, or if the room is encrypted and we have no key for it.
+    pub async fn send(&mut self, content: &str) -> Result<()> {
+        let sealed = {
+            let progress = lock(&self.progress);
+            match progress.keys.get(&progress.room) {
+                Some(key) => Some(key.seal(&progress.room, content)?),
+                None if progress.encrypted.contains(&progress.room) => bail!(
+                    "#{} is end-to-end encrypted; set its passphrase to send messages",
+                    progress.room
+                ),
+                None => None,
+            }
+        };
+        let frame = ClientFrame::Chat {
+            // The server ignores it, don't leak it next to the ciphertext
+            content: if sealed.is_some() {
+                String::new()
+            } else {
+                content.to_string()
+            },
+            sealed,
+        };
+        if let Some(max) = self.session.max_frame_bytes {
+            let len = frame.to_line()?.len() - 1;
+            if len > max {
+                bail!("Message is too long ({} of {} bytes allowed)", len, max);
+            }
+        }
+        self.send_frame(&frame).await
+    }
+
+    /// Switches the current room to end-to-end encryption with a key derived
+    /// from `passphrase`, which the other members need to share. Only the
+    /// key id, see `RoomKey::id`, ever leaves this client; it is returned so
+    /// members can compare theirs.
+    pub async fn encrypt_room(&mut self, passphrase: &str) -> Result<String> {
+        if !self.session.capabilities.contains(&Capability::E2e) {
+            bail!("The server does not support encrypted rooms");
+        }
+        let room = self.room();
+        let key = {
+            let (room, passphrase) = (room.clone(), passphrase.to_string());
+            tokio::task::spawn_blocking(move || RoomKey::derive(&room, &passphrase)).await??
+        };
+        let key_id = key.id().to_string();
+        let announce = {
+            let mut progress = lock(&self.progress);
+            progress.keys.insert(room.clone(), key);
+            !progress.encrypted.contains(&room)
+        };
+        if announce {
+            self.send_frame(&ClientFrame::Encrypt).await?;
+        }
+        Ok(key_id)
+    }
+
+    /// Drops the key of the current room; its messages can no longer be
+    /// read or sent. False if we held none.
+    pub fn forget_room_key(&mut self) -> bool {
+        let mut progress = lock(&self.progress);
+        let room = progress.room.clone();
+        progress.keys.remove(&room).is_some()
+    }
+
+    /// The room we are in.
+    pub fn room(&self) -> String {
+        lock(&self.progress).room.clone()
+    }
+
+    pub fn is_room_encrypted(&self, room: &str) -> bool {
+        lock(&self.progress).encrypted.contains(room)
+    }
+
+    pub fn has_room_key(&self, room: &str) -> bool {
+        lock(&self.progress).keys.contains_key(room)
+    }
+
+    /// Asks for a new nickname; the answer arrives as `UserRenamed` or `Error`.
+    pub async fn set_nick(&mut self, username: &str) -> Result<()> {
+        self.send_frame(&ClientFrame::Nick {
+            username: username.to_string(),
+        })
+        .await
+    }
+
+    pub async fn join(&mut self, room: &str) -> Result<()> {
+        self.send_frame(&ClientFrame::Join {
+            room: room.to_string(),
+        })
+        .await
+    }
+
+    /// Leaves the current room for the server's default one.
+    pub async fn part(&mut self) -> Result<()> {
+        self.send_frame(&ClientFrame::Part).await
+    }
+
+    pub async fn list_rooms(&mut self) -> Result<()> {
+        self.send_frame(&ClientFrame::ListRooms).await
+    }
+
+    /// Asks which identity key `username` is bound to; the answer arrives
+    /// as `Whois`.
+    pub async fn whois(&mut self, username: &str) -> Result<()> {
+        if !self.session.capabilities.contains(&Capability::Identity) {
+            bail!("The server does not know about identity keys");
+        }
+        self.send_frame(&ClientFrame::Whois {
+            username: username.to_string(),
+        })
+        .await
+    }
+
+    /// Registers our current nickname as an account protected by `password`;
+    /// the answer arrives as `LoggedIn` or `Error`.
+    pub async fn register(&mut self, password: &str) -> Result<()> {
+        if !self.session.capabilities.contains(&Capability::Accounts) {
+            bail!("The server does not support accounts");
+        }
+        let Some(username) = lock(&self.progress).username.clone() else {
+            bail!("Pick a nickname with /nick before registering it");
+        };
+        lock(&self.progress).pending_login = Some((username, password.to_string()));
+        self.send_frame(&ClientFrame::Register {
+            password: password.to_string(),
+        })
+        .await
+    }
+
+    /// Logs in to the account `username`, taking its name; the answer
+    /// arrives as `LoggedIn` or `Error`.
+    pub async fn login(&mut self, username: &str, password: &str) -> Result<()> {
+        if !self.session.capabilities.contains(&Capability::Accounts) {
+            bail!("The server does not support accounts");
+        }
+        lock(&self.progress).pending_login = Some((username.to_string(), password.to_string()));
+        self.send_frame(&ClientFrame::Login {
+            username: username.to_string(),
+            password: password.to_string(),
+        })
+        .await
+    }
+
+    /// Sets the topic of the current room, or clears it when `None`; the
+    /// room hears about it as `TopicChanged`.
+    pub async fn set_topic(&mut self, topic: Option<&str>) -> Result<()> {
+        self.check_roles()?;
+        self.send_frame(&ClientFrame::Topic {
+            topic: topic.map(str::to_string),
+        })
+        .await
+    }
+
+    // The server checks our role for each of these and answers with `Info`
+    // or `Error`.
+
+    pub async fn kick(&mut self, username: &str, reason: Option<&str>) -> Result<()> {
+        self.check_roles()?;
+        self.send_frame(&ClientFrame::Kick {
+            username: username.to_string(),
+            reason: reason.map(str::to_string),
+        })
+        .await
+    }
+
+    /// Bans a nickname or an IP address, for good when `duration` is `None`.
+    pub async fn ban(&mut self, target: &str, duration: Option<Duration>) -> Result<()> {
+        self.check_roles()?;
+        self.send_frame(&ClientFrame::Ban {
+            target: target.to_string(),
+            seconds: duration.map(|d| d.as_secs()),
+        })
+        .await
+    }
+
+    pub async fn unban(&mut self, target: &str) -> Result<()> {
+        self.check_roles()?;
+        self.send_frame(&ClientFrame::Unban {
+            target: target.to_string(),
+        })
+        .await
+    }
+
+    pub async fn mute(&mut self, username: &str, duration: Option<Duration>) -> Result<()> {
+        self.check_roles()?;
+        self.send_frame(&ClientFrame::Mute {
+            username: username.to_string(),
+            seconds: duration.map(|d| d.as_secs()),
+        })
+        .await
+    }
+
+    pub async fn unmute(&mut self, username: &str) -> Result<()> {
+        self.check_roles()?;
+        self.send_frame(&ClientFrame::Unmute {
+            username: username.to_string(),
+        })
+        .await
+    }
+
+    /// Changes the role of the account `username`.
+    pub async fn set_role(&mut self, username: &str, role: Role) -> Result<()> {
+        self.check_roles()?;
+        self.send_frame(&ClientFrame::SetRole {
+            username: username.to_string(),
+            role,
+        })
+        .await
+    }
+
+    fn check_roles(&self) -> Result<()> {
+        if !self.session.capabilities.contains(&Capability::Roles) {
+            bail!("The server does not support roles or moderation by clients");
+        }
+        Ok(())
+    }
+
+    /// The identity key we connect with, if any.
+    pub fn identity(&self) -> Option<&Identity> {
+        self.options.identity.as_ref()
+    }
+
+    /// Requests up to `limit` earlier messages, optionally older than `before`.
+    pub async fn history(&mut self, limit: usize, before: Option<u64>) -> Result<()> {
+        self.send_frame(&ClientFrame::History { limit, before })
+            .await
+    }
+
+    /// Measures the round trip to the server; the result arrives as `Pong`.
+    pub async fn ping(&mut self) -> Result<()> {
+        if !self.session.capabilities.contains(&Capability::Heartbeat) {
+            bail!("The server does not answer pings");
+        }
+        let nonce = rand::random();
+        lock(&self.progress).pings.insert(nonce, Instant::now());
+        self.send_frame(&ClientFrame::Ping { nonce }).await
+    }
+
+    async fn send_frame(&mut self, frame: &ClientFrame) -> Result<()> {
+        write_frame(&mut *self.writer.lock().await, frame)
+            .await
+            .context("Failed to send to server")
+    }
+
+    /// The next event, or `None` once the connection has ended and every
+    /// event was delivered.
+    pub async fn next_event(&mut self) -> Option<ClientEvent> {
+        self.events.recv().await
+    }
+
+    /// Closes the connection. The server sees an orderly disconnect.
+    pub async fn close(self) -> Result<()> {
+        self.writer.lock().await.shutdown().await?;
+        Ok(())
+    }
+}
+
+impl Stream for HotlineClient {
+    type Item = ClientEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Option<Self::Item>> {
+        self.events.poll_recv(cx)
+    }
+}
+
+impl Drop for HotlineClient {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+fn handshake_timed_out(request: &ConnectRequest, limit: Duration) -> anyhow::Error {
+    anyhow!(
+        "{} did not complete the handshake within {:.1}s",
+        request.address(),
+        limit.as_secs_f32()
+    )
+}
+
+/// Reads the server's reply to our hello, answering its identity challenge
+/// on the way, and returns the session and the token for resuming it.
+async fn read_welcome(
+    frames: &mut FrameReader<ReadHalf>,
+    writer: &mut BufWriter<WriteHalf>,
+    identity: Option<&Identity>,
+    local_addr: SocketAddr,
+    tls: Option<TlsInfo>,
+) -> Result<(SessionInfo, Option<String>)> {
+    let mut proven = None;
+    loop {
+        let line = match frames.next().await {
+            Some(Ok(Incoming::Line(line))) => line,
+            Some(Ok(Incoming::TooLong)) => bail!("Server sent an oversized welcome"),
+            Some(Err(e)) => return Err(e).context("Error reading from server"),
+            None => bail!("Server closed the connection during handshake"),
+        };
+        let frame = ServerFrame::from_line(&line);
+        if let Ok(ServerFrame::Challenge { nonce }) = &frame {
+            let Some(identity) = identity else {
+                bail!("Server asked for an identity key we didn't offer");
+            };
+            let signature = identity.sign_challenge(nonce);
+            write_frame(writer, &ClientFrame::Prove { signature }).await?;
+            proven = Some(identity.fingerprint());
+            continue;
+        }
+        return welcome_session(frame, local_addr, tls, proven);
+    }
+}
+
+fn welcome_session(
+    frame: Result<ServerFrame>,
+    local_addr: SocketAddr,
+    tls: Option<TlsInfo>,
+    identity: Option<String>,
+) -> Result<(SessionInfo, Option<String>)> {
+    match frame {
+        Ok(ServerFrame::Welcome {
+            version,
+            capabilities,
+            room,
+            username,
+            resume_token,
+            max_frame_bytes,
+        }) => {
+            let version =
+                negotiate_version(version).map_err(|e| anyhow!("Incompatible server: {}", e))?;
+            let session = SessionInfo {
+                room,
+                version,
+                capabilities,
+                username,
+                local_addr,
+                max_frame_bytes,
+                tls,
+                identity,
+            };
+            Ok((session, resume_token))
+        }
+        Ok(ServerFrame::Reject {
+            reason,
+            access_denied: true,
+        }) => Err(AccessDenied(reason).into()),
+        Ok(ServerFrame::Reject { reason, .. }) => Err(Rejected(reason).into()),
+        Ok(_) | Err(_) => bail!(
+            "Server did not complete the handshake; it may require TLS or run an older hotline build"
+        ),
+    }
+}
+
+/// Turns server frames into events until the connection ends, answering
+/// and sending pings along the way.
+async fn read_events(
+    mut frames: FrameReader<ReadHalf>,
+    writer: SharedWriter,
+    events: mpsc::Sender<ClientEvent>,
+    progress: Arc<Mutex<Progress>>,
+    heartbeat: HeartbeatConfig,
+) {
+    let mut heartbeat = Heartbeat::new(heartbeat);
+    loop {
+        let line = tokio::select! {
+            incoming = frames.next() => incoming,
+            _ = heartbeat.tick() => {
+                if heartbeat.expired() {
+                    let error = format!(
+                        "No response from server for {}s",
+                        heartbeat.silent_for().as_secs()
+                    );
+                    let _ = events.send(ClientEvent::Disconnected { error: Some(error) }).await;
+                    break;
+                }
+                // A failed write surfaces as a read error soon enough
+                let _ = write_frame(&mut *writer.lock().await, &ClientFrame::Ping { nonce: 0 }).await;
+                continue;
+            }
+        };
+
+        let event = match line {
+            None => ClientEvent::Disconnected { error: None },
+            Some(Err(e)) => ClientEvent::Disconnected {
+                error: Some(format!("Error reading from server: {}", e)),
+            },
+            Some(Ok(Incoming::TooLong)) => {
+                heartbeat.seen();
+                ClientEvent::Error(format!(
+                    "Skipped a frame over the {} byte limit",
+                    frames.decoder().max_bytes()
+                ))
+            }
+            Some(Ok(Incoming::Line(line))) => {
+                heartbeat.seen();
+                match ServerFrame::from_line(&line) {
+                    Ok(ServerFrame::Ping { nonce }) => {
+                        let _ =
+                            write_frame(&mut *writer.lock().await, &ClientFrame::Pong { nonce })
+                                .await;
+                        continue;
+                    }
+                    Ok(frame) => match frame_event(frame, &mut lock(&progress)) {
+                        Some(event) => event,
+                        None => continue,
+                    },
+                    // Not a valid frame, pass it on as plain text
+                    Err(_) => ClientEvent::Info(line.trim().to_string()),
+                }
+            }
+        };
+
+        let last = matches!(
+            event,
+            ClientEvent::Kicked { .. } | ClientEvent::Disconnected { .. }
+        );
+        if events.send(event).await.is_err() || last {
+            break;
+        }
+    }
+}
+
+/// Maps a frame to its event, tracking our name, room and the newest
+/// message seen.
+fn frame_event(frame: ServerFrame, progress: &mut Progress) -> Option<ClientEvent> {
+    let event = match frame {
+        ServerFrame::Chat(mut message) => {
+            progress.saw(&message);
+            progress.open(&mut message);
+            ClientEvent::Message {
+                own: message.sender == progress.name,
+                message,
+            }
+        }
+        ServerFrame::Join {
+            username,
+            room,
+            timestamp,
+        } => ClientEvent::UserJoined {
+            username,
+            room,
+            timestamp,
+        },
+        ServerFrame::Leave {
+            username,
+            room,
+            timestamp,
+        } => ClientEvent::UserLeft {
+            username,
+            room,
+            timestamp,
+        },
+        ServerFrame::Renamed {
+            old,
+            new,
+            room,
+            timestamp,
+        } => {
+            if old == progress.name {
+                progress.name = new.clone();
+                progress.username = Some(new.clone());
+            }
+            ClientEvent::UserRenamed {
+                old,
+                new,
+                room,
+                timestamp,
+            }
+        }
+        ServerFrame::Joined {
+            room,
+            members,
+            encrypted,
+            topic,
+        } => {
+            progress.room = room.clone();
+            if encrypted {
+                progress.encrypted.insert(room.clone());
+            } else {
+                progress.encrypted.remove(&room);
+            }
+            ClientEvent::RoomJoined {
+                room,
+                members,
+                encrypted,
+                topic,
+            }
+        }
+        ServerFrame::Encrypted { room, by, .. } => {
+            progress.encrypted.insert(room.clone());
+            ClientEvent::RoomEncrypted { room, by }
+        }
+        ServerFrame::Topic {
+            room,
+            topic,
+            by,
+            timestamp,
+        } => ClientEvent::TopicChanged {
+            room,
+            topic,
+            by,
+            timestamp,
+        },
+        ServerFrame::RoomList { rooms } => ClientEvent::Rooms(rooms),
+        ServerFrame::Whois {
+            username,
+            online,
+            fingerprint,
+            account,
+            role,
+        } => ClientEvent::Whois {
+            username,
+            online,
+            fingerprint,
+            account,
+            role,
+        },
+        ServerFrame::LoggedIn {
+            username,
+            registered,
+        } => {
+            if let Some((_, password)) = progress
+                .pending_login
+                .take()
+                .filter(|(name, _)| name.eq_ignore_ascii_case(&username))
+            {
+                progress.account = Some((username.clone(), password));
+            }
+            ClientEvent::LoggedIn {
+                username,
+                registered,
+            }
+        }
+        ServerFrame::History {
+            room,
+            mut messages,
+            has_more,
+            resumed,
+        } => {
+            for message in &mut messages {
+                progress.saw(message);
+                progress.open(message);
+            }
+            ClientEvent::History {
+                room,
+                messages,
+                has_more,
+                resumed,
+            }
+        }
+        ServerFrame::Info { message } => ClientEvent::Info(message),
+        ServerFrame::Error { message } => ClientEvent::Error(message),
+        ServerFrame::RateLimited { seconds, reason } => {
+            ClientEvent::RateLimited { seconds, reason }
+        }
+        ServerFrame::Lagged { missed } => ClientEvent::Lagged { missed },
+        ServerFrame::Kicked { reason } | ServerFrame::Reject { reason, .. } => {
+            ClientEvent::Kicked { reason }
+        }
+        // Heartbeat pongs have no entry and are only a sign of life
+        ServerFrame::Pong { nonce } => ClientEvent::Pong {
+            rtt: progress.pings.remove(&nonce)?.elapsed(),
+        },
+        // Answered by the reader before it gets here
+        ServerFrame::Ping { .. } => return None,
+        // Handshake is already done, a second welcome carries nothing new
+        ServerFrame::Welcome { .. } | ServerFrame::Challenge { .. } => return None,
+    };
+    Some(event)
+}
+
+fn lock(progress: &Mutex<Progress>) -> MutexGuard<'_, Progress> {
+    // The reader never panics while holding the lock, but don't die if it did
+    progress.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap_and_resets() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(8));
+        let expected = [1, 2, 4, 8, 8, 8];
+        for secs in expected {
+            let delay = backoff.next_delay().as_secs_f64();
+            let secs = secs as f64;
+            assert!((secs * 0.8..secs * 1.2).contains(&delay), "{}", delay);
+        }
+        backoff.reset();
+        assert!(backoff.next_delay() < Duration::from_millis(1200));
+    }
+
+    #[test]
+    fn backoff_jitter_spreads_clients_out() {
+        let delays: HashSet<Duration> = (0..20).map(|_| Backoff::default().next_delay()).collect();
+        assert!(delays.len() > 1);
+    }
+
+    /// Reads the client's hello from `frames`.
+    async fn hello(frames: &mut FrameReader<tokio::net::tcp::OwnedReadHalf>) -> ClientFrame {
+        match frames.next().await {
+            Some(Ok(Incoming::Line(line))) => ClientFrame::from_line(&line).unwrap(),
+            other => panic!("expected a hello, got {:?}", other),
+        }
+    }
+
+    fn welcome(resume_token: &str) -> ServerFrame {
+        ServerFrame::Welcome {
+            version: PROTOCOL_VERSION,
+            capabilities: vec![Capability::Resume],
+            room: "lobby".to_string(),
+            username: Some("alice".to_string()),
+            resume_token: Some(resume_token.to_string()),
+            max_frame_bytes: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn reconnecting_resumes_where_the_session_left_off() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = socket.into_split();
+            let mut frames = frame_reader(reader, DEFAULT_CLIENT_MAX_FRAME_BYTES);
+            let first = hello(&mut frames).await;
+            write_frame(&mut writer, &welcome("first")).await.unwrap();
+            let message = ChatPayload {
+                id: Some(42),
+                content: "hi".to_string(),
+                sender: "bob".to_string(),
+                username: Some("bob".to_string()),
+                timestamp: Utc::now(),
+                room: Some("lobby".to_string()),
+                sealed: None,
+            };
+            write_frame(&mut writer, &ServerFrame::Chat(message))
+                .await
+                .unwrap();
+            // Then the connection drops
+            drop((frames, writer));
+
+            let (socket, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = socket.into_split();
+            let mut frames = frame_reader(reader, DEFAULT_CLIENT_MAX_FRAME_BYTES);
+            let second = hello(&mut frames).await;
+            write_frame(&mut writer, &welcome("second")).await.unwrap();
+            (first, second)
+        });
+
+        let request = ConnectRequest::from_input("127.0.0.1", port, "alice").unwrap();
+        let mut client = HotlineClient::connect(&request).await.unwrap();
+        assert_eq!(client.resume_token.as_deref(), Some("first"));
+        loop {
+            match client.next_event().await {
+                Some(ClientEvent::Disconnected { .. }) | None => break,
+                _ => {}
+            }
+        }
+        client.reconnect().await.unwrap();
+        assert_eq!(client.resume_token.as_deref(), Some("second"));
+
+        let (first, second) = server.await.unwrap();
+        assert!(matches!(first, ClientFrame::Hello { resume: None, .. }));
+        let ClientFrame::Hello {
+            username,
+            resume: Some(resume),
+            ..
+        } = second
+        else {
+            panic!("expected a resuming hello");
+        };
+        assert_eq!(username, "alice");
+        assert_eq!(resume.token.as_deref(), Some("first"));
+        assert_eq!(resume.room, "lobby");
+        assert_eq!(resume.last_id, Some(42));
+    }
+
+    #[tokio::test]
+    async fn a_silent_server_times_out_the_handshake() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        // Accepts the connection and reads the hello, but never answers
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut frames = frame_reader(socket, DEFAULT_CLIENT_MAX_FRAME_BYTES);
+            while frames.next().await.is_some() {}
+        });
+        let request = ConnectRequest::from_input("127.0.0.1", port, "alice").unwrap();
+        let options = ClientOptions {
+            handshake_timeout: Duration::from_millis(100),
+            ..ClientOptions::default()
+        };
+        let refused = HotlineClient::connect_with(&request, options).await;
+        let error = refused.err().unwrap().to_string();
+        assert!(
+            error.contains("did not complete the handshake"),
+            "{}",
+            error
+        );
+        server.abort();
+    }
+}
@@ -1,271 +1,916 @@
 use anyhow::Result;
-use chrono::{DateTime, Utc};
-use serde::Deserialize;
+use chrono::Utc;
 use std::collections::VecDeque;
-use std::net::SocketAddr;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::{Duration, Instant};
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter};
-use tokio::net::TcpStream;
 use tokio::sync::mpsc;
 
+use crate::client::{AccessDenied, Backoff, ClientEvent, ClientOptions, HotlineClient, Rejected};
+use crate::commands::{ClientCommand, ConnectRequest};
+use crate::config::Config;
+use crate::identity::Identity;
+use crate::protocol::{ChatPayload, chat_frame_len, format_room_list};
 use crate::serializable_colours::*;
-use crate::types::{ChatMessage, OutputEvent, SystemEvent, TextLine};
-
-// Use the shared types from the types module
-#[derive(Debug, Clone, Deserialize)]
-struct ServerMessage {
-    content: String,
-    sender: String,
-    username: Option<String>,
-    timestamp: DateTime<Utc>,
+use crate::tls::{CertificateChanged, KnownServers, Trust};
+use crate::types::{ChatMessage, Encryption, OutputEvent, SystemEvent, TextLine};
+
+/// Reconnect attempts before giving up and asking the user again. With the
+/// default backoff this keeps trying for roughly eight minutes.
+const MAX_RECONNECT_ATTEMPTS: u32 = 20;
+
+/// How a reconnect ended.
+enum Reconnect {
+    Connected,
+    /// The user quit while we were waiting.
+    Quit,
+    /// The server refused us or never came back; the UI has been told.
+    GaveUp,
 }
 
+/// Drives a `HotlineClient` for the TUI: waits for a `Connect` command,
+/// forwards commands and events until the connection ends, then waits for
+/// the next `Connect`. Returns on `Quit` or when the UI goes away.
 pub async fn run_client_backend(
-    mut input_rx: mpsc::Receiver<String>,
+    mut input_rx: mpsc::Receiver<ClientCommand>,
     output_tx: mpsc::Sender<OutputEvent>,
     shutdown_signal: Arc<AtomicBool>,
 ) -> Result<()> {
-    // Wait for connection details from UI
-    let connection_details = if let Some(details) = input_rx.recv().await {
-        details
-    } else {
-        return Ok(());
-    };
-
-    // Parse connection details (format: "CONNECT:server:port:username")
-    if !connection_details.starts_with("CONNECT:") {
-        output_tx
-            .send(OutputEvent::SystemEvent(SystemEvent::ConnectionError {
-                message: "Invalid connection format".to_string(),
-            }))
-            .await?;
-        return Ok(());
-    }
-
-    let parts: Vec<&str> = connection_details.split(':').collect();
-    if parts.len() < 4 {
-        output_tx
-            .send(OutputEvent::SystemEvent(SystemEvent::ConnectionError {
-                message: "Invalid connection details".to_string(),
-            }))
-            .await?;
-        return Ok(());
-    }
-
-    let server_addr = parts[1];
-    let port = parts[2];
-    let username = parts[3];
-
-    let address: String = format!("{}:{}", server_addr, port);
-
-    // Connect to server
-    let stream: TcpStream = match TcpStream::connect(&address).await {
-        Ok(s) => s,
+    // Config errors were already reported when the UI started
+    let config = Config::load().unwrap_or_default().client;
+    // Without a key we still connect, only our nickname isn't protected
+    let identity_path = config
+        .identity_file
+        .clone()
+        .unwrap_or_else(Identity::default_path);
+    let identity = match Identity::load_or_create(&identity_path) {
+        Ok(identity) => Some(identity),
         Err(e) => {
             output_tx
-                .send(OutputEvent::SystemEvent(SystemEvent::ConnectionError {
-                    message: format!("Failed to connect: {}", e),
+                .send(OutputEvent::TextLine(TextLine {
+                    text: format!("{:#}; connecting without an identity key", e),
+                    color: Some(RED_COLOR.clone()),
                 }))
                 .await?;
-            return Ok(());
+            None
         }
     };
-
-    let my_socket_addr: SocketAddr = stream.local_addr()?;
-    let my_addr_str = my_socket_addr.to_string();
-
-    // Send connection established event
-    output_tx
-        .send(OutputEvent::SystemEvent(
-            SystemEvent::ConnectionEstablished {
-                address: my_addr_str.clone(),
+    let options = ClientOptions {
+        heartbeat: config.heartbeat,
+        max_frame_bytes: config.max_frame_bytes,
+        known_servers: config
+            .known_servers_file
+            .unwrap_or_else(KnownServers::default_path),
+        identity,
+        ..ClientOptions::default()
+    };
+    // For trusting a changed certificate, which needs the same server again
+    let mut last_request: Option<ConnectRequest> = None;
+
+    while !shutdown_signal.load(Ordering::SeqCst) {
+        // Wait for connection details from UI
+        let request = match input_rx.recv().await {
+            Some(ClientCommand::Connect(request)) => request,
+            Some(ClientCommand::TrustCertificate { fingerprint }) => match last_request.clone() {
+                Some(request) => ConnectRequest {
+                    accept_fingerprint: Some(fingerprint),
+                    ..request
+                },
+                None => continue,
             },
-        ))
-        .await?;
-
-    let (reader, writer) = stream.into_split();
-    let mut server_reader = BufReader::new(reader);
-    let mut server_writer = BufWriter::new(writer);
-
-    // Send username (even if empty)
-    server_writer
-        .write_all(format!("/username:{}\n", username).as_bytes())
-        .await?;
-    server_writer.flush().await?;
-
-    // Task to handle reading from server
-    let output_tx_clone = output_tx.clone();
-    let my_addr_str_clone = my_addr_str.clone();
+            Some(ClientCommand::Quit) | None => break,
+            Some(_) => {
+                output_tx
+                    .send(OutputEvent::TextLine(TextLine {
+                        text: "Not connected to a server".to_string(),
+                        color: Some(RED_COLOR.clone()),
+                    }))
+                    .await?;
+                continue;
+            }
+        };
 
-    tokio::spawn(async move {
-        let mut line = String::new();
-        loop {
-            match server_reader.read_line(&mut line).await {
-                Ok(0) => {
-                    let _ = output_tx_clone
-                        .send(OutputEvent::SystemEvent(SystemEvent::ConnectionClosed))
-                        .await;
-                    break;
-                }
-                Ok(_) => {
-                    let trimmed = line.trim();
-
-                    if trimmed.starts_with('{') {
-                        if let Ok(msg) = serde_json::from_str::<ServerMessage>(trimmed) {
-                            let is_self = msg.sender == my_addr_str_clone;
-
-                            if !is_self {
-                                // Convert to our ChatMessage type
-                                let chat_message = ChatMessage {
-                                    content: msg.content,
-                                    sender: msg.sender,
-                                    username: msg.username,
-                                    timestamp: msg.timestamp,
-                                    is_self: false,
-                                };
-
-                                let _ = output_tx_clone
-                                    .send(OutputEvent::ChatMessage(chat_message))
-                                    .await;
-                            }
-                        } else {
-                            // If it's not a valid message but starts with {, send as text
-                            let _ = output_tx_clone
-                                .send(OutputEvent::TextLine(TextLine {
-                                    text: trimmed.to_string(),
-                                    color: None,
-                                }))
-                                .await;
-                        }
-                    } else {
-                        // Plain text from server
-                        let _ = output_tx_clone
-                            .send(OutputEvent::TextLine(TextLine {
-                                text: trimmed.to_string(),
-                                color: None,
-                            }))
-                            .await;
-                    }
-                    line.clear();
-                }
-                Err(e) => {
-                    let _ = output_tx_clone
-                        .send(OutputEvent::SystemEvent(SystemEvent::ConnectionError {
-                            message: format!("Error reading from server: {}", e),
-                        }))
-                        .await;
-                    break;
-                }
+        last_request = Some(request.clone());
+        let client = match HotlineClient::connect_with(&request, options.clone()).await {
+            Ok(client) => client,
+            Err(e) => {
+                report_connect_error(&output_tx, e).await?;
+                continue;
             }
+        };
+
+        if !run_session(client, &mut input_rx, &output_tx, &shutdown_signal).await? {
+            break;
         }
-    });
+    }
 
-    // Send welcome message
+    // Clean shutdown message
     output_tx
         .send(OutputEvent::TextLine(TextLine {
-            text: "You can now chat! Type and press Enter. Type `/quit` to exit.".to_string(),
-            color: Some(GREEN_COLOR.clone()),
+            text: "Shutting down client connection...".to_string(),
+            color: Some(YELLOW_COLOR.clone()),
         }))
         .await?;
 
+    Ok(())
+}
+
+/// Runs one connection, reconnecting when it drops. Returns `false` if the
+/// user quit, `true` if the connection ended and the UI may connect again.
+async fn run_session(
+    mut client: HotlineClient,
+    input_rx: &mut mpsc::Receiver<ClientCommand>,
+    output_tx: &mpsc::Sender<OutputEvent>,
+    shutdown_signal: &AtomicBool,
+) -> Result<bool> {
+    announce_session(&client, output_tx).await?;
+
     let mut msg_times: VecDeque<Instant> = VecDeque::new();
     let mut timeout_until: Option<Instant> = None;
 
     loop {
-        // Send prompt
         if shutdown_signal.load(Ordering::SeqCst) {
-            break;
+            let _ = client.close().await;
+            return Ok(false);
         }
 
-        if let Some(line) = input_rx.recv().await {
-            if shutdown_signal.load(Ordering::SeqCst) {
-                break;
+        let command = tokio::select! {
+            command = input_rx.recv() => command,
+            event = client.next_event() => {
+                let error = match event {
+                    Some(ClientEvent::Disconnected { error }) => error,
+                    None => None,
+                    Some(event) => {
+                        if !forward_event(output_tx, event, &client).await {
+                            return Ok(true);
+                        }
+                        continue;
+                    }
+                };
+                match reconnect(&mut client, error, input_rx, output_tx, shutdown_signal).await? {
+                    Reconnect::Connected => announce_session(&client, output_tx).await?,
+                    Reconnect::Quit => return Ok(false),
+                    Reconnect::GaveUp => return Ok(true),
+                }
+                continue;
             }
+        };
+        let Some(command) = command else {
+            let _ = client.close().await;
+            return Ok(false);
+        };
+        if shutdown_signal.load(Ordering::SeqCst) {
+            let _ = client.close().await;
+            return Ok(false);
+        }
 
-            let trimmed = line.trim();
-
-            if trimmed == "/quit" {
+        let content = match command {
+            ClientCommand::Quit => {
                 output_tx
                     .send(OutputEvent::TextLine(TextLine {
                         text: "Exiting chat.".to_string(),
                         color: Some(YELLOW_COLOR.clone()),
                     }))
                     .await?;
-                break;
+                let _ = client.close().await;
+                return Ok(false);
             }
-
-            if trimmed.is_empty() {
+            ClientCommand::Connect(_) | ClientCommand::TrustCertificate { .. } => {
+                output_tx
+                    .send(OutputEvent::TextLine(TextLine {
+                        text: "Already connected".to_string(),
+                        color: Some(RED_COLOR.clone()),
+                    }))
+                    .await?;
                 continue;
             }
-
-            let now = Instant::now();
-
-            if let Some(timeout) = timeout_until {
-                if now < timeout {
-                    let remaining = timeout.duration_since(now);
+            ClientCommand::Ping => {
+                if let Err(e) = client.ping().await {
                     output_tx
-                        .send(OutputEvent::SystemEvent(SystemEvent::RateLimit {
-                            seconds: remaining.as_secs_f32(),
+                        .send(OutputEvent::TextLine(TextLine {
+                            text: e.to_string(),
+                            color: Some(RED_COLOR.clone()),
                         }))
                         .await?;
-                    continue;
-                } else {
-                    timeout_until = None;
                 }
+                continue;
             }
-
-            // Track messages within 5 seconds window
-            msg_times.push_back(now);
-            while msg_times
-                .front()
-                .map_or(false, |t| now.duration_since(*t) > Duration::from_secs(5))
-            {
-                msg_times.pop_front();
+            ClientCommand::Whois(None) => {
+                let text = match client.identity() {
+                    Some(identity) => format!("Your identity key is {}", identity.fingerprint()),
+                    None => "You have no identity key".to_string(),
+                };
+                output_tx
+                    .send(OutputEvent::TextLine(TextLine {
+                        text,
+                        color: Some(CYAN_COLOR.clone()),
+                    }))
+                    .await?;
+                continue;
             }
-
-            if msg_times.len() > 10 {
+            ClientCommand::Register(password) => {
+                if let Err(e) = client.register(&password).await {
+                    output_tx
+                        .send(OutputEvent::TextLine(TextLine {
+                            text: e.to_string(),
+                            color: Some(RED_COLOR.clone()),
+                        }))
+                        .await?;
+                }
+                continue;
+            }
+            ClientCommand::Login { username, password } => {
+                if let Err(e) = client.login(&username, &password).await {
+                    output_tx
+                        .send(OutputEvent::TextLine(TextLine {
+                            text: e.to_string(),
+                            color: Some(RED_COLOR.clone()),
+                        }))
+                        .await?;
+                }
+                continue;
+            }
+            ClientCommand::Join(_)
+            | ClientCommand::Part
+            | ClientCommand::Nick(_)
+            | ClientCommand::ListRooms
+            | ClientCommand::History { .. }
+            | ClientCommand::Whois(Some(_))
+            | ClientCommand::Topic(_)
+            | ClientCommand::Kick { .. }
+            | ClientCommand::Ban { .. }
+            | ClientCommand::Unban(_)
+            | ClientCommand::Mute { .. }
+            | ClientCommand::Unmute(_)
+            | ClientCommand::SetRole { .. } => {
+                // The server answers these with an event or an error. A failed
+                // write is only reported: the connection is gone then, and
+                // the reader's `Disconnected` takes us to `reconnect`
+                let sent = match command {
+                    ClientCommand::Join(room) => client.join(&room).await,
+                    ClientCommand::Part => client.part().await,
+                    ClientCommand::Nick(username) => client.set_nick(&username).await,
+                    ClientCommand::ListRooms => client.list_rooms().await,
+                    ClientCommand::History { limit, before } => client.history(limit, before).await,
+                    ClientCommand::Whois(Some(username)) => client.whois(&username).await,
+                    ClientCommand::Topic(topic) => client.set_topic(topic.as_deref()).await,
+                    ClientCommand::Kick { user, reason } => {
+                        client.kick(&user, reason.as_deref()).await
+                    }
+                    ClientCommand::Ban { target, duration } => client.ban(&target, duration).await,
+                    ClientCommand::Unban(target) => client.unban(&target).await,
+                    ClientCommand::Mute { user, duration } => client.mute(&user, duration).await,
+                    ClientCommand::Unmute(user) => client.unmute(&user).await,
+                    ClientCommand::SetRole { user, role } => client.set_role(&user, role).await,
+                    _ => Ok(()),
+                };
+                if let Err(e) = sent {
+                    output_tx
+                        .send(OutputEvent::TextLine(TextLine {
+                            text: e.to_string(),
+                            color: Some(RED_COLOR.clone()),
+                        }))
+                        .await?;
+                }
+                continue;
+            }
+            ClientCommand::Encrypt(passphrase) => {
+                let text = match client.encrypt_room(&passphrase).await {
+                    Ok(key_id) => TextLine {
+                        text: format!(
+                            "Messages in #{} are now end-to-end encrypted with key {}; members who enter the same passphrase see the same key",
+                            client.room(),
+                            key_id
+                        ),
+                        color: Some(GREEN_COLOR.clone()),
+                    },
+                    Err(e) => TextLine {
+                        text: format!("{:#}", e),
+                        color: Some(RED_COLOR.clone()),
+                    },
+                };
+                output_tx.send(OutputEvent::TextLine(text)).await?;
+                output_tx.send(room_status(&client)).await?;
+                continue;
+            }
+            ClientCommand::ForgetRoomKey => {
+                let room = client.room();
+                let text = if client.forget_room_key() {
+                    format!(
+                        "Forgot the key of #{}; its encrypted messages can no longer be read or sent here",
+                        room
+                    )
+                } else {
+                    format!("You hold no key for #{}", room)
+                };
                 output_tx
                     .send(OutputEvent::TextLine(TextLine {
-                        text: "You are sending messages too fast! Timeout for 10 seconds."
-                            .to_string(),
+                        text,
+                        color: Some(YELLOW_COLOR.clone()),
+                    }))
+                    .await?;
+                output_tx.send(room_status(&client)).await?;
+                continue;
+            }
+            ClientCommand::Send(text) => text.trim().to_string(),
+        };
+        if content.is_empty() {
+            continue;
+        }
+        // The UI checks too, but only once it has heard the limit
+        if let Some(max) = client.session().max_frame_bytes {
+            let len = chat_frame_len(&content);
+            if len > max {
+                output_tx
+                    .send(OutputEvent::TextLine(TextLine {
+                        text: format!(
+                            "Message is too long ({} of {} bytes allowed), not sent",
+                            len, max
+                        ),
                         color: Some(RED_COLOR.clone()),
                     }))
                     .await?;
-                timeout_until = Some(Instant::now() + Duration::from_secs(10));
                 continue;
             }
+        }
+
+        let now = Instant::now();
 
-            // Send self message to UI
-            let timestamp = Utc::now();
+        if let Some(timeout) = timeout_until {
+            if now < timeout {
+                let remaining = timeout.duration_since(now);
+                output_tx
+                    .send(OutputEvent::SystemEvent(SystemEvent::RateLimit {
+                        seconds: remaining.as_secs_f32(),
+                    }))
+                    .await?;
+                continue;
+            } else {
+                timeout_until = None;
+            }
+        }
+
+        // Track messages within 5 seconds window
+        msg_times.push_back(now);
+        while msg_times
+            .front()
+            .is_some_and(|t| now.duration_since(*t) > Duration::from_secs(5))
+        {
+            msg_times.pop_front();
+        }
+
+        if msg_times.len() > 10 {
             output_tx
-                .send(OutputEvent::ChatMessage(ChatMessage {
-                    content: trimmed.to_string(),
-                    sender: my_addr_str.clone(),
-                    username: Some("You".to_string()),
-                    timestamp,
-                    is_self: true,
+                .send(OutputEvent::TextLine(TextLine {
+                    text: "You are sending messages too fast! Timeout for 10 seconds.".to_string(),
+                    color: Some(RED_COLOR.clone()),
                 }))
                 .await?;
+            timeout_until = Some(Instant::now() + Duration::from_secs(10));
+            continue;
+        }
 
-            // Send message to server
-            server_writer
-                .write_all(format!("{}\n", trimmed).as_bytes())
+        // Encrypted rooms refuse messages until we hold their key
+        if let Err(e) = client.send(&content).await {
+            output_tx
+                .send(OutputEvent::TextLine(TextLine {
+                    text: format!("{:#}, not sent", e),
+                    color: Some(RED_COLOR.clone()),
+                }))
                 .await?;
-            server_writer.flush().await?;
-        } else {
-            break;
+            continue;
         }
+
+        // Show our own message, which the server's echo won't
+        let encryption = if client.has_room_key(&client.room()) {
+            Encryption::Decrypted
+        } else {
+            Encryption::Plain
+        };
+        output_tx
+            .send(OutputEvent::ChatMessage(ChatMessage {
+                content,
+                sender: client.session().local_addr.to_string(),
+                username: Some("You".to_string()),
+                timestamp: Utc::now(),
+                room: None,
+                is_self: true,
+                is_history: false,
+                encryption,
+            }))
+            .await?;
     }
+}
 
-    // Clean shutdown message
+/// Where the client is and whether it can read the room, for the status bar.
+fn room_status(client: &HotlineClient) -> OutputEvent {
+    let room = client.room();
+    OutputEvent::SystemEvent(SystemEvent::RoomStatus {
+        encrypted: client.is_room_encrypted(&room),
+        has_key: client.has_room_key(&room),
+        room,
+    })
+}
+
+/// Tells the UI a connection attempt failed, with the details needed to
+/// decide on a changed certificate.
+async fn report_connect_error(
+    output_tx: &mpsc::Sender<OutputEvent>,
+    error: anyhow::Error,
+) -> Result<()> {
+    let event = match error.downcast::<CertificateChanged>() {
+        Ok(CertificateChanged {
+            address,
+            pinned,
+            presented,
+        }) => SystemEvent::CertificateChanged {
+            address,
+            pinned,
+            presented,
+        },
+        Err(error) => match error.downcast::<AccessDenied>() {
+            Ok(denied) => SystemEvent::ConnectionError {
+                message: format!(
+                    "{}. Enter the chatroom password or an invite token from the host.",
+                    denied
+                ),
+            },
+            Err(error) => SystemEvent::ConnectionError {
+                message: format!("{:#}", error),
+            },
+        },
+    };
+    output_tx.send(OutputEvent::SystemEvent(event)).await?;
+    Ok(())
+}
+
+/// Tells the UI a connection is up, after connecting or reconnecting.
+async fn announce_session(
+    client: &HotlineClient,
+    output_tx: &mpsc::Sender<OutputEvent>,
+) -> Result<()> {
+    let session = client.session();
+
+    // Send connection established event
+    output_tx
+        .send(OutputEvent::SystemEvent(
+            SystemEvent::ConnectionEstablished {
+                address: session.local_addr.to_string(),
+            },
+        ))
+        .await?;
+    if let Some(max_bytes) = session.max_frame_bytes {
+        output_tx
+            .send(OutputEvent::SystemEvent(SystemEvent::MessageLimit {
+                max_bytes,
+            }))
+            .await?;
+    }
+
+    let capabilities = if session.capabilities.is_empty() {
+        "none".to_string()
+    } else {
+        session
+            .capabilities
+            .iter()
+            .map(|c| format!("{:?}", c).to_lowercase())
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
     output_tx
         .send(OutputEvent::TextLine(TextLine {
-            text: "Shutting down client connection...".to_string(),
+            text: format!(
+                "Connected to chatroom '{}' (protocol v{}, capabilities: {})",
+                session.room, session.version, capabilities
+            ),
+            color: Some(GREEN_COLOR.clone()),
+        }))
+        .await?;
+
+    let security = match &session.tls {
+        None => TextLine {
+            text: "The connection is not encrypted".to_string(),
             color: Some(YELLOW_COLOR.clone()),
+        },
+        Some(tls) if tls.trust == Trust::Known => TextLine {
+            text: "Encrypted with TLS; the certificate matches the one pinned earlier".to_string(),
+            color: Some(GREEN_COLOR.clone()),
+        },
+        Some(tls) => TextLine {
+            text: format!(
+                "Encrypted with TLS; {}. Compare its fingerprint with the one the host sees: {}",
+                if tls.trust == Trust::FirstUse {
+                    "first connection, so the certificate is now pinned"
+                } else {
+                    "the new certificate is now pinned"
+                },
+                tls.fingerprint
+            ),
+            color: Some(YELLOW_COLOR.clone()),
+        },
+    };
+    output_tx.send(OutputEvent::TextLine(security)).await?;
+
+    if let Some(fingerprint) = &session.identity {
+        output_tx
+            .send(OutputEvent::TextLine(TextLine {
+                text: format!(
+                    "Proved identity key {}; others can check it with /whois <your name>",
+                    fingerprint
+                ),
+                color: Some(GRAY_COLOR.clone()),
+            }))
+            .await?;
+    }
+
+    // Send welcome message
+    output_tx
+        .send(OutputEvent::TextLine(TextLine {
+            text: "You can now chat! Type and press Enter. Type `/quit` to exit.".to_string(),
+            color: Some(GREEN_COLOR.clone()),
         }))
         .await?;
 
     Ok(())
 }
+
+/// Waits with backoff and reconnects after the connection dropped. Commands
+/// typed meanwhile are refused, except `/quit`.
+async fn reconnect(
+    client: &mut HotlineClient,
+    error: Option<String>,
+    input_rx: &mut mpsc::Receiver<ClientCommand>,
+    output_tx: &mpsc::Sender<OutputEvent>,
+    shutdown_signal: &AtomicBool,
+) -> Result<Reconnect> {
+    let text = match error {
+        Some(error) => format!("Connection lost: {}", error),
+        None => "Connection lost".to_string(),
+    };
+    output_tx
+        .send(OutputEvent::TextLine(TextLine {
+            text,
+            color: Some(YELLOW_COLOR.clone()),
+        }))
+        .await?;
+
+    let mut backoff = Backoff::default();
+    for attempt in 1..=MAX_RECONNECT_ATTEMPTS {
+        let delay = backoff.next_delay();
+        output_tx
+            .send(OutputEvent::SystemEvent(SystemEvent::Reconnecting {
+                attempt,
+                seconds: delay.as_secs_f32(),
+            }))
+            .await?;
+
+        let sleep = tokio::time::sleep(delay);
+        tokio::pin!(sleep);
+        loop {
+            tokio::select! {
+                _ = &mut sleep => break,
+                command = input_rx.recv() => match command {
+                    Some(ClientCommand::Quit) | None => return Ok(Reconnect::Quit),
+                    Some(_) => {
+                        output_tx
+                            .send(OutputEvent::TextLine(TextLine {
+                                text: "Not connected; reconnecting...".to_string(),
+                                color: Some(RED_COLOR.clone()),
+                            }))
+                            .await?;
+                    }
+                },
+            }
+        }
+        if shutdown_signal.load(Ordering::SeqCst) {
+            return Ok(Reconnect::Quit);
+        }
+
+        match client.reconnect().await {
+            Ok(()) => return Ok(Reconnect::Connected),
+            // A ban, a stolen nickname, a revoked invite or a changed
+            // certificate won't go away by retrying
+            Err(e)
+                if e.downcast_ref::<Rejected>().is_some()
+                    || e.downcast_ref::<AccessDenied>().is_some()
+                    || e.downcast_ref::<CertificateChanged>().is_some() =>
+            {
+                report_connect_error(output_tx, e).await?;
+                return Ok(Reconnect::GaveUp);
+            }
+            Err(e) => {
+                output_tx
+                    .send(OutputEvent::TextLine(TextLine {
+                        text: format!("Reconnect failed: {:#}", e),
+                        color: Some(GRAY_COLOR.clone()),
+                    }))
+                    .await?;
+            }
+        }
+    }
+
+    output_tx
+        .send(OutputEvent::SystemEvent(SystemEvent::ConnectionError {
+            message: format!(
+                "Could not reconnect after {} attempts",
+                MAX_RECONNECT_ATTEMPTS
+            ),
+        }))
+        .await?;
+    Ok(Reconnect::GaveUp)
+}
+
+/// Shows a server event in the UI. Returns `false` once the connection is over.
+async fn forward_event(
+    output_tx: &mpsc::Sender<OutputEvent>,
+    event: ClientEvent,
+    client: &HotlineClient,
+) -> bool {
+    let output = match event {
+        // Our own messages were already shown when we sent them
+        ClientEvent::Message { own: true, .. } => return true,
+        ClientEvent::Message { message, .. } => {
+            OutputEvent::ChatMessage(message.into_chat_message(false))
+        }
+        ClientEvent::UserJoined { .. }
+        | ClientEvent::UserLeft { .. }
+        | ClientEvent::UserRenamed { .. }
+        | ClientEvent::TopicChanged { .. } => match event.notice_message() {
+            Some(payload) => OutputEvent::ChatMessage(payload.into_chat_message(false)),
+            None => return true,
+        },
+        // The key may have been set since the event was read, so ask the
+        // client rather than trusting the event
+        ClientEvent::RoomJoined {
+            room,
+            members,
+            encrypted,
+            topic,
+        } => {
+            let mut text = format!("You are now in #{} (members: {})", room, members.join(", "));
+            if let Some(topic) = topic {
+                text.push_str(&format!("\nTopic: {}", topic));
+            }
+            if encrypted && !client.has_room_key(&room) {
+                text.push_str(&format!(
+                    "\n#{} is end-to-end encrypted; enter its passphrase with /encrypt <passphrase> to read and send messages",
+                    room
+                ));
+            }
+            let _ = output_tx
+                .send(OutputEvent::TextLine(TextLine {
+                    text,
+                    color: Some(CYAN_COLOR.clone()),
+                }))
+                .await;
+            room_status(client)
+        }
+        ClientEvent::RoomEncrypted { room, by } => {
+            let mut text = format!("{} turned on end-to-end encryption in #{}", by, room);
+            if !client.has_room_key(&room) {
+                text.push_str("; enter the shared passphrase with /encrypt <passphrase> to read and send messages");
+            }
+            let _ = output_tx
+                .send(OutputEvent::TextLine(TextLine {
+                    text,
+                    color: Some(CYAN_COLOR.clone()),
+                }))
+                .await;
+            room_status(client)
+        }
+        ClientEvent::Whois {
+            username,
+            online,
+            account: true,
+            role,
+            ..
+        } => {
+            let mut text = if online {
+                format!("{} is online, logged in to their account", username)
+            } else {
+                format!(
+                    "{} is offline; the name belongs to a registered account",
+                    username
+                )
+            };
+            if let Some(role) = role {
+                text.push_str(&format!(" (role: {})", role));
+            }
+            OutputEvent::TextLine(TextLine {
+                text,
+                color: Some(CYAN_COLOR.clone()),
+            })
+        }
+        ClientEvent::Whois {
+            username,
+            online,
+            fingerprint,
+            ..
+        } => {
+            let (text, color) = match (online, fingerprint) {
+                (true, Some(fingerprint)) => (
+                    format!("{} is online with identity key {}", username, fingerprint),
+                    CYAN_COLOR.clone(),
+                ),
+                (true, None) => (
+                    format!(
+                        "{} is online without a registered identity key; anyone could be using this name",
+                        username
+                    ),
+                    YELLOW_COLOR.clone(),
+                ),
+                (false, Some(fingerprint)) => (
+                    format!(
+                        "{} is offline; the name is bound to identity key {}",
+                        username, fingerprint
+                    ),
+                    CYAN_COLOR.clone(),
+                ),
+                (false, None) => (
+                    format!(
+                        "Nobody named {} is online and the name isn't registered",
+                        username
+                    ),
+                    GRAY_COLOR.clone(),
+                ),
+            };
+            OutputEvent::TextLine(TextLine {
+                text,
+                color: Some(color),
+            })
+        }
+        ClientEvent::LoggedIn {
+            username,
+            registered,
+        } => OutputEvent::TextLine(TextLine {
+            text: if registered {
+                format!(
+                    "Registered the account '{}'; log in with /login {} <password> or the connection dialog from now on",
+                    username, username
+                )
+            } else {
+                format!("Logged in as {}", username)
+            },
+            color: Some(GREEN_COLOR.clone()),
+        }),
+        ClientEvent::Rooms(rooms) => OutputEvent::TextLine(TextLine {
+            text: format_room_list(&rooms),
+            color: Some(CYAN_COLOR.clone()),
+        }),
+        ClientEvent::History {
+            room,
+            messages,
+            has_more,
+            resumed: true,
+        } => {
+            forward_missed(output_tx, room, messages, has_more).await;
+            return true;
+        }
+        ClientEvent::History {
+            room,
+            messages,
+            has_more,
+            ..
+        } => {
+            forward_history(output_tx, room, messages, has_more).await;
+            return true;
+        }
+        ClientEvent::Info(text) => OutputEvent::TextLine(TextLine { text, color: None }),
+        ClientEvent::Lagged { missed } => OutputEvent::TextLine(TextLine {
+            text: format!(
+                "You missed {} updates because your connection couldn't keep up; use /history to catch up",
+                missed
+            ),
+            color: Some(YELLOW_COLOR.clone()),
+        }),
+        ClientEvent::Pong { rtt } => OutputEvent::TextLine(TextLine {
+            text: format!("Pong from server: {:.1} ms", rtt.as_secs_f64() * 1000.0),
+            color: Some(CYAN_COLOR.clone()),
+        }),
+        ClientEvent::Error(message) => OutputEvent::TextLine(TextLine {
+            text: format!("Server error: {}", message),
+            color: Some(RED_COLOR.clone()),
+        }),
+        ClientEvent::RateLimited { seconds, reason } => {
+            let _ = output_tx
+                .send(OutputEvent::TextLine(TextLine {
+                    text: reason,
+                    color: Some(RED_COLOR.clone()),
+                }))
+                .await;
+            OutputEvent::SystemEvent(SystemEvent::RateLimit { seconds })
+        }
+        ClientEvent::Kicked { reason } => {
+            let _ = output_tx
+                .send(OutputEvent::SystemEvent(SystemEvent::ConnectionError {
+                    message: format!("Disconnected by server: {}", reason),
+                }))
+                .await;
+            return false;
+        }
+        ClientEvent::Disconnected { error } => {
+            let event = match error {
+                Some(message) => SystemEvent::ConnectionError { message },
+                None => SystemEvent::ConnectionClosed,
+            };
+            let _ = output_tx.send(OutputEvent::SystemEvent(event)).await;
+            return false;
+        }
+    };
+    let _ = output_tx.send(output).await;
+    true
+}
+
+/// Shows a page of history in the UI, marked so it isn't mistaken for live chat.
+async fn forward_history(
+    output_tx: &mpsc::Sender<OutputEvent>,
+    room: String,
+    messages: Vec<ChatPayload>,
+    has_more: bool,
+) {
+    if messages.is_empty() {
+        let _ = output_tx
+            .send(OutputEvent::TextLine(TextLine {
+                text: format!("No earlier messages in #{}", room),
+                color: Some(GRAY_COLOR.clone()),
+            }))
+            .await;
+        return;
+    }
+
+    let oldest = messages.first().and_then(|m| m.id);
+    let _ = output_tx
+        .send(OutputEvent::TextLine(TextLine {
+            text: format!("--- {} earlier messages in #{} ---", messages.len(), room),
+            color: Some(GRAY_COLOR.clone()),
+        }))
+        .await;
+    for message in messages {
+        let mut chat_message = message.into_chat_message(false);
+        chat_message.is_history = true;
+        let _ = output_tx.send(OutputEvent::ChatMessage(chat_message)).await;
+    }
+
+    let footer = match (has_more, oldest) {
+        (true, Some(id)) => format!(
+            "--- end of history, type `/history before {}` for more ---",
+            id
+        ),
+        _ => "--- end of history ---".to_string(),
+    };
+    let _ = output_tx
+        .send(OutputEvent::TextLine(TextLine {
+            text: footer,
+            color: Some(GRAY_COLOR.clone()),
+        }))
+        .await;
+}
+
+/// Shows what was said in the room while we were reconnecting.
+async fn forward_missed(
+    output_tx: &mpsc::Sender<OutputEvent>,
+    room: String,
+    messages: Vec<ChatPayload>,
+    has_more: bool,
+) {
+    if messages.is_empty() {
+        let _ = output_tx
+            .send(OutputEvent::TextLine(TextLine {
+                text: format!("No messages missed in #{}", room),
+                color: Some(GRAY_COLOR.clone()),
+            }))
+            .await;
+        return;
+    }
+
+    let _ = output_tx
+        .send(OutputEvent::TextLine(TextLine {
+            text: format!(
+                "--- {} messages you missed in #{} ---",
+                messages.len(),
+                room
+            ),
+            color: Some(GRAY_COLOR.clone()),
+        }))
+        .await;
+    let oldest = messages.first().and_then(|m| m.id);
+    for message in messages {
+        let mut chat_message = message.into_chat_message(false);
+        chat_message.is_history = true;
+        let _ = output_tx.send(OutputEvent::ChatMessage(chat_message)).await;
+    }
+
+    let footer = match (has_more, oldest) {
+        (true, Some(id)) => format!(
+            "--- more were missed, type `/history before {}` to see them ---",
+            id
+        ),
+        _ => "--- you are up to date ---".to_string(),
+    };
+    let _ = output_tx
+        .send(OutputEvent::TextLine(TextLine {
+            text: footer,
+            color: Some(GRAY_COLOR.clone()),
+        }))
+        .await;
+}
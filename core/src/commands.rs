@@ -0,0 +1,550 @@
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::address::{format_host_port, parse_host_port};
+use crate::config::HistoryBackend;
+use crate::history::BACKFILL_LIMIT;
+use crate::moderation::{BanTarget, parse_duration};
+use crate::roles::Role;
+
+/// Where to connect and as whom. `host` is a hostname or a bare IP literal,
+/// IPv6 without brackets.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConnectRequest {
+    pub host: String,
+    pub port: u16,
+    /// `None` joins anonymously.
+    pub username: Option<String>,
+    /// Connect over TLS, pinning the server's certificate on first use.
+    pub tls: bool,
+    /// Trust the server even though its certificate no longer matches the
+    /// pinned one, provided it presents this fingerprint.
+    pub accept_fingerprint: Option<String>,
+    /// Chatroom password or invite token, for servers that require one.
+    pub password: Option<String>,
+    /// Logs in to the account named `username`.
+    pub account_password: Option<String>,
+}
+
+impl ConnectRequest {
+    /// Builds a request from dialog or command line input. `address` may
+    /// carry its own port (`host:port`, `[::1]:9000`), which wins over `port`.
+    pub fn from_input(address: &str, port: u16, username: &str) -> Result<Self, String> {
+        let (host, port) = parse_host_port(address, port)?;
+        let username = Some(username.trim())
+            .filter(|u| !u.is_empty())
+            .map(str::to_string);
+        Ok(ConnectRequest {
+            host,
+            port,
+            username,
+            tls: false,
+            accept_fingerprint: None,
+            password: None,
+            account_password: None,
+        })
+    }
+
+    /// `host:port` for display, with IPv6 hosts in brackets.
+    pub fn address(&self) -> String {
+        format_host_port(&self.host, self.port)
+    }
+}
+
+/// Everything the setup dialog or command line decides about a server.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StartRequest {
+    pub chatroom: String,
+    /// IP to listen on, e.g. `0.0.0.0` or `[::]`; `None` uses the config.
+    pub bind_address: Option<String>,
+    pub port: u16,
+    pub logging: bool,
+    pub history: HistoryBackend,
+    /// Overrides the configured log directory.
+    pub log_dir: Option<PathBuf>,
+    /// Accept only TLS connections, see `tls::TlsConfig`.
+    pub tls: bool,
+    /// Chatroom password; `None` lets anyone in unless `invite_only`.
+    pub password: Option<String>,
+    /// Require the password or an invite token, see `access::AccessConfig`.
+    pub invite_only: bool,
+}
+
+/// Commands accepted by `run_client_backend`. The first one must be
+/// `Connect`; the rest act on the open connection.
+#[derive(Debug, Clone)]
+pub enum ClientCommand {
+    Connect(ConnectRequest),
+    /// Chat message for the current room.
+    Send(String),
+    Join(String),
+    Part,
+    Nick(String),
+    ListRooms,
+    /// Older messages in the current room, newest `limit` by default or
+    /// only those older than `before`.
+    History {
+        limit: usize,
+        before: Option<u64>,
+    },
+    /// Measures the round trip to the server.
+    Ping,
+    /// Switches the current room to end-to-end encryption with a key
+    /// derived from this passphrase, or sets the key if it already is.
+    Encrypt(String),
+    /// Drops the key of the current room.
+    ForgetRoomKey,
+    /// Shows the identity key bound to a user, or our own when `None`.
+    Whois(Option<String>),
+    /// Registers the current nickname as an account with this password.
+    Register(String),
+    Login {
+        username: String,
+        password: String,
+    },
+    /// Sets the topic of the current room, or clears it when `None`.
+    Topic(Option<String>),
+    // Moderation like at the server console, if our role allows it
+    Kick {
+        user: String,
+        reason: Option<String>,
+    },
+    /// `target` is a nickname or an IP address.
+    Ban {
+        target: String,
+        duration: Option<Duration>,
+    },
+    Unban(String),
+    Mute {
+        user: String,
+        duration: Option<Duration>,
+    },
+    Unmute(String),
+    SetRole {
+        user: String,
+        role: Role,
+    },
+    /// Connects to the last server again after
+    /// `SystemEvent::CertificateChanged`, trusting the certificate with
+    /// `fingerprint` from now on.
+    TrustCertificate {
+        fingerprint: String,
+    },
+    Quit,
+}
+
+impl ClientCommand {
+    /// Parses a line typed into the chat input. Slash commands map to their
+    /// command, anything else is sent as a message.
+    pub fn parse(input: &str) -> Result<Self, String> {
+        let input = input.trim();
+        let Some(rest) = input.strip_prefix('/') else {
+            return Ok(ClientCommand::Send(input.to_string()));
+        };
+        let (command, args) = rest.split_once(' ').unwrap_or((rest, ""));
+        let args = args.trim();
+
+        match command {
+            "join" if !args.is_empty() => Ok(ClientCommand::Join(args.to_string())),
+            "join" => Err("Usage: /join <room>".to_string()),
+            "part" => Ok(ClientCommand::Part),
+            "nick" if !args.is_empty() => Ok(ClientCommand::Nick(args.to_string())),
+            "nick" => Err("Usage: /nick <name>".to_string()),
+            "rooms" => Ok(ClientCommand::ListRooms),
+            "history" => parse_history_args(args),
+            "ping" => Ok(ClientCommand::Ping),
+            "whois" => Ok(ClientCommand::Whois(
+                Some(args).filter(|a| !a.is_empty()).map(str::to_string),
+            )),
+            "register" if !args.is_empty() => Ok(ClientCommand::Register(args.to_string())),
+            "register" => Err("Usage: /register <password>".to_string()),
+            "login" => match args.split_once(' ') {
+                Some((username, password)) if !password.trim().is_empty() => {
+                    Ok(ClientCommand::Login {
+                        username: username.to_string(),
+                        password: password.trim().to_string(),
+                    })
+                }
+                _ => Err("Usage: /login <name> <password>".to_string()),
+            },
+            "topic" if args == "off" => Ok(ClientCommand::Topic(None)),
+            "topic" if !args.is_empty() => Ok(ClientCommand::Topic(Some(args.to_string()))),
+            "topic" => Err("Usage: /topic <text> or /topic off".to_string()),
+            "kick" | "ban" | "unban" | "mute" | "unmute" | "role" => {
+                parse_moderation_args(command, args)
+            }
+            "encrypt" if args == "off" => Ok(ClientCommand::ForgetRoomKey),
+            "encrypt" if !args.is_empty() => Ok(ClientCommand::Encrypt(args.to_string())),
+            "encrypt" => Err("Usage: /encrypt <passphrase> or /encrypt off".to_string()),
+            "quit" => Ok(ClientCommand::Quit),
+            _ => Err(format!("Unknown command: /{}", command)),
+        }
+    }
+}
+
+/// Accepts `/history`, `/history <n>` and `/history before <id> [n]`.
+fn parse_history_args(args: &str) -> Result<ClientCommand, String> {
+    const USAGE: &str = "Usage: /history [n] or /history before <id> [n]";
+    let parts: Vec<&str> = args.split_whitespace().collect();
+    let parse_number = |s: &str| s.parse::<u64>().map_err(|_| USAGE.to_string());
+
+    let (limit, before) = match parts.as_slice() {
+        [] => (BACKFILL_LIMIT, None),
+        [n] => (parse_number(n)? as usize, None),
+        ["before", id] => (BACKFILL_LIMIT, Some(parse_number(id)?)),
+        ["before", id, n] => (parse_number(n)? as usize, Some(parse_number(id)?)),
+        _ => return Err(USAGE.to_string()),
+    };
+    Ok(ClientCommand::History { limit, before })
+}
+
+/// Accepts the moderation commands with the same arguments as the server
+/// console, see `ServerCommand::parse`.
+fn parse_moderation_args(command: &str, args: &str) -> Result<ClientCommand, String> {
+    let args: Vec<&str> = args.split_whitespace().collect();
+    let optional_duration = |arg: Option<&&str>| arg.map(|d| parse_duration(d)).transpose();
+
+    match (command, args.as_slice()) {
+        ("kick", [user, reason @ ..]) => Ok(ClientCommand::Kick {
+            user: user.to_string(),
+            reason: (!reason.is_empty()).then(|| reason.join(" ")),
+        }),
+        ("kick", _) => Err("Usage: /kick <user> [reason]".to_string()),
+        ("ban", [target] | [target, _]) => {
+            optional_duration(args.get(1)).map(|duration| ClientCommand::Ban {
+                target: target.to_string(),
+                duration,
+            })
+        }
+        ("ban", _) => Err("Usage: /ban <user|ip> [duration]".to_string()),
+        ("unban", [target]) => Ok(ClientCommand::Unban(target.to_string())),
+        ("unban", _) => Err("Usage: /unban <user|ip>".to_string()),
+        ("mute", [user] | [user, _]) => {
+            optional_duration(args.get(1)).map(|duration| ClientCommand::Mute {
+                user: user.to_string(),
+                duration,
+            })
+        }
+        ("mute", _) => Err("Usage: /mute <user> [duration]".to_string()),
+        ("unmute", [user]) => Ok(ClientCommand::Unmute(user.to_string())),
+        ("unmute", _) => Err("Usage: /unmute <user>".to_string()),
+        ("role", [user, role]) => Ok(ClientCommand::SetRole {
+            user: user.to_string(),
+            role: role.parse()?,
+        }),
+        _ => Err(ROLE_USAGE.to_string()),
+    }
+}
+
+const ROLE_USAGE: &str = "Usage: /role <user> <guest|member|moderator|admin|owner>";
+
+/// Commands accepted by `run_server_backend`. The first one must be
+/// `Start`; the rest are what the host can do while the server runs.
+#[derive(Debug, Clone)]
+pub enum ServerCommand {
+    Start(StartRequest),
+    /// Host message, announced in every room.
+    Send(String),
+    ListRooms,
+    Kick {
+        user: String,
+        reason: Option<String>,
+    },
+    Ban {
+        target: BanTarget,
+        duration: Option<Duration>,
+    },
+    Unban {
+        target: BanTarget,
+    },
+    Mute {
+        user: String,
+        duration: Option<Duration>,
+    },
+    Unmute {
+        user: String,
+    },
+    ListBans,
+    /// Frees a nickname bound to an identity key.
+    ForgetKey {
+        user: String,
+    },
+    /// Deletes an account, freeing its name.
+    Unregister {
+        user: String,
+    },
+    /// Mints an invite token good for `uses` identity keys or IP addresses,
+    /// expiring after `duration` if given.
+    Invite {
+        duration: Option<Duration>,
+        uses: u32,
+    },
+    ListInvites,
+    Revoke {
+        token: String,
+    },
+    /// Sets the topic of a room, or clears it when `None`.
+    Topic {
+        room: String,
+        topic: Option<String>,
+    },
+    /// Changes the role of an account.
+    SetRole {
+        user: String,
+        role: Role,
+    },
+    /// Accounts with a role other than member.
+    ListRoles,
+    Stop,
+}
+
+impl ServerCommand {
+    /// Parses a line typed at the server console. Unknown slash commands
+    /// are sent as host messages, like plain text.
+    pub fn parse(input: &str) -> Result<Self, String> {
+        let input = input.trim();
+        let Some(rest) = input.strip_prefix('/') else {
+            return Ok(ServerCommand::Send(input.to_string()));
+        };
+        let mut parts = rest.split_whitespace();
+        let command = parts.next().unwrap_or_default();
+        let args: Vec<&str> = parts.collect();
+
+        let optional_duration = |arg: Option<&&str>| arg.map(|d| parse_duration(d)).transpose();
+
+        match (command, args.as_slice()) {
+            ("end", []) => Ok(ServerCommand::Stop),
+            ("rooms", []) => Ok(ServerCommand::ListRooms),
+            ("kick", [user, reason @ ..]) => Ok(ServerCommand::Kick {
+                user: user.to_string(),
+                reason: (!reason.is_empty()).then(|| reason.join(" ")),
+            }),
+            ("kick", _) => Err("Usage: /kick <user> [reason]".to_string()),
+            ("ban", [target]) | ("ban", [target, _]) => {
+                optional_duration(args.get(1)).map(|duration| ServerCommand::Ban {
+                    target: BanTarget::parse(target),
+                    duration,
+                })
+            }
+            ("ban", _) => Err("Usage: /ban <user|ip> [duration]".to_string()),
+            ("unban", [target]) => Ok(ServerCommand::Unban {
+                target: BanTarget::parse(target),
+            }),
+            ("unban", _) => Err("Usage: /unban <user|ip>".to_string()),
+            ("mute", [user]) | ("mute", [user, _]) => {
+                optional_duration(args.get(1)).map(|duration| ServerCommand::Mute {
+                    user: user.to_string(),
+                    duration,
+                })
+            }
+            ("mute", _) => Err("Usage: /mute <user> [duration]".to_string()),
+            ("unmute", [user]) => Ok(ServerCommand::Unmute {
+                user: user.to_string(),
+            }),
+            ("unmute", _) => Err("Usage: /unmute <user>".to_string()),
+            ("bans", []) => Ok(ServerCommand::ListBans),
+            ("forgetkey", [user]) => Ok(ServerCommand::ForgetKey {
+                user: user.to_string(),
+            }),
+            ("forgetkey", _) => Err("Usage: /forgetkey <user>".to_string()),
+            ("unregister", [user]) => Ok(ServerCommand::Unregister {
+                user: user.to_string(),
+            }),
+            ("unregister", _) => Err("Usage: /unregister <user>".to_string()),
+            ("invite", [] | [_] | [_, _]) => parse_invite_args(&args),
+            ("invite", _) => Err(INVITE_USAGE.to_string()),
+            ("invites", []) => Ok(ServerCommand::ListInvites),
+            ("revoke", [token]) => Ok(ServerCommand::Revoke {
+                token: token.to_string(),
+            }),
+            ("revoke", _) => Err("Usage: /revoke <token>".to_string()),
+            ("topic", [room, topic @ ..]) => Ok(ServerCommand::Topic {
+                room: room.to_string(),
+                topic: (!topic.is_empty()).then(|| topic.join(" ")),
+            }),
+            ("topic", _) => Err("Usage: /topic <room> [text]".to_string()),
+            ("role", [user, role]) => role.parse().map(|role| ServerCommand::SetRole {
+                user: user.to_string(),
+                role,
+            }),
+            ("role", _) => Err(ROLE_USAGE.to_string()),
+            ("roles", []) => Ok(ServerCommand::ListRoles),
+            _ => Ok(ServerCommand::Send(input.to_string())),
+        }
+    }
+}
+
+const INVITE_USAGE: &str = "Usage: /invite [duration|never] [uses]";
+
+/// Accepts `/invite`, `/invite <duration>` and `/invite <duration> <uses>`;
+/// invites are single-use and never expire by default.
+fn parse_invite_args(args: &[&str]) -> Result<ServerCommand, String> {
+    let duration = match args.first() {
+        None | Some(&"never") => None,
+        Some(duration) => Some(parse_duration(duration)?),
+    };
+    let uses = match args.get(1) {
+        None => 1,
+        Some(uses) => uses
+            .parse::<u32>()
+            .ok()
+            .filter(|&uses| uses > 0)
+            .ok_or_else(|| INVITE_USAGE.to_string())?,
+    };
+    Ok(ServerCommand::Invite { duration, uses })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client(input: &str) -> ClientCommand {
+        ClientCommand::parse(input).unwrap()
+    }
+
+    fn server(input: &str) -> ServerCommand {
+        ServerCommand::parse(input).unwrap()
+    }
+
+    #[test]
+    fn parses_client_commands() {
+        assert!(matches!(client("  hello "), ClientCommand::Send(text) if text == "hello"));
+        assert!(matches!(client("/join  lobby "), ClientCommand::Join(room) if room == "lobby"));
+        assert!(matches!(client("/nick bob"), ClientCommand::Nick(name) if name == "bob"));
+        assert!(matches!(
+            client("/history"),
+            ClientCommand::History {
+                limit: BACKFILL_LIMIT,
+                before: None
+            }
+        ));
+        assert!(matches!(
+            client("/history before 42 10"),
+            ClientCommand::History {
+                limit: 10,
+                before: Some(42)
+            }
+        ));
+        assert!(matches!(client("/whois"), ClientCommand::Whois(None)));
+        assert!(matches!(
+            client("/login bob correct horse"),
+            ClientCommand::Login { username, password } if username == "bob" && password == "correct horse"
+        ));
+        assert!(matches!(client("/topic off"), ClientCommand::Topic(None)));
+        assert!(matches!(
+            client("/encrypt off"),
+            ClientCommand::ForgetRoomKey
+        ));
+
+        for input in [
+            "/join",
+            "/nick",
+            "/history 1 2 3",
+            "/login bob",
+            "/encrypt",
+            "/dance",
+        ] {
+            assert!(
+                ClientCommand::parse(input).is_err(),
+                "'{}' was accepted",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn parses_client_moderation() {
+        assert!(matches!(
+            client("/kick bob being rude"),
+            ClientCommand::Kick { user, reason: Some(reason) } if user == "bob" && reason == "being rude"
+        ));
+        assert!(matches!(
+            client("/ban 10.0.0.1 2h"),
+            ClientCommand::Ban { target, duration: Some(d) } if target == "10.0.0.1" && d == Duration::from_secs(7200)
+        ));
+        assert!(matches!(
+            client("/mute bob"),
+            ClientCommand::Mute { duration: None, .. }
+        ));
+        assert!(matches!(
+            client("/role bob moderator"),
+            ClientCommand::SetRole {
+                role: Role::Moderator,
+                ..
+            }
+        ));
+
+        for input in [
+            "/kick",
+            "/ban bob 5w",
+            "/ban bob 99999999999d",
+            "/mute",
+            "/role bob king",
+        ] {
+            assert!(
+                ClientCommand::parse(input).is_err(),
+                "'{}' was accepted",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn parses_server_commands() {
+        assert!(matches!(server("/end"), ServerCommand::Stop));
+        assert!(matches!(
+            server("/ban bob 7d"),
+            ServerCommand::Ban { target: BanTarget::Name(name), duration: Some(_) } if name == "bob"
+        ));
+        assert!(matches!(
+            server("/unban 10.0.0.1"),
+            ServerCommand::Unban {
+                target: BanTarget::Ip(_)
+            }
+        ));
+        assert!(matches!(
+            server("/invite"),
+            ServerCommand::Invite {
+                duration: None,
+                uses: 1
+            }
+        ));
+        assert!(matches!(
+            server("/invite 1h 5"),
+            ServerCommand::Invite {
+                duration: Some(_),
+                uses: 5
+            }
+        ));
+        assert!(matches!(
+            server("/topic lobby"),
+            ServerCommand::Topic { topic: None, .. }
+        ));
+        // Unknown slash commands go out as host messages
+        assert!(matches!(server("/shrug"), ServerCommand::Send(text) if text == "/shrug"));
+
+        for input in [
+            "/invite never 0",
+            "/invite 1h 1 extra",
+            "/ban",
+            "/role bob",
+            "/mute bob 1y",
+        ] {
+            assert!(
+                ServerCommand::parse(input).is_err(),
+                "'{}' was accepted",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn connect_requests_take_the_port_from_the_address() {
+        let request = ConnectRequest::from_input("[::1]:9000", 8080, "  ").unwrap();
+        assert_eq!((request.host.as_str(), request.port), ("::1", 9000));
+        assert_eq!(request.username, None);
+        assert_eq!(request.address(), "[::1]:9000");
+        assert!(ConnectRequest::from_input("", 8080, "bob").is_err());
+    }
+}
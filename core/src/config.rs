@@ -0,0 +1,411 @@
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::access::AccessConfig;
+use crate::accounts::DEFAULT_ACCOUNTS_FILE;
+use crate::chat_log::LogConfig;
+use crate::heartbeat::HeartbeatConfig;
+use crate::identity::DEFAULT_IDENTITIES_FILE;
+use crate::moderation::DEFAULT_BANS_FILE;
+use crate::outbox::OutboxConfig;
+use crate::protocol::{DEFAULT_CLIENT_MAX_FRAME_BYTES, DEFAULT_MAX_FRAME_BYTES};
+use crate::rate_limit::RateLimitConfig;
+use crate::roles::PermissionsConfig;
+use crate::tls::TlsConfig;
+use crate::types::SerializableColor;
+
+pub const CONFIG_FILE_NAME: &str = "config.toml";
+
+/// Points at a config file to use instead of the per-user one.
+pub const CONFIG_PATH_ENV: &str = "HOTLINE_CONFIG";
+
+/// Prefix of variables overriding single settings, with `__` between the
+/// levels, e.g. `HOTLINE__SERVER__PORT=9000` or `HOTLINE__SERVER__LOG__KEEP_DAYS=7`.
+pub const ENV_PREFIX: &str = "HOTLINE__";
+
+/// Longest duration a `_secs` setting may ask for, a year.
+pub const MAX_SECS: f32 = 365.0 * 24.0 * 60.0 * 60.0;
+
+/// Settings read from `config.toml`. Every field has a default, so a file
+/// only needs the values it changes.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub client: ClientConfig,
+    pub theme: ThemeConfig,
+    /// Files that contributed to this config, lowest precedence first.
+    #[serde(skip)]
+    pub sources: Vec<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HistoryBackend {
+    Memory,
+    Disk,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub bind_address: String,
+    pub port: u16,
+    /// Default room; the setup dialog asks for one when unset.
+    pub room: Option<String>,
+    /// Extra rooms that exist from startup and are never dropped when empty.
+    pub rooms: Vec<String>,
+    pub logging: bool,
+    pub history: HistoryBackend,
+    pub log: LogConfig,
+    pub rate_limit: RateLimitConfig,
+    /// Shared by all connections from one IP address.
+    pub ip_rate_limit: RateLimitConfig,
+    /// History, nickname, join and whois requests, counted apart from chat.
+    pub request_rate_limit: RateLimitConfig,
+    /// Logins and registrations from one IP address, handshakes included.
+    pub auth_rate_limit: RateLimitConfig,
+    pub bans_file: PathBuf,
+    /// Which identity key owns each nickname, see `identity::NameBindings`.
+    pub identities_file: PathBuf,
+    /// Registered accounts and their password hashes, see `accounts`.
+    pub accounts_file: PathBuf,
+    /// Chatroom password and invites; the setup dialog can override them.
+    pub access: AccessConfig,
+    /// Which roles may kick, ban, post and so on, see `roles`.
+    pub permissions: PermissionsConfig,
+    pub heartbeat: HeartbeatConfig,
+    /// Longest frame accepted from a client, in bytes.
+    pub max_frame_bytes: usize,
+    /// Oversized frames tolerated before the connection is dropped.
+    pub oversized_frame_limit: u32,
+    /// Per-client outbound queue, see `outbox::Outbox`.
+    pub outbox: OutboxConfig,
+    pub tls: TlsConfig,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind_address: "0.0.0.0".to_string(),
+            port: 8080,
+            room: None,
+            rooms: Vec::new(),
+            logging: true,
+            history: HistoryBackend::Memory,
+            log: LogConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            ip_rate_limit: RateLimitConfig::per_ip_default(),
+            request_rate_limit: RateLimitConfig::default(),
+            auth_rate_limit: RateLimitConfig::per_ip_auth_default(),
+            bans_file: PathBuf::from(DEFAULT_BANS_FILE),
+            identities_file: PathBuf::from(DEFAULT_IDENTITIES_FILE),
+            accounts_file: PathBuf::from(DEFAULT_ACCOUNTS_FILE),
+            access: AccessConfig::default(),
+            permissions: PermissionsConfig::default(),
+            heartbeat: HeartbeatConfig::default(),
+            max_frame_bytes: DEFAULT_MAX_FRAME_BYTES,
+            oversized_frame_limit: 3,
+            outbox: OutboxConfig::default(),
+            tls: TlsConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ClientConfig {
+    pub username: Option<String>,
+    pub servers: Vec<SavedServer>,
+    /// Name of the entry in `servers` to pre-fill, otherwise the first one.
+    pub default_server: Option<String>,
+    pub heartbeat: HeartbeatConfig,
+    /// Longest frame accepted from the server, in bytes. Must hold a full
+    /// history page, so keep it well above the server's own limit.
+    pub max_frame_bytes: usize,
+    /// Pinned server certificates; defaults to `known_servers` next to the
+    /// per-user config file.
+    pub known_servers_file: Option<PathBuf>,
+    /// Identity key proving who we are to servers; defaults to `identity`
+    /// next to the per-user config file and is created on first use.
+    pub identity_file: Option<PathBuf>,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            username: None,
+            servers: Vec::new(),
+            default_server: None,
+            heartbeat: HeartbeatConfig::default(),
+            max_frame_bytes: DEFAULT_CLIENT_MAX_FRAME_BYTES,
+            known_servers_file: None,
+            identity_file: None,
+        }
+    }
+}
+
+impl ClientConfig {
+    pub fn default_server(&self) -> Option<&SavedServer> {
+        match &self.default_server {
+            Some(name) => self.servers.iter().find(|s| s.name == *name),
+            None => self.servers.first(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SavedServer {
+    pub name: String,
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    /// Overrides `client.username` for this server.
+    pub username: Option<String>,
+    #[serde(default)]
+    pub tls: bool,
+    /// Chatroom password or invite token for servers that require one.
+    pub password: Option<String>,
+}
+
+fn default_port() -> u16 {
+    8080
+}
+
+/// Overrides for the TUI palette. Colours are `"#rrggbb"` strings or
+/// `{ r = .., g = .., b = .. }` tables; unset entries keep the built-in theme.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ThemeConfig {
+    #[serde(deserialize_with = "color")]
+    pub background: Option<SerializableColor>,
+    #[serde(deserialize_with = "color")]
+    pub view: Option<SerializableColor>,
+    #[serde(deserialize_with = "color")]
+    pub primary: Option<SerializableColor>,
+    #[serde(deserialize_with = "color")]
+    pub secondary: Option<SerializableColor>,
+    #[serde(deserialize_with = "color")]
+    pub tertiary: Option<SerializableColor>,
+    #[serde(deserialize_with = "color")]
+    pub title_primary: Option<SerializableColor>,
+    #[serde(deserialize_with = "color")]
+    pub title_secondary: Option<SerializableColor>,
+}
+
+fn color<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<SerializableColor>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum ColorValue {
+        Hex(String),
+        Rgb(SerializableColor),
+    }
+
+    match ColorValue::deserialize(deserializer)? {
+        ColorValue::Rgb(color) => Ok(Some(color)),
+        ColorValue::Hex(hex) => parse_hex_color(&hex)
+            .map(Some)
+            .map_err(serde::de::Error::custom),
+    }
+}
+
+fn parse_hex_color(hex: &str) -> Result<SerializableColor, String> {
+    let digits = hex.trim().trim_start_matches('#');
+    let channel = |i: usize| {
+        digits
+            .get(i..i + 2)
+            .and_then(|c| u8::from_str_radix(c, 16).ok())
+    };
+    match (digits.len(), channel(0), channel(2), channel(4)) {
+        (6, Some(r), Some(g), Some(b)) => Ok(SerializableColor { r, g, b }),
+        _ => Err(format!("invalid colour '{}', expected #rrggbb", hex)),
+    }
+}
+
+/// System-wide config, read first so per-user settings win.
+pub fn system_config_path() -> Option<PathBuf> {
+    if cfg!(unix) {
+        Some(Path::new("/etc/hotline").join(CONFIG_FILE_NAME))
+    } else {
+        None
+    }
+}
+
+pub fn user_config_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("hotline").join(CONFIG_FILE_NAME))
+}
+
+impl Config {
+    /// Merges the system and per-user files (or `$HOTLINE_CONFIG`), then
+    /// applies `HOTLINE__*` environment overrides. Missing files are skipped.
+    pub fn load() -> Result<Self> {
+        let explicit = std::env::var_os(CONFIG_PATH_ENV).map(PathBuf::from);
+        if let Some(path) = &explicit
+            && !path.exists()
+        {
+            bail!(
+                "{} points at {}, which does not exist",
+                CONFIG_PATH_ENV,
+                path.display()
+            );
+        }
+
+        let candidates = [system_config_path(), explicit.or_else(user_config_path)];
+        let mut merged = toml::Table::new();
+        let mut sources = Vec::new();
+        for path in candidates.into_iter().flatten() {
+            if !path.exists() {
+                continue;
+            }
+            let text = fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read config {}", path.display()))?;
+            let table: toml::Table = text
+                .parse()
+                .with_context(|| format!("Invalid config {}", path.display()))?;
+            merge_tables(&mut merged, table);
+            sources.push(path);
+        }
+
+        apply_env_overrides(&mut merged, std::env::vars())?;
+
+        let mut config: Config = toml::Value::Table(merged)
+            .try_into()
+            .context("Invalid configuration")?;
+        config.validate().context("Invalid configuration")?;
+        config.sources = sources;
+        Ok(config)
+    }
+
+    /// Catches values that parse but can't be used, such as `inf` seconds.
+    fn validate(&self) -> Result<()> {
+        let server = &self.server;
+        server.rate_limit.validate().context("server.rate_limit")?;
+        server
+            .ip_rate_limit
+            .validate()
+            .context("server.ip_rate_limit")?;
+        server
+            .request_rate_limit
+            .validate()
+            .context("server.request_rate_limit")?;
+        server
+            .auth_rate_limit
+            .validate()
+            .context("server.auth_rate_limit")?;
+        server.heartbeat.validate().context("server.heartbeat")?;
+        server.outbox.validate().context("server.outbox")?;
+        self.client
+            .heartbeat
+            .validate()
+            .context("client.heartbeat")?;
+        Ok(())
+    }
+}
+
+/// Checks that the setting `name` is a usable number of seconds.
+pub fn check_secs(name: &str, value: f32) -> Result<()> {
+    if !(0.0..=MAX_SECS).contains(&value) {
+        bail!(
+            "{} must be between 0 and {} seconds, not {}",
+            name,
+            MAX_SECS,
+            value
+        );
+    }
+    Ok(())
+}
+
+/// Deep-merges `overlay` into `base`, with `overlay` winning on conflicts.
+fn merge_tables(base: &mut toml::Table, overlay: toml::Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(existing)), toml::Value::Table(table)) => {
+                merge_tables(existing, table)
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+fn apply_env_overrides(
+    table: &mut toml::Table,
+    vars: impl Iterator<Item = (String, String)>,
+) -> Result<()> {
+    for (name, raw) in vars {
+        let Some(path) = name.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+        let keys: Vec<String> = path.split("__").map(|k| k.to_lowercase()).collect();
+        let Some((last, parents)) = keys.split_last() else {
+            continue;
+        };
+
+        let mut current = &mut *table;
+        for key in parents {
+            let entry = current
+                .entry(key.clone())
+                .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+            current = match entry {
+                toml::Value::Table(t) => t,
+                _ => bail!("{} overrides {}, which is not a table", name, key),
+            };
+        }
+        current.insert(last.clone(), parse_env_value(&raw));
+    }
+    Ok(())
+}
+
+/// Reads a variable as a TOML value so numbers, booleans and arrays keep
+/// their type; anything else is taken as a plain string.
+fn parse_env_value(raw: &str) -> toml::Value {
+    format!("value = {}", raw)
+        .parse::<toml::Table>()
+        .ok()
+        .and_then(|mut t| t.remove("value"))
+        .unwrap_or_else(|| toml::Value::String(raw.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_seconds_that_are_not_a_usable_duration() {
+        assert!(Config::default().validate().is_ok());
+        for value in [f32::INFINITY, f32::NAN, -1.0, MAX_SECS * 2.0] {
+            let mut config = Config::default();
+            config.server.heartbeat.interval_secs = value;
+            assert!(config.validate().is_err(), "{} passed", value);
+            let mut config = Config::default();
+            config.server.request_rate_limit.mute_secs = value;
+            assert!(config.validate().is_err(), "{} passed", value);
+            let mut config = Config::default();
+            config.server.outbox.send_timeout_secs = value;
+            assert!(config.validate().is_err(), "{} passed", value);
+
+            // Built in code rather than loaded, they still mustn't panic
+            config.server.heartbeat.timeout_secs = value;
+            let _ = config.server.heartbeat.timeout();
+        }
+    }
+
+    #[test]
+    fn env_overrides_keep_their_type() {
+        let mut table = toml::Table::new();
+        let vars = [
+            ("HOTLINE__SERVER__PORT".to_string(), "9000".to_string()),
+            ("HOTLINE__SERVER__ROOM".to_string(), "lobby".to_string()),
+            ("OTHER".to_string(), "1".to_string()),
+        ];
+        apply_env_overrides(&mut table, vars.into_iter()).unwrap();
+        let config: Config = toml::Value::Table(table).try_into().unwrap();
+        assert_eq!(config.server.port, 9000);
+        assert_eq!(config.server.room.as_deref(), Some("lobby"));
+    }
+}
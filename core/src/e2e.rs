@@ -0,0 +1,161 @@
+use anyhow::{Result, anyhow};
+use argon2::Argon2;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+
+/// What the server stores and relays as `content` for encrypted messages,
+/// so logs, history and older clients never see the text.
+pub const PLACEHOLDER: &str = "[end-to-end encrypted message]";
+
+/// Salt prefix for deriving room keys; the room name completes it so the
+/// same passphrase gives every room a different key.
+const KEY_SALT: &str = "hotline-e2e-room:";
+
+/// An end-to-end encrypted message body as it travels over the wire.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Sealed {
+    /// Identifies the key it was sealed with, see `RoomKey::id`.
+    pub key_id: String,
+    /// Base64 XChaCha20-Poly1305 nonce.
+    pub nonce: String,
+    /// Base64 ciphertext including the authentication tag.
+    pub ciphertext: String,
+    /// Set by the client once the message's `content` holds the decrypted text.
+    #[serde(skip)]
+    pub opened: bool,
+}
+
+/// Key of one end-to-end encrypted room, derived from a passphrase the
+/// members share out of band. The server never sees it.
+#[derive(Clone)]
+pub struct RoomKey {
+    cipher: XChaCha20Poly1305,
+    id: String,
+}
+
+impl RoomKey {
+    /// Runs Argon2id over `passphrase`, salted with the room name. Slow on
+    /// purpose, so call it off the async runtime.
+    pub fn derive(room: &str, passphrase: &str) -> Result<Self> {
+        let mut key = [0u8; 32];
+        Argon2::default()
+            .hash_password_into(
+                passphrase.as_bytes(),
+                format!("{}{}", KEY_SALT, room).as_bytes(),
+                &mut key,
+            )
+            .map_err(|e| anyhow!("Failed to derive the room key: {}", e))?;
+        let digest = Sha256::new()
+            .chain_update(b"hotline-e2e-key-id")
+            .chain_update(key)
+            .finalize();
+        let id = digest[..8].iter().map(|b| format!("{:02x}", b)).collect();
+        Ok(RoomKey {
+            cipher: XChaCha20Poly1305::new(&key.into()),
+            id,
+        })
+    }
+
+    /// Short hash of the key, so members can tell a wrong passphrase from a
+    /// corrupted message. Reveals nothing about the key itself.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Encrypts `text` for `room`; the room name is authenticated too, so a
+    /// message can't be replayed into another room sharing the passphrase.
+    pub fn seal(&self, room: &str, text: &str) -> Result<Sealed> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: text.as_bytes(),
+                    aad: room.as_bytes(),
+                },
+            )
+            .map_err(|_| anyhow!("Failed to encrypt the message"))?;
+        Ok(Sealed {
+            key_id: self.id.clone(),
+            nonce: BASE64.encode(nonce),
+            ciphertext: BASE64.encode(ciphertext),
+            opened: false,
+        })
+    }
+
+    /// Decrypts a message sealed for `room`, failing if it was sealed with
+    /// another key or tampered with.
+    pub fn open(&self, room: &str, sealed: &Sealed) -> Result<String> {
+        let nonce = BASE64
+            .decode(&sealed.nonce)
+            .ok()
+            .filter(|nonce| nonce.len() == 24)
+            .ok_or_else(|| anyhow!("Malformed nonce"))?;
+        let ciphertext = BASE64
+            .decode(&sealed.ciphertext)
+            .map_err(|_| anyhow!("Malformed ciphertext"))?;
+        let text = self
+            .cipher
+            .decrypt(
+                XNonce::from_slice(&nonce),
+                Payload {
+                    msg: &ciphertext,
+                    aad: room.as_bytes(),
+                },
+            )
+            .map_err(|_| anyhow!("The message failed to decrypt"))?;
+        String::from_utf8(text).map_err(|_| anyhow!("The decrypted message is not text"))
+    }
+}
+
+impl fmt::Debug for RoomKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RoomKey").field("id", &self.id).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sealed_messages_open_only_with_the_same_key_and_room() {
+        let key = RoomKey::derive("secret", "hunter22").unwrap();
+        let same = RoomKey::derive("secret", "hunter22").unwrap();
+        let other = RoomKey::derive("secret", "hunter23").unwrap();
+        assert_eq!(key.id(), same.id());
+        assert_ne!(key.id(), other.id());
+
+        let sealed = key.seal("secret", "meet at noon").unwrap();
+        assert_eq!(sealed.key_id, key.id());
+        assert!(!sealed.ciphertext.contains("noon"));
+        assert_eq!(same.open("secret", &sealed).unwrap(), "meet at noon");
+        assert!(other.open("secret", &sealed).is_err());
+        // Replayed into another room sharing the passphrase
+        assert!(key.open("lobby", &sealed).is_err());
+
+        let mut tampered = sealed.clone();
+        let mut bytes = BASE64.decode(&tampered.ciphertext).unwrap();
+        bytes[0] ^= 1;
+        tampered.ciphertext = BASE64.encode(bytes);
+        assert!(key.open("secret", &tampered).is_err());
+        // Every message gets a fresh nonce
+        assert_ne!(
+            sealed.nonce,
+            key.seal("secret", "meet at noon").unwrap().nonce
+        );
+    }
+
+    #[test]
+    fn the_same_passphrase_gives_each_room_its_own_key() {
+        let lobby = RoomKey::derive("lobby", "hunter22").unwrap();
+        let secret = RoomKey::derive("secret", "hunter22").unwrap();
+        assert_ne!(lobby.id(), secret.id());
+    }
+}
@@ -0,0 +1,176 @@
+use anyhow::{Result, bail};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio::time::{Instant, Interval, MissedTickBehavior};
+
+use crate::config::{MAX_SECS, check_secs};
+
+/// Shortest interval between pings, short of turning them off.
+pub const MIN_INTERVAL_SECS: f32 = 1.0;
+
+/// How often to ping a peer and how long it may stay silent before the
+/// connection is given up as dead.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HeartbeatConfig {
+    /// Seconds between pings; 0 turns heartbeats off.
+    pub interval_secs: f32,
+    /// Seconds without hearing anything from the peer.
+    pub timeout_secs: f32,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        // Three missed pings, well below typical NAT idle timeouts
+        HeartbeatConfig {
+            interval_secs: 15.0,
+            timeout_secs: 45.0,
+        }
+    }
+}
+
+impl HeartbeatConfig {
+    /// For peers that don't speak the heartbeat capability.
+    pub fn disabled() -> Self {
+        HeartbeatConfig {
+            interval_secs: 0.0,
+            ..HeartbeatConfig::default()
+        }
+    }
+
+    /// Decided by the interval actually used, so a value too small to
+    /// measure turns heartbeats off rather than pinging nonstop.
+    pub fn enabled(&self) -> bool {
+        !self.interval().is_zero()
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        check_secs("interval_secs", self.interval_secs)?;
+        check_secs("timeout_secs", self.timeout_secs)?;
+        if self.interval_secs != 0.0 && self.interval_secs < MIN_INTERVAL_SECS {
+            bail!(
+                "interval_secs must be 0 or at least {} seconds, not {}",
+                MIN_INTERVAL_SECS,
+                self.interval_secs
+            );
+        }
+        Ok(())
+    }
+
+    pub fn interval(&self) -> Duration {
+        secs(self.interval_secs)
+    }
+
+    pub fn timeout(&self) -> Duration {
+        secs(self.timeout_secs).max(self.interval())
+    }
+}
+
+/// Unusable values, which `validate` turns away, count as no time; huge ones
+/// are capped so that adding them to the clock can't overflow.
+fn secs(value: f32) -> Duration {
+    Duration::try_from_secs_f32(value)
+        .unwrap_or(Duration::ZERO)
+        .min(Duration::from_secs_f32(MAX_SECS))
+}
+
+/// Keeps time for one connection: when to ping next and when the peer was
+/// last heard from.
+#[derive(Debug)]
+pub struct Heartbeat {
+    config: HeartbeatConfig,
+    last_seen: Instant,
+    ticker: Option<Interval>,
+}
+
+impl Heartbeat {
+    pub fn new(config: HeartbeatConfig) -> Self {
+        let ticker = config.enabled().then(|| {
+            let interval = config.interval();
+            let mut ticker = tokio::time::interval_at(Instant::now() + interval, interval);
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            ticker
+        });
+        Heartbeat {
+            config,
+            last_seen: Instant::now(),
+            ticker,
+        }
+    }
+
+    /// Records that something arrived from the peer.
+    pub fn seen(&mut self) {
+        self.last_seen = Instant::now();
+    }
+
+    /// Resolves when the next ping is due; never if heartbeats are off.
+    pub async fn tick(&mut self) {
+        match &mut self.ticker {
+            Some(ticker) => {
+                ticker.tick().await;
+            }
+            None => std::future::pending().await,
+        }
+    }
+
+    pub fn silent_for(&self) -> Duration {
+        self.last_seen.elapsed()
+    }
+
+    /// The peer has been silent past the timeout.
+    pub fn expired(&self) -> bool {
+        self.ticker.is_some() && self.silent_for() >= self.config.timeout()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(interval_secs: f32) -> HeartbeatConfig {
+        HeartbeatConfig {
+            interval_secs,
+            ..HeartbeatConfig::default()
+        }
+    }
+
+    #[test]
+    fn zero_turns_heartbeats_off() {
+        assert!(!config(0.0).enabled());
+        assert!(config(0.0).validate().is_ok());
+        assert!(!HeartbeatConfig::disabled().enabled());
+        assert!(config(15.0).enabled());
+    }
+
+    #[test]
+    fn tiny_intervals_are_rejected_or_off() {
+        let tiny = config(1e-10);
+        assert!(tiny.validate().is_err());
+        assert!(config(0.5).validate().is_err());
+        assert!(!tiny.enabled());
+        assert!(config(MIN_INTERVAL_SECS).validate().is_ok());
+    }
+
+    #[test]
+    fn huge_and_nan_values_are_rejected_and_capped() {
+        let (huge, nan) = (config(1e18), config(f32::NAN));
+        assert!(huge.validate().is_err());
+        assert!(nan.validate().is_err());
+        assert!(config(f32::INFINITY).validate().is_err());
+        assert_eq!(huge.interval(), Duration::from_secs_f32(MAX_SECS));
+        assert!(!nan.enabled());
+        let nan_timeout = HeartbeatConfig {
+            timeout_secs: f32::NAN,
+            ..HeartbeatConfig::default()
+        };
+        assert_eq!(nan_timeout.timeout(), nan_timeout.interval());
+    }
+
+    #[tokio::test]
+    async fn unvalidated_values_never_panic() {
+        for interval_secs in [0.0, 1e-10, 1e18, 1e30, f32::MAX, f32::NAN, -1.0] {
+            let heartbeat = Heartbeat::new(config(interval_secs));
+            assert!(!heartbeat.expired());
+        }
+    }
+}
@@ -0,0 +1,303 @@
+use anyhow::{Context, Result};
+use std::collections::{HashMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::protocol::ChatPayload;
+
+/// How many messages a client is sent right after joining a room.
+pub const BACKFILL_LIMIT: usize = 50;
+
+/// Upper bound for a single `/history` request.
+pub const MAX_HISTORY_PAGE: usize = 200;
+
+/// Where the server keeps chat messages so late joiners can catch up.
+/// Messages must already carry an `id` and a `room`.
+pub trait HistoryStore: Send {
+    fn append(&mut self, message: &ChatPayload) -> Result<()>;
+
+    /// The newest `limit` messages in `room`, oldest first.
+    fn recent(&self, room: &str, limit: usize) -> Result<Vec<ChatPayload>>;
+
+    /// Up to `limit` messages in `room` older than `before_id`, oldest first.
+    fn before(&self, room: &str, before_id: u64, limit: usize) -> Result<Vec<ChatPayload>>;
+
+    /// The newest `limit` messages in `room` newer than `after_id`, oldest first.
+    fn after(&self, room: &str, after_id: u64, limit: usize) -> Result<Vec<ChatPayload>>;
+
+    /// Highest message id stored so far, so ids keep increasing across restarts.
+    fn last_id(&self) -> u64;
+}
+
+/// Keeps the last `capacity` messages of each room in memory.
+pub struct MemoryHistory {
+    capacity: usize,
+    rooms: HashMap<String, VecDeque<ChatPayload>>,
+    last_id: u64,
+}
+
+impl MemoryHistory {
+    pub fn new(capacity: usize) -> Self {
+        MemoryHistory {
+            capacity,
+            rooms: HashMap::new(),
+            last_id: 0,
+        }
+    }
+}
+
+impl HistoryStore for MemoryHistory {
+    fn append(&mut self, message: &ChatPayload) -> Result<()> {
+        let room = message.room.clone().unwrap_or_default();
+        let ring = self.rooms.entry(room).or_default();
+        ring.push_back(message.clone());
+        while ring.len() > self.capacity {
+            ring.pop_front();
+        }
+        self.last_id = self.last_id.max(message.id.unwrap_or(0));
+        Ok(())
+    }
+
+    fn recent(&self, room: &str, limit: usize) -> Result<Vec<ChatPayload>> {
+        Ok(self
+            .rooms
+            .get(room)
+            .map(|ring| take_last(ring.iter(), limit))
+            .unwrap_or_default())
+    }
+
+    fn before(&self, room: &str, before_id: u64, limit: usize) -> Result<Vec<ChatPayload>> {
+        Ok(self
+            .rooms
+            .get(room)
+            .map(|ring| take_last(ring.iter().filter(|m| m.id.unwrap_or(0) < before_id), limit))
+            .unwrap_or_default())
+    }
+
+    fn after(&self, room: &str, after_id: u64, limit: usize) -> Result<Vec<ChatPayload>> {
+        Ok(self
+            .rooms
+            .get(room)
+            .map(|ring| take_last(ring.iter().filter(|m| m.id.unwrap_or(0) > after_id), limit))
+            .unwrap_or_default())
+    }
+
+    fn last_id(&self) -> u64 {
+        self.last_id
+    }
+}
+
+impl MemoryHistory {
+    /// True if every message in `room` newer than `after_id` is still held.
+    fn covers(&self, room: &str, after_id: u64) -> bool {
+        self.rooms.get(room).is_none_or(|ring| {
+            ring.len() < self.capacity
+                || ring.front().is_some_and(|m| m.id.unwrap_or(0) <= after_id)
+        })
+    }
+}
+
+/// Append-only JSON Lines file holding every message ever posted. Recent
+/// messages are also cached in memory so backfill doesn't touch the disk.
+pub struct FileHistory {
+    path: PathBuf,
+    file: File,
+    /// Id and file offset of every message, per room, in file order. Older
+    /// pages read just their own lines instead of the whole file.
+    index: HashMap<String, Vec<(u64, u64)>>,
+    /// Where the next message will be written.
+    end: u64,
+    cache: MemoryHistory,
+}
+
+impl FileHistory {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        // Large enough that `recent` never needs to fall back to the file
+        let mut cache = MemoryHistory::new(MAX_HISTORY_PAGE + 1);
+        let mut index: HashMap<String, Vec<(u64, u64)>> = HashMap::new();
+
+        if path.exists() {
+            for (offset, message) in read_messages(&path)? {
+                index
+                    .entry(message.room.clone().unwrap_or_default())
+                    .or_default()
+                    .push((message.id.unwrap_or(0), offset));
+                cache.append(&message)?;
+            }
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open history file {}", path.display()))?;
+        let end = file.metadata()?.len();
+
+        Ok(FileHistory {
+            path,
+            file,
+            index,
+            end,
+            cache,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Reads the last `limit` of `room`'s messages whose id passes `keep`.
+    fn read_indexed(
+        &self,
+        room: &str,
+        limit: usize,
+        keep: impl Fn(u64) -> bool,
+    ) -> Result<Vec<ChatPayload>> {
+        let Some(entries) = self.index.get(room) else {
+            return Ok(Vec::new());
+        };
+        let mut offsets: Vec<u64> = entries
+            .iter()
+            .rev()
+            .filter(|(id, _)| keep(*id))
+            .take(limit)
+            .map(|(_, offset)| *offset)
+            .collect();
+        offsets.reverse();
+
+        let file = File::open(&self.path)
+            .with_context(|| format!("Failed to read history file {}", self.path.display()))?;
+        let mut reader = BufReader::new(file);
+        let mut messages = Vec::with_capacity(offsets.len());
+        let mut line = String::new();
+        for offset in offsets {
+            reader.seek(SeekFrom::Start(offset))?;
+            line.clear();
+            reader.read_line(&mut line)?;
+            if let Ok(message) = serde_json::from_str::<ChatPayload>(&line) {
+                messages.push(message);
+            }
+        }
+        Ok(messages)
+    }
+}
+
+impl HistoryStore for FileHistory {
+    fn append(&mut self, message: &ChatPayload) -> Result<()> {
+        let line = format!("{}\n", serde_json::to_string(message)?);
+        self.file.write_all(line.as_bytes())?;
+        self.file.flush()?;
+        self.index
+            .entry(message.room.clone().unwrap_or_default())
+            .or_default()
+            .push((message.id.unwrap_or(0), self.end));
+        self.end += line.len() as u64;
+        self.cache.append(message)
+    }
+
+    fn recent(&self, room: &str, limit: usize) -> Result<Vec<ChatPayload>> {
+        if limit <= self.cache.capacity {
+            return self.cache.recent(room, limit);
+        }
+        self.read_indexed(room, limit, |_| true)
+    }
+
+    fn before(&self, room: &str, before_id: u64, limit: usize) -> Result<Vec<ChatPayload>> {
+        self.read_indexed(room, limit, |id| id < before_id)
+    }
+
+    fn after(&self, room: &str, after_id: u64, limit: usize) -> Result<Vec<ChatPayload>> {
+        // Resuming clients were usually only gone briefly, so try the cache first
+        if self.cache.covers(room, after_id) {
+            return self.cache.after(room, after_id, limit);
+        }
+        self.read_indexed(room, limit, |id| id > after_id)
+    }
+
+    fn last_id(&self) -> u64 {
+        self.cache.last_id()
+    }
+}
+
+/// Every message in the file with the offset of its line.
+fn read_messages(path: &Path) -> Result<Vec<(u64, ChatPayload)>> {
+    let file = File::open(path)
+        .with_context(|| format!("Failed to read history file {}", path.display()))?;
+    let mut reader = BufReader::new(file);
+    let mut messages = Vec::new();
+    let mut offset = 0;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let read = reader.read_line(&mut line)?;
+        if read == 0 {
+            break;
+        }
+        // Skip a torn final line left behind by a crash
+        if let Ok(message) = serde_json::from_str::<ChatPayload>(&line) {
+            messages.push((offset, message));
+        }
+        offset += read as u64;
+    }
+    Ok(messages)
+}
+
+fn take_last<'a>(
+    messages: impl DoubleEndedIterator<Item = &'a ChatPayload>,
+    limit: usize,
+) -> Vec<ChatPayload> {
+    let mut page: Vec<ChatPayload> = messages.rev().take(limit).cloned().collect();
+    page.reverse();
+    page
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn message(id: u64, room: &str) -> ChatPayload {
+        ChatPayload {
+            id: Some(id),
+            content: format!("message {}", id),
+            sender: "bob".to_string(),
+            username: Some("bob".to_string()),
+            timestamp: Utc::now(),
+            room: Some(room.to_string()),
+            sealed: None,
+        }
+    }
+
+    fn ids(messages: &[ChatPayload]) -> Vec<u64> {
+        messages.iter().filter_map(|m| m.id).collect()
+    }
+
+    #[test]
+    fn file_history_pages_through_the_index_across_reopens() {
+        let path =
+            std::env::temp_dir().join(format!("hotline-history-{}.jsonl", rand::random::<u64>()));
+        let mut history = FileHistory::open(&path).unwrap();
+        for id in 1..=500 {
+            let room = if id % 2 == 0 { "lobby" } else { "other" };
+            history.append(&message(id, room)).unwrap();
+        }
+        assert_eq!(ids(&history.before("lobby", 10, 3).unwrap()), vec![4, 6, 8]);
+        drop(history);
+
+        // Append some more after reopening, so offsets continue from the end
+        let mut history = FileHistory::open(&path).unwrap();
+        assert_eq!(history.last_id(), 500);
+        history.append(&message(501, "lobby")).unwrap();
+        assert_eq!(ids(&history.before("lobby", 10, 3).unwrap()), vec![4, 6, 8]);
+        assert_eq!(ids(&history.after("lobby", 0, 2).unwrap()), vec![500, 501]);
+        assert_eq!(ids(&history.recent("other", 2).unwrap()), vec![497, 499]);
+        assert_eq!(
+            history.recent("lobby", MAX_HISTORY_PAGE + 5).unwrap().len(),
+            205
+        );
+        assert!(history.before("nowhere", 10, 3).unwrap().is_empty());
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,315 @@
+use anyhow::{Context, Result, anyhow, bail};
+use base64::Engine;
+use base64::engine::general_purpose::{STANDARD as BASE64, STANDARD_NO_PAD as BASE64_NO_PAD};
+use chrono::{DateTime, Utc};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::tls::{write_atomic, write_new};
+
+/// Default location of the nickname bindings, relative to the working directory.
+pub const DEFAULT_IDENTITIES_FILE: &str = "identities.json";
+const IDENTITY_FILE: &str = "identity";
+
+/// Prefix of what a client signs to prove it holds its key, so the
+/// signature can't be passed off as anything else.
+const CHALLENGE_CONTEXT: &str = "hotline-identity-v1";
+
+/// A client's long-lived ed25519 keypair. Servers bind the nickname it first
+/// uses to the public key, so nobody else can take that name later.
+#[derive(Clone)]
+pub struct Identity {
+    key: SigningKey,
+}
+
+impl Identity {
+    /// `identity` next to the per-user config file.
+    pub fn default_path() -> PathBuf {
+        dirs::config_dir()
+            .map(|dir| dir.join("hotline").join(IDENTITY_FILE))
+            .unwrap_or_else(|| PathBuf::from(IDENTITY_FILE))
+    }
+
+    /// Reads the key in `path`, generating one readable only by the owner
+    /// if the file doesn't exist yet.
+    pub fn load_or_create(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            let key = SigningKey::from_bytes(&rand::random());
+            write_new(path, &format!("{}\n", BASE64.encode(key.to_bytes())), true)?;
+            return Ok(Identity { key });
+        }
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read identity key {}", path.display()))?;
+        let seed: [u8; 32] = BASE64
+            .decode(text.trim())
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .with_context(|| format!("Identity key {} is corrupt", path.display()))?;
+        Ok(Identity {
+            key: SigningKey::from_bytes(&seed),
+        })
+    }
+
+    /// Base64 public key, as sent in the hello.
+    pub fn public_key(&self) -> String {
+        BASE64.encode(self.key.verifying_key().as_bytes())
+    }
+
+    pub fn fingerprint(&self) -> String {
+        fingerprint(&self.public_key()).unwrap_or_default()
+    }
+
+    /// Answers the server's challenge; see `verify`.
+    pub fn sign_challenge(&self, nonce: &str) -> String {
+        let message = challenge_message(nonce, &self.public_key());
+        BASE64.encode(self.key.sign(message.as_bytes()).to_bytes())
+    }
+}
+
+impl fmt::Debug for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Identity")
+            .field("fingerprint", &self.fingerprint())
+            .finish()
+    }
+}
+
+fn challenge_message(nonce: &str, public_key: &str) -> String {
+    format!("{}\n{}\n{}", CHALLENGE_CONTEXT, nonce, public_key)
+}
+
+fn verifying_key(public_key: &str) -> Result<VerifyingKey> {
+    let bytes: [u8; 32] = BASE64
+        .decode(public_key)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| anyhow!("Malformed public key"))?;
+    VerifyingKey::from_bytes(&bytes).map_err(|_| anyhow!("Invalid public key"))
+}
+
+/// SHA-256 of a base64 public key in SSH's notation, e.g. `SHA256:q1Lx...`.
+pub fn fingerprint(public_key: &str) -> Result<String> {
+    let key = verifying_key(public_key)?;
+    Ok(format!(
+        "SHA256:{}",
+        BASE64_NO_PAD.encode(Sha256::digest(key.as_bytes()))
+    ))
+}
+
+/// A fresh challenge for a client to sign.
+pub fn new_challenge() -> String {
+    BASE64.encode(rand::random::<[u8; 32]>())
+}
+
+/// Checks that `signature` is the holder of `public_key` signing `nonce`.
+pub fn verify(public_key: &str, nonce: &str, signature: &str) -> Result<()> {
+    let key = verifying_key(public_key)?;
+    let signature = BASE64
+        .decode(signature)
+        .ok()
+        .and_then(|bytes| Signature::from_slice(&bytes).ok())
+        .ok_or_else(|| anyhow!("Malformed signature"))?;
+    if key
+        .verify_strict(challenge_message(nonce, public_key).as_bytes(), &signature)
+        .is_err()
+    {
+        bail!("Signature does not match the identity key");
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Binding {
+    /// As first claimed; lookups ignore case.
+    pub nickname: String,
+    pub public_key: String,
+    pub created: DateTime<Utc>,
+}
+
+/// Who may use a nickname, see `NameBindings::check`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Claim {
+    /// Bound to the key the client proved.
+    Verified,
+    /// Free, and the client has a key to bind it to.
+    Unbound,
+    /// Free, and the client has no identity key to bind it to.
+    Unverified,
+    /// Owned by an account the client logged in to, see `accounts`. Never
+    /// returned by `NameBindings::check`, accounts take precedence over it.
+    Account,
+}
+
+/// Which identity key owns each nickname, stored as a JSON array so it
+/// survives restarts. The first verified client to use a name gets it.
+pub struct NameBindings {
+    path: PathBuf,
+    bindings: BTreeMap<String, Binding>,
+}
+
+impl NameBindings {
+    /// Loads the bindings from `path`, starting empty if the file doesn't exist yet.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let bindings: Vec<Binding> = if path.exists() {
+            let data = fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read identities {}", path.display()))?;
+            serde_json::from_str(&data)
+                .with_context(|| format!("Identities file {} is corrupt", path.display()))?
+        } else {
+            Vec::new()
+        };
+        Ok(NameBindings {
+            path,
+            bindings: bindings
+                .into_iter()
+                .map(|b| (b.nickname.to_lowercase(), b))
+                .collect(),
+        })
+    }
+
+    /// No bindings yet, still saved to `path` once names are claimed.
+    pub fn empty(path: impl AsRef<Path>) -> Self {
+        NameBindings {
+            path: path.as_ref().to_path_buf(),
+            bindings: BTreeMap::new(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn len(&self) -> usize {
+        self.bindings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bindings.is_empty()
+    }
+
+    fn save(&self) -> Result<()> {
+        let bindings: Vec<&Binding> = self.bindings.values().collect();
+        let data = serde_json::to_string_pretty(&bindings)?;
+        write_atomic(&self.path, &data, false)
+            .with_context(|| format!("Failed to write identities {}", self.path.display()))
+    }
+
+    pub fn get(&self, nickname: &str) -> Option<&Binding> {
+        self.bindings.get(&nickname.to_lowercase())
+    }
+
+    /// Whether the holder of `public_key`, `None` if the client has no
+    /// verified key, may use `nickname`.
+    pub fn check(&self, nickname: &str, public_key: Option<&str>) -> Result<Claim, String> {
+        match (self.get(nickname), public_key) {
+            (Some(binding), Some(key)) if binding.public_key == key => Ok(Claim::Verified),
+            (Some(binding), _) => Err(format!(
+                "'{}' is registered to another identity key",
+                binding.nickname
+            )),
+            (None, Some(_)) => Ok(Claim::Unbound),
+            (None, None) => Ok(Claim::Unverified),
+        }
+    }
+
+    /// Binds `nickname` to `public_key` and writes the bindings to disk.
+    /// The binding holds for this run even if writing fails.
+    pub fn bind(&mut self, nickname: &str, public_key: &str) -> Result<()> {
+        self.bindings.insert(
+            nickname.to_lowercase(),
+            Binding {
+                nickname: nickname.to_string(),
+                public_key: public_key.to_string(),
+                created: Utc::now(),
+            },
+        );
+        self.save()
+    }
+
+    /// Frees `nickname` for whoever claims it next, e.g. after its owner
+    /// lost their key. Returns false if it wasn't bound.
+    pub fn release(&mut self, nickname: &str) -> Result<bool> {
+        if self.bindings.remove(&nickname.to_lowercase()).is_none() {
+            return Ok(false);
+        }
+        self.save()?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("hotline-{}-{}", name, rand::random::<u64>()))
+    }
+
+    fn identity() -> Identity {
+        Identity {
+            key: SigningKey::from_bytes(&rand::random()),
+        }
+    }
+
+    #[test]
+    fn signed_challenges_verify_only_for_the_same_key_and_nonce() {
+        let (alice, mallory) = (identity(), identity());
+        let nonce = new_challenge();
+        let signature = alice.sign_challenge(&nonce);
+        assert!(verify(&alice.public_key(), &nonce, &signature).is_ok());
+
+        assert!(verify(&alice.public_key(), &new_challenge(), &signature).is_err());
+        assert!(verify(&mallory.public_key(), &nonce, &signature).is_err());
+        let forged = mallory.sign_challenge(&nonce);
+        assert!(verify(&alice.public_key(), &nonce, &forged).is_err());
+        assert!(verify("not a key", &nonce, &signature).is_err());
+        assert!(verify(&alice.public_key(), &nonce, "bm9wZQ==").is_err());
+    }
+
+    #[test]
+    fn keys_survive_a_reload() {
+        let path = temp_path("identity");
+        let created = Identity::load_or_create(&path).unwrap();
+        let loaded = Identity::load_or_create(&path).unwrap();
+        assert_eq!(created.public_key(), loaded.public_key());
+        assert!(created.fingerprint().starts_with("SHA256:"));
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn names_belong_to_the_first_key_that_binds_them() {
+        let path = temp_path("identities.json");
+        let (alice, mallory) = (identity().public_key(), identity().public_key());
+        let mut bindings = NameBindings::empty(&path);
+        assert_eq!(bindings.check("alice", Some(&alice)), Ok(Claim::Unbound));
+        assert_eq!(bindings.check("alice", None), Ok(Claim::Unverified));
+        bindings.bind("Alice", &alice).unwrap();
+
+        let bindings = NameBindings::load(&path).unwrap();
+        assert_eq!(bindings.check("ALICE", Some(&alice)), Ok(Claim::Verified));
+        assert!(bindings.check("alice", Some(&mallory)).is_err());
+        assert!(bindings.check("alice", None).is_err());
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn corrupt_bindings_are_left_alone() {
+        let path = temp_path("identities.json");
+        fs::write(&path, "[{\"nickname\": ").unwrap();
+        assert!(NameBindings::load(&path).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "[{\"nickname\": ");
+
+        // Saving replaces the file whole, leaving no temporary file behind
+        let mut bindings = NameBindings::empty(&path);
+        bindings.bind("alice", &identity().public_key()).unwrap();
+        assert_eq!(NameBindings::load(&path).unwrap().len(), 1);
+        assert!(!path.with_extension("json.tmp").exists());
+        let _ = fs::remove_file(&path);
+    }
+}
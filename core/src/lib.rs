@@ -1,4 +1,25 @@
+pub mod access;
+pub mod accounts;
+pub mod address;
+pub mod chat_log;
+pub mod client;
 pub mod client_backend;
+pub mod commands;
+pub mod config;
+pub mod e2e;
+pub mod heartbeat;
+pub mod history;
+pub mod identity;
+pub mod moderation;
+pub mod nickname;
+pub mod outbox;
+pub mod protocol;
+pub mod rate_limit;
+pub mod roles;
+pub mod rooms;
 pub mod serializable_colours;
 pub mod server_backend;
+pub mod tls;
+pub mod transcript;
+pub mod transport;
 pub mod types;
@@ -0,0 +1,384 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::roles::Role;
+use crate::tls::write_atomic;
+
+/// Default location of the ban list, relative to the working directory.
+pub const DEFAULT_BANS_FILE: &str = "bans.json";
+
+/// Longest duration `parse_duration` accepts; anything meant to last
+/// longer should have no expiry at all.
+pub const MAX_DURATION: Duration = Duration::from_secs(10 * 365 * 24 * 60 * 60);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "value", rename_all = "snake_case")]
+pub enum BanTarget {
+    Ip(IpAddr),
+    /// Nicknames are matched case-insensitively.
+    Name(String),
+}
+
+impl BanTarget {
+    /// Anything that parses as an IP address is an IP ban, otherwise a name.
+    pub fn parse(target: &str) -> Self {
+        match target.parse::<IpAddr>() {
+            Ok(ip) => BanTarget::Ip(ip),
+            Err(_) => BanTarget::Name(target.to_string()),
+        }
+    }
+
+    /// Same target, names compared case-insensitively.
+    fn same_as(&self, other: &BanTarget) -> bool {
+        match (self, other) {
+            (BanTarget::Name(a), BanTarget::Name(b)) => a.eq_ignore_ascii_case(b),
+            (a, b) => a == b,
+        }
+    }
+
+    fn matches(&self, ip: IpAddr, name: Option<&str>) -> bool {
+        match self {
+            BanTarget::Ip(banned) => *banned == ip,
+            BanTarget::Name(banned) => name.is_some_and(|n| n.eq_ignore_ascii_case(banned)),
+        }
+    }
+}
+
+impl std::fmt::Display for BanTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BanTarget::Ip(ip) => write!(f, "{}", ip),
+            BanTarget::Name(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ban {
+    pub target: BanTarget,
+    /// `None` means the ban never expires.
+    pub until: Option<DateTime<Utc>>,
+    /// Role of the client who placed the ban, `None` if the host did. Only
+    /// clients that outrank it may lift the ban.
+    #[serde(default)]
+    pub role: Option<Role>,
+    pub created: DateTime<Utc>,
+}
+
+impl Ban {
+    fn is_active(&self, now: DateTime<Utc>) -> bool {
+        self.until.is_none_or(|until| now < until)
+    }
+
+    /// Human readable description used in reject messages and the host console.
+    pub fn describe(&self) -> String {
+        match self.until {
+            Some(until) => format!(
+                "{} is banned until {}",
+                self.target,
+                until.format("%Y-%m-%d %H:%M:%S UTC")
+            ),
+            None => format!("{} is banned permanently", self.target),
+        }
+    }
+}
+
+/// Bans that survive a server restart, stored as a JSON array.
+pub struct BanList {
+    path: PathBuf,
+    bans: Vec<Ban>,
+}
+
+impl BanList {
+    /// Loads the list from `path`, starting empty if the file doesn't exist yet.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let bans = if path.exists() {
+            let data = fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read ban list {}", path.display()))?;
+            serde_json::from_str(&data)
+                .with_context(|| format!("Ban list {} is corrupt", path.display()))?
+        } else {
+            Vec::new()
+        };
+        let mut list = BanList { path, bans };
+        list.prune();
+        Ok(list)
+    }
+
+    /// An empty list that is still saved to `path` once bans are added.
+    pub fn empty(path: impl AsRef<Path>) -> Self {
+        BanList {
+            path: path.as_ref().to_path_buf(),
+            bans: Vec::new(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn save(&self) -> Result<()> {
+        let data = serde_json::to_string_pretty(&self.bans)?;
+        write_atomic(&self.path, &data, false)
+            .with_context(|| format!("Failed to write ban list {}", self.path.display()))
+    }
+
+    fn prune(&mut self) {
+        let now = Utc::now();
+        self.bans.retain(|ban| ban.is_active(now));
+    }
+
+    /// Adds or replaces the ban on `target`, placed by a client with `role`
+    /// or by the host if `None`, and writes the list to disk.
+    pub fn ban(
+        &mut self,
+        target: BanTarget,
+        duration: Option<Duration>,
+        role: Option<Role>,
+    ) -> Result<Ban> {
+        let now = Utc::now();
+        let until = duration
+            .map(|d| {
+                chrono::Duration::from_std(d)
+                    .ok()
+                    .and_then(|d| now.checked_add_signed(d))
+                    .context("Ban duration is too long")
+            })
+            .transpose()?;
+        self.prune();
+        self.bans.retain(|ban| !ban.target.same_as(&target));
+        let ban = Ban {
+            target,
+            until,
+            role,
+            created: now,
+        };
+        self.bans.push(ban.clone());
+        self.save()?;
+        Ok(ban)
+    }
+
+    /// Lifts the ban on `target`. Returns false if there wasn't one.
+    pub fn unban(&mut self, target: &BanTarget) -> Result<bool> {
+        self.prune();
+        let before = self.bans.len();
+        self.bans.retain(|ban| !ban.target.same_as(target));
+        let removed = self.bans.len() != before;
+        if removed {
+            self.save()?;
+        }
+        Ok(removed)
+    }
+
+    /// The active ban on exactly `target`, if any.
+    pub fn get(&self, target: &BanTarget) -> Option<&Ban> {
+        let now = Utc::now();
+        self.bans
+            .iter()
+            .find(|ban| ban.is_active(now) && ban.target.same_as(target))
+    }
+
+    /// The active ban matching this address or nickname, if any.
+    pub fn find(&self, ip: IpAddr, name: Option<&str>) -> Option<&Ban> {
+        let now = Utc::now();
+        self.bans
+            .iter()
+            .find(|ban| ban.is_active(now) && ban.target.matches(ip, name))
+    }
+
+    pub fn active(&self) -> Vec<&Ban> {
+        let now = Utc::now();
+        self.bans.iter().filter(|ban| ban.is_active(now)).collect()
+    }
+}
+
+/// Mutes placed with `/mute`. They are kept by the server rather than the
+/// connection, so reconnecting, resuming or changing nickname doesn't lift
+/// them; they only last until the server stops.
+#[derive(Default)]
+pub struct MuteList {
+    mutes: Vec<Mute>,
+}
+
+/// A mute covers the name and the IP address the client had when muted.
+struct Mute {
+    name: String,
+    ip: IpAddr,
+    /// `None` means no expiry.
+    until: Option<Instant>,
+}
+
+impl Mute {
+    fn matches(&self, ip: IpAddr, name: Option<&str>) -> bool {
+        self.ip == ip || name.is_some_and(|n| n.eq_ignore_ascii_case(&self.name))
+    }
+}
+
+impl MuteList {
+    /// Mutes `name` and `ip`, replacing any mute on either.
+    pub fn mute(&mut self, name: &str, ip: IpAddr, until: Option<Instant>) {
+        self.unmute(name, ip);
+        self.mutes.push(Mute {
+            name: name.to_string(),
+            ip,
+            until,
+        });
+    }
+
+    /// Lifts every mute on `name` or `ip`. Returns false if there was none.
+    pub fn unmute(&mut self, name: &str, ip: IpAddr) -> bool {
+        let before = self.mutes.len();
+        self.mutes.retain(|mute| !mute.matches(ip, Some(name)));
+        self.mutes.len() != before
+    }
+
+    /// True while a mute on this address or nickname is in effect.
+    pub fn is_muted(&mut self, ip: IpAddr, name: Option<&str>, now: Instant) -> bool {
+        self.mutes
+            .retain(|mute| mute.until.is_none_or(|until| now < until));
+        self.mutes.iter().any(|mute| mute.matches(ip, name))
+    }
+}
+
+/// Parses durations such as `90`, `30s`, `10m`, `2h` or `7d`.
+pub fn parse_duration(text: &str) -> Result<Duration, String> {
+    let text = text.trim();
+    let (number, unit) = match text.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => text.split_at(i),
+        None => (text, "s"),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("Invalid duration '{}', use e.g. 30s, 10m, 2h or 7d", text))?;
+    let unit_seconds: u64 = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 60 * 60 * 24,
+        _ => {
+            return Err(format!(
+                "Invalid duration '{}', use e.g. 30s, 10m, 2h or 7d",
+                text
+            ));
+        }
+    };
+    number
+        .checked_mul(unit_seconds)
+        .map(Duration::from_secs)
+        .filter(|duration| *duration <= MAX_DURATION)
+        .ok_or_else(|| {
+            format!(
+                "Duration '{}' is too long, the most is {}d",
+                text,
+                MAX_DURATION.as_secs() / (60 * 60 * 24)
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("hotline-{}-{}.json", name, rand::random::<u64>()))
+    }
+
+    #[test]
+    fn parses_units() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("10m"), Ok(Duration::from_secs(600)));
+        assert_eq!(parse_duration("2h"), Ok(Duration::from_secs(7200)));
+        assert_eq!(parse_duration("7d"), Ok(Duration::from_secs(7 * 86400)));
+        assert!(parse_duration("5w").is_err());
+        assert!(parse_duration("m").is_err());
+        assert!(parse_duration("-5m").is_err());
+    }
+
+    #[test]
+    fn rejects_durations_that_overflow_or_exceed_the_cap() {
+        assert!(parse_duration("300000000000000d").is_err());
+        assert!(parse_duration("18446744073709551615").is_err());
+        assert!(parse_duration("100000000000d").is_err());
+        assert_eq!(parse_duration("3650d"), Ok(MAX_DURATION));
+        assert!(parse_duration("3651d").is_err());
+    }
+
+    #[test]
+    fn ban_with_huge_duration_is_an_error() {
+        let path = temp_path("bans");
+        let mut bans = BanList::empty(&path);
+        let target = BanTarget::Name("bob".to_string());
+        assert!(bans.ban(target.clone(), Some(Duration::MAX), None).is_err());
+        assert!(bans.active().is_empty());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn bans_match_names_case_insensitively_and_persist() {
+        let path = temp_path("bans");
+        let mut bans = BanList::empty(&path);
+        bans.ban(BanTarget::parse("Bob"), Some(Duration::from_secs(60)), None)
+            .unwrap();
+        bans.ban(BanTarget::parse("10.0.0.1"), None, Some(Role::Admin))
+            .unwrap();
+        let ip: IpAddr = "10.0.0.2".parse().unwrap();
+        assert!(bans.find(ip, Some("bob")).is_some());
+        assert!(bans.find("10.0.0.1".parse().unwrap(), None).is_some());
+        assert!(bans.find(ip, Some("carl")).is_none());
+
+        let reloaded = BanList::load(&path).unwrap();
+        assert_eq!(reloaded.active().len(), 2);
+        let ip_ban = reloaded.get(&BanTarget::parse("10.0.0.1")).unwrap();
+        assert_eq!(ip_ban.role, Some(Role::Admin));
+        assert_eq!(reloaded.get(&BanTarget::parse("BOB")).unwrap().role, None);
+        assert!(bans.unban(&BanTarget::parse("BOB")).unwrap());
+        assert!(bans.find(ip, Some("bob")).is_none());
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn rebanning_a_name_replaces_the_ban_whatever_its_case() {
+        let path = temp_path("bans");
+        let mut bans = BanList::empty(&path);
+        bans.ban(BanTarget::parse("Bob"), None, None).unwrap();
+        bans.ban(BanTarget::parse("bob"), Some(Duration::from_secs(60)), None)
+            .unwrap();
+        assert_eq!(bans.active().len(), 1);
+        assert!(bans.get(&BanTarget::parse("BOB")).unwrap().until.is_some());
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn mutes_follow_the_name_and_address_until_they_expire() {
+        let mut mutes = MuteList::default();
+        let (ip, other): (IpAddr, IpAddr) =
+            ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+        let now = Instant::now();
+        mutes.mute("Bob", ip, Some(now + Duration::from_secs(60)));
+        assert!(mutes.is_muted(other, Some("bob"), now));
+        assert!(mutes.is_muted(ip, Some("robert"), now));
+        assert!(!mutes.is_muted(other, Some("carl"), now));
+        assert!(!mutes.is_muted(ip, Some("bob"), now + Duration::from_secs(61)));
+
+        mutes.mute("bob", ip, None);
+        assert!(mutes.unmute("BOB", other));
+        assert!(!mutes.is_muted(ip, Some("bob"), now));
+        assert!(!mutes.unmute("bob", ip));
+    }
+
+    #[test]
+    fn corrupt_ban_lists_are_left_alone() {
+        let path = temp_path("bans");
+        fs::write(&path, "[{").unwrap();
+        assert!(BanList::load(&path).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "[{");
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+pub const MIN_NICKNAME_LEN: usize = 2;
+pub const MAX_NICKNAME_LEN: usize = 24;
+
+/// Names the backends use as senders of their own messages, plus the label
+/// the client shows for itself. Compared case-insensitively.
+pub const RESERVED_NICKNAMES: &[&str] = &[
+    "server",
+    "host",
+    "you",
+    "admin",
+    "administrator",
+    "system",
+    "hotline",
+];
+
+/// Checks a requested nickname and returns it trimmed.
+pub fn validate_nickname(name: &str) -> Result<String, String> {
+    let name = name.trim();
+    let len = name.chars().count();
+
+    if name.chars().any(char::is_control) {
+        return Err("Nicknames cannot contain control characters".to_string());
+    }
+    if !(MIN_NICKNAME_LEN..=MAX_NICKNAME_LEN).contains(&len) {
+        return Err(format!(
+            "Nicknames must be {} to {} characters long",
+            MIN_NICKNAME_LEN, MAX_NICKNAME_LEN
+        ));
+    }
+    // ASCII only, so look-alike letters from other scripts can't pass for
+    // someone else's name or a reserved one
+    if !name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+    {
+        return Err(
+            "Nicknames may only contain the letters a-z, digits, '-', '_' and '.'".to_string(),
+        );
+    }
+    if RESERVED_NICKNAMES
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(name))
+    {
+        return Err(format!("'{}' is reserved", name));
+    }
+    Ok(name.to_string())
+}
+
+/// Validates `name` and assigns it to `addr` unless another connection holds
+/// it already. Returns the previous name of `addr`, if any.
+pub fn claim_nickname(
+    usernames: &mut HashMap<SocketAddr, String>,
+    addr: SocketAddr,
+    name: &str,
+) -> Result<(String, Option<String>), String> {
+    let name = validate_nickname(name)?;
+    let taken = usernames
+        .iter()
+        .any(|(other, existing)| *other != addr && existing.to_lowercase() == name.to_lowercase());
+    if taken {
+        return Err(format!("'{}' is already in use", name));
+    }
+    let previous = usernames.insert(addr, name.clone());
+    Ok((name, previous))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn validates_length_characters_and_reserved_names() {
+        assert_eq!(
+            validate_nickname("  alice_1.b-c "),
+            Ok("alice_1.b-c".to_string())
+        );
+        assert!(validate_nickname("a").is_err());
+        assert!(validate_nickname(&"a".repeat(MAX_NICKNAME_LEN + 1)).is_err());
+        assert!(validate_nickname("al ice").is_err());
+        assert!(validate_nickname("al\u{7}ice").is_err());
+        assert!(validate_nickname("Server").is_err());
+        assert!(validate_nickname("HOST").is_err());
+    }
+
+    #[test]
+    fn rejects_look_alikes_from_other_scripts() {
+        // Greek capital eta and Cyrillic dze look like 'H' and 'S'
+        assert!(validate_nickname("\u{397}ost").is_err());
+        assert!(validate_nickname("\u{405}erver").is_err());
+        // Cyrillic 'а' in place of the Latin one
+        assert!(validate_nickname("\u{430}lice").is_err());
+        assert!(validate_nickname("zoë").is_err());
+    }
+
+    #[test]
+    fn names_are_claimed_case_insensitively() {
+        let mut usernames = HashMap::new();
+        assert_eq!(
+            claim_nickname(&mut usernames, addr(1), "Alice"),
+            Ok(("Alice".to_string(), None))
+        );
+        assert!(claim_nickname(&mut usernames, addr(2), "alice").is_err());
+        assert_eq!(
+            claim_nickname(&mut usernames, addr(1), "ALICE"),
+            Ok(("ALICE".to_string(), Some("Alice".to_string())))
+        );
+    }
+}
@@ -0,0 +1,208 @@
+use anyhow::{Result, anyhow, bail};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio_util::sync::CancellationToken;
+
+use crate::config::check_secs;
+use crate::protocol::{ServerFrame, write_frame};
+
+/// Limits on each client's queue of frames waiting to be written.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OutboxConfig {
+    /// Frames queued per client before room traffic to it is dropped.
+    pub queue_len: usize,
+    /// Room frames dropped before the client is disconnected; the count
+    /// restarts each time the client catches up.
+    pub max_missed: usize,
+    /// Seconds a reply may wait for room in the queue.
+    pub send_timeout_secs: f32,
+}
+
+impl Default for OutboxConfig {
+    fn default() -> Self {
+        OutboxConfig {
+            queue_len: 256,
+            max_missed: 1000,
+            send_timeout_secs: 30.0,
+        }
+    }
+}
+
+impl OutboxConfig {
+    pub fn validate(&self) -> Result<()> {
+        check_secs("send_timeout_secs", self.send_timeout_secs)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Lag {
+    missed: AtomicUsize,
+    /// Cancelled once the client is too far behind to keep.
+    overflow: CancellationToken,
+}
+
+/// Sending side of one client's outbound queue. Room traffic never waits on
+/// a slow client: frames that don't fit are dropped and counted, and the
+/// client is told how many it missed once its queue drains.
+#[derive(Debug, Clone)]
+pub struct Outbox {
+    tx: mpsc::Sender<ServerFrame>,
+    lag: Arc<Lag>,
+    max_missed: usize,
+    send_timeout: Duration,
+}
+
+/// Writes an `Outbox`'s frames to the client's socket.
+pub struct OutboxWriter {
+    rx: mpsc::Receiver<ServerFrame>,
+    lag: Arc<Lag>,
+}
+
+impl Outbox {
+    pub fn new(config: &OutboxConfig) -> (Outbox, OutboxWriter) {
+        let (tx, rx) = mpsc::channel(config.queue_len.max(1));
+        let lag = Arc::new(Lag::default());
+        let outbox = Outbox {
+            tx,
+            lag: Arc::clone(&lag),
+            max_missed: config.max_missed,
+            // Unusable values, which `validate` turns away, count as no time
+            send_timeout: Duration::try_from_secs_f32(config.send_timeout_secs)
+                .unwrap_or(Duration::ZERO),
+        };
+        (outbox, OutboxWriter { rx, lag })
+    }
+
+    /// Queues a reply to the client, waiting for room in the queue. Fails if
+    /// the connection is gone or the client stopped reading.
+    pub async fn send(&self, frame: ServerFrame) -> Result<()> {
+        tokio::select! {
+            result = self.tx.send(frame) => result.map_err(|_| anyhow!("connection closed")),
+            _ = self.lag.overflow.cancelled() => bail!("{}", self.overflow_reason()),
+            _ = tokio::time::sleep(self.send_timeout) => {
+                bail!("client stopped reading for {}s", self.send_timeout.as_secs())
+            }
+        }
+    }
+
+    /// Queues room traffic without waiting, dropping it if the queue is full.
+    pub fn offer(&self, frame: ServerFrame) {
+        if let Err(TrySendError::Full(_)) = self.tx.try_send(frame) {
+            let missed = self.lag.missed.fetch_add(1, Ordering::SeqCst) + 1;
+            if missed > self.max_missed {
+                self.lag.overflow.cancel();
+            }
+        }
+    }
+
+    /// Resolves once the client has fallen too far behind to keep.
+    pub async fn overflowed(&self) {
+        self.lag.overflow.cancelled().await
+    }
+
+    pub fn overflow_reason(&self) -> String {
+        format!(
+            "fell more than {} messages behind, dropping connection",
+            self.max_missed
+        )
+    }
+}
+
+impl OutboxWriter {
+    /// Writes queued frames until every `Outbox` for this client is dropped.
+    pub async fn run<W: AsyncWrite + Unpin>(mut self, mut writer: W) -> Result<()> {
+        while let Some(frame) = self.rx.recv().await {
+            write_frame(&mut writer, &frame).await?;
+
+            // Only report drops once caught up, so the notice follows
+            // whatever was still queued from before them
+            if self.rx.is_empty() {
+                let missed = self.lag.missed.swap(0, Ordering::SeqCst);
+                if missed > 0 {
+                    write_frame(&mut writer, &ServerFrame::Lagged { missed }).await?;
+                }
+            }
+        }
+        writer.shutdown().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Frame;
+
+    fn config(queue_len: usize, max_missed: usize) -> OutboxConfig {
+        OutboxConfig {
+            queue_len,
+            max_missed,
+            send_timeout_secs: 0.05,
+        }
+    }
+
+    fn info(message: &str) -> ServerFrame {
+        ServerFrame::Info {
+            message: message.to_string(),
+        }
+    }
+
+    async fn written(writer: OutboxWriter) -> Vec<ServerFrame> {
+        let mut out = Vec::new();
+        writer.run(&mut out).await.unwrap();
+        String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|line| ServerFrame::from_line(line).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn room_traffic_that_doesnt_fit_is_dropped_and_reported() {
+        let (outbox, writer) = Outbox::new(&config(2, 10));
+        for message in ["one", "two", "three", "four"] {
+            outbox.offer(info(message));
+        }
+        drop(outbox);
+
+        let frames = written(writer).await;
+        assert_eq!(frames.len(), 3);
+        assert!(matches!(&frames[0], ServerFrame::Info { message } if message == "one"));
+        assert!(matches!(&frames[1], ServerFrame::Info { message } if message == "two"));
+        // The notice follows what was queued before the drops
+        assert!(matches!(frames[2], ServerFrame::Lagged { missed: 2 }));
+    }
+
+    #[tokio::test]
+    async fn falling_too_far_behind_overflows() {
+        let (outbox, _writer) = Outbox::new(&config(1, 2));
+        for message in ["one", "two", "three"] {
+            outbox.offer(info(message));
+        }
+        assert!(!outbox.lag.overflow.is_cancelled());
+        outbox.offer(info("four"));
+        tokio::time::timeout(Duration::from_secs(1), outbox.overflowed())
+            .await
+            .expect("overflow should have resolved");
+
+        let refused = outbox.send(info("reply")).await.unwrap_err();
+        assert_eq!(refused.to_string(), outbox.overflow_reason());
+    }
+
+    #[tokio::test]
+    async fn replies_wait_for_room_but_not_forever() {
+        let (outbox, writer) = Outbox::new(&config(1, 10));
+        outbox.send(info("one")).await.unwrap();
+        let refused = outbox.send(info("two")).await.unwrap_err();
+        assert!(refused.to_string().contains("stopped reading"));
+
+        drop(writer);
+        let refused = outbox.send(info("three")).await.unwrap_err();
+        assert_eq!(refused.to_string(), "connection closed");
+    }
+}
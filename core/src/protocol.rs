@@ -0,0 +1,668 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio_util::bytes::BytesMut;
+use tokio_util::codec::{Decoder, FramedRead, LinesCodec, LinesCodecError};
+
+use crate::e2e::Sealed;
+use crate::roles::Role;
+use crate::types::{ChatMessage, Encryption};
+
+/// Version spoken by this build. Bump whenever a frame changes incompatibly;
+/// purely additive features are advertised as capabilities instead.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Oldest peer version this build can still talk to.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Largest frame a server accepts from clients unless configured otherwise.
+pub const DEFAULT_MAX_FRAME_BYTES: usize = 8 * 1024;
+
+/// Largest frame a client accepts from the server. Much higher than the
+/// server's limit because a history page carries many messages.
+pub const DEFAULT_CLIENT_MAX_FRAME_BYTES: usize = 1024 * 1024;
+
+/// Optional features a peer can advertise during the handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    History,
+    Rooms,
+    FileTransfer,
+    /// Reconnecting clients can pick up where they left off, see `Resume`.
+    Resume,
+    /// Both sides send `ping` frames and drop peers that stop answering.
+    Heartbeat,
+    /// Rooms can be switched to end-to-end encryption, see `e2e`.
+    E2e,
+    /// Clients prove an identity key and nicknames are bound to keys, see
+    /// `identity`.
+    Identity,
+    /// Names can be registered as password-protected accounts, see `accounts`.
+    Accounts,
+    /// Accounts carry roles, and clients with the right one can moderate
+    /// and set topics over the wire, see `roles`.
+    Roles,
+    /// Anything advertised by a newer peer that this build does not know about.
+    #[serde(other)]
+    Unknown,
+}
+
+/// Capabilities implemented by this build.
+pub fn supported_capabilities() -> Vec<Capability> {
+    vec![
+        Capability::Rooms,
+        Capability::History,
+        Capability::Resume,
+        Capability::Heartbeat,
+        Capability::E2e,
+        Capability::Identity,
+        Capability::Accounts,
+        Capability::Roles,
+    ]
+}
+
+/// Picks the protocol version two peers will speak, or explains why they can't.
+pub fn negotiate_version(peer_version: u32) -> Result<u32, String> {
+    let version = peer_version.min(PROTOCOL_VERSION);
+    if version < MIN_PROTOCOL_VERSION {
+        return Err(format!(
+            "protocol version {} is no longer supported (minimum {}, current {})",
+            peer_version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+        ));
+    }
+    Ok(version)
+}
+
+/// Keeps only the capabilities both peers understand.
+pub fn negotiate_capabilities(peer: &[Capability]) -> Vec<Capability> {
+    supported_capabilities()
+        .into_iter()
+        .filter(|capability| peer.contains(capability))
+        .collect()
+}
+
+/// A chat message as it travels over the wire.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatPayload {
+    /// Server-assigned, increasing message id; used for history paging.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+    pub content: String,
+    pub sender: String,
+    pub username: Option<String>,
+    pub timestamp: DateTime<Utc>,
+    /// Room the message was posted in; `None` for server-wide announcements.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub room: Option<String>,
+    /// Set on end-to-end encrypted messages, whose `content` is then only
+    /// `e2e::PLACEHOLDER` or, once opened by the client, the decrypted text.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sealed: Option<Sealed>,
+}
+
+/// A room and the names of the users currently in it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoomInfo {
+    pub name: String,
+    pub members: Vec<String>,
+    #[serde(default)]
+    pub encrypted: bool,
+}
+
+impl ChatPayload {
+    pub fn into_chat_message(self, is_self: bool) -> ChatMessage {
+        let encryption = match &self.sealed {
+            None => Encryption::Plain,
+            Some(sealed) if sealed.opened => Encryption::Decrypted,
+            Some(_) => Encryption::Undecryptable,
+        };
+        ChatMessage {
+            is_history: false,
+            content: self.content,
+            sender: self.sender,
+            username: self.username,
+            timestamp: self.timestamp,
+            room: self.room,
+            is_self,
+            encryption,
+        }
+    }
+}
+
+/// Sent in the hello of a client reconnecting after a dropped connection.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Resume {
+    /// Token from the previous welcome. If that connection still looks alive
+    /// to the server, it is closed so the nickname can be reclaimed.
+    pub token: Option<String>,
+    /// Room to return to.
+    pub room: String,
+    /// Newest message id the client has seen; anything newer is replayed.
+    pub last_id: Option<u64>,
+}
+
+/// Frames sent from a client to the server.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientFrame {
+    Hello {
+        version: u32,
+        capabilities: Vec<Capability>,
+        username: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        resume: Option<Resume>,
+        /// Base64 public identity key; the server answers with a `challenge`
+        /// for the client to sign before the welcome.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        identity: Option<String>,
+        /// Chatroom password or invite token, see `access::Access::check`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        password: Option<String>,
+        /// Password of the account named `username`, logging in to it.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        account_password: Option<String>,
+    },
+    /// Signature over the challenge, see `identity::verify`.
+    Prove {
+        signature: String,
+    },
+    Chat {
+        content: String,
+        /// Present in end-to-end encrypted rooms; `content` is then ignored.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        sealed: Option<Sealed>,
+    },
+    Join {
+        room: String,
+    },
+    Part,
+    /// Switches the current room to end-to-end encryption for as long as it
+    /// exists; plain-text messages are refused there from then on.
+    Encrypt,
+    Nick {
+        username: String,
+    },
+    ListRooms,
+    /// Asks who holds `username` and which identity key it is bound to.
+    Whois {
+        username: String,
+    },
+    /// Registers the current nickname as an account with this password.
+    Register {
+        password: String,
+    },
+    /// Logs in to an account, taking its name.
+    Login {
+        username: String,
+        password: String,
+    },
+    /// Asks for older messages in the current room, newest `limit` by
+    /// default or only those older than `before`.
+    History {
+        limit: usize,
+        before: Option<u64>,
+    },
+    /// Sets the topic of the current room, or clears it when `None`.
+    Topic {
+        topic: Option<String>,
+    },
+    // Moderation, allowed depending on the sender's role, see `roles`.
+    // Answered with `info` on success and `error` otherwise.
+    Kick {
+        username: String,
+        reason: Option<String>,
+    },
+    /// `target` is a nickname or an IP address; `None` seconds bans for good.
+    Ban {
+        target: String,
+        seconds: Option<u64>,
+    },
+    Unban {
+        target: String,
+    },
+    Mute {
+        username: String,
+        seconds: Option<u64>,
+    },
+    Unmute {
+        username: String,
+    },
+    /// Changes the role of the account `username`.
+    SetRole {
+        username: String,
+        role: Role,
+    },
+    /// Answered with a `pong` carrying the same `nonce`. Heartbeats don't
+    /// look at the reply and send 0.
+    Ping {
+        nonce: u64,
+    },
+    Pong {
+        nonce: u64,
+    },
+}
+
+/// Frames sent from the server to a client.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerFrame {
+    Welcome {
+        version: u32,
+        capabilities: Vec<Capability>,
+        room: String,
+        /// The name the server accepted, `None` if the client is anonymous.
+        #[serde(default)]
+        username: Option<String>,
+        /// Identifies this session when the client reconnects.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        resume_token: Option<String>,
+        /// Longest frame the server accepts, newline excluded.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_frame_bytes: Option<usize>,
+    },
+    Reject {
+        reason: String,
+        /// Set when the client lacks the chatroom password or a valid
+        /// invite, so it can ask for one instead of showing a plain error.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        access_denied: bool,
+    },
+    /// Sent during the handshake to a client that offered an identity key.
+    Challenge {
+        nonce: String,
+    },
+    Info {
+        message: String,
+    },
+    Error {
+        message: String,
+    },
+    Chat(ChatPayload),
+    Join {
+        username: String,
+        room: String,
+        timestamp: DateTime<Utc>,
+    },
+    Leave {
+        username: String,
+        room: String,
+        timestamp: DateTime<Utc>,
+    },
+    Renamed {
+        old: String,
+        new: String,
+        room: String,
+        timestamp: DateTime<Utc>,
+    },
+    /// Sent to a client after it moves into a room.
+    Joined {
+        room: String,
+        members: Vec<String>,
+        #[serde(default)]
+        encrypted: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        topic: Option<String>,
+    },
+    /// `by` switched `room` to end-to-end encryption.
+    Encrypted {
+        room: String,
+        by: String,
+        timestamp: DateTime<Utc>,
+    },
+    /// `by` set the topic of `room`, or cleared it.
+    Topic {
+        room: String,
+        topic: Option<String>,
+        by: String,
+        timestamp: DateTime<Utc>,
+    },
+    RoomList {
+        rooms: Vec<RoomInfo>,
+    },
+    Whois {
+        username: String,
+        online: bool,
+        /// Of the identity key the name is bound to; `None` if anyone may use it.
+        fingerprint: Option<String>,
+        /// The name belongs to a registered account, which takes precedence
+        /// over the identity key.
+        #[serde(default)]
+        account: bool,
+        /// Role of the account, set along with `account`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        role: Option<Role>,
+    },
+    /// Answers a successful `register` or `login`.
+    LoggedIn {
+        username: String,
+        registered: bool,
+    },
+    /// The last message was dropped because the client is sending too fast.
+    RateLimited {
+        seconds: f32,
+        reason: String,
+    },
+    /// Earlier messages, oldest first, either as join backfill or in reply
+    /// to a history request.
+    History {
+        room: String,
+        messages: Vec<ChatPayload>,
+        has_more: bool,
+        /// The messages a resuming client missed while disconnected.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        resumed: bool,
+    },
+    /// The server is about to close the connection, e.g. after a kick or ban.
+    Kicked {
+        reason: String,
+    },
+    Ping {
+        nonce: u64,
+    },
+    Pong {
+        nonce: u64,
+    },
+    /// `missed` room frames were dropped because the client fell behind.
+    Lagged {
+        missed: usize,
+    },
+}
+
+/// Every frame is a single line of JSON terminated by `\n`.
+pub trait Frame: Serialize + DeserializeOwned {
+    fn to_line(&self) -> Result<String> {
+        let mut line = serde_json::to_string(self)?;
+        line.push('\n');
+        Ok(line)
+    }
+
+    fn from_line(line: &str) -> Result<Self> {
+        Ok(serde_json::from_str(line.trim())?)
+    }
+}
+
+impl Frame for ClientFrame {}
+impl Frame for ServerFrame {}
+
+/// Size of the frame `content` would be sent in, newline excluded, so
+/// clients can check a message against the server's limit before sending.
+pub fn chat_frame_len(content: &str) -> usize {
+    serde_json::to_string(&ClientFrame::Chat {
+        content: content.to_string(),
+        sealed: None,
+    })
+    .map_or(usize::MAX, |line| line.len())
+}
+
+/// One line read by `FrameCodec`.
+#[derive(Debug)]
+pub enum Incoming {
+    Line(String),
+    /// A line longer than the limit; it was discarded without buffering it.
+    TooLong,
+}
+
+/// Splits a stream into lines of at most `max_bytes`. Unlike a bare
+/// `LinesCodec`, an oversized line is reported as `Incoming::TooLong` and
+/// reading carries on with the next line instead of ending the stream.
+#[derive(Debug)]
+pub struct FrameCodec {
+    lines: LinesCodec,
+    max_bytes: usize,
+}
+
+impl FrameCodec {
+    pub fn new(max_bytes: usize) -> Self {
+        FrameCodec {
+            lines: LinesCodec::new_with_max_length(max_bytes),
+            max_bytes,
+        }
+    }
+
+    pub fn max_bytes(&self) -> usize {
+        self.max_bytes
+    }
+}
+
+impl Decoder for FrameCodec {
+    type Item = Incoming;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> std::io::Result<Option<Incoming>> {
+        match self.lines.decode(buf) {
+            Ok(line) => Ok(line.map(Incoming::Line)),
+            Err(LinesCodecError::MaxLineLengthExceeded) => Ok(Some(Incoming::TooLong)),
+            Err(LinesCodecError::Io(e)) => Err(e),
+        }
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> std::io::Result<Option<Incoming>> {
+        match self.lines.decode_eof(buf) {
+            Ok(line) => Ok(line.map(Incoming::Line)),
+            Err(LinesCodecError::MaxLineLengthExceeded) => Ok(Some(Incoming::TooLong)),
+            Err(LinesCodecError::Io(e)) => Err(e),
+        }
+    }
+}
+
+/// Reads frames from `reader`, at most `max_bytes` each.
+pub type FrameReader<R> = FramedRead<R, FrameCodec>;
+
+pub fn frame_reader<R: AsyncRead>(reader: R, max_bytes: usize) -> FrameReader<R> {
+    FramedRead::new(reader, FrameCodec::new(max_bytes))
+}
+
+pub async fn write_frame<W, F>(writer: &mut W, frame: &F) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+    F: Frame,
+{
+    writer.write_all(frame.to_line()?.as_bytes()).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+impl ServerFrame {
+    /// Builds the chat line shown in the UI for join, leave, rename and
+    /// room notices.
+    pub fn notice_message(&self) -> Option<ChatPayload> {
+        match self {
+            ServerFrame::Join {
+                username,
+                room,
+                timestamp,
+            } => Some(ChatPayload {
+                id: None,
+                content: format!("{} has joined #{}", username, room),
+                sender: "Server".to_string(),
+                username: Some(username.clone()),
+                timestamp: *timestamp,
+                room: Some(room.clone()),
+                sealed: None,
+            }),
+            ServerFrame::Leave {
+                username,
+                room,
+                timestamp,
+            } => Some(ChatPayload {
+                id: None,
+                content: format!("{} has left #{}", username, room),
+                sender: "Server".to_string(),
+                username: Some(username.clone()),
+                timestamp: *timestamp,
+                room: Some(room.clone()),
+                sealed: None,
+            }),
+            ServerFrame::Renamed {
+                old,
+                new,
+                room,
+                timestamp,
+            } => Some(ChatPayload {
+                id: None,
+                content: format!("{} is now known as {}", old, new),
+                sender: "Server".to_string(),
+                username: Some(new.clone()),
+                timestamp: *timestamp,
+                room: Some(room.clone()),
+                sealed: None,
+            }),
+            ServerFrame::Encrypted {
+                room,
+                by,
+                timestamp,
+            } => Some(ChatPayload {
+                id: None,
+                content: format!("{} turned on end-to-end encryption in #{}", by, room),
+                sender: "Server".to_string(),
+                username: Some(by.clone()),
+                timestamp: *timestamp,
+                room: Some(room.clone()),
+                sealed: None,
+            }),
+            ServerFrame::Topic {
+                room,
+                topic,
+                by,
+                timestamp,
+            } => Some(ChatPayload {
+                id: None,
+                content: match topic {
+                    Some(topic) => format!("{} set the topic of #{}: {}", by, room, topic),
+                    None => format!("{} cleared the topic of #{}", by, room),
+                },
+                sender: "Server".to_string(),
+                username: Some(by.clone()),
+                timestamp: *timestamp,
+                room: Some(room.clone()),
+                sealed: None,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Renders a room list the same way on the server console and in clients.
+pub fn format_room_list(rooms: &[RoomInfo]) -> String {
+    let mut text = String::from("Rooms:");
+    for room in rooms {
+        text.push_str(&format!(
+            "\n  #{}{} ({}): {}",
+            room.name,
+            if room.encrypted { " [e2e]" } else { "" },
+            room.members.len(),
+            if room.members.is_empty() {
+                "-".to_string()
+            } else {
+                room.members.join(", ")
+            }
+        ));
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+    use tokio_stream::StreamExt;
+
+    fn decode_all(codec: &mut FrameCodec, bytes: &[u8]) -> Vec<Incoming> {
+        let mut buf = BytesMut::from(bytes);
+        let mut frames = Vec::new();
+        while let Some(frame) = codec.decode(&mut buf).unwrap() {
+            frames.push(frame);
+        }
+        frames
+    }
+
+    #[test]
+    fn oversized_frames_are_skipped_without_ending_the_stream() {
+        let mut codec = FrameCodec::new(16);
+        let mut bytes = vec![b'x'; 40];
+        bytes.extend_from_slice(b"\n{\"type\":\"ping\"}\n");
+        let frames = decode_all(&mut codec, &bytes);
+        assert!(matches!(frames[0], Incoming::TooLong));
+        assert!(matches!(&frames[1], Incoming::Line(line) if line == "{\"type\":\"ping\"}"));
+        assert_eq!(frames.len(), 2);
+
+        // Exactly at the limit is fine
+        let frames = decode_all(&mut codec, format!("{}\n", "y".repeat(16)).as_bytes());
+        assert!(matches!(&frames[..], [Incoming::Line(line)] if line.len() == 16));
+    }
+
+    #[test]
+    fn invalid_utf8_is_an_error() {
+        let mut codec = FrameCodec::new(64);
+        let mut buf = BytesMut::from(&b"{\"type\":\"chat\",\"content\":\"\xff\xfe\"}\n"[..]);
+        assert!(codec.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn invalid_json_and_unknown_frames_are_rejected() {
+        assert!(ClientFrame::from_line("not json").is_err());
+        assert!(ClientFrame::from_line("{\"type\":\"chat\"").is_err());
+        assert!(ClientFrame::from_line("{\"type\":\"teleport\"}").is_err());
+        assert!(ServerFrame::from_line("{\"content\":\"hi\"}").is_err());
+
+        let frame = ClientFrame::Chat {
+            content: "hi".to_string(),
+            sealed: None,
+        };
+        let line = frame.to_line().unwrap();
+        assert!(line.ends_with('\n'));
+        assert!(matches!(
+            ClientFrame::from_line(&line).unwrap(),
+            ClientFrame::Chat { content, sealed: None } if content == "hi"
+        ));
+        assert_eq!(chat_frame_len("hi"), line.len() - 1);
+    }
+
+    #[tokio::test]
+    async fn frames_split_across_reads_are_reassembled() {
+        let (mut writer, reader) = tokio::io::duplex(64);
+        let mut frames = frame_reader(reader, 64);
+        writer.write_all(b"{\"type\":\"pi").await.unwrap();
+        writer.flush().await.unwrap();
+        tokio::task::yield_now().await;
+        writer.write_all(b"ng\"}\n{\"type\"").await.unwrap();
+        drop(writer);
+
+        let Some(Ok(Incoming::Line(line))) = frames.next().await else {
+            panic!("expected a whole frame");
+        };
+        assert_eq!(line, "{\"type\":\"ping\"}");
+        // A partial frame at the end of the stream is still handed over
+        let Some(Ok(Incoming::Line(rest))) = frames.next().await else {
+            panic!("expected the trailing partial frame");
+        };
+        assert_eq!(rest, "{\"type\"");
+        assert!(frames.next().await.is_none());
+    }
+
+    #[test]
+    fn versions_are_negotiated_down_to_the_lower_one() {
+        assert_eq!(negotiate_version(PROTOCOL_VERSION), Ok(PROTOCOL_VERSION));
+        assert_eq!(
+            negotiate_version(PROTOCOL_VERSION + 5),
+            Ok(PROTOCOL_VERSION)
+        );
+        assert_eq!(negotiate_version(u32::MAX), Ok(PROTOCOL_VERSION));
+        let too_old = negotiate_version(MIN_PROTOCOL_VERSION - 1);
+        assert!(too_old.is_err_and(|reason| reason.contains("no longer supported")));
+    }
+
+    #[test]
+    fn only_shared_capabilities_are_kept() {
+        assert!(negotiate_capabilities(&[]).is_empty());
+        let shared = negotiate_capabilities(&supported_capabilities());
+        assert_eq!(shared, supported_capabilities());
+        assert_eq!(
+            negotiate_capabilities(&[Capability::Identity, Capability::Identity]),
+            vec![Capability::Identity]
+        );
+    }
+}
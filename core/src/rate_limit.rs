@@ -0,0 +1,329 @@
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::config::check_secs;
+
+/// Limits for one sliding window plus how violations escalate.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    /// Messages allowed inside `window_secs`.
+    pub max_messages: usize,
+    pub window_secs: f32,
+    /// Cool-down after the first violations.
+    pub timeout_secs: f32,
+    /// Violations before a timeout becomes a longer mute.
+    pub mute_after_strikes: u32,
+    pub mute_secs: f32,
+    /// Violations before the connection is dropped.
+    pub disconnect_after_strikes: u32,
+    /// Quiet period after which strikes are forgiven.
+    pub strike_reset_secs: f32,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        // Matches the client's own 10 messages per 5 seconds rule
+        RateLimitConfig {
+            max_messages: 10,
+            window_secs: 5.0,
+            timeout_secs: 10.0,
+            mute_after_strikes: 3,
+            mute_secs: 60.0,
+            disconnect_after_strikes: 5,
+            strike_reset_secs: 300.0,
+        }
+    }
+}
+
+impl RateLimitConfig {
+    /// Looser limits applied to all connections from one IP together.
+    pub fn per_ip_default() -> Self {
+        RateLimitConfig {
+            max_messages: 30,
+            ..RateLimitConfig::default()
+        }
+    }
+
+    /// Password attempts from one IP address. Never disconnects, as the
+    /// limit also applies to handshakes; repeat offenders wait longer.
+    pub fn per_ip_auth_default() -> Self {
+        RateLimitConfig {
+            max_messages: 5,
+            window_secs: 60.0,
+            timeout_secs: 60.0,
+            mute_after_strikes: 3,
+            mute_secs: 900.0,
+            disconnect_after_strikes: u32::MAX,
+            strike_reset_secs: 3600.0,
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        check_secs("window_secs", self.window_secs)?;
+        check_secs("timeout_secs", self.timeout_secs)?;
+        check_secs("mute_secs", self.mute_secs)?;
+        check_secs("strike_reset_secs", self.strike_reset_secs)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RateDecision {
+    Allow,
+    /// The message was dropped; the sender must wait `retry_after`.
+    Limited {
+        retry_after: Duration,
+        muted: bool,
+    },
+    /// Too many violations, the connection should be closed.
+    Disconnect,
+}
+
+/// Sliding-window limiter with escalating penalties for repeat offenders.
+#[derive(Debug)]
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    sent: VecDeque<Instant>,
+    strikes: u32,
+    last_strike: Option<Instant>,
+    blocked_until: Option<(Instant, bool)>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        RateLimiter {
+            config,
+            sent: VecDeque::new(),
+            strikes: 0,
+            last_strike: None,
+            blocked_until: None,
+        }
+    }
+
+    pub fn check(&mut self, now: Instant) -> RateDecision {
+        let blocked = self.blocked_until.filter(|(until, _)| now < *until);
+        if blocked.is_none() {
+            self.blocked_until = None;
+        }
+
+        if self
+            .last_strike
+            .is_some_and(|t| now.duration_since(t) > secs(self.config.strike_reset_secs))
+        {
+            self.strikes = 0;
+            self.last_strike = None;
+        }
+
+        let window = secs(self.config.window_secs);
+        while self
+            .sent
+            .front()
+            .is_some_and(|t| now.duration_since(*t) > window)
+        {
+            self.sent.pop_front();
+        }
+
+        match blocked {
+            None if self.sent.len() < self.config.max_messages => {
+                self.sent.push_back(now);
+                return RateDecision::Allow;
+            }
+            Some((until, muted)) => {
+                // Keep counting while blocked so flooding through a penalty escalates it
+                self.sent.push_back(now);
+                if self.sent.len() <= self.config.max_messages {
+                    return RateDecision::Limited {
+                        retry_after: until - now,
+                        muted,
+                    };
+                }
+            }
+            None => {}
+        }
+
+        self.strikes += 1;
+        self.last_strike = Some(now);
+        self.sent.clear();
+
+        if self.strikes >= self.config.disconnect_after_strikes {
+            return RateDecision::Disconnect;
+        }
+
+        let (penalty, muted) = if self.strikes >= self.config.mute_after_strikes {
+            (secs(self.config.mute_secs), true)
+        } else {
+            (secs(self.config.timeout_secs), false)
+        };
+        self.blocked_until = Some((now + penalty, muted));
+        RateDecision::Limited {
+            retry_after: penalty,
+            muted,
+        }
+    }
+
+    /// True once the limiter holds no state worth keeping.
+    fn is_idle(&self, now: Instant) -> bool {
+        let window = secs(self.config.window_secs);
+        self.blocked_until.is_none_or(|(until, _)| now >= until)
+            && self.last_strike.is_none()
+            && self
+                .sent
+                .back()
+                .is_none_or(|t| now.duration_since(*t) > window)
+    }
+}
+
+/// One limiter per key, e.g. per IP address, sharing a config.
+#[derive(Debug)]
+pub struct KeyedRateLimiter<K> {
+    config: RateLimitConfig,
+    limiters: HashMap<K, RateLimiter>,
+}
+
+impl<K: Eq + Hash + Clone> KeyedRateLimiter<K> {
+    pub fn new(config: RateLimitConfig) -> Self {
+        KeyedRateLimiter {
+            config,
+            limiters: HashMap::new(),
+        }
+    }
+
+    pub fn check(&mut self, key: &K, now: Instant) -> RateDecision {
+        self.limiters.retain(|k, l| k == key || !l.is_idle(now));
+        self.limiters
+            .entry(key.clone())
+            .or_insert_with(|| RateLimiter::new(self.config.clone()))
+            .check(now)
+    }
+}
+
+/// Negative or unusable values, which `validate` turns away, count as none.
+fn secs(value: f32) -> Duration {
+    Duration::try_from_secs_f32(value).unwrap_or(Duration::ZERO)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limited(retry_after: u64, muted: bool) -> RateDecision {
+        RateDecision::Limited {
+            retry_after: Duration::from_secs(retry_after),
+            muted,
+        }
+    }
+
+    #[test]
+    fn a_full_burst_is_allowed_then_limited() {
+        let mut limiter = RateLimiter::new(RateLimitConfig::default());
+        let now = Instant::now();
+        for _ in 0..10 {
+            assert_eq!(limiter.check(now), RateDecision::Allow);
+        }
+        assert_eq!(limiter.check(now), limited(10, false));
+        let later = now + Duration::from_secs(4);
+        assert_eq!(limiter.check(later), limited(6, false));
+    }
+
+    #[test]
+    fn the_window_refills_as_messages_age_out() {
+        let mut limiter = RateLimiter::new(RateLimitConfig::default());
+        let start = Instant::now();
+        for i in 0..10 {
+            let at = start + Duration::from_millis(400 * i);
+            assert_eq!(limiter.check(at), RateDecision::Allow);
+        }
+        // The first message leaves the five second window, freeing one slot
+        let refilled = start + Duration::from_millis(5001);
+        assert_eq!(limiter.check(refilled), RateDecision::Allow);
+        assert_ne!(limiter.check(refilled), RateDecision::Allow);
+    }
+
+    #[test]
+    fn repeat_offences_escalate_to_a_mute_then_a_disconnect() {
+        let mut limiter = RateLimiter::new(RateLimitConfig::default());
+        let mut now = Instant::now();
+        let mut strike = |now: Instant| {
+            for _ in 0..10 {
+                limiter.check(now);
+            }
+            limiter.check(now)
+        };
+        assert_eq!(strike(now), limited(10, false));
+        now += Duration::from_secs(11);
+        assert_eq!(strike(now), limited(10, false));
+        now += Duration::from_secs(11);
+        assert_eq!(strike(now), limited(60, true));
+        now += Duration::from_secs(61);
+        assert_eq!(strike(now), limited(60, true));
+        now += Duration::from_secs(61);
+        assert_eq!(strike(now), RateDecision::Disconnect);
+    }
+
+    #[test]
+    fn flooding_through_a_penalty_counts_as_another_strike() {
+        let mut limiter = RateLimiter::new(RateLimitConfig::default());
+        let now = Instant::now();
+        for _ in 0..11 {
+            limiter.check(now);
+        }
+        for _ in 0..10 {
+            assert!(matches!(limiter.check(now), RateDecision::Limited { .. }));
+        }
+        assert_eq!(limiter.check(now), limited(10, false));
+        assert_eq!(limiter.strikes, 2);
+    }
+
+    #[test]
+    fn strikes_are_forgiven_after_a_quiet_period() {
+        let mut limiter = RateLimiter::new(RateLimitConfig::default());
+        let now = Instant::now();
+        for _ in 0..11 {
+            limiter.check(now);
+        }
+        assert_eq!(limiter.strikes, 1);
+        let quiet = now + Duration::from_secs(301);
+        assert_eq!(limiter.check(quiet), RateDecision::Allow);
+        assert_eq!(limiter.strikes, 0);
+    }
+
+    #[test]
+    fn keys_are_limited_separately_and_idle_ones_dropped() {
+        let mut limiter = KeyedRateLimiter::new(RateLimitConfig {
+            max_messages: 1,
+            ..RateLimitConfig::default()
+        });
+        let now = Instant::now();
+        assert_eq!(limiter.check(&"a", now), RateDecision::Allow);
+        assert_eq!(limiter.check(&"b", now), RateDecision::Allow);
+        assert!(matches!(
+            limiter.check(&"a", now),
+            RateDecision::Limited { .. }
+        ));
+        assert_eq!(limiter.limiters.len(), 2);
+
+        // "b" has nothing left to remember, "a" still has its strike
+        let later = now + Duration::from_secs(6);
+        assert_eq!(limiter.check(&"c", later), RateDecision::Allow);
+        assert!(limiter.limiters.contains_key("a"));
+        assert!(!limiter.limiters.contains_key("b"));
+    }
+
+    #[test]
+    fn auth_limits_never_disconnect() {
+        let mut limiter = RateLimiter::new(RateLimitConfig::per_ip_auth_default());
+        let mut now = Instant::now();
+        for _ in 0..20 {
+            for _ in 0..5 {
+                limiter.check(now);
+            }
+            assert!(matches!(limiter.check(now), RateDecision::Limited { .. }));
+            now += Duration::from_secs(901);
+        }
+        assert!(RateLimitConfig::per_ip_auth_default().validate().is_ok());
+    }
+}
@@ -0,0 +1,213 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// Standing of a client on the server, lowest first. Roles belong to
+/// accounts; clients that haven't logged in are guests.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Guest,
+    /// What a freshly registered account starts as.
+    #[default]
+    Member,
+    Moderator,
+    Admin,
+    /// Only the host can make someone an owner.
+    Owner,
+}
+
+impl Role {
+    pub const ALL: [Role; 5] = [
+        Role::Guest,
+        Role::Member,
+        Role::Moderator,
+        Role::Admin,
+        Role::Owner,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Role::Guest => "guest",
+            Role::Member => "member",
+            Role::Moderator => "moderator",
+            Role::Admin => "admin",
+            Role::Owner => "owner",
+        }
+    }
+
+    /// Whether someone with this role may act on a client with `target`
+    /// role, e.g. kick them or change their role. Only lower ranks can be.
+    pub fn outranks(self, target: Role) -> bool {
+        self > target
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim().to_lowercase();
+        Role::ALL
+            .into_iter()
+            .find(|role| role.name() == s)
+            .ok_or_else(|| {
+                let names: Vec<&str> = Role::ALL.iter().map(|role| role.name()).collect();
+                format!("Unknown role '{}'; pick one of {}", s, names.join(", "))
+            })
+    }
+}
+
+/// Something only some roles may do, see `PermissionsConfig`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    Post,
+    /// Joining a room that doesn't exist yet, which opens it.
+    CreateRooms,
+    SetTopic,
+    /// Switching a room other than the default one to end-to-end encryption.
+    EncryptRooms,
+    Kick,
+    /// Also covers `/unmute`.
+    Mute,
+    /// Also covers `/unban`.
+    Ban,
+    /// Changing the role of an account ranked below one's own, to a role
+    /// below one's own.
+    ManageRoles,
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Permission::Post => "post",
+            Permission::CreateRooms => "create rooms",
+            Permission::SetTopic => "set the topic",
+            Permission::EncryptRooms => "encrypt rooms",
+            Permission::Kick => "kick",
+            Permission::Mute => "mute",
+            Permission::Ban => "ban",
+            Permission::ManageRoles => "change roles",
+        })
+    }
+}
+
+/// The lowest role holding each permission; every role above it holds it
+/// too. The host console may do everything regardless.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PermissionsConfig {
+    pub post: Role,
+    pub create_rooms: Role,
+    pub set_topic: Role,
+    pub encrypt_rooms: Role,
+    pub kick: Role,
+    pub mute: Role,
+    pub ban: Role,
+    pub manage_roles: Role,
+}
+
+impl Default for PermissionsConfig {
+    fn default() -> Self {
+        PermissionsConfig {
+            post: Role::Guest,
+            create_rooms: Role::Member,
+            set_topic: Role::Moderator,
+            encrypt_rooms: Role::Member,
+            kick: Role::Moderator,
+            mute: Role::Moderator,
+            ban: Role::Admin,
+            manage_roles: Role::Admin,
+        }
+    }
+}
+
+impl PermissionsConfig {
+    pub fn minimum(&self, permission: Permission) -> Role {
+        match permission {
+            Permission::Post => self.post,
+            Permission::CreateRooms => self.create_rooms,
+            Permission::SetTopic => self.set_topic,
+            Permission::EncryptRooms => self.encrypt_rooms,
+            Permission::Kick => self.kick,
+            Permission::Mute => self.mute,
+            Permission::Ban => self.ban,
+            Permission::ManageRoles => self.manage_roles,
+        }
+    }
+
+    /// Checks that `role` holds `permission`, explaining who does if not.
+    pub fn check(&self, role: Role, permission: Permission) -> Result<(), String> {
+        let minimum = self.minimum(permission);
+        if role >= minimum {
+            return Ok(());
+        }
+        let mut reason = match minimum {
+            Role::Owner => format!("Only owners can {}", permission),
+            _ => format!("Only {}s and above can {}", minimum, permission),
+        };
+        if role == Role::Guest {
+            reason.push_str("; log in to an account with /login first");
+        }
+        Err(reason)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_strictly_higher_roles_outrank() {
+        assert!(Role::Admin.outranks(Role::Moderator));
+        assert!(Role::Owner.outranks(Role::Admin));
+        assert!(!Role::Admin.outranks(Role::Admin));
+        assert!(!Role::Moderator.outranks(Role::Admin));
+        assert!(!Role::Guest.outranks(Role::Guest));
+    }
+
+    #[test]
+    fn roles_parse_by_name() {
+        for role in Role::ALL {
+            assert_eq!(role.name().parse::<Role>(), Ok(role));
+        }
+        assert_eq!(" Admin ".parse::<Role>(), Ok(Role::Admin));
+        assert!("root".parse::<Role>().is_err());
+    }
+
+    #[test]
+    fn permissions_hold_from_their_minimum_role_up() {
+        let permissions = PermissionsConfig::default();
+        assert!(permissions.check(Role::Guest, Permission::Post).is_ok());
+        assert!(permissions.check(Role::Moderator, Permission::Kick).is_ok());
+        assert!(permissions.check(Role::Owner, Permission::Ban).is_ok());
+        assert_eq!(
+            permissions.check(Role::Moderator, Permission::Ban),
+            Err("Only admins and above can ban".to_string())
+        );
+        assert_eq!(
+            permissions.check(Role::Guest, Permission::CreateRooms),
+            Err(
+                "Only members and above can create rooms; log in to an account with /login first"
+                    .to_string()
+            )
+        );
+
+        let permissions = PermissionsConfig {
+            manage_roles: Role::Owner,
+            ..PermissionsConfig::default()
+        };
+        assert_eq!(
+            permissions.check(Role::Admin, Permission::ManageRoles),
+            Err("Only owners can change roles".to_string())
+        );
+    }
+}
@@ -0,0 +1,213 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::SocketAddr;
+
+use crate::outbox::Outbox;
+use crate::protocol::{RoomInfo, ServerFrame};
+
+/// Longest room name accepted from `/join`.
+pub const MAX_ROOM_NAME_LEN: usize = 32;
+
+/// Longest topic accepted from `/topic`, in characters.
+pub const MAX_TOPIC_CHARS: usize = 200;
+
+#[derive(Default)]
+struct Room {
+    members: HashMap<SocketAddr, Outbox>,
+    /// Only end-to-end encrypted messages are relayed; cleared when the
+    /// room is dropped.
+    encrypted: bool,
+    /// Like encryption, lasts until the room is dropped.
+    topic: Option<String>,
+}
+
+/// All rooms hosted by one server process. Each room fans its traffic out to
+/// its members' outboxes; the default room and any permanent rooms always
+/// exist.
+pub struct RoomRegistry {
+    default_room: String,
+    permanent: HashSet<String>,
+    rooms: BTreeMap<String, Room>,
+}
+
+impl RoomRegistry {
+    pub fn new(default_room: &str) -> Self {
+        let mut rooms = BTreeMap::new();
+        rooms.insert(default_room.to_string(), Room::default());
+        RoomRegistry {
+            default_room: default_room.to_string(),
+            permanent: HashSet::from([default_room.to_string()]),
+            rooms,
+        }
+    }
+
+    /// Opens `room` now and keeps it open even while nobody is in it.
+    pub fn add_permanent(&mut self, room: &str) {
+        self.rooms.entry(room.to_string()).or_default();
+        self.permanent.insert(room.to_string());
+    }
+
+    pub fn default_room(&self) -> &str {
+        &self.default_room
+    }
+
+    /// Adds `addr` to `room`, creating the room on first use; the room's
+    /// traffic goes to `outbox` from now on.
+    pub fn join(&mut self, room: &str, addr: SocketAddr, outbox: Outbox) {
+        let room = self.rooms.entry(room.to_string()).or_default();
+        room.members.insert(addr, outbox);
+    }
+
+    /// Removes `addr` from `room`. Empty rooms are dropped unless permanent.
+    pub fn leave(&mut self, room: &str, addr: SocketAddr) {
+        let now_empty = match self.rooms.get_mut(room) {
+            Some(r) => {
+                r.members.remove(&addr);
+                r.members.is_empty()
+            }
+            None => return,
+        };
+        if now_empty && !self.permanent.contains(room) {
+            self.rooms.remove(room);
+        }
+    }
+
+    /// Queues `frame` for every member of `room` without waiting on any of
+    /// them; members whose queue is full miss it.
+    pub fn broadcast(&self, room: &str, frame: ServerFrame) {
+        if let Some(r) = self.rooms.get(room) {
+            for outbox in r.members.values() {
+                outbox.offer(frame.clone());
+            }
+        }
+    }
+
+    /// Switches `room` to end-to-end encryption; false if it already was.
+    pub fn encrypt(&mut self, room: &str) -> bool {
+        match self.rooms.get_mut(room) {
+            Some(r) if !r.encrypted => {
+                r.encrypted = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn is_encrypted(&self, room: &str) -> bool {
+        self.rooms.get(room).is_some_and(|r| r.encrypted)
+    }
+
+    pub fn contains(&self, room: &str) -> bool {
+        self.rooms.contains_key(room)
+    }
+
+    /// Sets or clears the topic of `room`; false if there is no such room.
+    pub fn set_topic(&mut self, room: &str, topic: Option<String>) -> bool {
+        match self.rooms.get_mut(room) {
+            Some(r) => {
+                r.topic = topic;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn topic(&self, room: &str) -> Option<String> {
+        self.rooms.get(room).and_then(|r| r.topic.clone())
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.rooms.keys().cloned().collect()
+    }
+
+    pub fn members(&self, room: &str) -> Vec<SocketAddr> {
+        self.rooms
+            .get(room)
+            .map(|r| r.members.keys().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// Describes every room, naming members by username where one is set.
+    pub fn list(&self, usernames: &HashMap<SocketAddr, String>) -> Vec<RoomInfo> {
+        self.rooms
+            .iter()
+            .map(|(name, room)| RoomInfo {
+                name: name.clone(),
+                members: self.member_names(name, usernames),
+                encrypted: room.encrypted,
+            })
+            .collect()
+    }
+
+    pub fn member_names(&self, room: &str, usernames: &HashMap<SocketAddr, String>) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .members(room)
+            .into_iter()
+            .map(|addr| {
+                usernames
+                    .get(&addr)
+                    .cloned()
+                    .unwrap_or_else(|| addr.to_string())
+            })
+            .collect();
+        names.sort();
+        names
+    }
+}
+
+/// Normalises a room name typed by a user, e.g. `#General` becomes `general`.
+pub fn normalize_room_name(name: &str) -> Result<String, String> {
+    let name = name.trim().trim_start_matches('#').to_ascii_lowercase();
+    if name.is_empty() {
+        return Err("Room name cannot be empty".to_string());
+    }
+    if name.chars().count() > MAX_ROOM_NAME_LEN {
+        return Err(format!(
+            "Room name must be at most {} characters",
+            MAX_ROOM_NAME_LEN
+        ));
+    }
+    if !name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+    {
+        // ASCII only, like nicknames, so lookalike names can't impersonate rooms
+        return Err(
+            "Room names may only contain ASCII letters, digits, '-', '_' and '.'".to_string(),
+        );
+    }
+    Ok(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn room_names_are_normalized() {
+        assert_eq!(normalize_room_name(" #General "), Ok("general".to_string()));
+        assert_eq!(
+            normalize_room_name("dev-ops_2.0"),
+            Ok("dev-ops_2.0".to_string())
+        );
+        assert!(normalize_room_name("#").is_err());
+        assert!(normalize_room_name(&"a".repeat(MAX_ROOM_NAME_LEN + 1)).is_err());
+        assert!(normalize_room_name("two words").is_err());
+    }
+
+    #[test]
+    fn room_names_are_ascii_like_nicknames() {
+        assert!(normalize_room_name("café").is_err());
+        assert!(normalize_room_name("Ｇeneral").is_err());
+        assert!(normalize_room_name("lobby\u{200b}").is_err());
+    }
+
+    #[test]
+    fn the_default_room_is_found_by_its_normalized_name() {
+        let room = normalize_room_name("General").unwrap();
+        let rooms = RoomRegistry::new(&room);
+        assert_eq!(
+            rooms.default_room(),
+            normalize_room_name("#GENERAL").unwrap()
+        );
+    }
+}
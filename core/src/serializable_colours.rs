@@ -26,11 +26,7 @@ pub static WHITE_COLOR: SerializableColor = SerializableColor {
     g: 255,
     b: 255,
 };
-pub static BLACK_COLOR: SerializableColor = SerializableColor {
-    r: 0,
-    g: 0,
-    b: 0,
-};
+pub static BLACK_COLOR: SerializableColor = SerializableColor { r: 0, g: 0, b: 0 };
 
 pub static ORANGE_COLOR: SerializableColor = SerializableColor {
     r: 255,
@@ -1,72 +1,159 @@
-use anyhow::{Context, Result};
-use chrono::{DateTime, Utc};
-use serde::{Deserialize, Serialize};
+use anyhow::{Context, Result, bail};
+use chrono::Utc;
+use socket2::{Domain, Protocol, Socket, Type};
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::Write;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
 use std::sync::Arc;
-use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tokio::net::TcpListener;
-use tokio::sync::{Mutex, broadcast, mpsc};
+use std::sync::atomic::{AtomicBool, AtomicU64};
+use std::time::Instant;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{Mutex, Semaphore, mpsc};
+use tokio::time::{Duration, timeout};
+use tokio_rustls::TlsAcceptor;
+use tokio_stream::StreamExt;
 
+use crate::access::{Access, Admission, InviteList};
+use crate::accounts::{self, AccountStore, MIN_PASSWORD_CHARS};
+use crate::address::parse_bind_address;
+use crate::chat_log::ChatLogger;
+use crate::commands::{ServerCommand, StartRequest};
+use crate::config::{Config, HistoryBackend};
+use crate::e2e;
+use crate::heartbeat::{Heartbeat, HeartbeatConfig};
+use crate::history::{BACKFILL_LIMIT, FileHistory, HistoryStore, MAX_HISTORY_PAGE, MemoryHistory};
+use crate::identity::{self, Claim, NameBindings};
+use crate::moderation::{Ban, BanList, BanTarget, MAX_DURATION, MuteList};
+use crate::nickname::claim_nickname;
+use crate::outbox::{Outbox, OutboxConfig};
+use crate::protocol::{
+    Capability, ChatPayload, ClientFrame, Frame, FrameReader, Incoming, Resume, ServerFrame,
+    format_room_list, frame_reader, negotiate_capabilities, negotiate_version, write_frame,
+};
+use crate::rate_limit::{KeyedRateLimiter, RateDecision, RateLimitConfig, RateLimiter};
+use crate::roles::{Permission, PermissionsConfig, Role};
+use crate::rooms::{MAX_TOPIC_CHARS, RoomRegistry, normalize_room_name};
 use crate::serializable_colours::*;
-use crate::types::{ChatMessage, OutputEvent, SystemEvent, TextLine};
+use crate::tls::ServerTls;
+use crate::transport::{self, ReadHalf, WriteHalf};
+use crate::types::{OutputEvent, SystemEvent, TextLine};
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-struct Message {
-    content: String,
-    sender: String,
-    username: Option<String>,
-    timestamp: DateTime<Utc>,
-}
+/// How long a freshly accepted client has to send its hello frame.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a closing connection gets to write out its queued frames.
+const OUTBOX_FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a client that got the password or invite wrong waits for its
+/// reject, to slow down guessing.
+const ACCESS_DENIED_DELAY: Duration = Duration::from_secs(1);
+
+/// Password hashes checked or made at once. Each takes Argon2's memory, so
+/// a burst of logins mustn't start them all together.
+const MAX_PASSWORD_JOBS: usize = 4;
+
+/// Messages kept per room when history lives in memory only.
+const MEMORY_HISTORY_CAPACITY: usize = 500;
 
 pub async fn run_server_backend(
-    mut input_rx: mpsc::Receiver<String>,
+    mut input_rx: mpsc::Receiver<ServerCommand>,
     output_tx: mpsc::Sender<OutputEvent>,
     shutdown_signal: Arc<AtomicBool>,
 ) -> Result<()> {
-    let mut server_config = None;
-    let mut log_file = None;
+    let config = load_config(&output_tx).await.server;
+    let mut request = None;
 
     // Wait for server configuration
-    while let Some(input) = input_rx.recv().await {
-        if input.starts_with("START:") {
-            let parts: Vec<&str> = input.split(':').collect();
-            if parts.len() >= 4 {
-                let chatroom = parts[1].to_string();
-                let port = parts[2].parse::<u16>().unwrap_or(8080);
-                let logging = parts[3].to_lowercase() == "yes";
-
-                // Set up logging if enabled
-                if logging {
-                    let filename = format!(
-                        "chatroom_{}_{}.log",
-                        chatroom,
-                        Utc::now().format("%Y%m%d_%H%M%S")
-                    );
-                    if let Ok(file) = File::create(&filename) {
-                        log_file = Some(file);
-                        let _ = output_tx
-                            .send(OutputEvent::TextLine(TextLine {
-                                text: format!("Logging enabled. Log file: {}\n", filename),
-                                color: Some(GREEN_COLOR.clone()),
-                            }))
-                            .await;
-                    }
-                }
-
-                server_config = Some((chatroom, port));
-                break;
-            }
+    while let Some(command) = input_rx.recv().await {
+        if let ServerCommand::Start(start) = command {
+            request = Some(start);
+            break;
         }
     }
 
-    let (chatroom, port) =
-        server_config.ok_or_else(|| anyhow::anyhow!("No server configuration received"))?;
-    let addr = format!("0.0.0.0:{}", port);
+    let StartRequest {
+        chatroom,
+        bind_address,
+        port,
+        logging,
+        history,
+        log_dir,
+        tls,
+        password,
+        invite_only,
+    } = request.ok_or_else(|| anyhow::anyhow!("No server configuration received"))?;
+
+    // The default room is named like any other, so `/join General` finds it
+    // and history and log files use the same name
+    let chatroom = normalize_room_name(&chatroom)
+        .map_err(|e| anyhow::anyhow!("Invalid chatroom name '{}': {}", chatroom, e))?;
+
+    let bind_address = bind_address.unwrap_or_else(|| config.bind_address.clone());
+    let bind_ip = parse_bind_address(&bind_address).map_err(|e| anyhow::anyhow!(e))?;
+    let addr = SocketAddr::new(bind_ip, port);
+
+    // Set up logging if enabled
+    let chat_log = logging.then(|| {
+        let mut log_config = config.log.clone();
+        if let Some(directory) = log_dir {
+            log_config.directory = directory;
+        }
+        ChatLogger::spawn(&chatroom, log_config, output_tx.clone())
+    });
+
+    let history = open_history(&chatroom, history == HistoryBackend::Disk, &output_tx).await;
+    let bans = load_bans(&config.bans_file, &output_tx).await?;
+    let bindings = load_bindings(&config.identities_file, &output_tx).await?;
+    let accounts = load_accounts(&config.accounts_file, &output_tx).await?;
+    let invites = load_invites(&config.access.invites_file, &output_tx).await;
+    let access = Access::new(password, invite_only, invites);
+
+    // Never fall back to plain text when the host asked for TLS
+    let tls = if tls {
+        let tls = ServerTls::load(&config.tls).context("Failed to set up TLS")?;
+        if tls.generated {
+            let _ = output_tx
+                .send(OutputEvent::TextLine(TextLine {
+                    text: format!(
+                        "Generated a self-signed certificate in {}",
+                        config.tls.cert_file.display()
+                    ),
+                    color: Some(YELLOW_COLOR.clone()),
+                }))
+                .await;
+        }
+        let _ = output_tx
+            .send(OutputEvent::TextLine(TextLine {
+                text: format!(
+                    "TLS enabled; clients should see the fingerprint {}",
+                    tls.fingerprint
+                ),
+                color: Some(GREEN_COLOR.clone()),
+            }))
+            .await;
+        Some(tls.acceptor)
+    } else {
+        None
+    };
+
+    if access.is_restricted() {
+        let _ = output_tx
+            .send(OutputEvent::TextLine(TextLine {
+                text: "Clients need the chatroom password or an invite token; create invites with /invite".to_string(),
+                color: Some(GREEN_COLOR.clone()),
+            }))
+            .await;
+        if tls.is_none() {
+            let _ = output_tx
+                .send(OutputEvent::TextLine(TextLine {
+                    text: "Without TLS, passwords and invite tokens travel in plain text"
+                        .to_string(),
+                    color: Some(YELLOW_COLOR.clone()),
+                }))
+                .await;
+        }
+    }
 
     let _ = output_tx
         .send(OutputEvent::TextLine(TextLine {
@@ -75,12 +162,50 @@ pub async fn run_server_backend(
         }))
         .await;
 
-    let listener: TcpListener = TcpListener::bind(&addr)
-        .await
-        .context("Failed to bind to address")?;
+    let listener = bind_listener(addr).with_context(|| format!("Failed to bind to {}", addr))?;
 
-    let (tx, _) = broadcast::channel::<Message>(100);
-    let usernames = Arc::new(Mutex::new(HashMap::<SocketAddr, String>::new()));
+    let mut rooms = RoomRegistry::new(&chatroom);
+    for room in &config.rooms {
+        match normalize_room_name(room) {
+            Ok(room) => rooms.add_permanent(&room),
+            Err(e) => {
+                let _ = output_tx
+                    .send(OutputEvent::TextLine(TextLine {
+                        text: format!("Skipping configured room '{}': {}", room, e),
+                        color: Some(RED_COLOR.clone()),
+                    }))
+                    .await;
+            }
+        }
+    }
+
+    let state = Arc::new(ServerState {
+        rooms: Mutex::new(rooms),
+        usernames: Mutex::new(HashMap::new()),
+        next_id: AtomicU64::new(history.last_id() + 1),
+        rate_limits: config.rate_limit.clone(),
+        request_rate_limits: config.request_rate_limit.clone(),
+        ip_limiter: Mutex::new(KeyedRateLimiter::new(config.ip_rate_limit.clone())),
+        auth_limiter: Mutex::new(KeyedRateLimiter::new(config.auth_rate_limit.clone())),
+        password_jobs: Semaphore::new(MAX_PASSWORD_JOBS),
+        history: Mutex::new(history),
+        bans: Mutex::new(bans),
+        mutes: Mutex::new(MuteList::default()),
+        bindings: Mutex::new(bindings),
+        accounts: Mutex::new(accounts),
+        logins: Mutex::new(HashMap::new()),
+        permissions: config.permissions.clone(),
+        access: Mutex::new(access),
+        clients: Mutex::new(HashMap::new()),
+        resume_tokens: Mutex::new(HashMap::new()),
+        heartbeat: config.heartbeat.clone(),
+        max_frame_bytes: config.max_frame_bytes,
+        oversized_frame_limit: config.oversized_frame_limit,
+        outbox: config.outbox.clone(),
+        tls,
+        chat_log,
+        output_tx: output_tx.clone(),
+    });
 
     let _ = output_tx
         .send(OutputEvent::TextLine(TextLine {
@@ -98,6 +223,9 @@ pub async fn run_server_backend(
             accept_result = listener.accept() => {
                 match accept_result {
                     Ok((socket, addr)) => {
+                        // IPv4 clients of a dual-stack listener show up as ::ffff:a.b.c.d;
+                        // unwrap them so IP bans and rate limits see the plain address
+                        let addr = SocketAddr::new(addr.ip().to_canonical(), addr.port());
                         let _ = output_tx.send(OutputEvent::TextLine(TextLine {
                             text: format!("New client connected: {}", addr),
                             color: None,
@@ -108,130 +236,15 @@ pub async fn run_server_backend(
                             address: addr.to_string(),
                         })).await;
 
-                        let tx = tx.clone();
-                        let mut rx = tx.subscribe();
-                        let usernames = Arc::clone(&usernames);
-                        let output_tx = output_tx.clone();
-                        let chatroom = chatroom.clone();
-                        // let log_file = Arc::new(Mutex::new(log_file.clone()));
+                        let state = Arc::clone(&state);
 
                         tokio::spawn(async move {
-                            let (reader, mut writer) = socket.into_split();
-                            let mut reader = BufReader::new(reader);
-                            let mut line = String::new();
-
-                            writer.write_all(
-                                format!("{{\"info\": \"Connected to chatroom '{}'\"}}\n", chatroom).as_bytes()
-                            ).await?;
-
-                            loop {
-                                tokio::select! {
-                                    result = reader.read_line(&mut line) => {
-                                        if result? == 0 {
-                                            // Client disconnected
-                                            let mut usernames_lock = usernames.lock().await;
-                                            if let Some(name) = usernames_lock.remove(&addr) {
-                                                let leave_msg = Message {
-                                                    content: format!("{} has left the chat", name),
-                                                    sender: "Server".to_string(),
-                                                    username: Some(name.clone()),
-                                                    timestamp: Utc::now(),
-                                                };
-                                                let _ = tx.send(leave_msg.clone());
-                                                let _ = output_tx.send(OutputEvent::ChatMessage(ChatMessage {
-                                                    content: leave_msg.content.clone(),
-                                                    sender: leave_msg.sender.clone(),
-                                                    username: leave_msg.username.clone(),
-                                                    timestamp: leave_msg.timestamp,
-                                                    is_self: false,
-                                                })).await;
-
-                                                // Send a ConnectionClosed event
-                                                let _ = output_tx.send(OutputEvent::SystemEvent(SystemEvent::ConnectionClosed)).await;
-                                            }
-                                            break;
-                                        }
-
-                                        let trimmed = line.trim();
-
-                                        if trimmed.starts_with("/username:") {
-                                            let name = trimmed.strip_prefix("/username:").unwrap_or("").trim().to_string();
-                                            if !name.is_empty() {
-                                                let mut usernames_lock = usernames.lock().await;
-                                                usernames_lock.insert(addr, name.clone());
-
-                                                writer.write_all(format!("{{\"info\": \"Username set to '{}'\"}}\n", name).as_bytes()).await?;
-
-                                                let join_msg = Message {
-                                                    content: format!("{} has joined the chat", name),
-                                                    sender: "Server".to_string(),
-                                                    username: Some(name.clone()),
-                                                    timestamp: Utc::now(),
-                                                };
-                                                let _ = tx.send(join_msg.clone());
-                                                let _ = output_tx.send(OutputEvent::ChatMessage(ChatMessage {
-                                                    content: join_msg.content.clone(),
-                                                    sender: join_msg.sender.clone(),
-                                                    username: join_msg.username.clone(),
-                                                    timestamp: join_msg.timestamp,
-                                                    is_self: false,
-                                                })).await;
-
-                                                // Send a notification about the new user
-                                                let _ = output_tx.send(OutputEvent::SystemEvent(SystemEvent::PromptInput {
-                                                    prompt: format!("User {} has joined the chat", name),
-                                                })).await;
-                                            } else {
-                                                writer.write_all(b"{\"error\": \"Invalid username command\"}\n").await?;
-                                            }
-                                        } else if !trimmed.is_empty() {
-                                            let usernames = usernames.lock().await;
-                                            let sender = usernames.get(&addr).cloned().unwrap_or_else(|| addr.to_string());
-                                            let username = usernames.get(&addr).cloned();
-
-                                            let msg = Message {
-                                                content: trimmed.to_string(),
-                                                sender: sender.clone(),
-                                                username: username.clone(),
-                                                timestamp: Utc::now(),
-                                            };
-
-                                            if let Err(e) = tx.send(msg.clone()) {
-                                                eprintln!("Broadcast failed: {}", e);
-                                            }
-
-                                            let _ = output_tx.send(OutputEvent::ChatMessage(ChatMessage {
-                                                content: msg.content.clone(),
-                                                sender: sender.clone(),
-                                                username: username.clone(),
-                                                timestamp: msg.timestamp,
-                                                is_self: false,
-                                            })).await;
-
-                                            // Log message if logging is enabled
-                                            // if let Some(ref mut log) = log_file.lock().await {
-                                            //     let _ = writeln!(log, "[{}] {}: {}",
-                                            //         msg.timestamp.format("%Y-%m-%d %H:%M:%S"),
-                                            //         msg.sender.clone(),
-                                            //         msg.content.clone()
-                                            //     );
-                                            // }
-                                        }
-
-                                        line.clear();
-                                    }
-
-                                    result = rx.recv() => {
-                                        if let Ok(msg) = result {
-                                            let json = serde_json::to_string(&msg)?;
-                                            writer.write_all(json.as_bytes()).await?;
-                                            writer.write_all(b"\n").await?;
-                                        }
-                                    }
-                                }
+                            if let Err(e) = handle_connection(socket, addr, Arc::clone(&state)).await {
+                                let _ = state.output_tx.send(OutputEvent::TextLine(TextLine {
+                                    text: format!("Client {} dropped: {}", addr, e),
+                                    color: Some(RED_COLOR.clone()),
+                                })).await;
                             }
-
-                            Ok::<_, anyhow::Error>(())
                         });
                     }
                     Err(e) => {
@@ -240,46 +253,55 @@ pub async fn run_server_backend(
                 }
             }
 
-            Some(input) = input_rx.recv() => {
-                if input == "/end" {
-                    break;
-                }
-
-                // Handle server host messages
-                let msg = Message {
-                    content: input,
-                    sender: "Host".to_string(),
-                    username: None,
-                    timestamp: Utc::now(),
+            Some(command) = input_rx.recv() => {
+                let result = match command {
+                    ServerCommand::Stop => break,
+                    ServerCommand::Send(text) => {
+                        state.host_message(text).await;
+                        continue;
+                    }
+                    ServerCommand::ListRooms => {
+                        let rooms = {
+                            let rooms = state.rooms.lock().await;
+                            let usernames = state.usernames.lock().await;
+                            rooms.list(&usernames)
+                        };
+                        let _ = output_tx.send(OutputEvent::TextLine(TextLine {
+                            text: format_room_list(&rooms),
+                            color: Some(CYAN_COLOR.clone()),
+                        })).await;
+                        continue;
+                    }
+                    ServerCommand::Start(_) => Err("The server is already running".to_string()),
+                    ServerCommand::Kick { user, reason } => state.kick_user(&user, reason, &Actor::Host).await,
+                    ServerCommand::Ban { target, duration } => state.ban(target, duration, &Actor::Host).await,
+                    ServerCommand::Unban { target } => state.unban(&target, &Actor::Host).await,
+                    ServerCommand::Mute { user, duration } => state.mute(&user, duration, &Actor::Host).await,
+                    ServerCommand::Unmute { user } => state.unmute(&user, &Actor::Host).await,
+                    ServerCommand::Topic { room, topic } => match normalize_room_name(&room) {
+                        Ok(room) => state.set_topic(&room, topic, "Host".to_string()).await,
+                        Err(e) => Err(e),
+                    },
+                    ServerCommand::ListBans => Ok(state.list_bans().await),
+                    ServerCommand::ForgetKey { user } => state.forget_key(&user).await,
+                    ServerCommand::Unregister { user } => state.unregister(&user).await,
+                    ServerCommand::Invite { duration, uses } => state.create_invite(duration, uses).await,
+                    ServerCommand::ListInvites => Ok(state.list_invites().await),
+                    ServerCommand::Revoke { token } => state.revoke_invite(&token).await,
+                    ServerCommand::SetRole { user, role } => state.set_role(&user, role, &Actor::Host).await,
+                    ServerCommand::ListRoles => Ok(state.list_roles().await),
                 };
-
-                let tx = tx.clone();
-                let output_tx = output_tx.clone();
-
-                if let Err(e) = tx.send(msg.clone()) {
-                    eprintln!("Broadcast failed: {}", e);
-                }
-
-                let _ = output_tx.send(OutputEvent::ChatMessage(ChatMessage {
-                    content: msg.content.clone(),
-                    sender: msg.sender.clone(),
-                    username: Some("Host".to_string()),
-                    timestamp: msg.timestamp,
-                    is_self: true,
+                let (text, color) = match result {
+                    Ok(text) => (text, GREEN_COLOR.clone()),
+                    Err(text) => (text, RED_COLOR.clone()),
+                };
+                let _ = output_tx.send(OutputEvent::TextLine(TextLine {
+                    text,
+                    color: Some(color),
                 })).await;
-
-                // Log message if logging is enabled
-                if let Some(ref mut log) = log_file {
-                    let _ = writeln!(log, "[{}] {}: {}",
-                        msg.timestamp.format("%Y-%m-%d %H:%M:%S"),
-                        msg.sender.clone(),
-                        msg.content.clone()
-                    );
-                }
             }
         }
     }
-
     let _ = output_tx
         .send(OutputEvent::TextLine(TextLine {
             text: "Server shutting down...".to_string(),
@@ -294,3 +316,2298 @@ pub async fn run_server_backend(
 
     Ok(())
 }
+
+/// Binds the listening socket. The IPv6 wildcard `[::]` is made explicitly
+/// dual-stack so IPv4 clients can connect too, whatever the OS default is.
+fn bind_listener(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() && addr.ip().is_unspecified() {
+        socket.set_only_v6(false)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Opens the history store for `chatroom`, falling back to memory if the
+/// on-disk store can't be opened.
+async fn open_history(
+    chatroom: &str,
+    on_disk: bool,
+    output_tx: &mpsc::Sender<OutputEvent>,
+) -> Box<dyn HistoryStore> {
+    if on_disk {
+        let path = format!("history_{}.jsonl", chatroom);
+        match FileHistory::open(&path) {
+            Ok(history) => {
+                let _ = output_tx
+                    .send(OutputEvent::TextLine(TextLine {
+                        text: format!("History stored in {}", history.path().display()),
+                        color: Some(GREEN_COLOR.clone()),
+                    }))
+                    .await;
+                return Box::new(history);
+            }
+            Err(e) => {
+                let _ = output_tx
+                    .send(OutputEvent::TextLine(TextLine {
+                        text: format!("{:#}; keeping history in memory only", e),
+                        color: Some(RED_COLOR.clone()),
+                    }))
+                    .await;
+            }
+        }
+    }
+    Box::new(MemoryHistory::new(MEMORY_HISTORY_CAPACITY))
+}
+
+/// Loads the config files, falling back to defaults if they are invalid.
+async fn load_config(output_tx: &mpsc::Sender<OutputEvent>) -> Config {
+    match Config::load() {
+        Ok(config) => {
+            for source in &config.sources {
+                let _ = output_tx
+                    .send(OutputEvent::TextLine(TextLine {
+                        text: format!("Loaded config from {}", source.display()),
+                        color: Some(GREEN_COLOR.clone()),
+                    }))
+                    .await;
+            }
+            config
+        }
+        Err(e) => {
+            let _ = output_tx
+                .send(OutputEvent::TextLine(TextLine {
+                    text: format!("{:#}; using default settings", e),
+                    color: Some(RED_COLOR.clone()),
+                }))
+                .await;
+            Config::default()
+        }
+    }
+}
+
+/// Loads the persisted ban list. An unreadable list stops the server, as
+/// starting without it would let everyone back in and overwrite the file
+/// on the next ban.
+async fn load_bans(path: &Path, output_tx: &mpsc::Sender<OutputEvent>) -> Result<BanList> {
+    let bans = BanList::load(path)
+        .context("Not starting, so the bans aren't lost; repair or move the file")?;
+    let count = bans.active().len();
+    if count > 0 {
+        let _ = output_tx
+            .send(OutputEvent::TextLine(TextLine {
+                text: format!("Loaded {} ban(s) from {}", count, bans.path().display()),
+                color: Some(GREEN_COLOR.clone()),
+            }))
+            .await;
+    }
+    Ok(bans)
+}
+
+/// Loads the nickname bindings. Unreadable bindings stop the server, as
+/// starting without them would free every bound name and overwrite the
+/// file on the next bind.
+async fn load_bindings(path: &Path, output_tx: &mpsc::Sender<OutputEvent>) -> Result<NameBindings> {
+    let bindings = NameBindings::load(path).context(
+        "Not starting, so the registered nicknames aren't lost; repair or move the file",
+    )?;
+    if !bindings.is_empty() {
+        let _ = output_tx
+            .send(OutputEvent::TextLine(TextLine {
+                text: format!(
+                    "Loaded {} registered nickname(s) from {}",
+                    bindings.len(),
+                    bindings.path().display()
+                ),
+                color: Some(GREEN_COLOR.clone()),
+            }))
+            .await;
+    }
+    Ok(bindings)
+}
+
+/// Loads the registered accounts. Unreadable accounts stop the server, as
+/// starting without them would free every registered name and overwrite
+/// the file on the next `/register`.
+async fn load_accounts(path: &Path, output_tx: &mpsc::Sender<OutputEvent>) -> Result<AccountStore> {
+    let accounts = AccountStore::load(path)
+        .context("Not starting, so the accounts aren't lost; repair or move the file")?;
+    if !accounts.is_empty() {
+        let _ = output_tx
+            .send(OutputEvent::TextLine(TextLine {
+                text: format!(
+                    "Loaded {} account(s) from {}",
+                    accounts.len(),
+                    accounts.path().display()
+                ),
+                color: Some(GREEN_COLOR.clone()),
+            }))
+            .await;
+    }
+    Ok(accounts)
+}
+
+/// Loads the invite list, starting with none if it's unreadable.
+async fn load_invites(path: &Path, output_tx: &mpsc::Sender<OutputEvent>) -> InviteList {
+    match InviteList::load(path) {
+        Ok(invites) => {
+            let count = invites.active().len();
+            if count > 0 {
+                let _ = output_tx
+                    .send(OutputEvent::TextLine(TextLine {
+                        text: format!(
+                            "Loaded {} invite(s) from {}",
+                            count,
+                            invites.path().display()
+                        ),
+                        color: Some(GREEN_COLOR.clone()),
+                    }))
+                    .await;
+            }
+            invites
+        }
+        Err(e) => {
+            let _ = output_tx
+                .send(OutputEvent::TextLine(TextLine {
+                    text: format!("{:#}; starting with no invites", e),
+                    color: Some(RED_COLOR.clone()),
+                }))
+                .await;
+            InviteList::empty(path)
+        }
+    }
+}
+
+/// Instructions from the host or a moderator to a single connection task.
+#[derive(Debug)]
+enum Control {
+    Kick {
+        reason: String,
+    },
+    /// `until` is `None` for a mute that lasts until `/unmute`.
+    Mute {
+        until: Option<Instant>,
+        by: String,
+    },
+    Unmute {
+        by: String,
+    },
+    /// Passed on to the client as an info frame.
+    Notify {
+        message: String,
+    },
+}
+
+/// Who a moderation request comes from.
+enum Actor {
+    /// The server console, which may do anything to anyone.
+    Host,
+    /// A client whose role holds the permission the request needs.
+    Client { name: String, role: Role },
+}
+
+impl Actor {
+    /// Completes "kicked by ...", "muted by ..." and the like.
+    fn name(&self) -> &str {
+        match self {
+            Actor::Host => "the host",
+            Actor::Client { name, .. } => name,
+        }
+    }
+
+    fn may_act_on(&self, target: Role) -> bool {
+        match self {
+            Actor::Host => true,
+            Actor::Client { role, .. } => role.outranks(target),
+        }
+    }
+
+    /// The role this actor's bans are recorded with, `None` for the host.
+    fn role(&self) -> Option<Role> {
+        match self {
+            Actor::Host => None,
+            Actor::Client { role, .. } => Some(*role),
+        }
+    }
+
+    /// Bans placed by the host can only be lifted by the host.
+    fn may_lift(&self, ban: &Ban) -> bool {
+        match (self, ban.role) {
+            (Actor::Host, _) => true,
+            (Actor::Client { .. }, None) => false,
+            (Actor::Client { role, .. }, Some(placed_by)) => role.outranks(placed_by),
+        }
+    }
+}
+
+/// Why a client may neither lift nor replace `ban`, see `Actor::may_lift`.
+fn protected_ban(target: &BanTarget, ban: &Ban) -> String {
+    match ban.role {
+        Some(role) => format!(
+            "The ban on {} was placed by {} {} and can't be lifted or changed by you",
+            target,
+            article(role),
+            role
+        ),
+        None => format!(
+            "The ban on {} was placed by the host and only they can lift or change it",
+            target
+        ),
+    }
+}
+
+/// A ban or mute duration sent by a client, held to the same limit as
+/// durations typed at the console.
+fn wire_duration(seconds: Option<u64>) -> Result<Option<Duration>, String> {
+    match seconds.map(Duration::from_secs) {
+        Some(duration) if duration > MAX_DURATION => Err(format!(
+            "Durations can be at most {}d",
+            MAX_DURATION.as_secs() / (60 * 60 * 24)
+        )),
+        duration => Ok(duration),
+    }
+}
+
+/// Why a request against `name`, who holds `role`, was refused.
+fn outranked(name: &str, role: Role) -> String {
+    format!(
+        "{} is {} {} and doesn't rank below you",
+        name,
+        article(role),
+        role
+    )
+}
+
+fn article(role: Role) -> &'static str {
+    match role {
+        Role::Admin | Role::Owner => "an",
+        _ => "a",
+    }
+}
+
+/// State shared by every connection task.
+struct ServerState {
+    rooms: Mutex<RoomRegistry>,
+    usernames: Mutex<HashMap<SocketAddr, String>>,
+    history: Mutex<Box<dyn HistoryStore>>,
+    next_id: AtomicU64,
+    rate_limits: RateLimitConfig,
+    request_rate_limits: RateLimitConfig,
+    ip_limiter: Mutex<KeyedRateLimiter<IpAddr>>,
+    /// Password attempts per IP address, see `check_password_rate`.
+    auth_limiter: Mutex<KeyedRateLimiter<IpAddr>>,
+    password_jobs: Semaphore,
+    bans: Mutex<BanList>,
+    mutes: Mutex<MuteList>,
+    bindings: Mutex<NameBindings>,
+    accounts: Mutex<AccountStore>,
+    /// Account each connection logged in to; its role applies to them.
+    logins: Mutex<HashMap<SocketAddr, String>>,
+    permissions: PermissionsConfig,
+    access: Mutex<Access>,
+    clients: Mutex<HashMap<SocketAddr, mpsc::Sender<Control>>>,
+    /// Resume token of each connection, see `protocol::Resume`.
+    resume_tokens: Mutex<HashMap<String, SocketAddr>>,
+    heartbeat: HeartbeatConfig,
+    max_frame_bytes: usize,
+    oversized_frame_limit: u32,
+    outbox: OutboxConfig,
+    /// Set when the host enabled TLS.
+    tls: Option<TlsAcceptor>,
+    /// Set when the host enabled logging.
+    chat_log: Option<ChatLogger>,
+    output_tx: mpsc::Sender<OutputEvent>,
+}
+
+impl ServerState {
+    /// Assigns the next message id and stores the message in history.
+    async fn record(&self, mut msg: ChatPayload) -> ChatPayload {
+        msg.id = Some(self.next_id.fetch_add(1, Ordering::SeqCst));
+        if let Err(e) = self.history.lock().await.append(&msg) {
+            let _ = self
+                .output_tx
+                .send(OutputEvent::TextLine(TextLine {
+                    text: format!("Failed to store message in history: {}", e),
+                    color: Some(RED_COLOR.clone()),
+                }))
+                .await;
+        }
+        msg
+    }
+
+    /// Fetches a page of history, reporting whether older messages remain.
+    async fn history_page(
+        &self,
+        room: &str,
+        limit: usize,
+        before: Option<u64>,
+    ) -> Result<(Vec<ChatPayload>, bool)> {
+        let history = self.history.lock().await;
+        let mut messages = match before {
+            Some(id) => history.before(room, id, limit + 1)?,
+            None => history.recent(room, limit + 1)?,
+        };
+        let has_more = messages.len() > limit;
+        if has_more {
+            messages.remove(0);
+        }
+        Ok((messages, has_more))
+    }
+
+    /// Messages posted in `room` after `after_id`, newest page only,
+    /// reporting whether even more were missed.
+    async fn missed_since(&self, room: &str, after_id: u64) -> Result<(Vec<ChatPayload>, bool)> {
+        let history = self.history.lock().await;
+        let mut messages = history.after(room, after_id, MAX_HISTORY_PAGE + 1)?;
+        let has_more = messages.len() > MAX_HISTORY_PAGE;
+        if has_more {
+            messages.remove(0);
+        }
+        Ok((messages, has_more))
+    }
+
+    /// Closes the connection that was given `token`, freeing its nickname
+    /// and its place in `room` for the client reconnecting with it.
+    async fn take_over(&self, token: &str, addr: SocketAddr, room: &str) {
+        let Some(previous) = self.resume_tokens.lock().await.remove(token) else {
+            return;
+        };
+        if previous == addr {
+            return;
+        }
+        self.usernames.lock().await.remove(&previous);
+        // Leave now rather than when the old connection gets to the kick,
+        // so the member list sent on rejoin doesn't show it
+        self.rooms.lock().await.leave(room, previous);
+        self.control(
+            previous,
+            Control::Kick {
+                reason: "You reconnected from another connection".to_string(),
+            },
+        )
+        .await;
+    }
+
+    /// Gives `name` to `addr` unless it is banned, another connection holds
+    /// it, it is a registered account other than the one `addr` logged in
+    /// to, or it is bound to another identity key. A free name is bound to
+    /// `public_key`. Every way of taking a name comes through here.
+    async fn claim_name(
+        &self,
+        addr: SocketAddr,
+        name: &str,
+        public_key: Option<&str>,
+        account: Option<&str>,
+    ) -> Result<(String, Claim), String> {
+        if let Some(ban) = self.bans.lock().await.find(addr.ip(), Some(name.trim())) {
+            return Err(ban.describe());
+        }
+        let mut bindings = self.bindings.lock().await;
+        let claim = match self.accounts.lock().await.get(name.trim()) {
+            Some(owner) if account.is_some_and(|a| a.eq_ignore_ascii_case(&owner.username)) => {
+                Claim::Account
+            }
+            Some(owner) => {
+                return Err(format!(
+                    "'{}' is a registered account; log in with /login {} <password>",
+                    owner.username, owner.username
+                ));
+            }
+            None => bindings.check(name.trim(), public_key)?,
+        };
+        let (name, _) = claim_nickname(&mut *self.usernames.lock().await, addr, name)?;
+        if let (Claim::Unbound, Some(key)) = (claim, public_key)
+            && let Err(e) = bindings.bind(&name, key)
+        {
+            let _ = self
+                .output_tx
+                .send(OutputEvent::TextLine(TextLine {
+                    text: format!("{:#}", e),
+                    color: Some(RED_COLOR.clone()),
+                }))
+                .await;
+        }
+        Ok((name, claim))
+    }
+
+    /// Lets a client in if the chatroom is open or `secret` is its password
+    /// or a valid invite. Returns the invite's token if letting the client
+    /// in takes one of its uses; pass it to `redeem_invite` once the client
+    /// is in, so a failed handshake doesn't use up the invite.
+    async fn admit(&self, secret: Option<&str>, redeemer: &str) -> Result<Option<String>, String> {
+        match self.access.lock().await.check(secret, redeemer)? {
+            Admission::Invite {
+                token,
+                first_use: true,
+            } => Ok(Some(token)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Records the first use of an invite by a client that got in with it.
+    /// False if another client took its last use in the meantime.
+    async fn redeem_invite(&self, addr: SocketAddr, token: &str, redeemer: &str) -> bool {
+        let text = match self.access.lock().await.invites.redeem(token, redeemer) {
+            Ok(false) => return false,
+            Ok(true) => format!("{} redeemed invite {}", addr, token),
+            Err(e) => format!("{:#}", e),
+        };
+        let _ = self
+            .output_tx
+            .send(OutputEvent::TextLine(TextLine {
+                text,
+                color: Some(GRAY_COLOR.clone()),
+            }))
+            .await;
+        true
+    }
+
+    async fn create_invite(&self, duration: Option<Duration>, uses: u32) -> Result<String, String> {
+        let mut access = self.access.lock().await;
+        let invite = access
+            .invites
+            .create(duration, uses)
+            .map_err(|e| format!("{:#}", e))?;
+        let mut text = format!(
+            "Created invite {}\nEach use admits one identity key, or one IP address for clients without a key",
+            invite.describe()
+        );
+        if !access.is_restricted() {
+            text.push_str("\nThe chatroom is open to anyone, so nobody needs it yet; set a password or invite_only");
+        }
+        Ok(text)
+    }
+
+    async fn list_invites(&self) -> String {
+        let access = self.access.lock().await;
+        let active = access.invites.active();
+        if active.is_empty() {
+            return "No active invites".to_string();
+        }
+        active
+            .iter()
+            .map(|invite| invite.describe())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Withdraws an invite. Clients already connected with it stay, but
+    /// can't reconnect.
+    async fn revoke_invite(&self, token: &str) -> Result<String, String> {
+        let removed = self
+            .access
+            .lock()
+            .await
+            .invites
+            .revoke(token)
+            .map_err(|e| format!("{:#}", e))?;
+        if removed {
+            Ok(format!("Revoked invite {}", token))
+        } else {
+            Err(format!("No active invite {}", token))
+        }
+    }
+
+    /// Checks `password` against the account `username`, returning the
+    /// account's name as registered. Argon2 is slow on purpose, so it runs
+    /// off the runtime; failures are slowed down further to make guessing slow.
+    async fn authenticate(
+        &self,
+        addr: SocketAddr,
+        username: &str,
+        password: &str,
+    ) -> Result<String, String> {
+        self.check_password_rate(addr).await?;
+        let account = self.accounts.lock().await.get(username.trim()).cloned();
+        let Some(account) = account else {
+            return Err(format!("No account named '{}'", username.trim()));
+        };
+        let (password, hash) = (password.to_string(), account.password_hash);
+        let verified = self
+            .password_job(move || accounts::verify_password(&password, &hash))
+            .await
+            .unwrap_or(false);
+        if verified {
+            Ok(account.username)
+        } else {
+            tokio::time::sleep(ACCESS_DENIED_DELAY).await;
+            Err(format!("Wrong password for '{}'", account.username))
+        }
+    }
+
+    /// Counts a password attempt from `addr`'s IP address, refusing it once
+    /// there were too many: every attempt costs an Argon2 hash, and it is
+    /// how passwords would be guessed.
+    async fn check_password_rate(&self, addr: SocketAddr) -> Result<(), String> {
+        let decision = self
+            .auth_limiter
+            .lock()
+            .await
+            .check(&addr.ip(), Instant::now());
+        match decision {
+            RateDecision::Allow => Ok(()),
+            RateDecision::Limited { retry_after, .. } => Err(format!(
+                "Too many password attempts, try again in {} seconds",
+                retry_after.as_secs().max(1)
+            )),
+            RateDecision::Disconnect => Err("Too many password attempts".to_string()),
+        }
+    }
+
+    /// Runs an Argon2 job off the runtime, at most `MAX_PASSWORD_JOBS` at once.
+    async fn password_job<T: Send + 'static>(
+        &self,
+        job: impl FnOnce() -> T + Send + 'static,
+    ) -> Result<T, String> {
+        let _permit = self
+            .password_jobs
+            .acquire()
+            .await
+            .map_err(|e| e.to_string())?;
+        tokio::task::spawn_blocking(job)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    /// Registers the nickname `addr` is using as an account.
+    async fn register(&self, addr: SocketAddr, password: String) -> Result<String, String> {
+        let Some(name) = self.usernames.lock().await.get(&addr).cloned() else {
+            return Err("Pick a nickname with /nick before registering it".to_string());
+        };
+        if password.chars().count() < MIN_PASSWORD_CHARS {
+            return Err(format!(
+                "Passwords need at least {} characters",
+                MIN_PASSWORD_CHARS
+            ));
+        }
+        if self.accounts.lock().await.get(&name).is_some() {
+            return Err(format!("'{}' is already registered", name));
+        }
+        self.check_password_rate(addr).await?;
+        let hash = self
+            .password_job(move || accounts::hash_password(&password))
+            .await?
+            .map_err(|e| format!("{:#}", e))?;
+        self.accounts
+            .lock()
+            .await
+            .register(&name, hash)
+            .map_err(|e| format!("{:#}", e))?;
+        Ok(name)
+    }
+
+    /// Deletes an account, e.g. after its owner forgot the password.
+    async fn unregister(&self, user: &str) -> Result<String, String> {
+        let removed = self
+            .accounts
+            .lock()
+            .await
+            .remove(user)
+            .map_err(|e| format!("{:#}", e))?;
+        if removed {
+            Ok(format!(
+                "Deleted the account '{}'; the next client to register it claims it",
+                user
+            ))
+        } else {
+            Err(format!("'{}' is not a registered account", user))
+        }
+    }
+
+    /// Frees a nickname bound to an identity key, e.g. after its owner lost it.
+    async fn forget_key(&self, user: &str) -> Result<String, String> {
+        let released = self
+            .bindings
+            .lock()
+            .await
+            .release(user)
+            .map_err(|e| format!("{:#}", e))?;
+        if released {
+            Ok(format!(
+                "'{}' is no longer bound to an identity key; the next client to use it claims it",
+                user
+            ))
+        } else {
+            Err(format!("'{}' is not bound to an identity key", user))
+        }
+    }
+
+    async fn whois(&self, username: &str) -> ServerFrame {
+        let online = self
+            .usernames
+            .lock()
+            .await
+            .values()
+            .any(|name| name.eq_ignore_ascii_case(username));
+        let account = self
+            .accounts
+            .lock()
+            .await
+            .get(username)
+            .map(|a| (a.username.clone(), a.role));
+        let bindings = self.bindings.lock().await;
+        let binding = bindings.get(username);
+        ServerFrame::Whois {
+            username: account
+                .as_ref()
+                .map(|(name, _)| name.clone())
+                .or_else(|| binding.map(|b| b.nickname.clone()))
+                .unwrap_or_else(|| username.to_string()),
+            online,
+            fingerprint: binding.and_then(|b| identity::fingerprint(&b.public_key).ok()),
+            account: account.is_some(),
+            role: account.map(|(_, role)| role),
+        }
+    }
+
+    /// Frees the nickname and resume token of a client that dropped before
+    /// it joined a room.
+    async fn release(&self, addr: SocketAddr) {
+        self.usernames.lock().await.remove(&addr);
+        self.resume_tokens.lock().await.retain(|_, a| *a != addr);
+    }
+
+    async fn display_name(&self, addr: SocketAddr) -> String {
+        self.usernames
+            .lock()
+            .await
+            .get(&addr)
+            .cloned()
+            .unwrap_or_else(|| addr.to_string())
+    }
+
+    /// Finds the connection using `name`, ignoring case.
+    async fn find_user(&self, name: &str) -> Option<(SocketAddr, String)> {
+        self.usernames
+            .lock()
+            .await
+            .iter()
+            .find(|(_, existing)| existing.eq_ignore_ascii_case(name))
+            .map(|(addr, existing)| (*addr, existing.clone()))
+    }
+
+    async fn control(&self, addr: SocketAddr, control: Control) {
+        let tx = self.clients.lock().await.get(&addr).cloned();
+        if let Some(tx) = tx {
+            let _ = tx.send(control).await;
+        }
+    }
+
+    /// Kicks a client, telling the rest of its room why it left. `reason`
+    /// completes "You were ...", e.g. "kicked by the host".
+    async fn kick(&self, addr: SocketAddr, reason: String) {
+        let name = self.display_name(addr).await;
+        let room = {
+            let rooms = self.rooms.lock().await;
+            rooms
+                .names()
+                .into_iter()
+                .find(|room| rooms.members(room).contains(&addr))
+        };
+        if let Some(room) = room {
+            self.rooms.lock().await.broadcast(
+                &room,
+                ServerFrame::Info {
+                    message: format!("{} was {}", name, reason),
+                },
+            );
+        }
+        self.control(
+            addr,
+            Control::Kick {
+                reason: format!("You were {}", reason),
+            },
+        )
+        .await;
+    }
+
+    /// Account `addr` logged in to, if any.
+    async fn account_of(&self, addr: SocketAddr) -> Option<String> {
+        self.logins.lock().await.get(&addr).cloned()
+    }
+
+    /// Role of the account `name`; names that aren't accounts are guests.
+    async fn account_role(&self, name: &str) -> Role {
+        self.accounts
+            .lock()
+            .await
+            .get(name)
+            .map_or(Role::Guest, |account| account.role)
+    }
+
+    /// Role of the client at `addr`, a guest unless it logged in.
+    async fn role_of(&self, addr: SocketAddr) -> Role {
+        match self.account_of(addr).await {
+            Some(account) => self.account_role(&account).await,
+            None => Role::Guest,
+        }
+    }
+
+    /// Checks that the role of the client at `addr` holds `permission`.
+    async fn permitted(&self, addr: SocketAddr, permission: Permission) -> Result<Role, String> {
+        let role = self.role_of(addr).await;
+        self.permissions.check(role, permission).map(|()| role)
+    }
+
+    /// The actor behind a moderation request from `addr`, if its role allows it.
+    async fn actor(&self, addr: SocketAddr, permission: Permission) -> Result<Actor, String> {
+        let role = self.permitted(addr, permission).await?;
+        Ok(Actor::Client {
+            name: self.display_name(addr).await,
+            role,
+        })
+    }
+
+    /// Refuses unless `by` outranks the client at `addr`, known as `name`.
+    async fn check_rank(&self, by: &Actor, addr: SocketAddr, name: &str) -> Result<(), String> {
+        let role = self.role_of(addr).await;
+        if by.may_act_on(role) {
+            Ok(())
+        } else {
+            Err(outranked(name, role))
+        }
+    }
+
+    /// Disconnects `user`, telling them and their room why.
+    async fn kick_user(
+        &self,
+        user: &str,
+        reason: Option<String>,
+        by: &Actor,
+    ) -> Result<String, String> {
+        let (addr, name) = self
+            .find_user(user)
+            .await
+            .ok_or_else(|| format!("No user named '{}' is connected", user))?;
+        self.check_rank(by, addr, &name).await?;
+        let reason = match reason {
+            Some(reason) => format!("kicked by {}: {}", by.name(), reason),
+            None => format!("kicked by {}", by.name()),
+        };
+        self.kick(addr, reason).await;
+        Ok(format!("Kicked {} ({})", name, addr))
+    }
+
+    /// Records a ban and disconnects everyone it covers. Refused if it
+    /// covers anyone `by` doesn't outrank.
+    async fn ban(
+        &self,
+        target: BanTarget,
+        duration: Option<Duration>,
+        by: &Actor,
+    ) -> Result<String, String> {
+        let affected: Vec<SocketAddr> = match &target {
+            BanTarget::Ip(ip) => self
+                .clients
+                .lock()
+                .await
+                .keys()
+                .filter(|addr| addr.ip() == *ip)
+                .copied()
+                .collect(),
+            BanTarget::Name(name) => self
+                .find_user(name)
+                .await
+                .map(|(addr, _)| addr)
+                .into_iter()
+                .collect(),
+        };
+        for addr in &affected {
+            let name = self.display_name(*addr).await;
+            self.check_rank(by, *addr, &name).await?;
+        }
+        // The name may belong to an account whose owner is offline
+        if let BanTarget::Name(name) = &target {
+            let role = self.account_role(name).await;
+            if !by.may_act_on(role) {
+                return Err(outranked(name, role));
+            }
+        }
+
+        let ban = {
+            let mut bans = self.bans.lock().await;
+            // Replacing a ban, say with a shorter one, is as good as lifting it
+            if let Some(existing) = bans.get(&target)
+                && !by.may_lift(existing)
+            {
+                return Err(protected_ban(&target, existing));
+            }
+            bans.ban(target.clone(), duration, by.role())
+                .map_err(|e| format!("{:#}", e))?
+        };
+        for addr in &affected {
+            self.kick(*addr, format!("banned by {}", by.name())).await;
+        }
+        Ok(format!(
+            "{}; disconnected {} client(s)",
+            ban.describe(),
+            affected.len()
+        ))
+    }
+
+    /// Lifts a ban, unless `by` doesn't outrank whoever placed it.
+    async fn unban(&self, target: &BanTarget, by: &Actor) -> Result<String, String> {
+        let mut bans = self.bans.lock().await;
+        let Some(ban) = bans.get(target) else {
+            return Err(format!("{} is not banned", target));
+        };
+        if !by.may_lift(ban) {
+            return Err(protected_ban(target, ban));
+        }
+        bans.unban(target).map_err(|e| format!("{:#}", e))?;
+        Ok(format!("Lifted the ban on {}", target))
+    }
+
+    /// Mutes a connected client by name and address, so that reconnecting
+    /// under another name doesn't lift it.
+    async fn mute(
+        &self,
+        user: &str,
+        duration: Option<Duration>,
+        by: &Actor,
+    ) -> Result<String, String> {
+        let (addr, name) = self
+            .find_user(user)
+            .await
+            .ok_or_else(|| format!("No user named '{}' is connected", user))?;
+        self.check_rank(by, addr, &name).await?;
+        let until = match duration {
+            Some(d) => Some(
+                Instant::now()
+                    .checked_add(d)
+                    .ok_or_else(|| "Mute duration is too long".to_string())?,
+            ),
+            None => None,
+        };
+        self.mutes.lock().await.mute(&name, addr.ip(), until);
+        let by = by.name().to_string();
+        self.control(addr, Control::Mute { until, by }).await;
+        Ok(match duration {
+            Some(d) => format!("Muted {} for {}s", name, d.as_secs()),
+            None => format!("Muted {} until /unmute", name),
+        })
+    }
+
+    /// True while a `/mute` on the client's address or current name lasts.
+    async fn is_muted(&self, addr: SocketAddr) -> bool {
+        let name = self.usernames.lock().await.get(&addr).cloned();
+        self.mutes
+            .lock()
+            .await
+            .is_muted(addr.ip(), name.as_deref(), Instant::now())
+    }
+
+    async fn unmute(&self, user: &str, by: &Actor) -> Result<String, String> {
+        let (addr, name) = self
+            .find_user(user)
+            .await
+            .ok_or_else(|| format!("No user named '{}' is connected", user))?;
+        self.check_rank(by, addr, &name).await?;
+        if !self.mutes.lock().await.unmute(&name, addr.ip()) {
+            return Err(format!("{} is not muted", name));
+        }
+        let by = by.name().to_string();
+        self.control(addr, Control::Unmute { by }).await;
+        Ok(format!("Unmuted {}", name))
+    }
+
+    /// Changes the role of an account, telling whoever is logged in to it.
+    /// Clients can only change accounts ranked below them, to a role below
+    /// their own.
+    async fn set_role(&self, user: &str, role: Role, by: &Actor) -> Result<String, String> {
+        let account = self
+            .accounts
+            .lock()
+            .await
+            .get(user)
+            .map(|a| (a.username.clone(), a.role));
+        let Some((username, current)) = account else {
+            return Err(format!(
+                "'{}' is not a registered account; only accounts have roles",
+                user
+            ));
+        };
+        if !by.may_act_on(current) {
+            return Err(outranked(&username, current));
+        }
+        if !by.may_act_on(role) {
+            return Err("You can only hand out roles below your own".to_string());
+        }
+        self.accounts
+            .lock()
+            .await
+            .set_role(&username, role)
+            .map_err(|e| format!("{:#}", e))?;
+
+        let online: Vec<SocketAddr> = self
+            .logins
+            .lock()
+            .await
+            .iter()
+            .filter(|(_, account)| account.eq_ignore_ascii_case(&username))
+            .map(|(addr, _)| *addr)
+            .collect();
+        for addr in online {
+            let message = format!("Your role is now {} (set by {})", role, by.name());
+            self.control(addr, Control::Notify { message }).await;
+        }
+        Ok(format!("{} is now {} {}", username, article(role), role))
+    }
+
+    /// Accounts with a role other than the default.
+    async fn list_roles(&self) -> String {
+        let accounts = self.accounts.lock().await;
+        let roles: Vec<String> = accounts
+            .iter()
+            .filter(|account| account.role != Role::default())
+            .map(|account| format!("{}: {}", account.username, account.role))
+            .collect();
+        if roles.is_empty() {
+            return "Every account is a member; hand out roles with /role <user> <role>"
+                .to_string();
+        }
+        roles.join("\n")
+    }
+
+    /// Sets or clears the topic of `room` and tells its members.
+    async fn set_topic(
+        &self,
+        room: &str,
+        topic: Option<String>,
+        by: String,
+    ) -> Result<String, String> {
+        let topic = topic
+            .map(|topic| topic.trim().to_string())
+            .filter(|topic| !topic.is_empty());
+        if topic
+            .as_ref()
+            .is_some_and(|topic| topic.chars().count() > MAX_TOPIC_CHARS)
+        {
+            return Err(format!(
+                "Topics can be at most {} characters",
+                MAX_TOPIC_CHARS
+            ));
+        }
+        if !self.rooms.lock().await.set_topic(room, topic.clone()) {
+            return Err(format!("There is no room #{}", room));
+        }
+        let text = match &topic {
+            Some(_) => format!("Set the topic of #{}", room),
+            None => format!("Cleared the topic of #{}", room),
+        };
+        self.announce(
+            ServerFrame::Topic {
+                room: room.to_string(),
+                topic,
+                by,
+                timestamp: Utc::now(),
+            },
+            room,
+        )
+        .await;
+        Ok(text)
+    }
+
+    async fn list_bans(&self) -> String {
+        let bans = self.bans.lock().await;
+        let active = bans.active();
+        if active.is_empty() {
+            return "No active bans".to_string();
+        }
+        active
+            .iter()
+            .map(|ban| ban.describe())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Announces a message from the host in every room that isn't end-to-end
+    /// encrypted; those only relay ciphertext, so the host is told instead.
+    async fn host_message(&self, content: String) {
+        if content.is_empty() {
+            return;
+        }
+        let msg = ChatPayload {
+            id: None,
+            content,
+            sender: "Host".to_string(),
+            username: None,
+            timestamp: Utc::now(),
+            room: None,
+            sealed: None,
+        };
+
+        // Each room gets its own copy so it lands in that room's history
+        let (room_names, encrypted): (Vec<String>, Vec<String>) = {
+            let rooms = self.rooms.lock().await;
+            rooms
+                .names()
+                .into_iter()
+                .partition(|room| !rooms.is_encrypted(room))
+        };
+        if !encrypted.is_empty() {
+            let _ = self
+                .output_tx
+                .send(OutputEvent::TextLine(TextLine {
+                    text: format!(
+                        "Not sent to end-to-end encrypted rooms: #{}",
+                        encrypted.join(", #")
+                    ),
+                    color: Some(YELLOW_COLOR.clone()),
+                }))
+                .await;
+        }
+        for room in room_names {
+            let copy = self
+                .record(ChatPayload {
+                    room: Some(room.clone()),
+                    ..msg.clone()
+                })
+                .await;
+            self.rooms
+                .lock()
+                .await
+                .broadcast(&room, ServerFrame::Chat(copy));
+        }
+
+        let _ = self
+            .output_tx
+            .send(OutputEvent::ChatMessage(
+                ChatPayload {
+                    username: Some("Host".to_string()),
+                    ..msg.clone()
+                }
+                .into_chat_message(true),
+            ))
+            .await;
+
+        // Logged once rather than per room copy
+        self.log(&ServerFrame::Chat(msg)).await;
+    }
+
+    /// Writes the event to the chat log if logging is enabled.
+    async fn log(&self, frame: &ServerFrame) {
+        if let Some(chat_log) = &self.chat_log {
+            chat_log.log(frame).await;
+        }
+    }
+
+    /// Broadcasts a join or leave notice to `room` and mirrors it on the host console.
+    async fn announce(&self, frame: ServerFrame, room: &str) {
+        if let Some(payload) = frame.notice_message() {
+            let _ = self
+                .output_tx
+                .send(OutputEvent::ChatMessage(payload.into_chat_message(false)))
+                .await;
+        }
+        self.log(&frame).await;
+        self.rooms.lock().await.broadcast(room, frame);
+    }
+}
+
+/// Per-connection view of which room the client is in.
+struct Session {
+    addr: SocketAddr,
+    room: String,
+    outbox: Outbox,
+    capabilities: Vec<Capability>,
+    /// Identity key proven in the handshake, base64.
+    identity: Option<String>,
+    limiter: RateLimiter,
+    /// For requests that make the server look something up or tell a room.
+    request_limiter: RateLimiter,
+    control_rx: mpsc::Receiver<Control>,
+    heartbeat: Heartbeat,
+    oversized_frames: u32,
+}
+
+impl Session {
+    /// Tells the client's room it changed its name from `old` to `new`.
+    async fn renamed(&self, state: &ServerState, old: String, new: String) {
+        state
+            .announce(
+                ServerFrame::Renamed {
+                    old,
+                    new,
+                    room: self.room.clone(),
+                    timestamp: Utc::now(),
+                },
+                &self.room,
+            )
+            .await;
+    }
+
+    /// Moves the client into `room`, telling both the old and new room.
+    async fn move_to(&mut self, state: &ServerState, room: String) -> Result<()> {
+        let name = state.display_name(self.addr).await;
+
+        state.rooms.lock().await.leave(&self.room, self.addr);
+        state
+            .announce(
+                ServerFrame::Leave {
+                    username: name.clone(),
+                    room: self.room.clone(),
+                    timestamp: Utc::now(),
+                },
+                &self.room,
+            )
+            .await;
+
+        state
+            .announce(
+                ServerFrame::Join {
+                    username: name,
+                    room: room.clone(),
+                    timestamp: Utc::now(),
+                },
+                &room,
+            )
+            .await;
+        state
+            .rooms
+            .lock()
+            .await
+            .join(&room, self.addr, self.outbox.clone());
+        self.room = room;
+
+        self.send_joined(state).await?;
+        self.send_backfill(state).await
+    }
+
+    /// Applies the per-connection limit, then the shared per-IP limit.
+    async fn check_rate(&mut self, state: &ServerState) -> RateDecision {
+        let now = Instant::now();
+        match self.limiter.check(now) {
+            RateDecision::Allow => state.ip_limiter.lock().await.check(&self.addr.ip(), now),
+            decision => decision,
+        }
+    }
+
+    /// Sends the room's recent messages if the client understands history.
+    async fn send_backfill(&self, state: &ServerState) -> Result<()> {
+        if !self.capabilities.contains(&Capability::History) {
+            return Ok(());
+        }
+        self.send_history(state, BACKFILL_LIMIT, None).await
+    }
+
+    async fn send_history(
+        &self,
+        state: &ServerState,
+        limit: usize,
+        before: Option<u64>,
+    ) -> Result<()> {
+        let frame = match state.history_page(&self.room, limit, before).await {
+            Ok((messages, has_more)) => ServerFrame::History {
+                room: self.room.clone(),
+                messages,
+                has_more,
+                resumed: false,
+            },
+            Err(e) => ServerFrame::Error {
+                message: format!("History unavailable: {}", e),
+            },
+        };
+        self.outbox.send(frame).await
+    }
+
+    /// Replays what a resuming client missed, or the usual backfill if it
+    /// had not seen any message yet.
+    async fn send_missed(&self, state: &ServerState, last_id: Option<u64>) -> Result<()> {
+        let Some(last_id) = last_id else {
+            return self.send_backfill(state).await;
+        };
+        let frame = match state.missed_since(&self.room, last_id).await {
+            Ok((messages, has_more)) => ServerFrame::History {
+                room: self.room.clone(),
+                messages,
+                has_more,
+                resumed: true,
+            },
+            Err(e) => ServerFrame::Error {
+                message: format!("History unavailable: {}", e),
+            },
+        };
+        self.outbox.send(frame).await
+    }
+
+    async fn send_joined(&self, state: &ServerState) -> Result<()> {
+        let (members, encrypted, topic) = {
+            let rooms = state.rooms.lock().await;
+            let usernames = state.usernames.lock().await;
+            (
+                rooms.member_names(&self.room, &usernames),
+                rooms.is_encrypted(&self.room),
+                rooms.topic(&self.room),
+            )
+        };
+        self.outbox
+            .send(ServerFrame::Joined {
+                room: self.room.clone(),
+                members,
+                encrypted,
+                topic,
+            })
+            .await
+    }
+
+    /// Answers a moderation request, noting on the host console what the
+    /// client did.
+    async fn answer(&self, state: &ServerState, result: Result<String, String>) -> Result<()> {
+        match result {
+            Ok(message) => {
+                let name = state.display_name(self.addr).await;
+                let _ = state
+                    .output_tx
+                    .send(OutputEvent::TextLine(TextLine {
+                        text: format!("{}: {}", name, message),
+                        color: Some(GRAY_COLOR.clone()),
+                    }))
+                    .await;
+                self.outbox.send(ServerFrame::Info { message }).await
+            }
+            Err(message) => self.outbox.send(ServerFrame::Error { message }).await,
+        }
+    }
+}
+
+/// Sends a reject frame and fails the handshake with the same reason.
+async fn reject<T>(writer: &mut WriteHalf, reason: String) -> Result<T> {
+    write_frame(
+        writer,
+        &ServerFrame::Reject {
+            reason: reason.clone(),
+            access_denied: false,
+        },
+    )
+    .await?;
+    bail!(reason)
+}
+
+/// Like `reject`, for a client without the password or a valid invite,
+/// after a pause that makes guessing slow.
+async fn deny_access<T>(writer: &mut WriteHalf, reason: String) -> Result<T> {
+    tokio::time::sleep(ACCESS_DENIED_DELAY).await;
+    write_frame(
+        writer,
+        &ServerFrame::Reject {
+            reason: reason.clone(),
+            access_denied: true,
+        },
+    )
+    .await?;
+    bail!("Access denied: {}", reason)
+}
+
+/// What the client and server agreed on in the handshake.
+struct Handshake {
+    username: Option<String>,
+    /// How the nickname relates to the client's identity key.
+    claim: Option<Claim>,
+    /// The identity key the client proved, base64.
+    identity: Option<String>,
+    /// The account the client logged in to.
+    account: Option<String>,
+    capabilities: Vec<Capability>,
+    /// Set when the client reconnects and both sides support resuming.
+    resume: Option<Resume>,
+    /// Where the client starts: the default room, or the one it resumes in.
+    room: String,
+}
+
+/// Waits for the client's hello and answers with a welcome, or rejects the
+/// client if the two sides can't agree on a protocol version, it lacks the
+/// chatroom password or an invite, or the requested nickname can't be used.
+async fn perform_handshake(
+    frames: &mut FrameReader<ReadHalf>,
+    writer: &mut WriteHalf,
+    state: &ServerState,
+    addr: SocketAddr,
+    chatroom: &str,
+) -> Result<Handshake> {
+    let line = match timeout(HANDSHAKE_TIMEOUT, frames.next())
+        .await
+        .context("Client did not send a hello in time")?
+    {
+        Some(Ok(Incoming::Line(line))) => line,
+        Some(Ok(Incoming::TooLong)) => {
+            let reason = format!("Hello is over the {} byte limit", state.max_frame_bytes);
+            return reject(writer, reason).await;
+        }
+        Some(Err(e)) => return Err(e.into()),
+        None => bail!("Client disconnected during handshake"),
+    };
+
+    let (version, capabilities, username, resume, identity, password, account_password) =
+        match ClientFrame::from_line(&line) {
+            Ok(ClientFrame::Hello {
+                version,
+                capabilities,
+                username,
+                resume,
+                identity,
+                password,
+                account_password,
+            }) => (
+                version,
+                capabilities,
+                username,
+                resume,
+                identity,
+                password,
+                account_password,
+            ),
+            Ok(_) | Err(_) => {
+                return reject(
+                    writer,
+                    "Expected a hello frame; please upgrade your client".to_string(),
+                )
+                .await;
+            }
+        };
+
+    let version = match negotiate_version(version) {
+        Ok(version) => version,
+        Err(reason) => return reject(writer, reason).await,
+    };
+
+    let banned = {
+        let bans = state.bans.lock().await;
+        let name = Some(username.trim()).filter(|n| !n.is_empty());
+        bans.find(addr.ip(), name).map(|ban| ban.describe())
+    };
+    if let Some(ban) = banned {
+        return reject(writer, ban).await;
+    }
+
+    let capabilities = negotiate_capabilities(&capabilities);
+
+    // Proven before anything else happens in the client's name
+    let identity = match identity.filter(|_| capabilities.contains(&Capability::Identity)) {
+        Some(public_key) => Some(prove_identity(frames, writer, public_key).await?),
+        None => None,
+    };
+
+    // Checked before the client can take over a session, a nickname or a
+    // room. Invites remember who redeemed them by something the client
+    // can't just claim: its proven identity key, else its IP address. So a
+    // use is per key or per IP, not per person, see `Invite::max_uses`
+    let redeemer = match &identity {
+        Some(key) => format!("key:{}", key),
+        None => format!("ip:{}", addr.ip()),
+    };
+    let invite = match state.admit(password.as_deref(), &redeemer).await {
+        Ok(invite) => invite,
+        Err(reason) => return deny_access(writer, reason).await,
+    };
+
+    let account = match account_password {
+        Some(password) if !username.trim().is_empty() => {
+            match state.authenticate(addr, &username, &password).await {
+                Ok(account) => Some(account),
+                Err(reason) => return reject(writer, format!("Login failed: {}", reason)).await,
+            }
+        }
+        _ => None,
+    };
+
+    let resume = resume.filter(|_| capabilities.contains(&Capability::Resume));
+    if let Some(Resume {
+        token: Some(token),
+        room,
+        ..
+    }) = &resume
+    {
+        state.take_over(token, addr, room).await;
+    }
+
+    // An empty username means the client stays anonymous
+    let (username, claim) = if username.trim().is_empty() {
+        (None, None)
+    } else {
+        match state
+            .claim_name(addr, &username, identity.as_deref(), account.as_deref())
+            .await
+        {
+            Ok((name, claim)) => (Some(name), Some(claim)),
+            Err(reason) => return reject(writer, format!("Username rejected: {}", reason)).await,
+        }
+    };
+
+    // A resuming client goes back to its room if that is still a valid name,
+    // but only reopens it if its role may create rooms, just like /join
+    let room = match resume.as_ref().map(|r| normalize_room_name(&r.room)) {
+        Some(Ok(room)) if room != chatroom => {
+            let exists = state.rooms.lock().await.contains(&room);
+            let role = match &account {
+                Some(account) => state.account_role(account).await,
+                None => Role::Guest,
+            };
+            if exists
+                || state
+                    .permissions
+                    .check(role, Permission::CreateRooms)
+                    .is_ok()
+            {
+                room
+            } else {
+                chatroom.to_string()
+            }
+        }
+        _ => chatroom.to_string(),
+    };
+
+    let resume_token = format!("{:032x}", rand::random::<u128>());
+    state
+        .resume_tokens
+        .lock()
+        .await
+        .insert(resume_token.clone(), addr);
+
+    let welcome = ServerFrame::Welcome {
+        version,
+        capabilities: capabilities.clone(),
+        room: room.clone(),
+        username: username.clone(),
+        resume_token: Some(resume_token),
+        max_frame_bytes: Some(state.max_frame_bytes),
+    };
+    if let Err(e) = write_frame(writer, &welcome).await {
+        state.release(addr).await;
+        return Err(e);
+    }
+
+    // Only a client that made it in takes one of the invite's uses
+    if let Some(token) = invite
+        && !state.redeem_invite(addr, &token, &redeemer).await
+    {
+        state.release(addr).await;
+        let reason = "Invite token is invalid, expired or used up".to_string();
+        write_frame(
+            writer,
+            &ServerFrame::Kicked {
+                reason: reason.clone(),
+            },
+        )
+        .await?;
+        bail!("Access denied: {}", reason);
+    }
+
+    Ok(Handshake {
+        username,
+        claim,
+        identity,
+        account,
+        capabilities,
+        resume,
+        room,
+    })
+}
+
+/// Has a client that offered an identity key sign a fresh challenge,
+/// returning the key once the signature checks out.
+async fn prove_identity(
+    frames: &mut FrameReader<ReadHalf>,
+    writer: &mut WriteHalf,
+    public_key: String,
+) -> Result<String> {
+    let nonce = identity::new_challenge();
+    write_frame(
+        writer,
+        &ServerFrame::Challenge {
+            nonce: nonce.clone(),
+        },
+    )
+    .await?;
+    let line = match timeout(HANDSHAKE_TIMEOUT, frames.next())
+        .await
+        .context("Client did not answer the identity challenge in time")?
+    {
+        Some(Ok(Incoming::Line(line))) => line,
+        Some(Ok(Incoming::TooLong)) => {
+            return reject(writer, "Identity proof is over the size limit".to_string()).await;
+        }
+        Some(Err(e)) => return Err(e.into()),
+        None => bail!("Client disconnected during handshake"),
+    };
+    let signature = match ClientFrame::from_line(&line) {
+        Ok(ClientFrame::Prove { signature }) => signature,
+        Ok(_) | Err(_) => {
+            return reject(writer, "Expected an identity proof".to_string()).await;
+        }
+    };
+    match identity::verify(&public_key, &nonce, &signature) {
+        Ok(()) => Ok(public_key),
+        Err(e) => reject(writer, format!("Identity proof failed: {}", e)).await,
+    }
+}
+
+async fn handle_connection(
+    socket: TcpStream,
+    addr: SocketAddr,
+    state: Arc<ServerState>,
+) -> Result<()> {
+    let (reader, mut writer) = match &state.tls {
+        Some(acceptor) => {
+            let stream = timeout(HANDSHAKE_TIMEOUT, acceptor.accept(socket))
+                .await
+                .context("Client did not complete the TLS handshake in time")?
+                .context("TLS handshake failed")?;
+            transport::split(stream)
+        }
+        None => transport::split(socket),
+    };
+    let mut frames = frame_reader(reader, state.max_frame_bytes);
+
+    let default_room = state.rooms.lock().await.default_room().to_string();
+    let Handshake {
+        username,
+        claim,
+        identity,
+        account,
+        capabilities,
+        resume,
+        room,
+    } = perform_handshake(&mut frames, &mut writer, &state, addr, &default_room).await?;
+
+    let message = match (&username, claim) {
+        (Some(name), Some(Claim::Verified)) => {
+            format!("Username set to '{}', verified by your identity key", name)
+        }
+        (Some(name), Some(Claim::Unbound)) => format!(
+            "Username set to '{}' and registered to your identity key",
+            name
+        ),
+        (Some(name), Some(Claim::Account)) => {
+            format!("Username set to '{}', logged in to your account", name)
+        }
+        (Some(name), _) => format!("Username set to '{}'", name),
+        (None, _) => format!("No username set, you will appear as {}", addr),
+    };
+    if let Err(e) = write_frame(&mut writer, &ServerFrame::Info { message }).await {
+        state.release(addr).await;
+        return Err(e);
+    }
+
+    // Announce before subscribing so the client doesn't see its own join
+    if let Some(name) = &username {
+        state
+            .announce(
+                ServerFrame::Join {
+                    username: name.clone(),
+                    room: room.clone(),
+                    timestamp: Utc::now(),
+                },
+                &room,
+            )
+            .await;
+
+        // Send a notification about the new user
+        let _ = state
+            .output_tx
+            .send(OutputEvent::SystemEvent(SystemEvent::PromptInput {
+                prompt: format!("User {} has joined the chat", name),
+            }))
+            .await;
+    }
+
+    let (control_tx, control_rx) = mpsc::channel(8);
+    state.clients.lock().await.insert(addr, control_tx);
+    if let Some(account) = account {
+        state.logins.lock().await.insert(addr, account);
+    }
+
+    // Clients that can't answer pings are never reaped for being quiet
+    let heartbeat = if capabilities.contains(&Capability::Heartbeat) {
+        state.heartbeat.clone()
+    } else {
+        HeartbeatConfig::disabled()
+    };
+
+    // From here on every frame goes through the outbox, so a client that
+    // reads slowly can't stall the rooms it is in
+    let (outbox, outbox_writer) = Outbox::new(&state.outbox);
+    let mut writer_task = tokio::spawn(outbox_writer.run(writer));
+    state.rooms.lock().await.join(&room, addr, outbox.clone());
+    let mut session = Session {
+        addr,
+        room,
+        outbox,
+        capabilities,
+        identity,
+        limiter: RateLimiter::new(state.rate_limits.clone()),
+        request_limiter: RateLimiter::new(state.request_rate_limits.clone()),
+        control_rx,
+        heartbeat: Heartbeat::new(heartbeat),
+        oversized_frames: 0,
+    };
+
+    let mut result = session.send_joined(&state).await;
+    if result.is_ok() {
+        result = match resume {
+            Some(resume) => session.send_missed(&state, resume.last_id).await,
+            None => session.send_backfill(&state).await,
+        };
+    }
+    if result.is_ok() {
+        result = client_loop(&mut frames, &mut session, &state).await;
+    }
+
+    // Client disconnected or errored, clean up either way
+    state.clients.lock().await.remove(&addr);
+    state.logins.lock().await.remove(&addr);
+    state.resume_tokens.lock().await.retain(|_, a| *a != addr);
+    state.rooms.lock().await.leave(&session.room, addr);
+    let name = state.usernames.lock().await.remove(&addr);
+    if let Some(name) = name {
+        state
+            .announce(
+                ServerFrame::Leave {
+                    username: name,
+                    room: session.room.clone(),
+                    timestamp: Utc::now(),
+                },
+                &session.room,
+            )
+            .await;
+
+        // Send a ConnectionClosed event
+        let _ = state
+            .output_tx
+            .send(OutputEvent::SystemEvent(SystemEvent::ConnectionClosed))
+            .await;
+    }
+
+    // Dropping the last outbox lets the writer flush what's queued, such as
+    // a Kicked frame, then close the socket
+    drop(session);
+    if timeout(OUTBOX_FLUSH_TIMEOUT, &mut writer_task)
+        .await
+        .is_err()
+    {
+        writer_task.abort();
+    }
+
+    result
+}
+
+async fn client_loop(
+    frames: &mut FrameReader<ReadHalf>,
+    session: &mut Session,
+    state: &ServerState,
+) -> Result<()> {
+    loop {
+        tokio::select! {
+            incoming = frames.next() => {
+                // A TLS client that closes without a close_notify has still just left
+                let incoming = incoming.filter(|incoming| {
+                    !matches!(incoming, Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof)
+                });
+                let Some(incoming) = incoming else {
+                    return Ok(());
+                };
+                session.heartbeat.seen();
+
+                let line = match incoming? {
+                    Incoming::Line(line) => line,
+                    Incoming::TooLong => {
+                        session.oversized_frames += 1;
+                        if session.oversized_frames >= state.oversized_frame_limit {
+                            session.outbox.send(ServerFrame::Kicked {
+                                reason: "Too many oversized messages".to_string(),
+                            }).await?;
+                            bail!(
+                                "sent {} oversized frames, dropping connection",
+                                session.oversized_frames
+                            );
+                        }
+                        session.outbox.send(ServerFrame::Error {
+                            message: format!(
+                                "Message discarded: over the {} byte limit",
+                                state.max_frame_bytes
+                            ),
+                        }).await?;
+                        continue;
+                    }
+                };
+                let trimmed = line.trim();
+                if trimmed.is_empty() {
+                    continue;
+                }
+
+                let frame = ClientFrame::from_line(trimmed);
+                // These read history from disk or announce to a room, so
+                // flooding them costs everyone
+                if let Ok(ClientFrame::History { .. } | ClientFrame::Nick { .. } | ClientFrame::Join { .. } | ClientFrame::Whois { .. }) = &frame {
+                    match session.request_limiter.check(Instant::now()) {
+                        RateDecision::Allow => {}
+                        RateDecision::Limited { retry_after, .. } => {
+                            session.outbox.send(ServerFrame::RateLimited {
+                                seconds: retry_after.as_secs_f32(),
+                                reason: "You are sending requests too fast".to_string(),
+                            }).await?;
+                            continue;
+                        }
+                        RateDecision::Disconnect => {
+                            session.outbox.send(ServerFrame::Kicked {
+                                reason: "Disconnected for repeatedly flooding the server with requests".to_string(),
+                            }).await?;
+                            let _ = state.output_tx.send(OutputEvent::TextLine(TextLine {
+                                text: format!("Disconnected {} for flooding requests", session.addr),
+                                color: Some(RED_COLOR.clone()),
+                            })).await;
+                            return Ok(());
+                        }
+                    }
+                }
+
+                match frame {
+                    Ok(ClientFrame::Hello { .. } | ClientFrame::Prove { .. }) => {
+                        session.outbox.send(ServerFrame::Error {
+                            message: "Handshake already completed".to_string(),
+                        }).await?;
+                    }
+                    Ok(ClientFrame::Chat { content, sealed }) => {
+                        // Encrypted rooms only relay ciphertext, which the
+                        // server can't read; it stores a placeholder instead
+                        let encrypted = state.rooms.lock().await.is_encrypted(&session.room);
+                        let content = match &sealed {
+                            // Nobody else in the room would be able to read it
+                            Some(_) if !encrypted => {
+                                session.outbox.send(ServerFrame::Error {
+                                    message: format!(
+                                        "#{} is not end-to-end encrypted; turn it on with /encrypt or drop your key with /encrypt off",
+                                        session.room
+                                    ),
+                                }).await?;
+                                continue;
+                            }
+                            Some(_) => e2e::PLACEHOLDER,
+                            None if encrypted => {
+                                session.outbox.send(ServerFrame::Error {
+                                    message: format!(
+                                        "#{} is end-to-end encrypted; plain-text messages are not relayed",
+                                        session.room
+                                    ),
+                                }).await?;
+                                continue;
+                            }
+                            None => content.trim(),
+                        };
+                        if content.is_empty() {
+                            continue;
+                        }
+
+                        if let Err(message) = state.permitted(session.addr, Permission::Post).await {
+                            session.outbox.send(ServerFrame::Error { message }).await?;
+                            continue;
+                        }
+
+                        if state.is_muted(session.addr).await {
+                            session.outbox.send(ServerFrame::Error {
+                                message: "You have been muted".to_string(),
+                            }).await?;
+                            continue;
+                        }
+
+                        match session.check_rate(state).await {
+                            RateDecision::Allow => {}
+                            RateDecision::Limited { retry_after, muted } => {
+                                let reason = if muted {
+                                    "You have been muted for repeatedly flooding the chat"
+                                } else {
+                                    "You are sending messages too fast"
+                                };
+                                session.outbox.send(ServerFrame::RateLimited {
+                                    seconds: retry_after.as_secs_f32(),
+                                    reason: reason.to_string(),
+                                }).await?;
+                                continue;
+                            }
+                            RateDecision::Disconnect => {
+                                session.outbox.send(ServerFrame::Kicked {
+                                    reason: "Disconnected for repeatedly flooding the chat".to_string(),
+                                }).await?;
+                                let _ = state.output_tx.send(OutputEvent::TextLine(TextLine {
+                                    text: format!("Disconnected {} for flooding", session.addr),
+                                    color: Some(RED_COLOR.clone()),
+                                })).await;
+                                return Ok(());
+                            }
+                        }
+
+                        {
+                            let username = state.usernames.lock().await.get(&session.addr).cloned();
+                            let sender = username.clone().unwrap_or_else(|| session.addr.to_string());
+
+                            let msg = state.record(ChatPayload {
+                                id: None,
+                                content: content.to_string(),
+                                sender,
+                                username,
+                                timestamp: Utc::now(),
+                                room: Some(session.room.clone()),
+                                sealed,
+                            }).await;
+
+                            let frame = ServerFrame::Chat(msg.clone());
+                            state.log(&frame).await;
+                            state.rooms.lock().await.broadcast(&session.room, frame);
+
+                            let _ = state.output_tx.send(OutputEvent::ChatMessage(msg.into_chat_message(false))).await;
+                        }
+                    }
+                    Ok(ClientFrame::Join { room }) => {
+                        match normalize_room_name(&room) {
+                            Ok(room) if room == session.room => {
+                                session.outbox.send(ServerFrame::Error {
+                                    message: format!("You are already in #{}", room),
+                                }).await?;
+                            }
+                            Ok(room) => {
+                                // Joining a room that doesn't exist opens it
+                                let exists = state.rooms.lock().await.contains(&room);
+                                let refused = if exists {
+                                    None
+                                } else {
+                                    state.permitted(session.addr, Permission::CreateRooms).await.err()
+                                };
+                                match refused {
+                                    Some(message) => {
+                                        session.outbox.send(ServerFrame::Error { message }).await?;
+                                    }
+                                    None => session.move_to(state, room).await?,
+                                }
+                            }
+                            Err(message) => {
+                                session.outbox.send(ServerFrame::Error { message }).await?;
+                            }
+                        }
+                    }
+                    Ok(ClientFrame::Part) => {
+                        let default_room = state.rooms.lock().await.default_room().to_string();
+                        if session.room == default_room {
+                            session.outbox.send(ServerFrame::Error {
+                                message: "You can't leave the default room".to_string(),
+                            }).await?;
+                        } else {
+                            session.move_to(state, default_room).await?;
+                        }
+                    }
+                    Ok(ClientFrame::Nick { username }) => {
+                        let old = state.display_name(session.addr).await;
+                        let account = state.account_of(session.addr).await;
+                        let claimed = state.claim_name(session.addr, &username, session.identity.as_deref(), account.as_deref()).await;
+                        match claimed {
+                            Ok((new, _)) if new == old => {
+                                session.outbox.send(ServerFrame::Info {
+                                    message: format!("You are already known as {}", new),
+                                }).await?;
+                            }
+                            Ok((new, claim)) => {
+                                session.renamed(state, old, new.clone()).await;
+                                if claim == Claim::Unbound {
+                                    session.outbox.send(ServerFrame::Info {
+                                        message: format!("'{}' is now registered to your identity key", new),
+                                    }).await?;
+                                }
+                            }
+                            Err(message) => {
+                                session.outbox.send(ServerFrame::Error { message }).await?;
+                            }
+                        }
+                    }
+                    Ok(ClientFrame::Encrypt) => {
+                        // Plain-text clients would be locked out of the room everyone starts in
+                        let is_default = state.rooms.lock().await.default_room() == session.room;
+                        if is_default {
+                            session.outbox.send(ServerFrame::Error {
+                                message: "The default room can't be end-to-end encrypted; /join another room first".to_string(),
+                            }).await?;
+                            continue;
+                        }
+                        if let Err(message) = state.permitted(session.addr, Permission::EncryptRooms).await {
+                            session.outbox.send(ServerFrame::Error { message }).await?;
+                            continue;
+                        }
+                        let switched = state.rooms.lock().await.encrypt(&session.room);
+                        if switched {
+                            let by = state.display_name(session.addr).await;
+                            state.announce(ServerFrame::Encrypted {
+                                room: session.room.clone(),
+                                by,
+                                timestamp: Utc::now(),
+                            }, &session.room).await;
+                        } else {
+                            session.outbox.send(ServerFrame::Info {
+                                message: format!("#{} is already end-to-end encrypted", session.room),
+                            }).await?;
+                        }
+                    }
+                    Ok(ClientFrame::ListRooms) => {
+                        let rooms = {
+                            let rooms = state.rooms.lock().await;
+                            let usernames = state.usernames.lock().await;
+                            rooms.list(&usernames)
+                        };
+                        session.outbox.send(ServerFrame::RoomList { rooms }).await?;
+                    }
+                    Ok(ClientFrame::Whois { username }) => {
+                        let frame = state.whois(username.trim()).await;
+                        session.outbox.send(frame).await?;
+                    }
+                    Ok(ClientFrame::Register { password }) => {
+                        match state.register(session.addr, password).await {
+                            Ok(username) => {
+                                state.logins.lock().await.insert(session.addr, username.clone());
+                                session.outbox.send(ServerFrame::LoggedIn { username, registered: true }).await?;
+                            }
+                            Err(message) => {
+                                session.outbox.send(ServerFrame::Error { message }).await?;
+                            }
+                        }
+                    }
+                    Ok(ClientFrame::Login { username, password }) => {
+                        let account = match state.authenticate(session.addr, &username, &password).await {
+                            Ok(account) => account,
+                            Err(message) => {
+                                session.outbox.send(ServerFrame::Error { message: format!("Login failed: {}", message) }).await?;
+                                continue;
+                            }
+                        };
+                        // Only a client that got the account's name holds its role
+                        let old = state.display_name(session.addr).await;
+                        match state.claim_name(session.addr, &account, session.identity.as_deref(), Some(&account)).await {
+                            Ok((new, _)) => {
+                                state.logins.lock().await.insert(session.addr, account);
+                                if new != old {
+                                    session.renamed(state, old, new.clone()).await;
+                                }
+                                session.outbox.send(ServerFrame::LoggedIn { username: new, registered: false }).await?;
+                            }
+                            Err(message) => {
+                                session.outbox.send(ServerFrame::Error { message: format!("Login failed: {}", message) }).await?;
+                            }
+                        }
+                    }
+                    Ok(ClientFrame::History { limit, before }) => {
+                        let limit = limit.clamp(1, MAX_HISTORY_PAGE);
+                        session.send_history(state, limit, before).await?;
+                    }
+                    Ok(ClientFrame::Topic { topic }) => {
+                        let result = match state.permitted(session.addr, Permission::SetTopic).await {
+                            Ok(_) => {
+                                let by = state.display_name(session.addr).await;
+                                state.set_topic(&session.room, topic, by).await
+                            }
+                            Err(message) => Err(message),
+                        };
+                        // On success the room's notice is answer enough
+                        if let Err(message) = result {
+                            session.outbox.send(ServerFrame::Error { message }).await?;
+                        }
+                    }
+                    Ok(ClientFrame::Kick { username, reason }) => {
+                        let result = match state.actor(session.addr, Permission::Kick).await {
+                            Ok(by) => state.kick_user(&username, reason, &by).await,
+                            Err(message) => Err(message),
+                        };
+                        session.answer(state, result).await?;
+                    }
+                    Ok(ClientFrame::Ban { target, seconds }) => {
+                        let result = match (state.actor(session.addr, Permission::Ban).await, wire_duration(seconds)) {
+                            (Ok(by), Ok(duration)) => state.ban(BanTarget::parse(&target), duration, &by).await,
+                            (Err(message), _) | (_, Err(message)) => Err(message),
+                        };
+                        session.answer(state, result).await?;
+                    }
+                    Ok(ClientFrame::Unban { target }) => {
+                        let result = match state.actor(session.addr, Permission::Ban).await {
+                            Ok(by) => state.unban(&BanTarget::parse(&target), &by).await,
+                            Err(message) => Err(message),
+                        };
+                        session.answer(state, result).await?;
+                    }
+                    Ok(ClientFrame::Mute { username, seconds }) => {
+                        let result = match (state.actor(session.addr, Permission::Mute).await, wire_duration(seconds)) {
+                            (Ok(by), Ok(duration)) => state.mute(&username, duration, &by).await,
+                            (Err(message), _) | (_, Err(message)) => Err(message),
+                        };
+                        session.answer(state, result).await?;
+                    }
+                    Ok(ClientFrame::Unmute { username }) => {
+                        let result = match state.actor(session.addr, Permission::Mute).await {
+                            Ok(by) => state.unmute(&username, &by).await,
+                            Err(message) => Err(message),
+                        };
+                        session.answer(state, result).await?;
+                    }
+                    Ok(ClientFrame::SetRole { username, role }) => {
+                        let result = match state.actor(session.addr, Permission::ManageRoles).await {
+                            Ok(by) => state.set_role(&username, role, &by).await,
+                            Err(message) => Err(message),
+                        };
+                        session.answer(state, result).await?;
+                    }
+                    Ok(ClientFrame::Ping { nonce }) => {
+                        session.outbox.send(ServerFrame::Pong { nonce }).await?;
+                    }
+                    // Receiving it already counted as a sign of life
+                    Ok(ClientFrame::Pong { .. }) => {}
+                    Err(e) => {
+                        session.outbox.send(ServerFrame::Error {
+                            message: format!("Malformed frame: {}", e),
+                        }).await?;
+                    }
+                }
+
+            }
+
+            _ = session.heartbeat.tick() => {
+                // Half-open connections never return from read_line, so
+                // without this their user would stay online forever
+                if session.heartbeat.expired() {
+                    bail!(
+                        "no response for {}s, dropping connection",
+                        session.heartbeat.silent_for().as_secs()
+                    );
+                }
+                session.outbox.send(ServerFrame::Ping { nonce: 0 }).await?;
+            }
+
+            Some(control) = session.control_rx.recv() => {
+                match control {
+                    Control::Kick { reason } => {
+                        session.outbox.send(ServerFrame::Kicked { reason }).await?;
+                        return Ok(());
+                    }
+                    Control::Mute { until, by } => {
+                        let message = match until {
+                            Some(until) => format!(
+                                "You have been muted by {} for {}s",
+                                by,
+                                until.saturating_duration_since(Instant::now()).as_secs()
+                            ),
+                            None => format!("You have been muted by {}", by),
+                        };
+                        session.outbox.send(ServerFrame::Info { message }).await?;
+                    }
+                    Control::Unmute { by } => {
+                        session.outbox.send(ServerFrame::Info {
+                            message: format!("You have been unmuted by {}", by),
+                        }).await?;
+                    }
+                    Control::Notify { message } => {
+                        session.outbox.send(ServerFrame::Info { message }).await?;
+                    }
+                }
+            }
+
+            // Room traffic is written by the outbox's own task; this only
+            // notices when the client can't keep up with it
+            _ = session.outbox.overflowed() => {
+                bail!("{}", session.outbox.overflow_reason());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// A server keeping its files in a fresh temporary directory. Hold on
+    /// to the receiver, the state reports to it.
+    fn test_state() -> (ServerState, PathBuf, mpsc::Receiver<OutputEvent>) {
+        let dir = std::env::temp_dir().join(format!("hotline-server-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = crate::config::ServerConfig::default();
+        let (output_tx, output_rx) = mpsc::channel(1024);
+        let state = ServerState {
+            rooms: Mutex::new(RoomRegistry::new("lobby")),
+            usernames: Mutex::new(HashMap::new()),
+            history: Mutex::new(Box::new(MemoryHistory::new(MEMORY_HISTORY_CAPACITY))),
+            next_id: AtomicU64::new(1),
+            rate_limits: config.rate_limit.clone(),
+            request_rate_limits: config.request_rate_limit.clone(),
+            ip_limiter: Mutex::new(KeyedRateLimiter::new(config.ip_rate_limit.clone())),
+            auth_limiter: Mutex::new(KeyedRateLimiter::new(config.auth_rate_limit.clone())),
+            password_jobs: Semaphore::new(MAX_PASSWORD_JOBS),
+            bans: Mutex::new(BanList::empty(dir.join("bans.json"))),
+            mutes: Mutex::new(MuteList::default()),
+            bindings: Mutex::new(NameBindings::empty(dir.join("identities.json"))),
+            accounts: Mutex::new(AccountStore::empty(dir.join("accounts.json"))),
+            logins: Mutex::new(HashMap::new()),
+            permissions: config.permissions.clone(),
+            access: Mutex::new(Access::new(
+                None,
+                false,
+                InviteList::empty(dir.join("invites.json")),
+            )),
+            clients: Mutex::new(HashMap::new()),
+            resume_tokens: Mutex::new(HashMap::new()),
+            heartbeat: config.heartbeat.clone(),
+            max_frame_bytes: config.max_frame_bytes,
+            oversized_frame_limit: config.oversized_frame_limit,
+            outbox: config.outbox.clone(),
+            tls: None,
+            chat_log: None,
+            output_tx,
+        };
+        (state, dir, output_rx)
+    }
+
+    fn client_addr(last: u8) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, last], 5000))
+    }
+
+    #[tokio::test]
+    async fn banned_names_cannot_be_taken_after_connecting() {
+        let (state, dir, _output) = test_state();
+        state
+            .bans
+            .lock()
+            .await
+            .ban(BanTarget::parse("bob"), None, None)
+            .unwrap();
+        // Connected anonymously, then tries /nick or /login as the banned name
+        let addr = client_addr(1);
+        let refused = state.claim_name(addr, "Bob", None, None).await;
+        assert!(refused.is_err_and(|reason| reason.contains("banned")));
+        assert!(
+            state
+                .claim_name(addr, "bob", None, Some("bob"))
+                .await
+                .is_err()
+        );
+        assert!(state.usernames.lock().await.is_empty());
+
+        assert!(state.claim_name(addr, "alice", None, None).await.is_ok());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn mutes_outlast_reconnecting() {
+        let (state, dir, _output) = test_state();
+        let addr = client_addr(1);
+        state.claim_name(addr, "bob", None, None).await.unwrap();
+        state.mute("Bob", None, &Actor::Host).await.unwrap();
+        assert!(state.is_muted(addr).await);
+
+        // Reconnects from another port, then from elsewhere under the same name
+        state.release(addr).await;
+        let again = SocketAddr::from(([10, 0, 0, 1], 5001));
+        state.claim_name(again, "robert", None, None).await.unwrap();
+        assert!(state.is_muted(again).await);
+        state.release(again).await;
+        let elsewhere = client_addr(2);
+        state
+            .claim_name(elsewhere, "bob", None, None)
+            .await
+            .unwrap();
+        assert!(state.is_muted(elsewhere).await);
+
+        state.unmute("bob", &Actor::Host).await.unwrap();
+        assert!(!state.is_muted(elsewhere).await);
+        assert!(!state.is_muted(again).await);
+        assert!(state.unmute("bob", &Actor::Host).await.is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn password_attempts_are_limited_per_ip() {
+        let (state, dir, _output) = test_state();
+        let addr = client_addr(1);
+        for _ in 0..5 {
+            let refused = state.authenticate(addr, "alice", "guess").await;
+            assert!(refused.is_err_and(|reason| reason.contains("No account")));
+        }
+        let refused = state.authenticate(addr, "alice", "guess").await;
+        assert!(refused.is_err_and(|reason| reason.contains("Too many password attempts")));
+        // Registering hashes a password too
+        state.claim_name(addr, "bob", None, None).await.unwrap();
+        let refused = state.register(addr, "long enough secret".to_string()).await;
+        assert!(refused.is_err_and(|reason| reason.contains("Too many password attempts")));
+
+        // Other addresses aren't affected
+        let refused = state.authenticate(client_addr(2), "alice", "guess").await;
+        assert!(refused.is_err_and(|reason| reason.contains("No account")));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    fn client(role: Role) -> Actor {
+        Actor::Client {
+            name: "alice".to_string(),
+            role,
+        }
+    }
+
+    fn ban(role: Option<Role>) -> Ban {
+        Ban {
+            target: BanTarget::parse("bob"),
+            until: None,
+            role,
+            created: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn clients_only_act_on_and_unban_lower_ranks() {
+        assert!(client(Role::Admin).may_act_on(Role::Moderator));
+        assert!(!client(Role::Moderator).may_act_on(Role::Moderator));
+        assert!(Actor::Host.may_act_on(Role::Owner));
+
+        assert!(client(Role::Admin).may_lift(&ban(Some(Role::Moderator))));
+        assert!(!client(Role::Admin).may_lift(&ban(Some(Role::Admin))));
+        assert!(!client(Role::Owner).may_lift(&ban(None)));
+        assert!(Actor::Host.may_lift(&ban(Some(Role::Owner))));
+    }
+
+    #[tokio::test]
+    async fn bans_can_only_be_replaced_by_those_who_may_lift_them() {
+        let (state, dir, _output) = test_state();
+        let bob = BanTarget::parse("bob");
+        state.ban(bob.clone(), None, &Actor::Host).await.unwrap();
+        // A short ban over the host's permanent one would lift it early
+        let short = Some(Duration::from_secs(1));
+        assert!(
+            state
+                .ban(bob.clone(), short, &client(Role::Owner))
+                .await
+                .is_err()
+        );
+        assert_eq!(state.bans.lock().await.get(&bob).unwrap().until, None);
+
+        let carl = BanTarget::parse("carl");
+        state
+            .ban(carl.clone(), None, &client(Role::Admin))
+            .await
+            .unwrap();
+        assert!(
+            state
+                .ban(carl.clone(), short, &client(Role::Admin))
+                .await
+                .is_err()
+        );
+        state
+            .ban(carl.clone(), short, &client(Role::Owner))
+            .await
+            .unwrap();
+        let replaced = state.bans.lock().await.get(&carl).unwrap().clone();
+        assert_eq!(replaced.role, Some(Role::Owner));
+        assert!(replaced.until.is_some());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn client_durations_are_capped() {
+        assert_eq!(wire_duration(None), Ok(None));
+        assert_eq!(wire_duration(Some(60)), Ok(Some(Duration::from_secs(60))));
+        assert!(wire_duration(Some(MAX_DURATION.as_secs() + 1)).is_err());
+        assert!(wire_duration(Some(u64::MAX)).is_err());
+    }
+}
@@ -0,0 +1,476 @@
+use anyhow::{Context, Result, anyhow, bail};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use tokio_rustls::rustls::crypto::{
+    CryptoProvider, ring, verify_tls12_signature, verify_tls13_signature,
+};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use tokio_rustls::rustls::{self, DigitallySignedStruct, SignatureScheme};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use crate::commands::ConnectRequest;
+
+pub const DEFAULT_CERT_FILE: &str = "hotline-cert.pem";
+pub const DEFAULT_KEY_FILE: &str = "hotline-key.pem";
+const KNOWN_SERVERS_FILE: &str = "known_servers";
+
+/// Where the server's certificate comes from.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TlsConfig {
+    /// Serve TLS only; plain-text clients can no longer connect.
+    pub enabled: bool,
+    /// PEM certificate chain. When neither this nor `key_file` exists, a
+    /// self-signed certificate is generated on first start.
+    pub cert_file: PathBuf,
+    /// PEM private key for `cert_file`.
+    pub key_file: PathBuf,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        TlsConfig {
+            enabled: false,
+            cert_file: PathBuf::from(DEFAULT_CERT_FILE),
+            key_file: PathBuf::from(DEFAULT_KEY_FILE),
+        }
+    }
+}
+
+/// The server side of TLS, ready to accept connections.
+pub struct ServerTls {
+    pub acceptor: TlsAcceptor,
+    /// Of the certificate clients will see, for the host to share so users
+    /// can check what their client pinned.
+    pub fingerprint: String,
+    /// Set when the certificate was generated during this start.
+    pub generated: bool,
+}
+
+impl ServerTls {
+    /// Loads the configured certificate and key, generating a self-signed
+    /// pair first if neither file exists yet.
+    pub fn load(config: &TlsConfig) -> Result<Self> {
+        let generated = !config.cert_file.exists() && !config.key_file.exists();
+        if generated {
+            generate_self_signed(&config.cert_file, &config.key_file)?;
+        }
+
+        let certs = CertificateDer::pem_file_iter(&config.cert_file)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| {
+                anyhow!(
+                    "Failed to read certificate {}: {}",
+                    config.cert_file.display(),
+                    e
+                )
+            })?;
+        let Some(leaf) = certs.first() else {
+            bail!("No certificate found in {}", config.cert_file.display());
+        };
+        let fingerprint = fingerprint(leaf);
+        let key = PrivateKeyDer::from_pem_file(&config.key_file).map_err(|e| {
+            anyhow!(
+                "Failed to read private key {}: {}",
+                config.key_file.display(),
+                e
+            )
+        })?;
+
+        let server_config = rustls::ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .context("The certificate and private key don't match")?;
+        Ok(ServerTls {
+            acceptor: TlsAcceptor::from(Arc::new(server_config)),
+            fingerprint,
+            generated,
+        })
+    }
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn generate_self_signed(cert_file: &Path, key_file: &Path) -> Result<()> {
+    let rcgen::CertifiedKey { cert, key_pair } =
+        rcgen::generate_simple_self_signed(vec!["localhost".to_string()])
+            .context("Failed to generate a self-signed certificate")?;
+    write_new(cert_file, &cert.pem(), false)?;
+    write_new(key_file, &key_pair.serialize_pem(), true)
+}
+
+/// Creates `path`, readable only by the owner when `private` is set.
+pub(crate) fn write_new(path: &Path, contents: &str, private: bool) -> Result<()> {
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    if private {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    #[cfg(not(unix))]
+    let _ = private;
+    options
+        .open(path)
+        .and_then(|mut file| file.write_all(contents.as_bytes()))
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Replaces `path` with `contents` through a temporary file, so a crash or
+/// a full disk leaves either the old or the new contents behind. With
+/// `private` set the file is readable only by the owner.
+pub(crate) fn write_atomic(path: &Path, contents: &str, private: bool) -> Result<()> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    let temp = PathBuf::from(temp);
+    let _ = fs::remove_file(&temp);
+    write_new(&temp, contents, private)
+        .and_then(|()| {
+            fs::File::open(&temp)
+                .and_then(|file| file.sync_all())
+                .with_context(|| format!("Failed to write {}", temp.display()))
+        })
+        .and_then(|()| {
+            fs::rename(&temp, path).with_context(|| format!("Failed to replace {}", path.display()))
+        })
+        .inspect_err(|_| {
+            let _ = fs::remove_file(&temp);
+        })
+}
+
+/// SHA-256 of the certificate as colon-separated hex, e.g. `SHA256:AB:CD:...`.
+pub fn fingerprint(cert: &CertificateDer<'_>) -> String {
+    let digest = Sha256::digest(cert.as_ref());
+    let hex: Vec<String> = digest.iter().map(|b| format!("{:02X}", b)).collect();
+    format!("SHA256:{}", hex.join(":"))
+}
+
+/// How the server's certificate compared with the one pinned for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trust {
+    /// Same certificate as on earlier connections.
+    Known,
+    /// First connection to this server; its certificate is now pinned.
+    FirstUse,
+    /// The certificate changed and the user accepted the new one.
+    Replaced,
+}
+
+/// The encrypted side of a session.
+#[derive(Debug, Clone)]
+pub struct TlsInfo {
+    pub fingerprint: String,
+    pub trust: Trust,
+}
+
+/// The server presented a different certificate from the one pinned for
+/// it. Nothing was sent; reconnect with `ConnectRequest::accept_fingerprint`
+/// set to `presented` to trust the new one.
+#[derive(Debug, Clone)]
+pub struct CertificateChanged {
+    pub address: String,
+    pub pinned: String,
+    pub presented: String,
+}
+
+impl fmt::Display for CertificateChanged {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The certificate of {} has changed", self.address)
+    }
+}
+
+impl std::error::Error for CertificateChanged {}
+
+/// Fingerprints of servers connected to before, one `host:port fingerprint`
+/// line each, like SSH's known_hosts.
+pub struct KnownServers {
+    path: PathBuf,
+    pins: BTreeMap<String, String>,
+}
+
+impl KnownServers {
+    /// `known_servers` next to the per-user config file.
+    pub fn default_path() -> PathBuf {
+        dirs::config_dir()
+            .map(|dir| dir.join("hotline").join(KNOWN_SERVERS_FILE))
+            .unwrap_or_else(|| PathBuf::from(KNOWN_SERVERS_FILE))
+    }
+
+    /// Reads the pins in `path`; a missing file means none yet.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        };
+        let pins = text
+            .lines()
+            .filter_map(|line| line.trim().split_once(' '))
+            .map(|(address, fingerprint)| (address.to_string(), fingerprint.trim().to_string()))
+            .collect();
+        Ok(KnownServers { path, pins })
+    }
+
+    pub fn get(&self, address: &str) -> Option<&str> {
+        self.pins.get(address).map(String::as_str)
+    }
+
+    /// Remembers `fingerprint` for `address`, replacing any earlier pin.
+    pub fn pin(&mut self, address: &str, fingerprint: &str) -> Result<()> {
+        self.pins
+            .insert(address.to_string(), fingerprint.to_string());
+        if let Some(dir) = self.path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        let text: String = self
+            .pins
+            .iter()
+            .map(|(address, fingerprint)| format!("{} {}\n", address, fingerprint))
+            .collect();
+        fs::write(&self.path, text)
+            .with_context(|| format!("Failed to write {}", self.path.display()))
+    }
+}
+
+/// Wraps `stream` in TLS and checks the server's certificate against the pin
+/// in `known_servers`, pinning it if this is the first connection. Fails with
+/// `CertificateChanged` before anything is sent if it doesn't match.
+pub async fn connect(
+    stream: TcpStream,
+    request: &ConnectRequest,
+    known_servers: &Path,
+) -> Result<(TlsStream<TcpStream>, TlsInfo)> {
+    let address = request.address();
+    let provider = provider();
+    let config = rustls::ClientConfig::builder_with_provider(Arc::clone(&provider))
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(TrustOnFirstUse(provider)))
+        .with_no_client_auth();
+    let name = ServerName::try_from(request.host.clone())
+        .with_context(|| format!("'{}' can't be used as a TLS server name", request.host))?;
+    let mut stream = TlsConnector::from(Arc::new(config))
+        .connect(name, stream)
+        .await
+        .with_context(|| {
+            format!(
+                "TLS handshake with {} failed; is TLS enabled on the server?",
+                address
+            )
+        })?;
+
+    let presented = stream
+        .get_ref()
+        .1
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .map(fingerprint)
+        .context("The server sent no certificate")?;
+    let mut known = KnownServers::load(known_servers)?;
+    let trust = match known.get(&address) {
+        Some(pinned) if pinned == presented => Trust::Known,
+        Some(_) if request.accept_fingerprint.as_deref() == Some(presented.as_str()) => {
+            known.pin(&address, &presented)?;
+            Trust::Replaced
+        }
+        Some(pinned) => {
+            let changed = CertificateChanged {
+                address,
+                pinned: pinned.to_string(),
+                presented,
+            };
+            let _ = stream.shutdown().await;
+            return Err(changed.into());
+        }
+        None => {
+            known.pin(&address, &presented)?;
+            Trust::FirstUse
+        }
+    };
+    Ok((
+        stream,
+        TlsInfo {
+            fingerprint: presented,
+            trust,
+        },
+    ))
+}
+
+/// Accepts any certificate during the handshake, leaving the decision to the
+/// pin check in `connect`. Handshake signatures are still verified, so the
+/// server must hold the key of the certificate it presents.
+#[derive(Debug)]
+struct TrustOnFirstUse(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for TrustOnFirstUse {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("hotline-tls-{}", rand::random::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// A server with a freshly generated certificate.
+    fn server(dir: &Path, name: &str) -> ServerTls {
+        let tls = ServerTls::load(&TlsConfig {
+            enabled: true,
+            cert_file: dir.join(format!("{}-cert.pem", name)),
+            key_file: dir.join(format!("{}-key.pem", name)),
+        })
+        .unwrap();
+        assert!(tls.generated);
+        tls
+    }
+
+    /// Accepts one connection on `listener` with `server`, then connects to it.
+    async fn connect_to(
+        listener: &TcpListener,
+        server: &ServerTls,
+        request: &ConnectRequest,
+        known_servers: &Path,
+    ) -> Result<TlsInfo> {
+        let acceptor = server.acceptor.clone();
+        let stream = TcpStream::connect(listener.local_addr().unwrap());
+        let (stream, accepted) = tokio::join!(stream, listener.accept());
+        let accepted = tokio::spawn(acceptor.accept(accepted.unwrap().0));
+        let result = connect(stream.unwrap(), request, known_servers).await;
+        let _ = accepted.await;
+        result.map(|(_, info)| info)
+    }
+
+    #[tokio::test]
+    async fn a_changed_certificate_is_refused_until_accepted() {
+        let dir = temp_dir();
+        let known_servers = dir.join("known_servers");
+        let (original, replacement) = (server(&dir, "original"), server(&dir, "replacement"));
+        assert_ne!(original.fingerprint, replacement.fingerprint);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut request = ConnectRequest::from_input("127.0.0.1", port, "alice").unwrap();
+        request.tls = true;
+
+        let info = connect_to(&listener, &original, &request, &known_servers)
+            .await
+            .unwrap();
+        assert_eq!(info.trust, Trust::FirstUse);
+        assert_eq!(info.fingerprint, original.fingerprint);
+        let info = connect_to(&listener, &original, &request, &known_servers)
+            .await
+            .unwrap();
+        assert_eq!(info.trust, Trust::Known);
+
+        let refused = connect_to(&listener, &replacement, &request, &known_servers)
+            .await
+            .unwrap_err();
+        let changed = refused.downcast_ref::<CertificateChanged>().unwrap();
+        assert_eq!(changed.address, request.address());
+        assert_eq!(changed.pinned, original.fingerprint);
+        assert_eq!(changed.presented, replacement.fingerprint);
+        let known = KnownServers::load(&known_servers).unwrap();
+        assert_eq!(
+            known.get(&request.address()),
+            Some(original.fingerprint.as_str())
+        );
+
+        // Accepting some other fingerprint doesn't help
+        request.accept_fingerprint = Some("SHA256:00".to_string());
+        let refused = connect_to(&listener, &replacement, &request, &known_servers).await;
+        assert!(refused.unwrap_err().is::<CertificateChanged>());
+
+        request.accept_fingerprint = Some(replacement.fingerprint.clone());
+        let info = connect_to(&listener, &replacement, &request, &known_servers)
+            .await
+            .unwrap();
+        assert_eq!(info.trust, Trust::Replaced);
+        let known = KnownServers::load(&known_servers).unwrap();
+        assert_eq!(
+            known.get(&request.address()),
+            Some(replacement.fingerprint.as_str())
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn known_servers_survive_a_reload() {
+        let dir = temp_dir();
+        let path = dir.join("nested").join("known_servers");
+        let mut known = KnownServers::load(&path).unwrap();
+        assert_eq!(known.get("example.org:9000"), None);
+        known.pin("example.org:9000", "SHA256:AA").unwrap();
+        known.pin("[::1]:9000", "SHA256:BB").unwrap();
+        known.pin("example.org:9000", "SHA256:CC").unwrap();
+        let known = KnownServers::load(&path).unwrap();
+        assert_eq!(known.get("example.org:9000"), Some("SHA256:CC"));
+        assert_eq!(known.get("[::1]:9000"), Some("SHA256:BB"));
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
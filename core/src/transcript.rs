@@ -0,0 +1,270 @@
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+use std::str::FromStr;
+
+use flate2::read::GzDecoder;
+
+use crate::protocol::{ChatPayload, ServerFrame};
+use crate::serializable_colours::*;
+
+/// Output formats supported by `export`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Text,
+    Markdown,
+    Html,
+}
+
+impl ExportFormat {
+    /// Guesses the format from a file extension such as `.md` or `.html`.
+    pub fn from_extension(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()?.to_lowercase().as_str() {
+            "txt" | "log" => Some(ExportFormat::Text),
+            "md" | "markdown" => Some(ExportFormat::Markdown),
+            "html" | "htm" => Some(ExportFormat::Html),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "text" | "txt" => Ok(ExportFormat::Text),
+            "markdown" | "md" => Ok(ExportFormat::Markdown),
+            "html" => Ok(ExportFormat::Html),
+            other => Err(format!(
+                "unknown format '{}', expected text, markdown or html",
+                other
+            )),
+        }
+    }
+}
+
+/// One transcript line: either something said or a membership notice.
+enum Entry {
+    Message(ChatPayload),
+    Notice(ChatPayload),
+}
+
+impl Entry {
+    fn from_frame(frame: &ServerFrame) -> Option<Self> {
+        match frame {
+            ServerFrame::Chat(msg) => Some(Entry::Message(msg.clone())),
+            other => other.notice_message().map(Entry::Notice),
+        }
+    }
+}
+
+/// What `read_transcript` made of a file.
+pub struct Transcript {
+    pub frames: Vec<ServerFrame>,
+    /// Lines that weren't a transcript entry, e.g. one torn by a crash.
+    pub skipped: usize,
+    /// 1-based number of the first of them.
+    pub first_skipped: Option<usize>,
+}
+
+/// Reads a JSON Lines transcript written by the server's chat log,
+/// including ones compressed to `.gz` by log retention. Malformed lines
+/// are skipped and counted rather than failing the whole file.
+pub fn read_transcript(path: impl AsRef<Path>) -> Result<Transcript> {
+    let path = path.as_ref();
+    let file = File::open(path)
+        .with_context(|| format!("Failed to open transcript {}", path.display()))?;
+    let input: Box<dyn Read> = if path.extension().is_some_and(|ext| ext == "gz") {
+        Box::new(GzDecoder::new(file))
+    } else {
+        Box::new(file)
+    };
+    let mut transcript = Transcript {
+        frames: Vec::new(),
+        skipped: 0,
+        first_skipped: None,
+    };
+    // Split on bytes so a line that isn't UTF-8 is skipped like any other
+    for (number, line) in BufReader::new(input).split(b'\n').enumerate() {
+        let line = line.with_context(|| format!("Failed to read transcript {}", path.display()))?;
+        if line.trim_ascii().is_empty() {
+            continue;
+        }
+        match serde_json::from_slice::<ServerFrame>(&line) {
+            Ok(frame) => transcript.frames.push(frame),
+            Err(_) => {
+                transcript.skipped += 1;
+                transcript.first_skipped.get_or_insert(number + 1);
+            }
+        }
+    }
+    Ok(transcript)
+}
+
+/// Renders transcript frames in the requested format. `title` heads the
+/// Markdown and HTML output.
+pub fn export_transcript(frames: &[ServerFrame], format: ExportFormat, title: &str) -> String {
+    let entries: Vec<Entry> = frames.iter().filter_map(Entry::from_frame).collect();
+    match format {
+        ExportFormat::Text => entries.iter().map(text_line).collect(),
+        ExportFormat::Markdown => export_markdown(&entries, title),
+        ExportFormat::Html => export_html(&entries, title),
+    }
+}
+
+fn timestamp(msg: &ChatPayload) -> String {
+    msg.timestamp.format("%Y-%m-%d %H:%M:%S").to_string()
+}
+
+fn room_tag(msg: &ChatPayload) -> String {
+    msg.room
+        .as_ref()
+        .map(|room| format!("[#{}] ", room))
+        .unwrap_or_default()
+}
+
+/// e.g. `[2024-01-01 12:00:00] [#lobby] alice: hi`
+fn text_line(entry: &Entry) -> String {
+    match entry {
+        Entry::Message(msg) => format!(
+            "[{}] {}{}: {}\n",
+            timestamp(msg),
+            room_tag(msg),
+            msg.sender,
+            msg.content
+        ),
+        Entry::Notice(msg) => format!("[{}] {}* {}\n", timestamp(msg), room_tag(msg), msg.content),
+    }
+}
+
+fn export_markdown(entries: &[Entry], title: &str) -> String {
+    let mut out = format!("# {}\n\n", escape_markdown(title));
+    for entry in entries {
+        let line = match entry {
+            Entry::Message(msg) => format!(
+                "`{}` {}**{}**: {}",
+                timestamp(msg),
+                escape_markdown(&room_tag(msg)),
+                escape_markdown(&msg.sender),
+                escape_markdown(&msg.content)
+            ),
+            Entry::Notice(msg) => format!(
+                "`{}` {}_{}_",
+                timestamp(msg),
+                escape_markdown(&room_tag(msg)),
+                escape_markdown(&msg.content)
+            ),
+        };
+        // Two trailing spaces keep each entry on its own line
+        out.push_str(&line);
+        out.push_str("  \n");
+    }
+    out
+}
+
+fn export_html(entries: &[Entry], title: &str) -> String {
+    let mut out = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{title}</title>\n\
+         <style>\n\
+         body {{ background: {bg}; color: {fg}; font-family: monospace; }}\n\
+         .time {{ color: {gray}; }}\n\
+         .room {{ color: {cyan}; }}\n\
+         .sender {{ color: {green}; font-weight: bold; }}\n\
+         .host {{ color: {blue}; font-weight: bold; }}\n\
+         .notice {{ color: {gray}; font-style: italic; }}\n\
+         </style>\n</head>\n<body>\n<h1>{title}</h1>\n",
+        title = escape_html(title),
+        bg = BLACK_COLOR.to_hex(),
+        fg = WHITE_COLOR.to_hex(),
+        gray = GRAY_COLOR.to_hex(),
+        cyan = CYAN_COLOR.to_hex(),
+        green = GREEN_COLOR.to_hex(),
+        blue = BLUE_COLOR.to_hex(),
+    );
+    for entry in entries {
+        let (msg, body) = match entry {
+            Entry::Message(msg) => {
+                // Host messages are the only ones without a room
+                let class = if msg.room.is_none() { "host" } else { "sender" };
+                let body = format!(
+                    "<span class=\"{}\">{}:</span> {}",
+                    class,
+                    escape_html(&msg.sender),
+                    escape_html(&msg.content)
+                );
+                (msg, body)
+            }
+            Entry::Notice(msg) => (
+                msg,
+                format!(
+                    "<span class=\"notice\">{}</span>",
+                    escape_html(&msg.content)
+                ),
+            ),
+        };
+        out.push_str(&format!(
+            "<div><span class=\"time\">[{}]</span> <span class=\"room\">{}</span>{}</div>\n",
+            timestamp(msg),
+            escape_html(&room_tag(msg)),
+            body
+        ));
+    }
+    out.push_str("</body>\n</html>\n");
+    out
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn escape_markdown(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        if "\\`*_[]<>|".contains(c) {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    #[test]
+    fn skips_and_counts_malformed_lines() {
+        let frame = ServerFrame::Chat(ChatPayload {
+            id: Some(1),
+            content: "hi".to_string(),
+            sender: "bob".to_string(),
+            username: Some("bob".to_string()),
+            timestamp: Utc::now(),
+            room: Some("lobby".to_string()),
+            sealed: None,
+        });
+        let good = serde_json::to_string(&frame).unwrap();
+        let mut data = format!("not json\n{}\n\n", good).into_bytes();
+        data.extend_from_slice(b"\xff\xfe\n");
+        data.extend_from_slice(good.as_bytes());
+        data.extend_from_slice(b"\n{\"type\":\"cha");
+
+        let path = std::env::temp_dir().join(format!(
+            "hotline-transcript-{}.jsonl",
+            rand::random::<u64>()
+        ));
+        std::fs::write(&path, data).unwrap();
+        let transcript = read_transcript(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(transcript.frames.len(), 2);
+        assert_eq!(transcript.skipped, 3);
+        assert_eq!(transcript.first_skipped, Some(1));
+    }
+}
@@ -0,0 +1,16 @@
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Read side of a connection, plain TCP or TLS.
+pub type ReadHalf = Box<dyn AsyncRead + Send + Unpin>;
+
+/// Write side of a connection, plain TCP or TLS.
+pub type WriteHalf = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// Splits a stream so reading and writing can happen in different tasks.
+pub fn split<S>(stream: S) -> (ReadHalf, WriteHalf)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (reader, writer) = tokio::io::split(stream);
+    (Box::new(reader), Box::new(writer))
+}
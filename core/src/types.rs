@@ -16,6 +16,13 @@ pub struct SerializableColor {
     pub b: u8,
 }
 
+impl SerializableColor {
+    /// CSS notation, e.g. `#00ff00`.
+    pub fn to_hex(&self) -> String {
+        format!("#{:02x}{:02x}{:02x}", self.r, self.g, self.b)
+    }
+}
+
 impl From<SerializableColor> for Color {
     fn from(color: SerializableColor) -> Self {
         Color::Rgb(color.r, color.g, color.b)
@@ -45,14 +52,63 @@ pub struct ChatMessage {
     pub sender: String,
     pub username: Option<String>,
     pub timestamp: DateTime<Utc>,
+    #[serde(default)]
+    pub room: Option<String>,
     pub is_self: bool,
+    /// Backfilled from the server's history rather than received live.
+    #[serde(default)]
+    pub is_history: bool,
+    #[serde(default)]
+    pub encryption: Encryption,
+}
+
+/// Whether a chat message was end-to-end encrypted and could be read.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Encryption {
+    #[default]
+    Plain,
+    /// Encrypted, and decrypted with the room key.
+    Decrypted,
+    /// Encrypted with a key this client doesn't have.
+    Undecryptable,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum SystemEvent {
-    ConnectionEstablished { address: String },
+    ConnectionEstablished {
+        address: String,
+    },
     ConnectionClosed,
-    ConnectionError { message: String },
-    PromptInput { prompt: String },
-    RateLimit { seconds: f32 },
+    ConnectionError {
+        message: String,
+    },
+    PromptInput {
+        prompt: String,
+    },
+    RateLimit {
+        seconds: f32,
+    },
+    /// The connection dropped; the next attempt starts in `seconds`.
+    Reconnecting {
+        attempt: u32,
+        seconds: f32,
+    },
+    /// Longest chat frame the server accepts, see `protocol::chat_frame_len`.
+    MessageLimit {
+        max_bytes: usize,
+    },
+    /// The server's certificate no longer matches the pinned one; nothing
+    /// was sent. Answer with `ClientCommand::TrustCertificate` to accept it.
+    CertificateChanged {
+        address: String,
+        pinned: String,
+        presented: String,
+    },
+    /// The room the client is in, and whether its messages are end-to-end
+    /// encrypted and readable.
+    RoomStatus {
+        room: String,
+        encrypted: bool,
+        has_key: bool,
+    },
 }
@@ -5,20 +5,25 @@ pub use chrono::Local;
 pub use cursive::CbSink;
 pub use cursive::Cursive;
 pub use cursive::align::HAlign;
-pub use cursive::theme::{BaseColor, Color, Palette, PaletteColor, Theme};
+pub use cursive::theme::{BaseColor, Color};
 pub use cursive::traits::*;
 pub use cursive::utils::markup::StyledString;
-pub use cursive::views::ScrollView;
 pub use cursive::views::{Dialog, EditView, LinearLayout, TextContent, TextView};
+pub use cursive::views::{ScrollView, SelectView};
 pub use std::sync::Arc;
 pub use std::sync::Mutex;
 pub use std::sync::atomic::AtomicBool;
+pub use std::sync::atomic::AtomicUsize;
 pub use std::sync::atomic::Ordering;
 pub use tokio::runtime::Runtime;
 pub use tokio::sync::mpsc;
 
-pub use crate::shared::global_quit;
+pub use crate::shared::{config, global_quit, set_custom_theme};
 
+pub use core::address::format_host_port;
 pub use core::client_backend::run_client_backend;
+pub use core::commands::{ClientCommand, ConnectRequest};
+pub use core::config::SavedServer;
+pub use core::protocol::chat_frame_len;
 pub use core::serializable_colours::*;
-pub use core::types::{ChatMessage, OutputEvent, SystemEvent, TextLine};
+pub use core::types::{ChatMessage, Encryption, OutputEvent, SystemEvent, TextLine};
@@ -4,12 +4,14 @@ mod utils;
 pub use imports::*;
 pub use utils::*;
 
-pub fn run_chat_tui() {
+/// Runs the chat client TUI. With a `connect` command the connection dialog
+/// is skipped and the client connects right away.
+pub fn run_chat_tui(connect: Option<ConnectRequest>) {
     // Create a shared shutdown signal
     let shutdown_signal = Arc::new(AtomicBool::new(false));
 
     // Create standard synchronous channels for UI to communicate with the async thread
-    let (ui_to_async_tx, ui_to_async_rx) = std_mpsc::channel::<String>();
+    let (ui_to_async_tx, ui_to_async_rx) = std_mpsc::channel::<ClientCommand>();
     let (async_to_ui_tx, async_to_ui_rx) = std_mpsc::channel::<OutputEvent>();
 
     // Clone the shutdown signal for the async thread
@@ -23,7 +25,7 @@ pub fn run_chat_tui() {
         // Run the async code in this runtime
         rt.block_on(async {
             // Create Tokio channels for the async code
-            let (input_tx, input_rx) = mpsc::channel::<String>(100);
+            let (input_tx, input_rx) = mpsc::channel::<ClientCommand>(100);
             let (output_tx, mut output_rx) = mpsc::channel::<OutputEvent>(100);
 
             // Bridge between std_mpsc and tokio channels
@@ -71,7 +73,7 @@ pub fn run_chat_tui() {
     });
 
     // Run the UI in the main thread
-    chat_tui(ui_to_async_tx, async_to_ui_rx, shutdown_signal);
+    chat_tui(ui_to_async_tx, async_to_ui_rx, shutdown_signal, connect);
 
     // Wait for the async thread to finish
     let _ = async_thread.join();
@@ -79,28 +81,100 @@ pub fn run_chat_tui() {
 
 fn show_connection_dialog(
     siv: &mut Cursive,
-    input_tx: std_mpsc::Sender<String>,
+    input_tx: std_mpsc::Sender<ClientCommand>,
     content: TextContent,
     quit_signal: Arc<AtomicBool>,
 ) {
-    // Create input fields for server address and port
-    let server_input = EditView::new().with_name("server_addr").fixed_width(30);
+    // Create input fields for server address and port, pre-filled from the config file
+    let defaults = &config().client;
+    let saved = defaults.default_server();
+
+    let server_input = EditView::new()
+        .content(saved.map(|s| s.host.clone()).unwrap_or_default())
+        .with_name("server_addr")
+        .fixed_width(30);
 
     let port_input = EditView::new()
-        .content("8080")
+        .content(saved.map_or(8080, |s| s.port).to_string())
         .with_name("port")
         .fixed_width(10);
 
-    let username_input = EditView::new().with_name("username").fixed_width(30);
+    let username_input = EditView::new()
+        .content(saved_username(saved).unwrap_or_default())
+        .with_name("username")
+        .fixed_width(30);
+
+    let tls_input = EditView::new()
+        .content(yes_no(saved.is_some_and(|s| s.tls)))
+        .with_name("tls")
+        .fixed_width(5);
+
+    let password_input = EditView::new()
+        .content(saved.and_then(|s| s.password.clone()).unwrap_or_default())
+        .secret()
+        .with_name("password")
+        .fixed_width(30);
+
+    let account_password_input = EditView::new()
+        .secret()
+        .with_name("account_password")
+        .fixed_width(30);
 
     // Create the layout for the dialog
-    let layout = LinearLayout::vertical()
-        .child(TextView::new("Server Address:"))
+    let mut layout = LinearLayout::vertical();
+    if !defaults.servers.is_empty() {
+        // Picking a saved server fills in the fields below
+        let mut servers = SelectView::<SavedServer>::new().on_select(|s, server| {
+            s.call_on_name("server_addr", |view: &mut EditView| {
+                view.set_content(server.host.clone());
+            });
+            s.call_on_name("port", |view: &mut EditView| {
+                view.set_content(server.port.to_string());
+            });
+            s.call_on_name("username", |view: &mut EditView| {
+                view.set_content(saved_username(Some(server)).unwrap_or_default());
+            });
+            s.call_on_name("tls", |view: &mut EditView| {
+                view.set_content(yes_no(server.tls));
+            });
+            s.call_on_name("password", |view: &mut EditView| {
+                view.set_content(server.password.clone().unwrap_or_default());
+            });
+        });
+        for server in &defaults.servers {
+            servers.add_item(
+                format!(
+                    "{} ({})",
+                    server.name,
+                    format_host_port(&server.host, server.port)
+                ),
+                server.clone(),
+            );
+        }
+        if let Some(index) = defaults
+            .servers
+            .iter()
+            .position(|s| saved.is_some_and(|d| d.name == s.name))
+        {
+            servers.set_selection(index);
+        }
+        layout = layout.child(TextView::new("Saved Servers:")).child(servers);
+    }
+    let layout = layout
+        .child(TextView::new("Server Address (host, IPv6 or [addr]:port):"))
         .child(server_input)
         .child(TextView::new("Port:"))
         .child(port_input)
         .child(TextView::new("Username (optional):"))
-        .child(username_input);
+        .child(username_input)
+        .child(TextView::new("Use TLS (yes/no):"))
+        .child(tls_input)
+        .child(TextView::new("Account Password (optional):"))
+        .child(account_password_input)
+        .child(TextView::new(
+            "Chatroom Password or Invite Token (optional):",
+        ))
+        .child(password_input);
 
     // Create the dialog with buttons
     let dialog = Dialog::around(layout)
@@ -123,11 +197,48 @@ fn show_connection_dialog(
                 })
                 .unwrap_or_default();
 
+            let tls = s
+                .call_on_name("tls", |view: &mut EditView| view.get_content().to_string())
+                .unwrap_or_default();
+
+            let password = s
+                .call_on_name("password", |view: &mut EditView| {
+                    view.get_content().to_string()
+                })
+                .unwrap_or_default();
+
+            let account_password = s
+                .call_on_name("account_password", |view: &mut EditView| {
+                    view.get_content().to_string()
+                })
+                .unwrap_or_default();
+
             if server_addr.trim().is_empty() {
                 s.add_layer(Dialog::info("Please enter a server address").title("Error"));
                 return;
             }
 
+            let Ok(port) = port.trim().parse::<u16>() else {
+                s.add_layer(
+                    Dialog::info("Please enter a valid port number (0-65535)").title("Error"),
+                );
+                return;
+            };
+
+            let request = match ConnectRequest::from_input(&server_addr, port, &username) {
+                Ok(request) => ConnectRequest {
+                    tls: tls.trim().eq_ignore_ascii_case("yes"),
+                    password: Some(password.trim().to_string()).filter(|p| !p.is_empty()),
+                    account_password: Some(account_password.trim().to_string())
+                        .filter(|p| !p.is_empty()),
+                    ..request
+                },
+                Err(e) => {
+                    s.add_layer(Dialog::info(e).title("Error"));
+                    return;
+                }
+            };
+
             // Remove the dialog
             s.pop_layer();
 
@@ -136,14 +247,14 @@ fn show_connection_dialog(
             s.call_on_name("messages", |_view: &mut TextView| {
                 let mut styled = StyledString::new();
                 styled.append_styled(
-                    format!("Connecting to {}:{}...\n", server_addr, port),
+                    format!("Connecting to {}...\n", request.address()),
                     Color::Light(BaseColor::Blue),
                 );
                 content_clone.append(styled);
             });
 
             // Send connection details to backend
-            let _ = input_tx.send(format!("CONNECT:{}:{}:{}", server_addr, port, username));
+            let _ = input_tx.send(ClientCommand::Connect(request));
         })
         .button("Quit", {
             move |s| global_quit(&s.cb_sink().clone(), &quit_signal)
@@ -152,10 +263,22 @@ fn show_connection_dialog(
     siv.add_layer(dialog);
 }
 
+fn yes_no(value: bool) -> &'static str {
+    if value { "yes" } else { "no" }
+}
+
+/// Username to pre-fill for `server`, falling back to the config-wide one.
+fn saved_username(server: Option<&SavedServer>) -> Option<String> {
+    server
+        .and_then(|s| s.username.clone())
+        .or_else(|| config().client.username.clone())
+}
+
 fn chat_tui(
-    input_tx: std_mpsc::Sender<String>,
+    input_tx: std_mpsc::Sender<ClientCommand>,
     output_rx: std_mpsc::Receiver<OutputEvent>,
     shutdown_signal: Arc<AtomicBool>,
+    connect: Option<ConnectRequest>,
 ) {
     let mut siv = cursive::default();
     set_custom_theme(&mut siv);
@@ -202,6 +325,9 @@ fn chat_tui(
     let content_clone = content.clone();
     let siv_sink = siv.cb_sink().clone();
 
+    // Server's frame limit once connected, 0 until then
+    let message_limit = Arc::new(AtomicUsize::new(0));
+
     // Add welcome messages from the frontend
     print_textline_to_output(
         &siv_sink,
@@ -219,59 +345,110 @@ fn chat_tui(
         .full_height()
         .fixed_height(20);
 
-    let input_label = TextView::new("Enter message (type '/quit' to exit)").h_align(HAlign::Left);
+    let input_label = TextView::new(
+        "Enter message ('/join <room>', '/part', '/rooms', '/nick', '/history', '/ping', '/whois', '/register', '/login', '/encrypt', '/topic', '/kick', '/mute', '/ban', '/role', '/quit' to exit)",
+    )
+    .h_align(HAlign::Left);
 
     // Use a standard channel sender in the UI callback - NO TOKIO HERE
     let input_tx_clone = input_tx.clone();
     let quit_signal = Arc::clone(&shutdown_signal);
+    let input_content = content.clone();
+    let input_auto_scroll = auto_scroll.clone();
+    let edit_limit = message_limit.clone();
+    let submit_limit = message_limit.clone();
 
     let input = EditView::new()
-        .on_submit(move |s, text| {
-            if text != "/quit" {
-                let _ = input_tx_clone.send(text.to_string());
+        .on_edit(move |s, text, _| show_input_size(s, text, edit_limit.load(Ordering::SeqCst)))
+        .on_submit(move |s, text| match ClientCommand::parse(text) {
+            Ok(ClientCommand::Send(message))
+                if too_long(&message, submit_limit.load(Ordering::SeqCst)) =>
+            {
+                // Keep the text so the user can shorten it
+                let max = submit_limit.load(Ordering::SeqCst);
+                print_textline_to_output(
+                    &s.cb_sink().clone(),
+                    &input_content,
+                    TextLine {
+                        text: format!(
+                            "Message is too long ({} of {} bytes allowed)",
+                            chat_frame_len(&message),
+                            max
+                        ),
+                        color: Some(RED_COLOR.clone()),
+                    },
+                    &input_auto_scroll,
+                );
+            }
+            Ok(ClientCommand::Quit) => {
+                // Set the shutdown signal before quitting the UI
+                global_quit(&s.cb_sink().clone(), &quit_signal);
+            }
+            Ok(command) => {
+                let _ = input_tx_clone.send(command);
 
                 s.call_on_name("input", |view: &mut EditView| {
                     view.set_content("");
                 });
-            } else {
-                // Set the shutdown signal before quitting the UI
-                global_quit(&s.cb_sink().clone(), &quit_signal);
+                show_input_size(s, "", 0);
             }
+            Err(usage) => print_textline_to_output(
+                &s.cb_sink().clone(),
+                &input_content,
+                TextLine {
+                    text: usage,
+                    color: Some(RED_COLOR.clone()),
+                },
+                &input_auto_scroll,
+            ),
         })
         .with_name("input")
         .fixed_height(1);
 
+    let status = TextView::new("Not connected")
+        .h_align(HAlign::Right)
+        .with_name("status");
+
     let layout = LinearLayout::vertical()
         .child(messages)
+        .child(status)
         .child(input_label)
-        .child(input.full_width());
+        .child(
+            LinearLayout::horizontal()
+                .child(input.full_width())
+                .child(TextView::new("").with_name("input_size")),
+        );
 
     siv.add_layer(Dialog::around(layout).title("Hotline Chat"));
 
-    // Show connection dialog at startup
+    // Show connection dialog at startup unless the command line said where to connect
     let dialog_input_tx = input_tx.clone();
     let dialog_content = content.clone();
 
-    siv.add_layer(
-        Dialog::text("Would you like to connect to a server?")
-            .title("Connection")
-            .button("Connect", {
-                let shutdown_signal = shutdown_signal.clone(); // clone for this closure
-                let dialog_input_tx = dialog_input_tx.clone();
-                let dialog_content = dialog_content.clone();
-                move |s| {
+    if let Some(connect) = connect {
+        let _ = input_tx.send(ClientCommand::Connect(connect));
+    } else {
+        siv.add_layer(
+            Dialog::text("Would you like to connect to a server?")
+                .title("Connection")
+                .button("Connect", {
+                    let shutdown_signal = shutdown_signal.clone(); // clone for this closure
                     let dialog_input_tx = dialog_input_tx.clone();
                     let dialog_content = dialog_content.clone();
-                    let shutdown_signal = shutdown_signal.clone();
-                    s.pop_layer();
-                    show_connection_dialog(s, dialog_input_tx, dialog_content, shutdown_signal);
-                }
-            })
-            .button("Quit", {
-                let quit_signal = shutdown_signal.clone(); // Clone before the move closure
-                move |s| global_quit(&s.cb_sink().clone(), &quit_signal)
-            }),
-    );
+                    move |s| {
+                        let dialog_input_tx = dialog_input_tx.clone();
+                        let dialog_content = dialog_content.clone();
+                        let shutdown_signal = shutdown_signal.clone();
+                        s.pop_layer();
+                        show_connection_dialog(s, dialog_input_tx, dialog_content, shutdown_signal);
+                    }
+                })
+                .button("Quit", {
+                    let quit_signal = shutdown_signal.clone(); // Clone before the move closure
+                    move |s| global_quit(&s.cb_sink().clone(), &quit_signal)
+                }),
+        );
+    }
 
     // Spawn a thread to handle output events
     let siv_sink_clone = siv.cb_sink().clone();
@@ -310,6 +487,7 @@ fn chat_tui(
                         input_tx.clone(),
                         shutdown_signal_for_thread.clone(),
                         &auto_scroll_for_thread,
+                        &message_limit,
                     );
                 }
             }
@@ -333,15 +511,23 @@ fn handle_system_event(
     siv_sink: &CbSink,
     content: &TextContent,
     event: SystemEvent,
-    input_tx: std_mpsc::Sender<String>,
+    input_tx: std_mpsc::Sender<ClientCommand>,
     shutdown_signal: Arc<AtomicBool>,
     auto_scroll: &Arc<Mutex<bool>>,
+    message_limit: &Arc<AtomicUsize>,
 ) {
     let content = content.clone();
     let sink = siv_sink.clone();
     let input_tx = input_tx.clone();
     let auto_scroll = auto_scroll.clone();
 
+    // Recorded here rather than in the UI callback so a message typed
+    // right away is already checked
+    if let SystemEvent::MessageLimit { max_bytes } = event {
+        message_limit.store(max_bytes, Ordering::SeqCst);
+        return;
+    }
+
     sink.send(Box::new(move |s| {
         let mut styled = StyledString::new();
 
@@ -352,14 +538,15 @@ fn handle_system_event(
                     Color::Light(BaseColor::Green),
                 );
                 content.append(styled);
+                set_status(s, "Connected", Color::Light(BaseColor::Green));
 
                 // Auto-scroll if enabled
-                if let Ok(scroll) = auto_scroll.lock() {
-                    if *scroll {
-                        s.call_on_name("messages_scroll", |view: &mut ScrollView<TextView>| {
-                            view.scroll_to_bottom();
-                        });
-                    }
+                if let Ok(scroll) = auto_scroll.lock()
+                    && *scroll
+                {
+                    s.call_on_name("messages_scroll", |view: &mut ScrollView<TextView>| {
+                        view.scroll_to_bottom();
+                    });
                 }
             }
             SystemEvent::ConnectionClosed => {
@@ -368,14 +555,15 @@ fn handle_system_event(
                     Color::Light(BaseColor::Red),
                 );
                 content.append(styled);
+                set_status(s, "Disconnected", Color::Light(BaseColor::Red));
 
                 // Auto-scroll if enabled
-                if let Ok(scroll) = auto_scroll.lock() {
-                    if *scroll {
-                        s.call_on_name("messages_scroll", |view: &mut ScrollView<TextView>| {
-                            view.scroll_to_bottom();
-                        });
-                    }
+                if let Ok(scroll) = auto_scroll.lock()
+                    && *scroll
+                {
+                    s.call_on_name("messages_scroll", |view: &mut ScrollView<TextView>| {
+                        view.scroll_to_bottom();
+                    });
                 }
 
                 // Show connection dialog again
@@ -392,14 +580,15 @@ fn handle_system_event(
                     Color::Light(BaseColor::Red),
                 );
                 content.append(styled);
+                set_status(s, "Disconnected", Color::Light(BaseColor::Red));
 
                 // Auto-scroll if enabled
-                if let Ok(scroll) = auto_scroll.lock() {
-                    if *scroll {
-                        s.call_on_name("messages_scroll", |view: &mut ScrollView<TextView>| {
-                            view.scroll_to_bottom();
-                        });
-                    }
+                if let Ok(scroll) = auto_scroll.lock()
+                    && *scroll
+                {
+                    s.call_on_name("messages_scroll", |view: &mut ScrollView<TextView>| {
+                        view.scroll_to_bottom();
+                    });
                 }
 
                 // Show connection dialog again
@@ -415,12 +604,12 @@ fn handle_system_event(
                 content.append(styled);
 
                 // Auto-scroll if enabled
-                if let Ok(scroll) = auto_scroll.lock() {
-                    if *scroll {
-                        s.call_on_name("messages_scroll", |view: &mut ScrollView<TextView>| {
-                            view.scroll_to_bottom();
-                        });
-                    }
+                if let Ok(scroll) = auto_scroll.lock()
+                    && *scroll
+                {
+                    s.call_on_name("messages_scroll", |view: &mut ScrollView<TextView>| {
+                        view.scroll_to_bottom();
+                    });
                 }
             }
             SystemEvent::RateLimit { seconds } => {
@@ -431,15 +620,135 @@ fn handle_system_event(
                 content.append(styled);
 
                 // Auto-scroll if enabled
-                if let Ok(scroll) = auto_scroll.lock() {
-                    if *scroll {
-                        s.call_on_name("messages_scroll", |view: &mut ScrollView<TextView>| {
-                            view.scroll_to_bottom();
-                        });
-                    }
+                if let Ok(scroll) = auto_scroll.lock()
+                    && *scroll
+                {
+                    s.call_on_name("messages_scroll", |view: &mut ScrollView<TextView>| {
+                        view.scroll_to_bottom();
+                    });
                 }
             }
+            SystemEvent::MessageLimit { .. } => {}
+            SystemEvent::CertificateChanged {
+                address,
+                pinned,
+                presented,
+            } => {
+                styled.append_styled(
+                    format!("The certificate of {} has changed!\n", address),
+                    Color::Light(BaseColor::Red),
+                );
+                content.append(styled);
+                set_status(s, "Disconnected", Color::Light(BaseColor::Red));
+                show_certificate_warning(
+                    s,
+                    address,
+                    pinned,
+                    presented,
+                    input_tx.clone(),
+                    content.clone(),
+                    shutdown_signal.clone(),
+                );
+            }
+            SystemEvent::RoomStatus {
+                room,
+                encrypted,
+                has_key,
+            } => {
+                // A key we just set counts before the server confirms the switch
+                let (text, color) = match (encrypted, has_key) {
+                    (_, true) => (
+                        format!("Connected · #{} · end-to-end encrypted", room),
+                        Color::Light(BaseColor::Magenta),
+                    ),
+                    (true, false) => (
+                        format!("Connected · #{} · encrypted, no passphrase set", room),
+                        Color::Light(BaseColor::Red),
+                    ),
+                    (false, false) => (
+                        format!("Connected · #{}", room),
+                        Color::Light(BaseColor::Green),
+                    ),
+                };
+                set_status(s, &text, color);
+            }
+            SystemEvent::Reconnecting { attempt, seconds } => {
+                set_status(
+                    s,
+                    &format!("Reconnecting in {:.0}s (attempt {})...", seconds, attempt),
+                    Color::Light(BaseColor::Yellow),
+                );
+            }
         }
     }))
     .unwrap();
 }
+
+/// Asks whether to trust a server whose certificate no longer matches the
+/// pinned one. Cancelling goes back to the connection dialog.
+fn show_certificate_warning(
+    s: &mut Cursive,
+    address: String,
+    pinned: String,
+    presented: String,
+    input_tx: std_mpsc::Sender<ClientCommand>,
+    content: TextContent,
+    quit_signal: Arc<AtomicBool>,
+) {
+    let mut text = StyledString::styled(
+        format!("WARNING: the certificate of {} has changed!\n\n", address),
+        Color::Light(BaseColor::Red),
+    );
+    text.append_plain(
+        "Someone may be intercepting the connection, or the server may have \
+         a new certificate. Nothing has been sent.\n\n\
+         Ask the host which fingerprint the server shows before trusting it.\n\n",
+    );
+    text.append_plain(format!("Pinned:\n{}\n\nPresented:\n{}", pinned, presented));
+
+    let trust_tx = input_tx.clone();
+    let dialog = Dialog::around(TextView::new(text).fixed_width(70))
+        .title("Certificate Changed")
+        .button("Disconnect", move |s| {
+            s.pop_layer();
+            show_connection_dialog(s, input_tx.clone(), content.clone(), quit_signal.clone());
+        })
+        .button("Trust New Certificate", move |s| {
+            s.pop_layer();
+            let _ = trust_tx.send(ClientCommand::TrustCertificate {
+                fingerprint: presented.clone(),
+            });
+        });
+    s.add_layer(dialog);
+}
+
+/// Whether `message` would be over the server's limit. A `max` of 0 means
+/// the limit isn't known yet.
+fn too_long(message: &str, max: usize) -> bool {
+    max > 0 && chat_frame_len(message) > max
+}
+
+/// Shows how close the input is to the server's limit, once it gets close.
+fn show_input_size(s: &mut Cursive, text: &str, max: usize) {
+    let len = chat_frame_len(text);
+    let counter = if max == 0 || len * 5 < max * 4 {
+        StyledString::new()
+    } else {
+        let color = if len > max {
+            Color::Light(BaseColor::Red)
+        } else {
+            Color::Light(BaseColor::Yellow)
+        };
+        StyledString::styled(format!(" {}/{}", len, max), color)
+    };
+    s.call_on_name("input_size", |view: &mut TextView| {
+        view.set_content(counter)
+    });
+}
+
+/// Updates the connection indicator above the input line.
+fn set_status(s: &mut Cursive, text: &str, color: Color) {
+    s.call_on_name("status", |view: &mut TextView| {
+        view.set_content(StyledString::styled(text, color));
+    });
+}
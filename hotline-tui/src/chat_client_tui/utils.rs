@@ -43,12 +43,12 @@ pub fn print_textline_to_output(
         content.append(styled);
 
         // Auto-scroll if enabled
-        if let Ok(scroll) = auto_scroll.lock() {
-            if *scroll {
-                s.call_on_name("messages_scroll", |view: &mut ScrollView<TextView>| {
-                    view.scroll_to_bottom();
-                });
-            }
+        if let Ok(scroll) = auto_scroll.lock()
+            && *scroll
+        {
+            s.call_on_name("messages_scroll", |view: &mut ScrollView<TextView>| {
+                view.scroll_to_bottom();
+            });
         }
     }))
     .unwrap();
@@ -67,54 +67,62 @@ pub fn print_chat_message_to_output(
     sink.send(Box::new(move |s| {
         let mut styled = StyledString::new();
 
-        // Format timestamp
-        let local_time = message.timestamp.with_timezone(&Local).format("%H:%M:%S");
-        styled.append_styled(
-            format!("[{}] ", local_time),
-            Color::Light(BaseColor::Yellow),
-        );
+        // Backfilled history is dimmed so it can't be mistaken for live chat
+        let (time_color, name_color, content_color) = if message.is_history {
+            (
+                Color::Dark(BaseColor::White),
+                Color::Dark(BaseColor::Green),
+                Color::Dark(BaseColor::Cyan),
+            )
+        } else {
+            (
+                Color::Light(BaseColor::Yellow),
+                Color::Light(BaseColor::Green),
+                Color::Light(BaseColor::Cyan),
+            )
+        };
+
+        // Format timestamp, with the date for history since it may be old
+        let local_time = message.timestamp.with_timezone(&Local);
+        let local_time = if message.is_history {
+            local_time.format("%Y-%m-%d %H:%M:%S")
+        } else {
+            local_time.format("%H:%M:%S")
+        };
+        styled.append_styled(format!("[{}] ", local_time), time_color);
 
         // Format sender name
         let sender_name = message.username.unwrap_or(message.sender);
         let display_name = if message.is_self { "You" } else { &sender_name };
 
-        styled.append_styled(
-            format!("{}: ", display_name),
-            Color::Light(BaseColor::Green),
-        );
+        styled.append_styled(format!("{}: ", display_name), name_color);
+
+        // Encrypted messages are tagged; those we can't read stand out in red
+        let content_color = match message.encryption {
+            Encryption::Plain => content_color,
+            Encryption::Decrypted => {
+                styled.append_styled("[e2e] ", Color::Light(BaseColor::Magenta));
+                content_color
+            }
+            Encryption::Undecryptable => {
+                styled.append_styled("[e2e] ", Color::Light(BaseColor::Red));
+                Color::Light(BaseColor::Red)
+            }
+        };
 
         // Format content
-        styled.append_styled(
-            format!("{}\n", message.content),
-            Color::Light(BaseColor::Cyan),
-        );
+        styled.append_styled(format!("{}\n", message.content), content_color);
 
         content.append(styled);
 
         // Auto-scroll if enabled
-        if let Ok(scroll) = auto_scroll.lock() {
-            if *scroll {
-                s.call_on_name("messages_scroll", |view: &mut ScrollView<TextView>| {
-                    view.scroll_to_bottom();
-                });
-            }
+        if let Ok(scroll) = auto_scroll.lock()
+            && *scroll
+        {
+            s.call_on_name("messages_scroll", |view: &mut ScrollView<TextView>| {
+                view.scroll_to_bottom();
+            });
         }
     }))
     .unwrap();
 }
-
-pub fn set_custom_theme(siv: &mut cursive::CursiveRunnable) {
-    let mut theme = Theme::default();
-    let mut palette = Palette::default();
-
-    palette[PaletteColor::Background] = Color::TerminalDefault;
-    palette[PaletteColor::View] = Color::TerminalDefault;
-    palette[PaletteColor::Primary] = Color::Dark(BaseColor::Blue);
-    palette[PaletteColor::Secondary] = Color::Light(BaseColor::Blue);
-    palette[PaletteColor::Tertiary] = Color::Light(BaseColor::White);
-    palette[PaletteColor::TitlePrimary] = Color::Light(BaseColor::Green);
-    palette[PaletteColor::TitleSecondary] = Color::Dark(BaseColor::Green);
-
-    theme.palette = palette;
-    siv.set_theme(theme);
-}
@@ -4,11 +4,13 @@ pub use std::thread;
 pub use cursive::CbSink;
 pub use cursive::Cursive;
 pub use cursive::align::HAlign;
-pub use cursive::theme::{BaseColor, Color, Palette, PaletteColor, Theme};
+pub use cursive::theme::{BaseColor, Color};
 pub use cursive::traits::*;
 pub use cursive::utils::markup::StyledString;
 pub use cursive::views::ScrollView;
 pub use cursive::views::{Dialog, EditView, LinearLayout, TextContent, TextView};
+pub use std::net::SocketAddr;
+pub use std::path::PathBuf;
 pub use std::sync::Arc;
 pub use std::sync::Mutex;
 pub use std::sync::atomic::AtomicBool;
@@ -16,8 +18,12 @@ pub use std::sync::atomic::Ordering;
 pub use tokio::runtime::Runtime;
 pub use tokio::sync::mpsc;
 
-pub use crate::shared::global_quit;
+pub use crate::shared::{config, global_quit, set_custom_theme};
 
+pub use core::address::parse_bind_address;
+pub use core::commands::{ServerCommand, StartRequest};
+pub use core::config::HistoryBackend;
+pub use core::rooms::normalize_room_name;
 pub use core::serializable_colours::*;
 pub use core::server_backend::run_server_backend;
 pub use core::types::{ChatMessage, OutputEvent, SystemEvent, TextLine};
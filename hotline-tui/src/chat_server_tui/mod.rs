@@ -14,16 +14,18 @@ pub fn restart_server_tui(shutdown_signal: Arc<AtomicBool>, siv_cb_sink: cursive
     // Spawn a new thread to restart the TUI after a short delay
     std::thread::spawn(move || {
         std::thread::sleep(std::time::Duration::from_millis(200));
-        crate::chat_server_tui::run_server_tui();
+        crate::chat_server_tui::run_server_tui(None);
     });
 }
 
-pub fn run_server_tui() {
+/// Runs the server TUI. With a `start` command the setup dialog is skipped
+/// and the server starts right away.
+pub fn run_server_tui(start: Option<StartRequest>) {
     // Create a shared shutdown signal
     let shutdown_signal = Arc::new(AtomicBool::new(false));
 
     // Create standard synchronous channels for UI to communicate with the async thread
-    let (ui_to_async_tx, ui_to_async_rx) = std_mpsc::channel::<String>();
+    let (ui_to_async_tx, ui_to_async_rx) = std_mpsc::channel::<ServerCommand>();
     let (async_to_ui_tx, async_to_ui_rx) = std_mpsc::channel::<OutputEvent>();
 
     // Clone the shutdown signal for the async thread
@@ -37,7 +39,7 @@ pub fn run_server_tui() {
         // Run the async code in this runtime
         rt.block_on(async {
             // Create Tokio channels for the async code
-            let (input_tx, input_rx) = mpsc::channel::<String>(100);
+            let (input_tx, input_rx) = mpsc::channel::<ServerCommand>(100);
             let (output_tx, mut output_rx) = mpsc::channel::<OutputEvent>(100);
 
             // Bridge between std_mpsc and tokio channels
@@ -93,7 +95,12 @@ pub fn run_server_tui() {
     });
 
     // Run the UI in the main thread
-    server_tui(ui_to_async_tx, async_to_ui_rx, shutdown_signal.clone());
+    server_tui(
+        ui_to_async_tx,
+        async_to_ui_rx,
+        shutdown_signal.clone(),
+        start,
+    );
 
     // Wait for the async thread to finish
     let _ = async_thread.join();
@@ -3,32 +3,82 @@ use super::restart_server_tui;
 
 pub fn show_server_setup_dialog(
     siv: &mut Cursive,
-    input_tx: std_mpsc::Sender<String>,
+    input_tx: std_mpsc::Sender<ServerCommand>,
     content: TextContent,
     quit_signal: Arc<AtomicBool>,
 ) {
     // Clear existing content before showing setup
     content.set_content("");
 
-    // Create input fields for server configuration
-    let chatroom_input = EditView::new().with_name("chatroom").fixed_width(30);
+    // Create input fields for server configuration, pre-filled from the config file
+    let defaults = &config().server;
+    let chatroom_input = EditView::new()
+        .content(defaults.room.clone().unwrap_or_default())
+        .with_name("chatroom")
+        .fixed_width(30);
+    let bind_input = EditView::new()
+        .content(defaults.bind_address.clone())
+        .with_name("bind_address")
+        .fixed_width(30);
     let port_input = EditView::new()
-        .content("8080")
+        .content(defaults.port.to_string())
         .with_name("port")
         .fixed_width(10);
     let logging_input = EditView::new()
-        .content("yes")
+        .content(if defaults.logging { "yes" } else { "no" })
         .with_name("logging")
         .fixed_width(5);
+    let tls_input = EditView::new()
+        .content(if defaults.tls.enabled { "yes" } else { "no" })
+        .with_name("tls")
+        .fixed_width(5);
+    let password_input = EditView::new()
+        .content(defaults.access.password.clone().unwrap_or_default())
+        .secret()
+        .with_name("password")
+        .fixed_width(30);
+    let invite_only_input = EditView::new()
+        .content(if defaults.access.invite_only {
+            "yes"
+        } else {
+            "no"
+        })
+        .with_name("invite_only")
+        .fixed_width(5);
+    let history_input = EditView::new()
+        .content(match defaults.history {
+            HistoryBackend::Memory => "memory",
+            HistoryBackend::Disk => "disk",
+        })
+        .with_name("history")
+        .fixed_width(10);
+    let log_dir_input = EditView::new()
+        .content(defaults.log.directory.display().to_string())
+        .with_name("log_dir")
+        .fixed_width(30);
 
     // Create the layout for the dialog
     let layout = LinearLayout::vertical()
         .child(TextView::new("Chatroom Name:"))
         .child(chatroom_input)
+        .child(TextView::new(
+            "Bind Address (0.0.0.0, [::] for IPv4 and IPv6):",
+        ))
+        .child(bind_input)
         .child(TextView::new("Port:"))
         .child(port_input)
         .child(TextView::new("Enable Logging (yes/no):"))
-        .child(logging_input);
+        .child(logging_input)
+        .child(TextView::new("Require TLS (yes/no):"))
+        .child(tls_input)
+        .child(TextView::new("Chatroom Password (optional):"))
+        .child(password_input)
+        .child(TextView::new("Invite Only (yes/no):"))
+        .child(invite_only_input)
+        .child(TextView::new("History Storage (memory/disk):"))
+        .child(history_input)
+        .child(TextView::new("Log Directory:"))
+        .child(log_dir_input);
 
     // Create the dialog with buttons
     let dialog = Dialog::around(layout)
@@ -41,6 +91,12 @@ pub fn show_server_setup_dialog(
                 })
                 .unwrap_or_default();
 
+            let bind_address = s
+                .call_on_name("bind_address", |view: &mut EditView| {
+                    view.get_content().to_string()
+                })
+                .unwrap_or_default();
+
             let port = s
                 .call_on_name("port", |view: &mut EditView| view.get_content().to_string())
                 .unwrap_or("8080".to_string());
@@ -51,21 +107,78 @@ pub fn show_server_setup_dialog(
                 })
                 .unwrap_or("yes".to_string());
 
-            if chatroom.trim().is_empty() {
-                s.add_layer(Dialog::info("Please enter a chatroom name").title("Error"));
-                return;
-            }
+            let tls = s
+                .call_on_name("tls", |view: &mut EditView| view.get_content().to_string())
+                .unwrap_or("no".to_string());
+
+            let password = s
+                .call_on_name("password", |view: &mut EditView| {
+                    view.get_content().to_string()
+                })
+                .unwrap_or_default();
+
+            let invite_only = s
+                .call_on_name("invite_only", |view: &mut EditView| {
+                    view.get_content().to_string()
+                })
+                .unwrap_or("no".to_string());
+
+            let history = s
+                .call_on_name("history", |view: &mut EditView| {
+                    view.get_content().to_string()
+                })
+                .unwrap_or("memory".to_string());
+
+            let log_dir = s
+                .call_on_name("log_dir", |view: &mut EditView| {
+                    view.get_content().to_string()
+                })
+                .unwrap_or("logs".to_string());
+
+            let chatroom = match normalize_room_name(&chatroom) {
+                Ok(chatroom) => chatroom,
+                Err(e) => {
+                    s.add_layer(Dialog::info(e).title("Error"));
+                    return;
+                }
+            };
 
             // Try to parse port number
-            match port.trim().parse::<u16>() {
-                Ok(_) => {} // Valid port
+            let port = match port.trim().parse::<u16>() {
+                Ok(port) => port,
                 Err(_) => {
                     s.add_layer(
                         Dialog::info("Please enter a valid port number (0-65535)").title("Error"),
                     );
                     return;
                 }
-            }
+            };
+
+            let bind_ip = match parse_bind_address(&bind_address) {
+                Ok(ip) => ip,
+                Err(e) => {
+                    s.add_layer(Dialog::info(e).title("Error"));
+                    return;
+                }
+            };
+
+            let history = match history.trim().to_lowercase().as_str() {
+                "disk" => HistoryBackend::Disk,
+                _ => HistoryBackend::Memory,
+            };
+            let request = StartRequest {
+                chatroom,
+                bind_address: Some(bind_ip.to_string()),
+                port,
+                logging: logging.trim().eq_ignore_ascii_case("yes"),
+                history,
+                log_dir: Some(log_dir.trim())
+                    .filter(|d| !d.is_empty())
+                    .map(PathBuf::from),
+                tls: tls.trim().eq_ignore_ascii_case("yes"),
+                password: Some(password.trim().to_string()).filter(|p| !p.is_empty()),
+                invite_only: invite_only.trim().eq_ignore_ascii_case("yes"),
+            };
 
             // Remove the dialog
             s.pop_layer();
@@ -76,8 +189,9 @@ pub fn show_server_setup_dialog(
                 let mut styled = StyledString::new();
                 styled.append_styled(
                     format!(
-                        "Starting server for chatroom '{}' on port {}...\n",
-                        chatroom, port
+                        "Starting server for chatroom '{}' on {}...\n",
+                        request.chatroom,
+                        SocketAddr::new(bind_ip, port)
                     ),
                     Color::Light(BaseColor::Blue),
                 );
@@ -85,7 +199,7 @@ pub fn show_server_setup_dialog(
             });
 
             // Send server configuration to backend
-            let _ = input_tx.send(format!("START:{}:{}:{}", chatroom, port, logging));
+            let _ = input_tx.send(ServerCommand::Start(request));
         })
         .button("Quit", {
             let quit_signal = quit_signal.clone();
@@ -96,9 +210,10 @@ pub fn show_server_setup_dialog(
 }
 
 pub fn server_tui(
-    input_tx: std_mpsc::Sender<String>,
+    input_tx: std_mpsc::Sender<ServerCommand>,
     output_rx: std_mpsc::Receiver<OutputEvent>,
     shutdown_signal: Arc<AtomicBool>,
+    start: Option<StartRequest>,
 ) {
     let mut siv = cursive::default();
     set_custom_theme(&mut siv);
@@ -163,25 +278,39 @@ pub fn server_tui(
         .fixed_height(20);
 
     let input_label =
-        TextView::new("Enter message (type '/end' to stop server)").h_align(HAlign::Left);
+        TextView::new(
+            "Enter message ('/rooms', '/kick', '/ban', '/unban', '/mute', '/unmute', '/bans', '/forgetkey', '/unregister', '/invite', '/invites', '/revoke', '/role', '/roles', '/topic', '/end' to stop server)",
+        )
+            .h_align(HAlign::Left);
 
     let input_tx_clone = input_tx.clone();
     let shutdown_signal_clone = shutdown_signal.clone();
     let siv_cb_sink = siv.cb_sink().clone();
+    let input_content = content.clone();
+    let input_auto_scroll = auto_scroll.clone();
 
     let input = EditView::new()
-        .on_submit(move |s, text| {
-            if text != "/end" {
-                let _ = input_tx_clone.send(text.to_string());
+        .on_submit(move |s, text| match ServerCommand::parse(text) {
+            Ok(ServerCommand::Stop) => {
+                let _ = input_tx_clone.send(ServerCommand::Stop);
+                // Call restart_server_tui
+                restart_server_tui(shutdown_signal_clone.clone(), siv_cb_sink.clone());
+            }
+            Ok(command) => {
+                let _ = input_tx_clone.send(command);
                 s.call_on_name("input", |view: &mut EditView| {
                     view.set_content("");
                 });
-            } else {
-                // Send /end command to server
-                let _ = input_tx_clone.send("/end".to_string());
-                // Call restart_server_tui
-                restart_server_tui(shutdown_signal_clone.clone(), siv_cb_sink.clone());
             }
+            Err(usage) => print_textline_to_output(
+                &siv_cb_sink,
+                &input_content,
+                TextLine {
+                    text: format!("{}\n", usage),
+                    color: Some(RED_COLOR.clone()),
+                },
+                &input_auto_scroll,
+            ),
         })
         .with_name("input")
         .fixed_height(1);
@@ -242,13 +371,18 @@ pub fn server_tui(
         }
     });
 
-    // Show server setup dialog at startup
-    show_server_setup_dialog(
-        &mut siv,
-        input_tx.clone(),
-        content.clone(),
-        shutdown_signal.clone(),
-    );
+    // Show server setup dialog at startup unless the command line configured the server
+    match start {
+        Some(start) => {
+            let _ = input_tx.send(ServerCommand::Start(start));
+        }
+        None => show_server_setup_dialog(
+            &mut siv,
+            input_tx.clone(),
+            content.clone(),
+            shutdown_signal.clone(),
+        ),
+    }
 
     // Run the UI
     siv.run();
@@ -264,7 +398,7 @@ pub fn handle_system_event(
     siv_sink: &CbSink,
     content: &TextContent,
     event: SystemEvent,
-    _input_tx: std_mpsc::Sender<String>,
+    _input_tx: std_mpsc::Sender<ServerCommand>,
     shutdown_signal: Arc<AtomicBool>,
     auto_scroll: &Arc<Mutex<bool>>,
     siv_cb_sink: cursive::CbSink,
@@ -287,12 +421,12 @@ pub fn handle_system_event(
                 content.append(styled);
 
                 // Auto-scroll if enabled
-                if let Ok(scroll) = auto_scroll.lock() {
-                    if *scroll {
-                        s.call_on_name("messages_scroll", |view: &mut ScrollView<TextView>| {
-                            view.scroll_to_bottom();
-                        });
-                    }
+                if let Ok(scroll) = auto_scroll.lock()
+                    && *scroll
+                {
+                    s.call_on_name("messages_scroll", |view: &mut ScrollView<TextView>| {
+                        view.scroll_to_bottom();
+                    });
                 }
             }
             SystemEvent::ConnectionClosed => {
@@ -300,12 +434,12 @@ pub fn handle_system_event(
                 content.append(styled);
 
                 // Auto-scroll if enabled
-                if let Ok(scroll) = auto_scroll.lock() {
-                    if *scroll {
-                        s.call_on_name("messages_scroll", |view: &mut ScrollView<TextView>| {
-                            view.scroll_to_bottom();
-                        });
-                    }
+                if let Ok(scroll) = auto_scroll.lock()
+                    && *scroll
+                {
+                    s.call_on_name("messages_scroll", |view: &mut ScrollView<TextView>| {
+                        view.scroll_to_bottom();
+                    });
                 }
             }
             SystemEvent::ConnectionError { message } => {
@@ -316,12 +450,12 @@ pub fn handle_system_event(
                 content.append(styled);
 
                 // Auto-scroll if enabled
-                if let Ok(scroll) = auto_scroll.lock() {
-                    if *scroll {
-                        s.call_on_name("messages_scroll", |view: &mut ScrollView<TextView>| {
-                            view.scroll_to_bottom();
-                        });
-                    }
+                if let Ok(scroll) = auto_scroll.lock()
+                    && *scroll
+                {
+                    s.call_on_name("messages_scroll", |view: &mut ScrollView<TextView>| {
+                        view.scroll_to_bottom();
+                    });
                 }
 
                 // Call restart_server_tui after a brief delay
@@ -337,15 +471,19 @@ pub fn handle_system_event(
                 content.append(styled);
 
                 // Auto-scroll if enabled
-                if let Ok(scroll) = auto_scroll.lock() {
-                    if *scroll {
-                        s.call_on_name("messages_scroll", |view: &mut ScrollView<TextView>| {
-                            view.scroll_to_bottom();
-                        });
-                    }
+                if let Ok(scroll) = auto_scroll.lock()
+                    && *scroll
+                {
+                    s.call_on_name("messages_scroll", |view: &mut ScrollView<TextView>| {
+                        view.scroll_to_bottom();
+                    });
                 }
             }
-            SystemEvent::RateLimit { .. } => {
+            SystemEvent::RateLimit { .. }
+            | SystemEvent::Reconnecting { .. }
+            | SystemEvent::MessageLimit { .. }
+            | SystemEvent::CertificateChanged { .. }
+            | SystemEvent::RoomStatus { .. } => {
                 // Rate limit events are handled by the backend, no need to show in UI
             }
         }
@@ -375,12 +513,12 @@ pub fn print_textline_to_output(
         content.append(styled);
 
         // Auto-scroll if enabled
-        if let Ok(scroll) = auto_scroll.lock() {
-            if *scroll {
-                s.call_on_name("messages_scroll", |view: &mut ScrollView<TextView>| {
-                    view.scroll_to_bottom();
-                });
-            }
+        if let Ok(scroll) = auto_scroll.lock()
+            && *scroll
+        {
+            s.call_on_name("messages_scroll", |view: &mut ScrollView<TextView>| {
+                view.scroll_to_bottom();
+            });
         }
     }))
     .unwrap();
@@ -404,6 +542,11 @@ pub fn print_chat_message_to_output(
             msg.sender.clone()
         };
 
+        // Prefix messages with the room they were posted in
+        if let Some(room) = &msg.room {
+            styled.append_styled(format!("[#{}] ", room), Color::Light(BaseColor::Cyan));
+        }
+
         // Format based on whether this is a message from the host or not
         if msg.is_self {
             styled.append_styled(format!("{}: ", sender_name), Color::Light(BaseColor::Blue));
@@ -416,29 +559,13 @@ pub fn print_chat_message_to_output(
         content.append(styled);
 
         // Auto-scroll if enabled
-        if let Ok(scroll) = auto_scroll.lock() {
-            if *scroll {
-                s.call_on_name("messages_scroll", |view: &mut ScrollView<TextView>| {
-                    view.scroll_to_bottom();
-                });
-            }
+        if let Ok(scroll) = auto_scroll.lock()
+            && *scroll
+        {
+            s.call_on_name("messages_scroll", |view: &mut ScrollView<TextView>| {
+                view.scroll_to_bottom();
+            });
         }
     }))
     .unwrap();
 }
-
-pub fn set_custom_theme(siv: &mut cursive::CursiveRunnable) {
-    let mut theme = Theme::default();
-    let mut palette = Palette::default();
-
-    palette[PaletteColor::Background] = Color::TerminalDefault;
-    palette[PaletteColor::View] = Color::TerminalDefault;
-    palette[PaletteColor::Primary] = Color::Dark(BaseColor::Blue);
-    palette[PaletteColor::Secondary] = Color::Light(BaseColor::Blue);
-    palette[PaletteColor::Tertiary] = Color::Light(BaseColor::White);
-    palette[PaletteColor::TitlePrimary] = Color::Light(BaseColor::Green);
-    palette[PaletteColor::TitleSecondary] = Color::Dark(BaseColor::Green);
-
-    theme.palette = palette;
-    siv.set_theme(theme);
-}
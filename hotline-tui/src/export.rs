@@ -0,0 +1,39 @@
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::Path;
+
+use core::transcript::{ExportFormat, export_transcript, read_transcript};
+
+/// Renders `transcript` and writes it to `output`, or stdout if none is given.
+/// Without an explicit format it is guessed from the output file name.
+pub fn run_export(
+    transcript: &Path,
+    format: Option<ExportFormat>,
+    output: Option<&Path>,
+) -> Result<()> {
+    let format = format
+        .or_else(|| output.and_then(ExportFormat::from_extension))
+        .unwrap_or(ExportFormat::Text);
+
+    let read = read_transcript(transcript)?;
+    if let Some(first) = read.first_skipped {
+        eprintln!(
+            "Warning: skipped {} malformed line(s) in {}, the first at line {}",
+            read.skipped,
+            transcript.display(),
+            first
+        );
+    }
+    let title = transcript
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "Chat transcript".to_string());
+    let rendered = export_transcript(&read.frames, format, &title);
+
+    match output {
+        Some(path) => fs::write(path, rendered)
+            .with_context(|| format!("Failed to write {}", path.display()))?,
+        None => print!("{}", rendered),
+    }
+    Ok(())
+}
@@ -0,0 +1,129 @@
+use anyhow::Result;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc;
+
+use core::commands::{ServerCommand, StartRequest};
+use core::server_backend::run_server_backend;
+use core::types::{ChatMessage, OutputEvent, SystemEvent};
+
+/// Runs the server without a terminal UI, printing every event to stdout.
+/// Lines typed on stdin are handled like the server TUI's input box, so host
+/// commands still work when a terminal is attached. Ctrl-C or SIGTERM stops
+/// the server cleanly.
+pub fn run_headless_server(start: StartRequest) -> Result<()> {
+    let rt = Runtime::new()?;
+    let result = rt.block_on(async {
+        let shutdown_signal = Arc::new(AtomicBool::new(false));
+        let (input_tx, input_rx) = mpsc::channel::<ServerCommand>(100);
+        let (output_tx, mut output_rx) = mpsc::channel::<OutputEvent>(100);
+
+        input_tx.send(ServerCommand::Start(start)).await?;
+
+        let printer = tokio::spawn(async move {
+            while let Some(event) = output_rx.recv().await {
+                print_event(event);
+            }
+        });
+
+        // Forward stdin; under a supervisor it is usually closed, which is fine
+        let stdin_tx = input_tx.clone();
+        tokio::spawn(async move {
+            let mut lines = BufReader::new(tokio::io::stdin()).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let command = match ServerCommand::parse(&line) {
+                    Ok(command) => command,
+                    Err(usage) => {
+                        eprintln!("{}", usage);
+                        continue;
+                    }
+                };
+                if stdin_tx.send(command).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let signal_shutdown = Arc::clone(&shutdown_signal);
+        let signal_tx = input_tx.clone();
+        tokio::spawn(async move {
+            wait_for_shutdown().await;
+            signal_shutdown.store(true, Ordering::SeqCst);
+            let _ = signal_tx.send(ServerCommand::Stop).await;
+        });
+
+        let result = run_server_backend(input_rx, output_tx, shutdown_signal).await;
+        // Connected clients keep the output channel open, so only drain briefly
+        let _ = tokio::time::timeout(Duration::from_millis(500), printer).await;
+        result
+    });
+
+    // Don't wait for the stdin reader, it may be blocked on a terminal read
+    rt.shutdown_background();
+    result
+}
+
+#[cfg(unix)]
+async fn wait_for_shutdown() {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    match signal(SignalKind::terminate()) {
+        Ok(mut terminate) => {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = terminate.recv() => {}
+            }
+        }
+        Err(_) => {
+            let _ = tokio::signal::ctrl_c().await;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_shutdown() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+fn print_event(event: OutputEvent) {
+    match event {
+        OutputEvent::TextLine(line) => println!("{}", line.text.trim_end()),
+        OutputEvent::ChatMessage(msg) => println!("{}", format_chat_message(&msg)),
+        OutputEvent::SystemEvent(event) => match event {
+            SystemEvent::ConnectionEstablished { address } => {
+                println!("Connection established: {}", address)
+            }
+            SystemEvent::ConnectionError { message } => eprintln!("Error: {}", message),
+            SystemEvent::PromptInput { prompt } => println!("{}", prompt),
+            // Per-client disconnects and rate limits are already reported as text
+            SystemEvent::ConnectionClosed
+            | SystemEvent::RateLimit { .. }
+            | SystemEvent::Reconnecting { .. }
+            | SystemEvent::MessageLimit { .. }
+            | SystemEvent::CertificateChanged { .. }
+            | SystemEvent::RoomStatus { .. } => {}
+        },
+    }
+}
+
+fn format_chat_message(msg: &ChatMessage) -> String {
+    let sender = msg.username.as_deref().unwrap_or(&msg.sender);
+    let room = msg
+        .room
+        .as_ref()
+        .map(|room| format!("[#{}] ", room))
+        .unwrap_or_default();
+    format!(
+        "[{}] {}{}: {}",
+        msg.timestamp.format("%Y-%m-%d %H:%M:%S"),
+        room,
+        sender,
+        msg.content
+    )
+}
@@ -1,13 +1,215 @@
 mod chat_client_tui;
 mod chat_server_tui;
+mod export;
+mod headless;
 mod shared;
 
+use clap::{Parser, Subcommand};
+use core::address::parse_bind_address;
+use core::commands::{ConnectRequest, StartRequest};
+use core::config::HistoryBackend;
+use core::rooms::normalize_room_name;
+use core::transcript::ExportFormat;
 use cursive::align::HAlign;
-use cursive::theme::{BaseColor, Color, Palette, PaletteColor, Theme};
 use cursive::traits::*;
 use cursive::views::{Dialog, EditView, LinearLayout, TextView};
+use std::path::PathBuf;
+
+use shared::{config, init_config, set_custom_theme};
+
+#[derive(Parser)]
+#[command(about = "Terminal chat server and client")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Render a JSONL chat transcript as plain text, Markdown or HTML
+    Export {
+        /// Transcript written by a server with logging enabled
+        transcript: PathBuf,
+        /// text, markdown or html; guessed from --output when omitted
+        #[arg(short, long)]
+        format: Option<ExportFormat>,
+        /// Write to this file instead of stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Host a chatroom; unset options come from the config file
+    Server {
+        /// Name of the default room
+        #[arg(long)]
+        room: Option<String>,
+        /// IP address to listen on, e.g. 0.0.0.0 or [::] for IPv4 and IPv6
+        #[arg(long)]
+        bind: Option<String>,
+        #[arg(short, long)]
+        port: Option<u16>,
+        /// Enable logging and write transcripts to this directory
+        #[arg(long, conflicts_with = "no_log")]
+        log_dir: Option<PathBuf>,
+        /// Disable logging even if the config enables it
+        #[arg(long)]
+        no_log: bool,
+        /// Keep message history on disk instead of in memory
+        #[arg(long)]
+        persist_history: bool,
+        /// Accept only TLS connections, generating a self-signed certificate
+        /// if the configured one doesn't exist yet
+        #[arg(long)]
+        tls: bool,
+        /// Require this password from clients; prefer server.access.password
+        /// in the config, which other users can't see in the process list
+        #[arg(long)]
+        password: Option<String>,
+        /// Require the password or an invite token minted with /invite
+        #[arg(long)]
+        invite_only: bool,
+        /// Run without the terminal UI, printing events to stdout
+        #[arg(long)]
+        headless: bool,
+    },
+    /// Connect to a chatroom; unset options come from the config file
+    Client {
+        /// One of the saved servers in the config file
+        #[arg(long, conflicts_with = "host")]
+        server: Option<String>,
+        /// Hostname or IP address, optionally with a port as host:port or [::1]:port
+        #[arg(long)]
+        host: Option<String>,
+        #[arg(short, long)]
+        port: Option<u16>,
+        /// Stay anonymous when neither this nor the config sets a name
+        #[arg(short, long)]
+        user: Option<String>,
+        /// Connect over TLS, pinning the server's certificate on first use
+        #[arg(long)]
+        tls: bool,
+        /// Chatroom password or invite token, for servers that require one
+        #[arg(long)]
+        password: Option<String>,
+    },
+}
 
 fn main() {
+    let cli = Cli::parse();
+
+    if let Err(e) = init_config() {
+        eprintln!("Error: {:#}", e);
+        std::process::exit(1);
+    }
+
+    match cli.command {
+        Some(Command::Export {
+            transcript,
+            format,
+            output,
+        }) => {
+            if let Err(e) = export::run_export(&transcript, format, output.as_deref()) {
+                eprintln!("Error: {:#}", e);
+                std::process::exit(1);
+            }
+        }
+        Some(Command::Server {
+            room,
+            bind,
+            port,
+            log_dir,
+            no_log,
+            persist_history,
+            tls,
+            password,
+            invite_only,
+            headless,
+        }) => {
+            let defaults = &config().server;
+            let Some(room) = room.or_else(|| defaults.room.clone()) else {
+                eprintln!("Error: no room given; pass --room or set server.room in the config");
+                std::process::exit(2);
+            };
+            if let Some(Err(e)) = bind.as_deref().map(parse_bind_address) {
+                eprintln!("Error: {}", e);
+                std::process::exit(2);
+            }
+            let room = normalize_room_name(&room).unwrap_or_else(|e| {
+                eprintln!("Error: {}", e);
+                std::process::exit(2);
+            });
+            let start = StartRequest {
+                chatroom: room,
+                bind_address: bind,
+                port: port.unwrap_or(defaults.port),
+                logging: !no_log && (log_dir.is_some() || defaults.logging),
+                history: if persist_history {
+                    HistoryBackend::Disk
+                } else {
+                    defaults.history
+                },
+                log_dir,
+                tls: tls || defaults.tls.enabled,
+                password: password.or_else(|| defaults.access.password.clone()),
+                invite_only: invite_only || defaults.access.invite_only,
+            };
+            if headless {
+                if let Err(e) = headless::run_headless_server(start) {
+                    eprintln!("Server error: {:#}", e);
+                    std::process::exit(1);
+                }
+            } else {
+                chat_server_tui::run_server_tui(Some(start));
+            }
+        }
+        Some(Command::Client {
+            server,
+            host,
+            port,
+            user,
+            tls,
+            password,
+        }) => {
+            let defaults = &config().client;
+            let saved = match &server {
+                Some(name) => match defaults.servers.iter().find(|s| s.name == *name) {
+                    Some(saved) => Some(saved),
+                    None => {
+                        eprintln!("Error: no saved server named '{}' in the config", name);
+                        std::process::exit(2);
+                    }
+                },
+                None if host.is_none() => defaults.default_server(),
+                None => None,
+            };
+            let Some(host) = host.or_else(|| saved.map(|s| s.host.clone())) else {
+                eprintln!("Error: no host given; pass --host or save a server in the config");
+                std::process::exit(2);
+            };
+            let port = port.or(saved.map(|s| s.port)).unwrap_or(8080);
+            let user = user
+                .or_else(|| saved.and_then(|s| s.username.clone()))
+                .or_else(|| defaults.username.clone())
+                .unwrap_or_default();
+            let tls = tls || saved.is_some_and(|s| s.tls);
+            let password = password.or_else(|| saved.and_then(|s| s.password.clone()));
+            let connect = match ConnectRequest::from_input(&host, port, &user) {
+                Ok(request) => ConnectRequest {
+                    tls,
+                    password,
+                    ..request
+                },
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(2);
+                }
+            };
+            chat_client_tui::run_chat_tui(Some(connect));
+        }
+        None => run_mode_selection(),
+    }
+}
+
+fn run_mode_selection() {
     let mut siv = cursive::default();
     set_custom_theme(&mut siv);
 
@@ -33,7 +235,7 @@ fn show_mode_selection(siv: &mut cursive::Cursive) {
                     s.quit(); // Quit the current Cursive instance
 
                     // Start the server TUI
-                    chat_server_tui::run_server_tui();
+                    chat_server_tui::run_server_tui(None);
                 }
                 "2" => {
                     // Client mode
@@ -41,7 +243,7 @@ fn show_mode_selection(siv: &mut cursive::Cursive) {
                     s.quit(); // Quit the current Cursive instance
 
                     // Start the chat client TUI
-                    chat_client_tui::run_chat_tui();
+                    chat_client_tui::run_chat_tui(None);
                 }
                 _ => {
                     // Invalid option
@@ -62,20 +264,3 @@ fn show_mode_selection(siv: &mut cursive::Cursive) {
     // Add the layout to the screen
     siv.add_layer(Dialog::around(layout).title("Hotline Chat"));
 }
-
-/// Sets a custom theme for the TUI
-fn set_custom_theme(siv: &mut cursive::CursiveRunnable) {
-    let mut theme = Theme::default();
-    let mut palette = Palette::default();
-
-    palette[PaletteColor::Background] = Color::TerminalDefault;
-    palette[PaletteColor::View] = Color::TerminalDefault;
-    palette[PaletteColor::Primary] = Color::Dark(BaseColor::Blue);
-    palette[PaletteColor::Secondary] = Color::Light(BaseColor::Blue);
-    palette[PaletteColor::Tertiary] = Color::Light(BaseColor::White);
-    palette[PaletteColor::TitlePrimary] = Color::Light(BaseColor::Green);
-    palette[PaletteColor::TitleSecondary] = Color::Dark(BaseColor::Green);
-
-    theme.palette = palette;
-    siv.set_theme(theme);
-}
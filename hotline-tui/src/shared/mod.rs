@@ -21,3 +21,50 @@ pub fn global_quit(siv_sink: &CbSink, shutdown_signal: &Arc<AtomicBool>) {
         std::process::exit(0);
     });
 }
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// Reads the config files once at startup. Must run before `config()` is
+/// first used, otherwise the defaults stick.
+pub fn init_config() -> anyhow::Result<()> {
+    let _ = CONFIG.set(Config::load()?);
+    Ok(())
+}
+
+pub fn config() -> &'static Config {
+    CONFIG.get_or_init(Config::default)
+}
+
+/// Sets a custom theme for the TUI, with colours from the config's `[theme]`
+/// table taking precedence over the built-in palette
+pub fn set_custom_theme(siv: &mut cursive::CursiveRunnable) {
+    let mut theme = Theme::default();
+    let mut palette = Palette::default();
+
+    palette[PaletteColor::Background] = Color::TerminalDefault;
+    palette[PaletteColor::View] = Color::TerminalDefault;
+    palette[PaletteColor::Primary] = Color::Dark(BaseColor::Blue);
+    palette[PaletteColor::Secondary] = Color::Light(BaseColor::Blue);
+    palette[PaletteColor::Tertiary] = Color::Light(BaseColor::White);
+    palette[PaletteColor::TitlePrimary] = Color::Light(BaseColor::Green);
+    palette[PaletteColor::TitleSecondary] = Color::Dark(BaseColor::Green);
+
+    let overrides = &config().theme;
+    let configured: [(PaletteColor, &Option<SerializableColor>); 7] = [
+        (PaletteColor::Background, &overrides.background),
+        (PaletteColor::View, &overrides.view),
+        (PaletteColor::Primary, &overrides.primary),
+        (PaletteColor::Secondary, &overrides.secondary),
+        (PaletteColor::Tertiary, &overrides.tertiary),
+        (PaletteColor::TitlePrimary, &overrides.title_primary),
+        (PaletteColor::TitleSecondary, &overrides.title_secondary),
+    ];
+    for (slot, color) in configured {
+        if let Some(color) = color {
+            palette[slot] = color.clone().into();
+        }
+    }
+
+    theme.palette = palette;
+    siv.set_theme(theme);
+}
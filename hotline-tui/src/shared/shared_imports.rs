@@ -1,5 +1,9 @@
+pub use core::config::Config;
+pub use core::types::SerializableColor;
 pub use cursive::CbSink;
+pub use cursive::theme::{BaseColor, Color, Palette, PaletteColor, Theme};
 pub use std::sync::Arc;
+pub use std::sync::OnceLock;
 pub use std::sync::atomic::AtomicBool;
 pub use std::sync::atomic::Ordering;
 pub use tokio::time::Duration;